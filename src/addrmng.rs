@@ -10,14 +10,13 @@ use std::cmp::PartialEq;
 
 use std::io::net::ip::SocketAddr;
 use std::io::net::ip::IpAddr;
-use std::io::net::ip::Ipv4Addr;
-use std::io::net::ip::Ipv6Addr;
 use std::io::Timer;
 use std::time::duration::Duration;
 use std::iter::AdditiveIterator;
 use std::rand::Rng;
 use std::comm::Handle;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
 use self::time::Timespec;
 
@@ -26,18 +25,17 @@ use std::comm::Select;
 use std::comm::Empty;
 use std::comm::Disconnected;
 
-use datatype::netaddr::NetAddr;
+use datatype::netaddr::NetAddrV2;
+use datatype::netaddr::NetworkId;
 use crypto::rand_interval;
 use comm::DuplexChannel;
+use config::Config;
+use peer::PeerInfo;
 
 pub const ADDRMNG_CHANNEL_BUF_CAP : uint = 8;
 
-const MAX_ADDRESSES : uint = 2500;
 const BUCKETS : uint = 64;
 
-const MAX_ADDRS_PER_PEER : uint = (0.02*(MAX_ADDRESSES as f32)) as uint;
-const MAX_ADDRS_PER_BUCKET : uint = MAX_ADDRESSES/BUCKETS;
-
 const ANNOUNCE_SOME_ADDRS_MIN : uint = 5;
 const ANNOUNCE_SOME_ADDRS_MAX : uint = 25;
 
@@ -53,15 +51,32 @@ type PeerChannel = DuplexChannel<AddrManagerReply,AddrManagerRequest>;
 
 pub enum AddrManagerRequest
 {
-    AddrMngAddAddresses(IpAddr, Vec<NetAddr>),
+    AddrMngAddAddresses(IpAddr, Vec<NetAddrV2>),
     AddrMngAddPeerChannel(PeerChannel),
     AddrMngGetSomeAddresses,
-    AddrMngGetManyAddresses
+    AddrMngGetManyAddresses,
+    AddrMngSetLocalAddress(NetAddrV2),
+    AddrMngGetLocalAddress,
+    AddrMngUpdatePeer(PeerInfo),
+    AddrMngRemovePeer(SocketAddr),
+    AddrMngGetPeers,
+    AddrMngDisconnectPeer(SocketAddr),
+    AddrMngBan(IpAddr, Timespec),
+    AddrMngUnban(IpAddr),
+    AddrMngIsBanned(IpAddr),
+    AddrMngGetBucketSizes,
+    AddrMngGetNetworkSizes
 }
 
 pub enum AddrManagerReply
 {
-    AddrMngAddresses(Vec<NetAddr>)
+    AddrMngAddresses(Vec<NetAddrV2>),
+    AddrMngLocalAddress(Option<NetAddrV2>),
+    AddrMngKeepPeer(bool),
+    AddrMngPeers(Vec<(uint,PeerInfo)>),
+    AddrMngResult(bool),
+    AddrMngBucketSizes(Vec<uint>),
+    AddrMngNetworkSizes(Vec<uint>)
 }
 
 impl Show for AddrManagerRequest
@@ -77,7 +92,29 @@ impl Show for AddrManagerRequest
             AddrManagerRequest::AddrMngGetSomeAddresses =>
                 write!(f,"Some addresses request"),
             AddrManagerRequest::AddrMngGetManyAddresses =>
-                write!(f,"Many addresses request")
+                write!(f,"Many addresses request"),
+            AddrManagerRequest::AddrMngSetLocalAddress(ref addr) =>
+                write!(f,"Set local address: {}",addr),
+            AddrManagerRequest::AddrMngGetLocalAddress =>
+                write!(f,"Local address request"),
+            AddrManagerRequest::AddrMngUpdatePeer(ref info) =>
+                write!(f,"{}: Update peer",info.addr),
+            AddrManagerRequest::AddrMngRemovePeer(ref addr) =>
+                write!(f,"{}: Remove peer",addr),
+            AddrManagerRequest::AddrMngGetPeers =>
+                write!(f,"Peers request"),
+            AddrManagerRequest::AddrMngDisconnectPeer(ref addr) =>
+                write!(f,"{}: Disconnect peer",addr),
+            AddrManagerRequest::AddrMngBan(ref ip, ref until) =>
+                write!(f,"{}: Ban until {}",ip,until.sec),
+            AddrManagerRequest::AddrMngUnban(ref ip) =>
+                write!(f,"{}: Unban",ip),
+            AddrManagerRequest::AddrMngIsBanned(ref ip) =>
+                write!(f,"{}: Is banned request",ip),
+            AddrManagerRequest::AddrMngGetBucketSizes =>
+                write!(f,"Bucket sizes request"),
+            AddrManagerRequest::AddrMngGetNetworkSizes =>
+                write!(f,"Network sizes request")
         }
     }
 }
@@ -89,21 +126,36 @@ impl Show for AddrManagerReply
         match *self
         {
             AddrManagerReply::AddrMngAddresses(ref addrs) =>
-                write!(f,"Addresses: {}",addrs)
+                write!(f,"Addresses: {}",addrs),
+            AddrManagerReply::AddrMngLocalAddress(ref addr) =>
+                write!(f,"Local address: {}",addr),
+            AddrManagerReply::AddrMngKeepPeer(keep) =>
+                write!(f,"Keep peer: {}",keep),
+            AddrManagerReply::AddrMngPeers(ref peers) =>
+                write!(f,"Peers: {}",peers.len()),
+            AddrManagerReply::AddrMngResult(result) =>
+                write!(f,"Result: {}",result),
+            AddrManagerReply::AddrMngBucketSizes(ref sizes) =>
+                write!(f,"Bucket sizes: {}",sizes),
+            AddrManagerReply::AddrMngNetworkSizes(ref sizes) =>
+                write!(f,"Network sizes: {}",sizes)
         }
     }
 }
 
+/* Addresses of all the networks are told apart by network, bytes and port */
+type AddrKey = (u8, Vec<u8>, u16);
+
 #[deriving(Clone)]
 struct Address
 {
     pub peer    : IpAddr,
-    pub netaddr : NetAddr
+    pub netaddr : NetAddrV2
 }
 
 impl Address
 {
-    pub fn new(netaddr : NetAddr, peer : IpAddr) -> Address
+    pub fn new(netaddr : NetAddrV2, peer : IpAddr) -> Address
     {
         Address
         {
@@ -123,13 +175,18 @@ impl Address
 
         age > Duration::minutes(OLD_ADDRESS_AGE_M as i64)
     }
+
+    pub fn get_key(&self) -> AddrKey
+    {
+        (self.netaddr.network, self.netaddr.addr.clone(), self.netaddr.port)
+    }
 }
 
 impl Hash for Address
 {
     fn hash(&self, state: &mut SipState)
     {
-        self.netaddr.addr.hash(state);
+        self.get_key().hash(state);
     }
 }
 
@@ -137,7 +194,7 @@ impl PartialEq for Address
 {
     fn eq(&self, other: &Address) -> bool
     {
-        self.netaddr.addr == other.netaddr.addr
+        self.get_key() == other.get_key()
     }
 }
 
@@ -146,14 +203,26 @@ impl Eq for Address {}
 pub struct AddrManager
 {
     channels       : Vec<PeerChannel>,
-    addresses      : Vec<HashMap<SocketAddr,Address>>,
+    addresses      : Vec<HashMap<AddrKey,Address>>,
     addrs_per_peer : HashMap<IpAddr,uint>,
-    secret         : [u8, ..256]
+    secret         : [u8, ..256],
+    /* Address we are reachable at, i.e. our onion service */
+    local_address  : Option<NetAddrV2>,
+    /* Limits derived from the maximum number of addresses we keep */
+    max_per_peer   : uint,
+    max_per_bucket : uint,
+    /* Connected peers, as they last told us, by the id we gave them */
+    peers          : HashMap<SocketAddr,(uint,PeerInfo)>,
+    next_peer_id   : uint,
+    /* Peers to tell to go away next time they update */
+    to_disconnect  : HashSet<SocketAddr>,
+    /* Until when */
+    banned         : HashMap<IpAddr,Timespec>
 }
 
 impl AddrManager
 {
-    pub fn new(orchestrator : PeerChannel) -> AddrManager
+    pub fn new(orchestrator : PeerChannel, config : &Config) -> AddrManager
     {
         let mut channels = Vec::with_capacity(512);
         let mut secret : [u8, ..256] = [0u8, ..256];
@@ -167,7 +236,14 @@ impl AddrManager
             channels:       channels,
             addresses:      Vec::from_fn(BUCKETS, |_| HashMap::new()),
             addrs_per_peer: HashMap::with_capacity(512),
-            secret:         secret
+            secret:         secret,
+            local_address:  None,
+            max_per_peer:   (0.02*(config.max_addresses as f32)) as uint,
+            max_per_bucket: config.max_addresses/BUCKETS,
+            peers:          HashMap::new(),
+            next_peer_id:   0,
+            to_disconnect:  HashSet::new(),
+            banned:         HashMap::new()
         }
     }
 
@@ -206,23 +282,29 @@ impl AddrManager
         self.addresses.iter().map(|b| b.len()).sum()
     }
 
-    /* We only take into account the /12 subnet.
+    /* We only take into account the network and the /12 subnet for IPv4, the
+     * /32 for IPv6 and the first 4 bits for the others.
      */
-    fn get_bucket_idx(&self, socketaddr : &SocketAddr) -> uint
+    fn get_bucket_idx(&self, netaddr : &NetAddrV2) -> uint
     {
         let mut data : Vec<u8> = Vec::new();
+        let a : &[u8] = netaddr.addr.as_slice();
 
         data.push_all(&self.secret);
+        data.push(netaddr.network);
 
-        match *socketaddr
+        if netaddr.network == NetworkId::NetIPv4 as u8
         {
-            SocketAddr { ip: Ipv6Addr(..), port: _ } =>
-                unimplemented!(), /* TODO */
-            SocketAddr { ip: Ipv4Addr(h0,h1,_,_), port: _ } =>
-            {
-                data.push(h0);
-                data.push(h1&0xf0);
-            }
+            data.push(a[0]);
+            data.push(a[1]&0xf0);
+        }
+        else if netaddr.network == NetworkId::NetIPv6 as u8
+        {
+            data.push_all(a.slice_to(4));
+        }
+        else
+        {
+            data.push(a[0]&0xf0);
         }
 
         data.push_all(&self.secret);
@@ -238,59 +320,57 @@ impl AddrManager
         {
             if address.is_old()
             {
-                to_remove.push(*address);
+                to_remove.push(address.clone());
             }
         }
 
         for address in to_remove.iter()
         {
-            let socketaddr : &SocketAddr = &address.netaddr.addr.unwrap();
-
-            self.addresses[bucket].remove(socketaddr);
+            self.addresses[bucket].remove(&address.get_key());
             self.dec_peer_addresses(&address.peer);
         }
     }
 
-    fn get_known_address_time(&self, socketaddr : &SocketAddr) -> Option<Timespec>
+    fn get_known_address_time(&self, address : &Address) -> Option<Timespec>
     {
-        let bucket : uint = self.get_bucket_idx(socketaddr);
+        let bucket : uint = self.get_bucket_idx(&address.netaddr);
         let known_addr : Option<&Address>;
 
-        known_addr = self.addresses[bucket].get(socketaddr);
+        known_addr = self.addresses[bucket].get(&address.get_key());
 
         known_addr.map(|address| address.netaddr.time.unwrap())
     }
 
     fn update_address_timestamp(&mut self, address : Address)
     {
-        let socketaddr : &SocketAddr = &address.netaddr.addr.unwrap();
-        let bucket : uint = self.get_bucket_idx(socketaddr);
+        let key : AddrKey = address.get_key();
+        let bucket : uint = self.get_bucket_idx(&address.netaddr);
         let old : Address;
         let new_addr : bool;
 
         /* We might be changing the peer of the address */
-        old = self.addresses[bucket].get(socketaddr).unwrap().clone();
+        old = self.addresses[bucket].get(&key).unwrap().clone();
         self.dec_peer_addresses(&old.peer);
 
         assert!(old.netaddr.time.unwrap() < address.netaddr.time.unwrap());
 
-        new_addr = self.addresses[bucket].insert(*socketaddr,address).is_none();
-        self.inc_peer_addresses(&address.peer);
-
-        ::logger::log_addr_mng_timestamp_update(&address.netaddr.addr.unwrap(),
+        ::logger::log_addr_mng_timestamp_update(&address.netaddr,
                                                 &old.netaddr.time.unwrap(),
                                                 &address.netaddr.time.unwrap());
+
+        self.inc_peer_addresses(&address.peer);
+        new_addr = self.addresses[bucket].insert(key,address).is_none();
+
         assert!(!new_addr);
     }
 
     fn add_address(&mut self, address : Address)
     {
-        let socketaddr : &SocketAddr = &address.netaddr.addr.unwrap();
-        let bucket : uint = self.get_bucket_idx(socketaddr);
+        let bucket : uint = self.get_bucket_idx(&address.netaddr);
         let known_addr_time : Option<time::Timespec>;
         let new_addr : bool;
 
-        known_addr_time = self.get_known_address_time(socketaddr);
+        known_addr_time = self.get_known_address_time(&address);
 
         /* Refresh known address timestamp */
         if known_addr_time.is_some()
@@ -304,7 +384,7 @@ impl AddrManager
         }
 
         if !self.allow_peer_to_add(&address.peer)
-            || self.addresses[bucket].len() > MAX_ADDRS_PER_BUCKET
+            || self.addresses[bucket].len() > self.max_per_bucket
         {
             return;
         }
@@ -312,8 +392,8 @@ impl AddrManager
         assert!(known_addr_time.is_none());
         assert!(!address.is_old());
 
-        new_addr = self.addresses[bucket].insert(*socketaddr,address).is_none();
         self.inc_peer_addresses(&address.peer);
+        new_addr = self.addresses[bucket].insert(address.get_key(),address).is_none();
 
         assert!(new_addr);
 
@@ -358,18 +438,18 @@ impl AddrManager
     {
         match self.addrs_per_peer.get(peer)
         {
-            Some(num) => *num <= MAX_ADDRS_PER_PEER,
+            Some(num) => *num <= self.max_per_peer,
             None      => true
         }
     }
 
-    fn handle_add_addresses(&mut self, peer : IpAddr, addrs : Vec<NetAddr>)
+    fn handle_add_addresses(&mut self, peer : IpAddr, addrs : Vec<NetAddrV2>)
     {
         /* TODO How about netaddr.services?
          */
         for addr in addrs.iter().filter(|addr| addr.is_valid_addr())
         {
-            let address : Address = Address::new(*addr,peer.clone());
+            let address : Address = Address::new(addr.clone(),peer.clone());
 
             assert!(addr.time.is_some());
 
@@ -385,9 +465,9 @@ impl AddrManager
         self.channels.push(channel);
     }
 
-    fn get_addrs(&self, num : uint) -> Vec<NetAddr>
+    fn get_addrs(&self, num : uint) -> Vec<NetAddrV2>
     {
-        let mut addrs : Vec<NetAddr> = Vec::with_capacity(num);
+        let mut addrs : Vec<NetAddrV2> = Vec::with_capacity(num);
 
         for _ in range(0,5*num)
         {
@@ -441,6 +521,126 @@ impl AddrManager
         self.send(channelid,AddrManagerReply::AddrMngAddresses(addrs));
     }
 
+    fn handle_set_local_address(&mut self, addr : NetAddrV2)
+    {
+        self.local_address = Some(addr);
+    }
+
+    fn handle_get_local_address(&self, channelid : uint)
+    {
+        self.send(channelid,AddrManagerReply::AddrMngLocalAddress(self.local_address.clone()));
+    }
+
+    fn is_banned(&self, ip : &IpAddr) -> bool
+    {
+        match self.banned.get(ip)
+        {
+            Some(until) => *until > time::now_utc().to_timespec(),
+            None        => false
+        }
+    }
+
+    fn handle_update_peer(&mut self, channelid : uint, info : PeerInfo)
+    {
+        let addr : SocketAddr = info.addr;
+        let id : uint;
+        let keep : bool;
+
+        id = match self.peers.get(&addr)
+        {
+            Some(&(id,_)) => id,
+            None          =>
+            {
+                self.next_peer_id += 1;
+                self.next_peer_id-1
+            }
+        };
+
+        keep = !self.to_disconnect.remove(&addr) && !self.is_banned(&addr.ip);
+
+        if keep
+        {
+            self.peers.insert(addr,(id,info));
+        }
+        else
+        {
+            self.peers.remove(&addr);
+        }
+
+        self.send(channelid,AddrManagerReply::AddrMngKeepPeer(keep));
+    }
+
+    fn handle_remove_peer(&mut self, addr : SocketAddr)
+    {
+        self.peers.remove(&addr);
+        self.to_disconnect.remove(&addr);
+    }
+
+    fn handle_get_peers(&self, channelid : uint)
+    {
+        let mut peers : Vec<(uint,PeerInfo)>;
+
+        peers = self.peers.values().map(|peer| peer.clone()).collect();
+        peers.sort_by(|&(a,_), &(b,_)| a.cmp(&b));
+
+        self.send(channelid,AddrManagerReply::AddrMngPeers(peers));
+    }
+
+    /* The peer finds out when it next updates */
+    fn handle_disconnect_peer(&mut self, channelid : uint, addr : SocketAddr)
+    {
+        let known : bool = self.peers.contains_key(&addr);
+
+        if known
+        {
+            self.to_disconnect.insert(addr);
+        }
+
+        self.send(channelid,AddrManagerReply::AddrMngResult(known));
+    }
+
+    fn handle_ban(&mut self, ip : IpAddr, until : Timespec)
+    {
+        self.banned.insert(ip,until);
+    }
+
+    fn handle_unban(&mut self, channelid : uint, ip : IpAddr)
+    {
+        let was_banned : bool = self.is_banned(&ip);
+
+        self.banned.remove(&ip);
+
+        self.send(channelid,AddrManagerReply::AddrMngResult(was_banned));
+    }
+
+    fn handle_is_banned(&self, channelid : uint, ip : IpAddr)
+    {
+        self.send(channelid,AddrManagerReply::AddrMngResult(self.is_banned(&ip)));
+    }
+
+    fn handle_get_bucket_sizes(&self, channelid : uint)
+    {
+        let sizes : Vec<uint> = self.addresses.iter().map(|b| b.len()).collect();
+
+        self.send(channelid,AddrManagerReply::AddrMngBucketSizes(sizes));
+    }
+
+    /* Indexed by the network ids of BIP155 */
+    fn handle_get_network_sizes(&self, channelid : uint)
+    {
+        let mut sizes : Vec<uint> = Vec::from_elem(NetworkId::NetCJDNS as uint+1,0u);
+
+        for bucket in self.addresses.iter()
+        {
+            for (_, address) in bucket.iter()
+            {
+                *sizes.get_mut(address.netaddr.network as uint) += 1;
+            }
+        }
+
+        self.send(channelid,AddrManagerReply::AddrMngNetworkSizes(sizes));
+    }
+
     fn handle_request(&mut self,
                       channelid : uint,
                       request   : AddrManagerRequest)
@@ -456,19 +656,51 @@ impl AddrManager
             AddrManagerRequest::AddrMngGetManyAddresses  =>
                 self.handle_get_many_addrs(channelid),
             AddrManagerRequest::AddrMngAddPeerChannel(c) =>
-                self.handle_add_channel(c)
+                self.handle_add_channel(c),
+            AddrManagerRequest::AddrMngSetLocalAddress(addr) =>
+                self.handle_set_local_address(addr),
+            AddrManagerRequest::AddrMngGetLocalAddress   =>
+                self.handle_get_local_address(channelid),
+            AddrManagerRequest::AddrMngUpdatePeer(info)  =>
+                self.handle_update_peer(channelid,info),
+            AddrManagerRequest::AddrMngRemovePeer(addr)  =>
+                self.handle_remove_peer(addr),
+            AddrManagerRequest::AddrMngGetPeers          =>
+                self.handle_get_peers(channelid),
+            AddrManagerRequest::AddrMngDisconnectPeer(addr) =>
+                self.handle_disconnect_peer(channelid,addr),
+            AddrManagerRequest::AddrMngBan(ip,until)     =>
+                self.handle_ban(ip,until),
+            AddrManagerRequest::AddrMngUnban(ip)         =>
+                self.handle_unban(channelid,ip),
+            AddrManagerRequest::AddrMngIsBanned(ip)      =>
+                self.handle_is_banned(channelid,ip),
+            AddrManagerRequest::AddrMngGetBucketSizes    =>
+                self.handle_get_bucket_sizes(channelid),
+            AddrManagerRequest::AddrMngGetNetworkSizes   =>
+                self.handle_get_network_sizes(channelid)
         }
     }
 
     fn periodic_cleanup(&mut self)
     {
         let before : uint = self.address_count();
+        let now : Timespec = time::now_utc().to_timespec();
+        let expired : Vec<IpAddr>;
 
         for i in range(0,BUCKETS)
         {
             self.bucket_cleanup(i);
         }
 
+        expired = self.banned.iter()
+            .filter(|&(_,until)| *until <= now).map(|(ip,_)| *ip).collect();
+
+        for ip in expired.iter()
+        {
+            self.banned.remove(ip);
+        }
+
         ::logger::log_addr_mng_cleanup(before,self.address_count());
     }
 
@@ -0,0 +1,486 @@
+extern crate time;
+
+use std::collections::HashMap;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::blockfilter::BlockFilter;
+use datatype::transaction::OutPoint;
+use datatype::hash::Hash;
+use datatype::script::Script;
+use datatype::value::Value;
+
+use consensus::ValidationError;
+use consensus::ChainView;
+
+use config::NetworkParams;
+
+use utxo::UtxoSet;
+use utxo::Coin;
+
+/* Version, previous block and merkle root of the header of the genesis block,
+ * which are the same on every network.
+ */
+const GENESIS_HEADER_HEX : &'static [&'static str] = &[
+    "0100000000000000000000000000000000000000000000000000000000000000",
+    "000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa",
+    "4b1e5e4a" ];
+
+/* The coinbase of the genesis block */
+const GENESIS_TXS_HEX : &'static [&'static str] = &[
+    "01010000000100000000000000000000",
+    "00000000000000000000000000000000000000000000ffffffff4d04ffff001d",
+    "0104455468652054696d65732030332f4a616e2f32303039204368616e63656c",
+    "6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f75742066",
+    "6f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe554827",
+    "1967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4",
+    "f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000" ];
+
+/* The genesis block of the network */
+pub fn genesis_block(params : &NetworkParams) -> Block
+{
+    let mut data : Vec<u8>;
+    let mut fields = ::marshalling::Marshalling::new();
+
+    data = ::crypto::from_hexstr(GENESIS_HEADER_HEX.concat().as_slice()).unwrap();
+
+    fields.write_uint32(params.genesis_time);
+    fields.write_uint32(params.genesis_bits);
+    fields.write_uint32(params.genesis_nonce);
+
+    data.push_all(fields.get().as_slice());
+    data.push_all(::crypto::from_hexstr(GENESIS_TXS_HEX.concat().as_slice()).unwrap().as_slice());
+
+    ::marshalling::Unmarshalling::new(&data).read_block()
+}
+
+/* A change made to the UTXO set while connecting a block */
+enum UtxoChange
+{
+    UtxoSpent(OutPoint, Coin),
+    UtxoCreated(OutPoint)
+}
+
+/* What we need to undo when a block fails to connect, in the order the
+ * changes were made.
+ */
+struct Undo
+{
+    changes : Vec<UtxoChange>
+}
+
+/* At most this many blocks outside of the active chain are kept.  They cost
+ * memory and, without reorganizations, nothing else.
+ */
+const MAX_SIDE_BLOCKS : uint = 1000;
+
+/* TODO: Blocks are only kept in memory, they should be stored on disk.  So
+ *       are the filters and their headers, which should go to an index next
+ *       to the blocks once they are on disk.
+ * TODO: Reorganizations.  We only extend the active chain, blocks that do not
+ *       build on the tip are checked and stored but never connected.
+ */
+pub struct Chain
+{
+    heights        : HashMap<Hash,u32>,          /* Height of every block we know of */
+    blocks         : HashMap<Hash,Block>,
+    active         : Vec<Hash>,                  /* Active chain, indexed by height */
+    utxos          : UtxoSet,
+    filters        : HashMap<Hash,BlockFilter>,  /* Basic filters of the active chain */
+    filter_headers : HashMap<Hash,Hash>,
+    params         : &'static NetworkParams      /* Network the chain is on */
+}
+
+/* The chain ending with a block we know of, which may leave the active chain
+ * at some point.  The headers of a block are checked against it.
+ */
+struct BranchView<'a>
+{
+    chain  : &'a Chain,
+    tip    : &'a Hash,
+    height : u32
+}
+
+#[allow(dead_code)]
+impl Chain
+{
+    pub fn new(params : &'static NetworkParams) -> Chain
+    {
+        let genesis : Block = genesis_block(params);
+        let hash : Hash = genesis.get_hash();
+        let mut chain : Chain;
+
+        chain = Chain
+        {
+            heights:        HashMap::new(),
+            blocks:         HashMap::new(),
+            active:         Vec::new(),
+            utxos:          UtxoSet::new(),
+            filters:        HashMap::new(),
+            filter_headers: HashMap::new(),
+            params:         params
+        };
+
+        chain.index_filter(&genesis,&[],&Hash::zero());
+
+        /* The genesis coinbase is not spendable, so its outputs are not added
+         * to the UTXO set, just like in the reference implementation.
+         */
+        chain.heights.insert(hash.clone(),0);
+        chain.blocks.insert(hash.clone(),genesis);
+        chain.active.push(hash);
+
+        chain
+    }
+
+    pub fn get_height(&self) -> u32
+    {
+        (self.active.len()-1) as u32
+    }
+
+    pub fn get_tip(&self) -> &Hash
+    {
+        self.active.last().unwrap()
+    }
+
+    pub fn get_tip_header(&self) -> &BlockHeader
+    {
+        self.blocks.get(self.get_tip()).unwrap().get_header()
+    }
+
+    pub fn contains_block(&self, hash : &Hash) -> bool
+    {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn get_block(&self, hash : &Hash) -> Option<&Block>
+    {
+        self.blocks.get(hash)
+    }
+
+    /* Height of the block, if it is in the active chain */
+    pub fn get_active_height(&self, hash : &Hash) -> Option<u32>
+    {
+        match self.heights.get(hash)
+        {
+            Some(h) if self.active[*h as uint] == *hash => Some(*h),
+            _                                           => None
+        }
+    }
+
+    /* Height of the last block the branch ending with the given block has in
+     * common with the active chain.
+     */
+    fn get_fork_height(&self, hash : &Hash) -> u32
+    {
+        let mut hash : &Hash = hash;
+
+        loop
+        {
+            match self.get_active_height(hash)
+            {
+                Some(h) => return h,
+                None    => hash = self.blocks.get(hash).unwrap().get_header().get_prev_block()
+            }
+        }
+    }
+
+    /* Height of the last checkpoint we have reached */
+    fn get_last_checkpoint_height(&self) -> u32
+    {
+        self.params.checkpoints.iter()
+            .map(|&(h, _)| h)
+            .filter(|h| *h <= self.get_height())
+            .max().unwrap_or(0)
+    }
+
+    /* A block that does not extend the tip is only kept if its branch leaves
+     * the active chain after the last checkpoint, and if there is room.
+     */
+    fn check_side_block(&self, prev : &Hash) -> Result<(),ValidationError>
+    {
+        if self.get_fork_height(prev) < self.get_last_checkpoint_height()
+        {
+            return Err(ValidationError::BlockForkBeforeCheckpoint);
+        }
+
+        if self.blocks.len()-self.active.len() >= MAX_SIDE_BLOCKS
+        {
+            return Err(ValidationError::BlockTooManySideBlocks);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_hash_at(&self, height : u32) -> Option<&Hash>
+    {
+        self.active.get(height as uint)
+    }
+
+    /* Hashes of the active chain from the tip back to the genesis block, the
+     * last ten ones then exponentially sparser, for a peer to find where its
+     * chain and ours fork.
+     */
+    pub fn get_locator(&self) -> Vec<Hash>
+    {
+        let mut locator : Vec<Hash> = Vec::new();
+        let mut height : u32 = self.get_height();
+        let mut step : u32 = 1;
+
+        loop
+        {
+            locator.push(self.active[height as uint].clone());
+
+            if height == 0
+            {
+                return locator;
+            }
+
+            if locator.len() >= 10
+            {
+                step *= 2;
+            }
+
+            height = if height > step { height-step } else { 0 };
+        }
+    }
+
+    /* Height of the first block of the locator in the active chain, the
+     * genesis block if there is none.
+     */
+    pub fn find_fork(&self, locator : &[Hash]) -> u32
+    {
+        locator.iter().filter_map(|hash| self.get_active_height(hash)).next().unwrap_or(0)
+    }
+
+    pub fn get_utxos(&self) -> &UtxoSet
+    {
+        &self.utxos
+    }
+
+    pub fn get_filter(&self, hash : &Hash) -> Option<&BlockFilter>
+    {
+        self.filters.get(hash)
+    }
+
+    pub fn get_filter_header(&self, hash : &Hash) -> Option<&Hash>
+    {
+        self.filter_headers.get(hash)
+    }
+
+    /* Build the basic filter of a block being connected and chain its header
+     * to the previous one (zero for the genesis block).
+     */
+    fn index_filter(&mut self, block : &Block, spent : &[Script], prev_header : &Hash)
+    {
+        let filter : BlockFilter = BlockFilter::build_basic(block,spent);
+        let header : Hash = filter.compute_header(prev_header);
+
+        self.filter_headers.insert(block.get_hash(),header);
+        self.filters.insert(block.get_hash(),filter);
+    }
+
+    /* In reverse order, since a block can spend the outputs it creates */
+    fn undo(&mut self, undo : Undo)
+    {
+        for change in undo.changes.into_iter().rev()
+        {
+            match change
+            {
+                UtxoChange::UtxoSpent(outpoint, coin) => self.utxos.add(outpoint,coin),
+                UtxoChange::UtxoCreated(outpoint)     =>
+                {
+                    self.utxos.spend(&outpoint);
+                }
+            }
+        }
+    }
+
+    /* Spend the inputs and add the outputs of every transaction in the block,
+     * checking them along the way.
+     *
+     * The scripts of the inputs are not run: the interpreter only knows the
+     * standard scripts (see the TODO of verify_script), and would reject
+     * valid blocks spending anything else.
+     */
+    fn connect_txs(&mut self, block : &Block, height : u32, undo : &mut Undo)
+                   -> Result<(),ValidationError>
+    {
+        let mut fees : Value = Value::zero();
+
+        for tx in block.get_txs().iter()
+        {
+            let hash : Hash = tx.get_hash();
+
+            if !tx.is_coinbase()
+            {
+                let fee = try!(::consensus::check_tx_inputs(tx,&self.utxos,height));
+
+                fees = fees.checked_add(&fee).unwrap();
+
+                for in_tx in tx.get_in_txs().iter()
+                {
+                    let outpoint : &OutPoint = in_tx.get_prev_out();
+                    let coin : Coin = self.utxos.spend(outpoint).unwrap();
+
+                    undo.changes.push(UtxoChange::UtxoSpent(outpoint.clone(),coin));
+                }
+            }
+
+            try!(::consensus::check_tx_outputs_new(tx,&self.utxos,block,height));
+
+            self.utxos.add_tx_outputs(tx,height);
+
+            for i in range(0,tx.get_out_txs().len())
+            {
+                undo.changes.push(UtxoChange::UtxoCreated(OutPoint::new(hash.clone(),i as u32)));
+            }
+        }
+
+        ::consensus::check_coinbase_value(block,height,&fees,&self.params.consensus)
+    }
+
+    /* Returns what the block changed in the UTXO set */
+    fn connect_block(&mut self, block : &Block, height : u32) -> Result<Undo,ValidationError>
+    {
+        let mut undo : Undo = Undo { changes: Vec::new() };
+
+        match self.connect_txs(block,height,&mut undo)
+        {
+            Ok(())   => Ok(undo),
+            Err(err) =>
+            {
+                self.undo(undo);
+
+                Err(err)
+            }
+        }
+    }
+
+    /* Validate the block and, if it extends the active chain, connect it.
+     * Returns whether the block was connected.
+     */
+    pub fn accept_block(&mut self, block : Block) -> Result<bool,ValidationError>
+    {
+        let hash : Hash = block.get_hash();
+        let prev_height : u32;
+        let extends_tip : bool;
+        let undo : Undo;
+        let spent : Vec<Script>;
+        let prev_filter_header : Hash;
+        let now = time::now_utc().to_timespec();
+
+        if self.contains_block(&hash)
+        {
+            return Err(ValidationError::BlockDuplicate);
+        }
+
+        try!(::consensus::check_block(&block,&self.params.consensus));
+
+        prev_height = match self.heights.get(block.get_header().get_prev_block())
+        {
+            Some(h) => *h,
+            None    => return Err(ValidationError::BlockPrevNotFound)
+        };
+
+        extends_tip = *block.get_header().get_prev_block() == *self.get_tip();
+
+        if !extends_tip
+        {
+            try!(self.check_side_block(block.get_header().get_prev_block()));
+        }
+
+        {
+            let branch : BranchView = BranchView
+            {
+                chain:  self,
+                tip:    block.get_header().get_prev_block(),
+                height: prev_height
+            };
+
+            try!(::consensus::check_block_header_contextual(block.get_header(),
+                                                            prev_height+1,&branch,now));
+            try!(::consensus::check_block_contextual(&block,prev_height+1,&branch));
+        }
+
+        if !extends_tip
+        {
+            /* TODO reorganizations */
+            self.heights.insert(hash.clone(),prev_height+1);
+            self.blocks.insert(hash,block);
+
+            return Ok(false);
+        }
+
+        undo = try!(self.connect_block(&block,prev_height+1));
+
+        spent = undo.changes.iter().filter_map(|change| match *change
+        {
+            UtxoChange::UtxoSpent(_, ref coin) => Some(coin.get_output().get_script().clone()),
+            UtxoChange::UtxoCreated(_)         => None
+        }).collect();
+        prev_filter_header = self.filter_headers.get(self.get_tip()).unwrap().clone();
+
+        self.index_filter(&block,spent.as_slice(),&prev_filter_header);
+
+        self.heights.insert(hash.clone(),prev_height+1);
+        self.blocks.insert(hash.clone(),block);
+        self.active.push(hash);
+
+        ::logger::log_chain_new_tip(self.get_tip(),self.get_height());
+
+        Ok(true)
+    }
+}
+
+impl<'a> ChainView for BranchView<'a>
+{
+    /* Walk back the branch until the height, or until it joins the active
+     * chain where the height can be looked up.
+     */
+    fn get_header_at(&self, height : u32) -> Option<&BlockHeader>
+    {
+        let mut hash : &Hash = self.tip;
+        let mut h : u32 = self.height;
+
+        if height > h
+        {
+            return None;
+        }
+
+        while h > height && self.chain.get_active_height(hash).is_none()
+        {
+            hash = self.chain.blocks.get(hash).unwrap().get_header().get_prev_block();
+            h -= 1;
+        }
+
+        if h == height
+        {
+            return self.chain.blocks.get(hash).map(|b| b.get_header());
+        }
+
+        self.chain.get_header_at(height)
+    }
+
+    fn get_params(&self) -> &'static NetworkParams
+    {
+        self.chain.params
+    }
+}
+
+impl ChainView for Chain
+{
+    fn get_header_at(&self, height : u32) -> Option<&BlockHeader>
+    {
+        match self.active.get(height as uint)
+        {
+            Some(hash) => self.blocks.get(hash).map(|b| b.get_header()),
+            None       => None
+        }
+    }
+
+    fn get_params(&self) -> &'static NetworkParams
+    {
+        self.params
+    }
+}
@@ -0,0 +1,1048 @@
+extern crate time;
+
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use std::io::Timer;
+use std::time::duration::Duration;
+
+use std::comm::Receiver;
+use std::comm::Handle;
+use std::comm::Select;
+use std::comm::Empty;
+use std::comm::Disconnected;
+
+use std::io::net::ip::SocketAddr;
+
+use std::collections::HashSet;
+use std::collections::RingBuf;
+
+use std::iter::range_step_inclusive;
+
+use self::time::Timespec;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::transaction::Transaction;
+use datatype::transaction::OutPoint;
+use datatype::hash::Hash;
+use datatype::script::Script;
+use datatype::blockfilter::BlockFilter;
+use datatype::compact::HeaderAndShortIds;
+use datatype::compact::PartialBlock;
+use datatype::compact::CompactError;
+
+use consensus::ValidationError;
+
+use chain::Chain;
+
+use utxo::Coin;
+use utxo::UtxoView;
+
+use mempool::Mempool;
+use mempool::MempoolError;
+
+use orphanage::Orphanage;
+
+use relay::TxRequestTracker;
+use relay::RelayLog;
+
+use comm::DuplexChannel;
+
+use config::Config;
+
+use message::getcfilters::MAX_GETCFILTERS_SIZE;
+use message::getcfheaders::MAX_GETCFHEADERS_SIZE;
+use message::getcfcheckpt::CFCHECKPT_INTERVAL;
+use message::headers::MAX_HEADERS_RESULTS;
+
+pub const CHAINMNG_CHANNEL_BUF_CAP : uint = 8;
+
+const PERIODIC_EXPIRE_M : uint = 10;
+
+/* Peers we ask to send us new blocks as cmpctblock without announcing them
+ * first (BIP152 high bandwidth mode).
+ */
+const MAX_HIGH_BANDWIDTH_PEERS : uint = 3;
+
+pub type ChainManagerChannel = DuplexChannel<ChainManagerRequest,ChainManagerReply>;
+
+type PeerChannel = DuplexChannel<ChainManagerReply,ChainManagerRequest>;
+
+pub enum ChainManagerRequest
+{
+    ChainMngAddPeerChannel(PeerChannel),
+    ChainMngAddBlock(SocketAddr,Block),
+    ChainMngAddTx(SocketAddr,Transaction),
+    ChainMngGetTx(Hash),
+    ChainMngGetBlock(Hash),
+    ChainMngGetMempool(u64),            /* Minimum fee rate */
+    ChainMngTxNotFound(SocketAddr,Vec<Hash>),
+    ChainMngTxAnnounced(SocketAddr,Vec<Hash>),
+    ChainMngGetRerequests(SocketAddr),
+    ChainMngBlocksAnnounced(SocketAddr,Vec<Hash>),
+    ChainMngBlockNotFound(SocketAddr,Vec<Hash>),
+    ChainMngGetBlockRerequests(SocketAddr),
+    ChainMngGetRelay(Option<u64>,u64),  /* Sequence number, minimum fee rate */
+    ChainMngGetCFilters(u32,Hash),      /* Start height, stop hash */
+    ChainMngGetCFHeaders(u32,Hash),
+    ChainMngGetCFCheckpt(Hash),
+    ChainMngGetBlockRelay(Option<u64>),
+    ChainMngReconstruct(HeaderAndShortIds),
+    ChainMngCompactPeer(SocketAddr),
+    ChainMngIsHighBandwidth(SocketAddr),
+    ChainMngRemovePeer(SocketAddr),
+    ChainMngGetMinFeeRate,
+    ChainMngGetTip,
+    ChainMngGetChainInfo,
+    ChainMngGetActiveHeight(Hash),
+    ChainMngGetHeaders(Hash,uint),      /* First block, maximum count */
+    ChainMngGetLocator,
+    ChainMngFindHeaders(Vec<Hash>,Hash),  /* Locator, stop hash */
+    ChainMngGetCoins(Vec<OutPoint>,bool),  /* Whether to look at the mempool too */
+    ChainMngGenerate(uint,Script)       /* Number of blocks, script to pay to */
+}
+
+pub enum ChainManagerReply
+{
+    ChainMngBlockAccepted,
+    ChainMngBlockRejected(ValidationError),
+    ChainMngTxAccepted,
+    ChainMngTxRejected(MempoolError),
+    ChainMngTxOrphan(Vec<Hash>),   /* Missing parents */
+    ChainMngTx(Option<Transaction>),
+    ChainMngBlock(Option<Block>),
+    ChainMngMempool(Vec<Hash>),
+    ChainMngTxToRequest(Vec<Hash>),
+    ChainMngBlocksToRequest(Vec<Hash>),
+    ChainMngRelay(Vec<Hash>,u64),  /* Txs to announce, next sequence number */
+    /* None if the request was invalid */
+    ChainMngCFilters(Option<Vec<BlockFilter>>),
+    ChainMngCFHeaders(Option<(Hash, Vec<Hash>)>),  /* Previous header, filter hashes */
+    ChainMngCFCheckpt(Option<Vec<Hash>>),
+    ChainMngBlockRelay(Vec<Hash>,u64),  /* New tips to announce, next sequence number */
+    ChainMngPartialBlock(Result<PartialBlock,CompactError>),
+    ChainMngHighBandwidth(bool),
+    ChainMngMinFeeRate(u64),
+    ChainMngTip(Hash,u32),         /* Hash and height */
+    ChainMngChainInfo(ChainInfo),
+    /* With the height of the tip, so both come from the same chain */
+    ChainMngActiveHeight(Option<u32>,u32),
+    ChainMngHeaders(Vec<BlockHeader>,Option<u32>,u32),  /* Height of the first and of the tip */
+    ChainMngLocator(Vec<Hash>),
+    ChainMngCoins(u32,Hash,Vec<Option<Coin>>),  /* Height and tip they are at */
+    ChainMngGenerated(Vec<Hash>)
+}
+
+/* What the control interface shows about the chain and the mempool */
+#[deriving(Clone)]
+pub struct ChainInfo
+{
+    pub tip          : Hash,
+    pub height       : u32,
+    pub bits         : u32,
+    pub time         : Timespec,
+    pub median_time  : Timespec,
+    pub mempool_txs  : uint,
+    pub mempool_size : uint,
+    pub min_fee_rate : u64
+}
+
+impl Show for ChainManagerRequest
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        match *self
+        {
+            ChainManagerRequest::ChainMngAddPeerChannel(_) =>
+                write!(f,"New channel"),
+            ChainManagerRequest::ChainMngAddBlock(ref addr, ref block) =>
+                write!(f,"Add block {} from {}",block.get_hash(),addr),
+            ChainManagerRequest::ChainMngAddTx(ref addr, ref tx) =>
+                write!(f,"Add tx {} from {}",tx.get_hash(),addr),
+            ChainManagerRequest::ChainMngGetTx(ref hash) =>
+                write!(f,"Get tx {}",hash),
+            ChainManagerRequest::ChainMngGetBlock(ref hash) =>
+                write!(f,"Get block {}",hash),
+            ChainManagerRequest::ChainMngGetMempool(ref rate) =>
+                write!(f,"Get mempool above {}",rate),
+            ChainManagerRequest::ChainMngTxNotFound(ref addr, ref hashes) =>
+                write!(f,"{} txs not found by {}",hashes.len(),addr),
+            ChainManagerRequest::ChainMngTxAnnounced(ref addr, ref hashes) =>
+                write!(f,"{} txs announced by {}",hashes.len(),addr),
+            ChainManagerRequest::ChainMngGetRerequests(ref addr) =>
+                write!(f,"Get rerequests for {}",addr),
+            ChainManagerRequest::ChainMngBlocksAnnounced(ref addr, ref hashes) =>
+                write!(f,"{} blocks announced by {}",hashes.len(),addr),
+            ChainManagerRequest::ChainMngBlockNotFound(ref addr, ref hashes) =>
+                write!(f,"{} blocks not found by {}",hashes.len(),addr),
+            ChainManagerRequest::ChainMngGetBlockRerequests(ref addr) =>
+                write!(f,"Get block rerequests for {}",addr),
+            ChainManagerRequest::ChainMngGetRelay(ref seq, ref rate) =>
+                write!(f,"Get relay since {} above {}",seq,rate),
+            ChainManagerRequest::ChainMngGetCFilters(ref start, ref stop) =>
+                write!(f,"Get filters from {} to {}",start,stop),
+            ChainManagerRequest::ChainMngGetCFHeaders(ref start, ref stop) =>
+                write!(f,"Get filter headers from {} to {}",start,stop),
+            ChainManagerRequest::ChainMngGetCFCheckpt(ref stop) =>
+                write!(f,"Get filter checkpoints to {}",stop),
+            ChainManagerRequest::ChainMngGetBlockRelay(ref seq) =>
+                write!(f,"Get block relay since {}",seq),
+            ChainManagerRequest::ChainMngReconstruct(ref cmpct) =>
+                write!(f,"Reconstruct block {}",cmpct.get_header().get_hash()),
+            ChainManagerRequest::ChainMngCompactPeer(ref addr) =>
+                write!(f,"{} speaks compact blocks",addr),
+            ChainManagerRequest::ChainMngRemovePeer(ref addr) =>
+                write!(f,"Remove peer {}",addr),
+            ChainManagerRequest::ChainMngIsHighBandwidth(ref addr) =>
+                write!(f,"Is {} high bandwidth",addr),
+            ChainManagerRequest::ChainMngGetMinFeeRate =>
+                write!(f,"Get min fee rate"),
+            ChainManagerRequest::ChainMngGetTip =>
+                write!(f,"Get tip"),
+            ChainManagerRequest::ChainMngGetChainInfo =>
+                write!(f,"Get chain info"),
+            ChainManagerRequest::ChainMngGetActiveHeight(ref hash) =>
+                write!(f,"Get active height of {}",hash),
+            ChainManagerRequest::ChainMngGetHeaders(ref hash, ref count) =>
+                write!(f,"Get {} headers from {}",count,hash),
+            ChainManagerRequest::ChainMngGetLocator =>
+                write!(f,"Get locator"),
+            ChainManagerRequest::ChainMngFindHeaders(ref locator, ref stop) =>
+                write!(f,"Find headers after {} hashes up to {}",locator.len(),stop),
+            ChainManagerRequest::ChainMngGetCoins(ref outpoints, ref mempool) =>
+                write!(f,"Get {} coins, mempool {}",outpoints.len(),mempool),
+            ChainManagerRequest::ChainMngGenerate(ref count, ref script) =>
+                write!(f,"Generate {} blocks to {}",count,script)
+        }
+    }
+}
+
+impl Show for ChainManagerReply
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        match *self
+        {
+            ChainManagerReply::ChainMngBlockAccepted =>
+                write!(f,"Block accepted"),
+            ChainManagerReply::ChainMngBlockRejected(ref err) =>
+                write!(f,"Block rejected: {}",err),
+            ChainManagerReply::ChainMngTxAccepted =>
+                write!(f,"Tx accepted"),
+            ChainManagerReply::ChainMngTxRejected(ref err) =>
+                write!(f,"Tx rejected: {}",err),
+            ChainManagerReply::ChainMngTxOrphan(ref parents) =>
+                write!(f,"Tx orphan: {} missing parents",parents.len()),
+            ChainManagerReply::ChainMngTx(ref tx) =>
+                write!(f,"Tx: {}",tx.as_ref().map(|tx| tx.get_hash())),
+            ChainManagerReply::ChainMngBlock(ref block) =>
+                write!(f,"Block: {}",block.as_ref().map(|block| block.get_hash())),
+            ChainManagerReply::ChainMngMempool(ref hashes) =>
+                write!(f,"Mempool: {} txs",hashes.len()),
+            ChainManagerReply::ChainMngTxToRequest(ref hashes) =>
+                write!(f,"{} txs to request",hashes.len()),
+            ChainManagerReply::ChainMngBlocksToRequest(ref hashes) =>
+                write!(f,"{} blocks to request",hashes.len()),
+            ChainManagerReply::ChainMngRelay(ref hashes, ref seq) =>
+                write!(f,"{} txs to relay, next {}",hashes.len(),seq),
+            ChainManagerReply::ChainMngCFilters(ref filters) =>
+                write!(f,"Filters: {}",filters.as_ref().map(|v| v.len())),
+            ChainManagerReply::ChainMngCFHeaders(ref headers) =>
+                write!(f,"Filter headers: {}",headers.as_ref().map(|&(_, ref h)| h.len())),
+            ChainManagerReply::ChainMngCFCheckpt(ref headers) =>
+                write!(f,"Filter checkpoints: {}",headers.as_ref().map(|h| h.len())),
+            ChainManagerReply::ChainMngBlockRelay(ref hashes, ref seq) =>
+                write!(f,"{} blocks to relay, next {}",hashes.len(),seq),
+            ChainManagerReply::ChainMngPartialBlock(Ok(ref partial)) =>
+                write!(f,"Partial block {}: {} missing",partial.get_hash(),
+                       partial.get_missing().len()),
+            ChainManagerReply::ChainMngPartialBlock(Err(ref err)) =>
+                write!(f,"Partial block: {}",err),
+            ChainManagerReply::ChainMngHighBandwidth(ref hb) =>
+                write!(f,"High bandwidth: {}",hb),
+            ChainManagerReply::ChainMngMinFeeRate(ref rate) =>
+                write!(f,"Min fee rate: {}",rate),
+            ChainManagerReply::ChainMngTip(ref hash, ref height) =>
+                write!(f,"Tip {} at {}",hash,height),
+            ChainManagerReply::ChainMngChainInfo(ref info) =>
+                write!(f,"Chain info: tip {} at {}",info.tip,info.height),
+            ChainManagerReply::ChainMngActiveHeight(ref height, ref tip) =>
+                write!(f,"Active height: {}, tip at {}",height,tip),
+            ChainManagerReply::ChainMngHeaders(ref headers, _, ref tip) =>
+                write!(f,"{} headers, tip at {}",headers.len(),tip),
+            ChainManagerReply::ChainMngLocator(ref locator) =>
+                write!(f,"Locator of {} hashes",locator.len()),
+            ChainManagerReply::ChainMngCoins(ref height, _, ref coins) =>
+                write!(f,"{} coins at {}",coins.len(),height),
+            ChainManagerReply::ChainMngGenerated(ref hashes) =>
+                write!(f,"Generated {} blocks",hashes.len())
+        }
+    }
+}
+
+/* The chain manager owns the chain state, the mempool and the orphan
+ * transactions.  It also coordinates tx relay between peers.  Every peer talks
+ * to it through its own channel, like with the address manager.
+ */
+pub struct ChainManager
+{
+    channels       : Vec<PeerChannel>,
+    chain          : Chain,
+    mempool        : Mempool,
+    orphanage      : Orphanage,
+    tracker        : TxRequestTracker,
+    /* Blocks are requested from one peer at a time too */
+    block_tracker  : TxRequestTracker,
+    relay_log      : RelayLog,
+    block_log      : RelayLog,
+    /* Peers that speak compact blocks and the ones in high bandwidth mode,
+     * the ones that gave us a new block last at the back.
+     */
+    compact_peers  : HashSet<SocketAddr>,
+    high_bandwidth : RingBuf<SocketAddr>
+}
+
+impl ChainManager
+{
+    pub fn new(orchestrator : PeerChannel, config : &Config) -> ChainManager
+    {
+        let mut channels = Vec::with_capacity(512);
+
+        channels.push(orchestrator);
+
+        ChainManager
+        {
+            channels:       channels,
+            chain:          Chain::new(config.get_params()),
+            mempool:        Mempool::new(),
+            orphanage:      Orphanage::new(),
+            tracker:        TxRequestTracker::new(),
+            block_tracker:  TxRequestTracker::new(),
+            relay_log:      RelayLog::new(),
+            block_log:      RelayLog::new(),
+            compact_peers:  HashSet::new(),
+            high_bandwidth: RingBuf::new()
+        }
+    }
+
+    fn send(&self, channelid : uint, msg : ChainManagerReply)
+    {
+        self.channels[channelid].sender.send(msg);
+    }
+
+    fn handle_add_channel(&mut self, channel : PeerChannel)
+    {
+        self.channels.push(channel);
+    }
+
+    /* Returns whether the block was connected, in which case its txs leave
+     * the mempool and it is announced to the peers.
+     */
+    fn accept_block(&mut self, block : Block) -> Result<bool,ValidationError>
+    {
+        let hash = block.get_hash();
+        let connected : bool;
+
+        connected = match self.chain.accept_block(block)
+        {
+            Ok(connected) => connected,
+            Err(err)      =>
+            {
+                ::logger::log_chain_block_rejected(&hash,&err);
+
+                return Err(err);
+            }
+        };
+
+        if connected
+        {
+            {
+                let block : &Block = self.chain.get_block(&hash).unwrap();
+
+                self.mempool.remove_for_block(block);
+                self.orphanage.remove_for_block(block);
+            }
+
+            self.block_log.push(hash);
+        }
+
+        Ok(connected)
+    }
+
+    fn handle_add_block(&mut self, channelid : uint, peer : SocketAddr, block : Block)
+    {
+        self.block_tracker.received(&block.get_hash());
+
+        match self.accept_block(block)
+        {
+            Ok(connected) =>
+            {
+                if connected
+                {
+                    self.select_high_bandwidth(peer);
+                }
+
+                self.send(channelid,ChainManagerReply::ChainMngBlockAccepted)
+            },
+            Err(err) =>
+                self.send(channelid,ChainManagerReply::ChainMngBlockRejected(err))
+        }
+    }
+
+    /* Mine the blocks ourselves, with the mempool txs.  Stops at the first
+     * block the chain does not take.
+     */
+    fn handle_generate(&mut self, channelid : uint, count : uint, script : Script)
+    {
+        let mut hashes : Vec<Hash> = Vec::with_capacity(count);
+
+        for _ in range(0,count)
+        {
+            let block : Block = ::miner::mine_block(&self.chain,&self.mempool,&script);
+            let hash : Hash = block.get_hash();
+
+            match self.accept_block(block)
+            {
+                Ok(true) => hashes.push(hash),
+                _        => break
+            }
+        }
+
+        self.send(channelid,ChainManagerReply::ChainMngGenerated(hashes));
+    }
+
+    fn handle_get_tip(&self, channelid : uint)
+    {
+        self.send(channelid,ChainManagerReply::ChainMngTip(self.chain.get_tip().clone(),
+                                                           self.chain.get_height()));
+    }
+
+    fn handle_get_chain_info(&self, channelid : uint)
+    {
+        let height : u32 = self.chain.get_height();
+        let info : ChainInfo = ChainInfo
+        {
+            tip:          self.chain.get_tip().clone(),
+            height:       height,
+            bits:         self.chain.get_tip_header().get_bits(),
+            time:         self.chain.get_tip_header().get_time(),
+            median_time:  ::consensus::get_median_time_past(&self.chain,height),
+            mempool_txs:  self.mempool.len(),
+            mempool_size: self.mempool.get_size(),
+            min_fee_rate: self.mempool.get_min_fee_rate()
+        };
+
+        self.send(channelid,ChainManagerReply::ChainMngChainInfo(info));
+    }
+
+    fn handle_get_active_height(&self, channelid : uint, hash : Hash)
+    {
+        self.send(channelid,ChainManagerReply::ChainMngActiveHeight(
+            self.chain.get_active_height(&hash),self.chain.get_height()));
+    }
+
+    /* Headers of the active chain from the given block on */
+    fn handle_get_headers(&self, channelid : uint, hash : Hash, count : uint)
+    {
+        let mut headers : Vec<BlockHeader> = Vec::new();
+        let start : Option<u32> = self.chain.get_active_height(&hash);
+
+        if start.is_some()
+        {
+            for height in range(start.unwrap(),self.chain.get_height()+1).take(count)
+            {
+                let hash : &Hash = self.chain.get_hash_at(height).unwrap();
+
+                headers.push(self.chain.get_block(hash).unwrap().get_header().clone());
+            }
+        }
+
+        self.send(channelid,ChainManagerReply::ChainMngHeaders(headers,start,
+                                                               self.chain.get_height()));
+    }
+
+    fn handle_get_locator(&self, channelid : uint)
+    {
+        self.send(channelid,ChainManagerReply::ChainMngLocator(self.chain.get_locator()));
+    }
+
+    /* What a getheaders asks for: the headers of the active chain after the
+     * locator, up to the stop hash.
+     */
+    fn handle_find_headers(&self, channelid : uint, locator : Vec<Hash>, stop : Hash)
+    {
+        let mut headers : Vec<BlockHeader> = Vec::new();
+        let start : u32 = self.chain.find_fork(locator.as_slice())+1;
+
+        for height in range(start,self.chain.get_height()+1).take(MAX_HEADERS_RESULTS)
+        {
+            let hash : &Hash = self.chain.get_hash_at(height).unwrap();
+
+            headers.push(self.chain.get_block(hash).unwrap().get_header().clone());
+
+            if *hash == stop
+            {
+                break;
+            }
+        }
+
+        self.send(channelid,ChainManagerReply::ChainMngHeaders(headers,Some(start),
+                                                               self.chain.get_height()));
+    }
+
+    fn handle_get_coins(&self, channelid : uint, outpoints : Vec<OutPoint>, mempool : bool)
+    {
+        let mut coins : Vec<Option<Coin>> = Vec::with_capacity(outpoints.len());
+
+        for outpoint in outpoints.iter()
+        {
+            coins.push(if mempool { self.mempool.get_coin(outpoint,&self.chain) }
+                       else { self.chain.get_utxos().get_coin(outpoint) });
+        }
+
+        self.send(channelid,ChainManagerReply::ChainMngCoins(self.chain.get_height(),
+                                                             self.chain.get_tip().clone(),
+                                                             coins));
+    }
+
+    /* The peers that gave us new blocks last are likely to be the fastest
+     * to do it again, so they are the ones in high bandwidth mode.
+     */
+    fn select_high_bandwidth(&mut self, peer : SocketAddr)
+    {
+        if !self.compact_peers.contains(&peer)
+        {
+            return;
+        }
+
+        self.high_bandwidth = self.high_bandwidth.iter()
+            .filter(|p| **p != peer)
+            .map(|p| *p)
+            .collect();
+        self.high_bandwidth.push_back(peer);
+
+        if self.high_bandwidth.len() > MAX_HIGH_BANDWIDTH_PEERS
+        {
+            self.high_bandwidth.pop_front();
+        }
+    }
+
+    fn handle_compact_peer(&mut self, peer : SocketAddr)
+    {
+        self.compact_peers.insert(peer);
+    }
+
+    /* The peer disconnected, it must not keep a high bandwidth slot */
+    fn handle_remove_peer(&mut self, peer : SocketAddr)
+    {
+        self.compact_peers.remove(&peer);
+        self.orphanage.erase_for_peer(&peer);
+        self.tracker.remove_peer(peer);
+        self.block_tracker.remove_peer(peer);
+
+        self.high_bandwidth = self.high_bandwidth.iter()
+            .filter(|p| **p != peer)
+            .map(|p| *p)
+            .collect();
+    }
+
+    fn handle_is_high_bandwidth(&self, channelid : uint, peer : SocketAddr)
+    {
+        let hb : bool = self.high_bandwidth.iter().any(|p| *p == peer);
+
+        self.send(channelid,ChainManagerReply::ChainMngHighBandwidth(hb));
+    }
+
+    fn handle_get_block_relay(&self, channelid : uint, seq : Option<u64>)
+    {
+        let (hashes, next) = self.block_log.get_since(seq);
+
+        self.send(channelid,ChainManagerReply::ChainMngBlockRelay(hashes,next));
+    }
+
+    /* Fill what we can of a compact block with the txs in our mempool */
+    fn handle_reconstruct(&self, channelid : uint, cmpct : HeaderAndShortIds)
+    {
+        let reply : Result<PartialBlock,CompactError>;
+
+        reply = PartialBlock::new(cmpct).map(|mut partial|
+        {
+            for tx in self.mempool.get_txs().iter()
+            {
+                partial.add_candidate(*tx);
+            }
+
+            partial
+        });
+
+        self.send(channelid,ChainManagerReply::ChainMngPartialBlock(reply));
+    }
+
+    /* Parents of the transaction that are neither in the UTXO set nor in the
+     * mempool.
+     */
+    fn get_missing_parents(&self, tx : &Transaction) -> Vec<Hash>
+    {
+        let mut parents : Vec<Hash> = Vec::new();
+
+        for in_tx in tx.get_in_txs().iter()
+        {
+            let prev_out = in_tx.get_prev_out();
+            let parent : &Hash = prev_out.get_hash();
+
+            if !self.chain.get_utxos().contains(prev_out) &&
+               !self.mempool.contains(parent) &&
+               !parents.contains(parent)
+            {
+                parents.push(parent.clone());
+            }
+        }
+
+        parents
+    }
+
+    /* A transaction was accepted, so the orphans waiting for it may now be
+     * accepted too (and then their own orphans, and so on).
+     */
+    fn process_orphans(&mut self, parent : Hash)
+    {
+        let mut queue : Vec<Hash> = vec![parent];
+
+        loop
+        {
+            let hash : Hash = match queue.pop()
+            {
+                Some(hash) => hash,
+                None       => break
+            };
+
+            for child in self.orphanage.get_children(&hash).iter()
+            {
+                let (tx, peer) = match self.orphanage.take(child)
+                {
+                    Some(orphan) => orphan,
+                    None         => continue
+                };
+
+                match self.mempool.accept(tx.clone(),&self.chain)
+                {
+                    Ok(()) =>
+                    {
+                        ::logger::log_mempool_accepted(child,self.mempool.len(),
+                                                       self.mempool.get_size());
+
+                        self.relay_log.push(child.clone());
+                        queue.push(child.clone());
+                    },
+                    Err(MempoolError::MempoolInvalid(ValidationError::TxMissingInputs)) =>
+                    {
+                        /* Still waiting for other parents */
+                        let parents : Vec<Hash> = self.get_missing_parents(&tx);
+
+                        self.orphanage.add(tx,peer,parents);
+                    },
+                    Err(err) => ::logger::log_mempool_rejected(child,&err)
+                }
+            }
+        }
+    }
+
+    fn handle_add_tx(&mut self, channelid : uint, peer : SocketAddr, tx : Transaction)
+    {
+        let hash = tx.get_hash();
+
+        self.tracker.received(&hash);
+
+        if self.orphanage.contains(&hash)
+        {
+            let parents : Vec<Hash> = self.request_parents(peer,&tx);
+
+            self.send(channelid,ChainManagerReply::ChainMngTxOrphan(parents));
+            return;
+        }
+
+        match self.mempool.accept(tx.clone(),&self.chain)
+        {
+            Ok(())   =>
+            {
+                ::logger::log_mempool_accepted(&hash,self.mempool.len(),
+                                               self.mempool.get_size());
+
+                self.relay_log.push(hash.clone());
+                self.process_orphans(hash);
+
+                self.send(channelid,ChainManagerReply::ChainMngTxAccepted)
+            },
+            Err(MempoolError::MempoolInvalid(ValidationError::TxMissingInputs)) =>
+            {
+                let parents : Vec<Hash> = self.get_missing_parents(&tx);
+
+                if !parents.is_empty() && self.orphanage.add(tx.clone(),peer,parents)
+                {
+                    let requests : Vec<Hash> = self.request_parents(peer,&tx);
+
+                    self.send(channelid,ChainManagerReply::ChainMngTxOrphan(requests));
+                }
+                else
+                {
+                    let err = MempoolError::MempoolInvalid(ValidationError::TxMissingInputs);
+
+                    ::logger::log_mempool_rejected(&hash,&err);
+
+                    self.send(channelid,ChainManagerReply::ChainMngTxRejected(err));
+                }
+            },
+            Err(err) =>
+            {
+                ::logger::log_mempool_rejected(&hash,&err);
+
+                self.send(channelid,ChainManagerReply::ChainMngTxRejected(err));
+            }
+        }
+    }
+
+    /* Missing parents of an orphan that the peer should request, i.e. that
+     * we did not request from someone else already.
+     */
+    fn request_parents(&mut self, peer : SocketAddr, tx : &Transaction) -> Vec<Hash>
+    {
+        let parents : Vec<Hash> = self.get_missing_parents(tx);
+
+        parents.into_iter().filter(|h| self.tracker.announced(peer,h.clone())).collect()
+    }
+
+    fn handle_tx_announced(&mut self, channelid : uint, peer : SocketAddr, hashes : Vec<Hash>)
+    {
+        let mut requests : Vec<Hash> = Vec::new();
+
+        for hash in hashes.into_iter()
+        {
+            if self.mempool.contains(&hash) || self.orphanage.contains(&hash)
+            {
+                continue;
+            }
+
+            if self.tracker.announced(peer,hash.clone())
+            {
+                requests.push(hash);
+            }
+        }
+
+        self.send(channelid,ChainManagerReply::ChainMngTxToRequest(requests));
+    }
+
+    fn handle_get_rerequests(&mut self, channelid : uint, peer : SocketAddr)
+    {
+        let requests : Vec<Hash> = self.tracker.get_rerequests(peer);
+
+        self.send(channelid,ChainManagerReply::ChainMngTxToRequest(requests));
+    }
+
+    /* Blocks announced by a peer, the same way as txs: those we do not have
+     * and nobody else is sending us already are to be requested from it.
+     */
+    fn handle_blocks_announced(&mut self, channelid : uint, peer : SocketAddr, hashes : Vec<Hash>)
+    {
+        let mut requests : Vec<Hash> = Vec::new();
+
+        for hash in hashes.into_iter()
+        {
+            if self.chain.contains_block(&hash)
+            {
+                continue;
+            }
+
+            if self.block_tracker.announced(peer,hash.clone())
+            {
+                requests.push(hash);
+            }
+        }
+
+        self.send(channelid,ChainManagerReply::ChainMngBlocksToRequest(requests));
+    }
+
+    fn handle_get_block_rerequests(&mut self, channelid : uint, peer : SocketAddr)
+    {
+        let requests : Vec<Hash> = self.block_tracker.get_rerequests(peer);
+
+        self.send(channelid,ChainManagerReply::ChainMngBlocksToRequest(requests));
+    }
+
+    /* The peer does not have the blocks, another peer that announced them is
+     * asked next.  No reply.
+     */
+    fn handle_block_not_found(&mut self, peer : SocketAddr, hashes : Vec<Hash>)
+    {
+        for hash in hashes.iter()
+        {
+            self.block_tracker.not_found(peer,hash);
+        }
+    }
+
+    /* Txs still in the mempool whose fee rate is at least the given one */
+    fn filter_by_fee_rate(&self, hashes : Vec<Hash>, min_fee_rate : u64) -> Vec<Hash>
+    {
+        hashes.into_iter()
+              .filter(|h| self.mempool.get_fee_rate(h).map_or(false,|r| r >= min_fee_rate))
+              .collect()
+    }
+
+    fn handle_get_relay(&self, channelid : uint, seq : Option<u64>, min_fee_rate : u64)
+    {
+        let (hashes, next) = self.relay_log.get_since(seq);
+
+        self.send(channelid,ChainManagerReply::ChainMngRelay(
+            self.filter_by_fee_rate(hashes,min_fee_rate),next));
+    }
+
+    fn handle_get_min_fee_rate(&self, channelid : uint)
+    {
+        self.send(channelid,ChainManagerReply::ChainMngMinFeeRate(self.mempool.get_min_fee_rate()));
+    }
+
+    fn handle_get_tx(&self, channelid : uint, hash : Hash)
+    {
+        let tx : Option<Transaction> = self.mempool.get(&hash).map(|tx| tx.clone());
+
+        self.send(channelid,ChainManagerReply::ChainMngTx(tx));
+    }
+
+    fn handle_get_block(&self, channelid : uint, hash : Hash)
+    {
+        let block : Option<Block> = self.chain.get_block(&hash).map(|block| block.clone());
+
+        self.send(channelid,ChainManagerReply::ChainMngBlock(block));
+    }
+
+    fn handle_get_mempool(&self, channelid : uint, min_fee_rate : u64)
+    {
+        let hashes : Vec<Hash> = self.mempool.get_hashes_by_fee_rate();
+
+        self.send(channelid,ChainManagerReply::ChainMngMempool(
+            self.filter_by_fee_rate(hashes,min_fee_rate)));
+    }
+
+    /* Hashes of the active chain blocks from the start height to the stop
+     * hash, or None if the stop hash is not in the active chain or the range
+     * is empty or bigger than max.
+     */
+    fn get_filter_range(&self, start : u32, stop : &Hash, max : u32) -> Option<Vec<Hash>>
+    {
+        let stop_height : u32 = match self.chain.get_active_height(stop)
+        {
+            Some(height) => height,
+            None         => return None
+        };
+
+        if start > stop_height || stop_height-start >= max
+        {
+            return None;
+        }
+
+        Some(range(start,stop_height+1).map(|h| self.chain.get_hash_at(h).unwrap().clone())
+                                       .collect())
+    }
+
+    fn handle_get_cfilters(&self, channelid : uint, start : u32, stop : Hash)
+    {
+        let filters : Option<Vec<BlockFilter>>;
+
+        filters = self.get_filter_range(start,&stop,MAX_GETCFILTERS_SIZE).map(|hashes|
+            hashes.iter().map(|h| self.chain.get_filter(h).unwrap().clone()).collect());
+
+        self.send(channelid,ChainManagerReply::ChainMngCFilters(filters));
+    }
+
+    fn handle_get_cfheaders(&self, channelid : uint, start : u32, stop : Hash)
+    {
+        let headers : Option<(Hash, Vec<Hash>)>;
+
+        headers = self.get_filter_range(start,&stop,MAX_GETCFHEADERS_SIZE).map(|hashes|
+        {
+            let prev : Hash = match start
+            {
+                0 => Hash::zero(),
+                _ => self.chain.get_filter_header(self.chain.get_hash_at(start-1).unwrap())
+                               .unwrap().clone()
+            };
+
+            (prev, hashes.iter().map(|h| self.chain.get_filter(h).unwrap().get_hash()).collect())
+        });
+
+        self.send(channelid,ChainManagerReply::ChainMngCFHeaders(headers));
+    }
+
+    fn handle_get_cfcheckpt(&self, channelid : uint, stop : Hash)
+    {
+        let headers : Option<Vec<Hash>>;
+
+        headers = self.chain.get_active_height(&stop).map(|stop_height|
+            range_step_inclusive(CFCHECKPT_INTERVAL,stop_height,CFCHECKPT_INTERVAL)
+                .map(|h| self.chain.get_filter_header(self.chain.get_hash_at(h).unwrap())
+                                   .unwrap().clone())
+                .collect());
+
+        self.send(channelid,ChainManagerReply::ChainMngCFCheckpt(headers));
+    }
+
+    /* The peer does not have the txs we asked for, so let another peer that
+     * announced them be asked.  No reply.
+     */
+    fn handle_tx_not_found(&mut self, peer : SocketAddr, hashes : Vec<Hash>)
+    {
+        for hash in hashes.iter()
+        {
+            self.tracker.not_found(peer,hash);
+        }
+    }
+
+    fn handle_request(&mut self,
+                      channelid : uint,
+                      request   : ChainManagerRequest)
+    {
+        match request
+        {
+            ChainManagerRequest::ChainMngAddPeerChannel(c) =>
+                self.handle_add_channel(c),
+            ChainManagerRequest::ChainMngAddBlock(peer,block) =>
+                self.handle_add_block(channelid,peer,block),
+            ChainManagerRequest::ChainMngAddTx(peer,tx) =>
+                self.handle_add_tx(channelid,peer,tx),
+            ChainManagerRequest::ChainMngGetTx(hash) =>
+                self.handle_get_tx(channelid,hash),
+            ChainManagerRequest::ChainMngGetBlock(hash) =>
+                self.handle_get_block(channelid,hash),
+            ChainManagerRequest::ChainMngGetMempool(rate) =>
+                self.handle_get_mempool(channelid,rate),
+            ChainManagerRequest::ChainMngTxNotFound(peer,hashes) =>
+                self.handle_tx_not_found(peer,hashes),
+            ChainManagerRequest::ChainMngTxAnnounced(peer,hashes) =>
+                self.handle_tx_announced(channelid,peer,hashes),
+            ChainManagerRequest::ChainMngGetRerequests(peer) =>
+                self.handle_get_rerequests(channelid,peer),
+            ChainManagerRequest::ChainMngBlocksAnnounced(peer,hashes) =>
+                self.handle_blocks_announced(channelid,peer,hashes),
+            ChainManagerRequest::ChainMngBlockNotFound(peer,hashes) =>
+                self.handle_block_not_found(peer,hashes),
+            ChainManagerRequest::ChainMngGetBlockRerequests(peer) =>
+                self.handle_get_block_rerequests(channelid,peer),
+            ChainManagerRequest::ChainMngGetRelay(seq,rate) =>
+                self.handle_get_relay(channelid,seq,rate),
+            ChainManagerRequest::ChainMngGetCFilters(start,stop) =>
+                self.handle_get_cfilters(channelid,start,stop),
+            ChainManagerRequest::ChainMngGetCFHeaders(start,stop) =>
+                self.handle_get_cfheaders(channelid,start,stop),
+            ChainManagerRequest::ChainMngGetCFCheckpt(stop) =>
+                self.handle_get_cfcheckpt(channelid,stop),
+            ChainManagerRequest::ChainMngGetBlockRelay(seq) =>
+                self.handle_get_block_relay(channelid,seq),
+            ChainManagerRequest::ChainMngReconstruct(cmpct) =>
+                self.handle_reconstruct(channelid,cmpct),
+            ChainManagerRequest::ChainMngCompactPeer(peer) =>
+                self.handle_compact_peer(peer),
+            ChainManagerRequest::ChainMngIsHighBandwidth(peer) =>
+                self.handle_is_high_bandwidth(channelid,peer),
+            ChainManagerRequest::ChainMngRemovePeer(peer) =>
+                self.handle_remove_peer(peer),
+            ChainManagerRequest::ChainMngGetMinFeeRate =>
+                self.handle_get_min_fee_rate(channelid),
+            ChainManagerRequest::ChainMngGetTip =>
+                self.handle_get_tip(channelid),
+            ChainManagerRequest::ChainMngGetChainInfo =>
+                self.handle_get_chain_info(channelid),
+            ChainManagerRequest::ChainMngGetActiveHeight(hash) =>
+                self.handle_get_active_height(channelid,hash),
+            ChainManagerRequest::ChainMngGetHeaders(hash,count) =>
+                self.handle_get_headers(channelid,hash,count),
+            ChainManagerRequest::ChainMngGetLocator =>
+                self.handle_get_locator(channelid),
+            ChainManagerRequest::ChainMngFindHeaders(locator,stop) =>
+                self.handle_find_headers(channelid,locator,stop),
+            ChainManagerRequest::ChainMngGetCoins(outpoints,mempool) =>
+                self.handle_get_coins(channelid,outpoints,mempool),
+            ChainManagerRequest::ChainMngGenerate(count,script) =>
+                self.handle_generate(channelid,count,script)
+        }
+    }
+
+    fn wait(&self, periodic : &Receiver<()>)
+    {
+        let sel : Select;
+        let mut handlers : Vec<Handle<ChainManagerRequest>>;
+        let mut periodic_handler : Handle<()>;
+        let cap : uint;
+
+        sel = Select::new();
+        /* This really needed with_capacity().  See the unsafe block bellow.
+         */
+        handlers = Vec::with_capacity(self.channels.len());
+
+        cap = handlers.capacity();
+
+        /* handlers indices must match self.clients */
+        for channel in self.channels.iter()
+        {
+            let receiver = &channel.receiver;
+
+            handlers.push(sel.handle(receiver));
+
+            /* We make sure no rellocation happens, otherwise we moved the
+             * handlers, which cannot happen.  (It is required by the unsafe
+             * block above.)
+             */
+            assert_eq!(cap,handlers.capacity());
+
+            /* Handlers cannot be moved after they are added to select.
+             */
+            unsafe { handlers.last_mut().unwrap().add(); }
+        }
+
+        periodic_handler = sel.handle(periodic);
+        unsafe { periodic_handler.add(); }
+
+        sel.wait();
+
+        for handler in handlers.iter_mut()
+        {
+            unsafe { handler.remove(); }
+        }
+    }
+
+    pub fn read_loop(&mut self)
+    {
+        let mut expire_timer : Timer = Timer::new().unwrap();
+        let expire_periodic : Receiver<()>;
+
+        expire_periodic = expire_timer.periodic(Duration::minutes(PERIODIC_EXPIRE_M as i64));
+
+        loop
+        {
+            self.wait(&expire_periodic);
+
+            if expire_periodic.try_recv().is_ok()
+            {
+                self.mempool.expire();
+                self.orphanage.expire();
+                self.tracker.expire();
+                self.block_tracker.expire();
+            }
+
+            for i in range(0,self.channels.len())
+            {
+                let maybereq = self.channels[i].receiver.try_recv();
+
+                match maybereq
+                {
+                    Ok(request)       => self.handle_request(i,request),
+                    Err(Empty)        => (),
+                    Err(Disconnected) =>
+                    {
+                        self.channels.remove(i);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,655 @@
+#![feature(macro_rules)]
+
+extern crate getopts;
+extern crate serialize;
+
+use std::io::BufferedStream;
+use std::io::TcpStream;
+use std::io::File;
+use std::io::net::ip::SocketAddr;
+use std::io::net::ip::IpAddr;
+
+use std::collections::TreeMap;
+
+use getopts::optflag;
+use getopts::optopt;
+
+use serialize::json;
+use serialize::json::Json;
+use serialize::json::ToJson;
+use serialize::base64::ToBase64;
+use serialize::base64::STANDARD;
+
+use common::ConfigFileError;
+
+mod common;
+
+/* Command line client of the JSON-RPC server of a running node.  It finds the
+ * node and the credentials the same way the node does: the data directory,
+ * the configuration file in it and the cookie file the node writes in the
+ * directory of the network.
+ *
+ * It is a program of its own, it only shares the common module with the
+ * node.
+ */
+
+const NAME : &'static str = "rustybit-cli";
+
+const DEFAULT_NETWORK : &'static str = "main";
+
+pub const OPT_DESC_HELP : &'static str
+    = "Display this help and exit";
+pub const OPT_DESC_VERSION : &'static str
+    = "Output version information and exit";
+pub const OPT_DESC_DATADIR : &'static str
+    = "Data directory of the node (default ~/.rustybit)";
+pub const OPT_DESC_NETWORK : &'static str
+    = "Network of the node: main, test, signet or regtest (default main)";
+pub const OPT_DESC_RPC_CONNECT : &'static str
+    = "Address of the node (default its rpc-bind, or 127.0.0.1)";
+pub const OPT_DESC_RPC_PORT : &'static str
+    = "Port of the node (default the one of the network)";
+pub const OPT_DESC_RPC_USER : &'static str
+    = "Username for JSON-RPC requests";
+pub const OPT_DESC_RPC_PASSWORD : &'static str
+    = "Password for JSON-RPC requests, the cookie file is used if unset";
+pub const OPT_DESC_NAMED : &'static str
+    = "Take the arguments by name, as name=value";
+pub const OPT_DESC_GETINFO : &'static str
+    = "Show a summary of the state of the node";
+
+/* Width of the option column in the usage */
+const OPT_USAGE_WIDTH : uint = 38;
+
+/* The arguments that are JSON values, by method, position and name.  The
+ * others are strings.
+ */
+static JSON_PARAMS : &'static [(&'static str, uint, &'static str)] = &[
+    ("getrawmempool",     0, "verbose"),
+    ("getrawtransaction", 1, "verbose"),
+    ("disconnectnode",    1, "nodeid"),
+    ("setban",            2, "bantime"),
+    ("setban",            3, "absolute"),
+    ("logging",           0, "include"),
+    ("logging",           1, "exclude"),
+    ("logging",           3, "peers") ];
+
+#[deriving(Show)]
+enum CliError
+{
+    InvalidArgument(String),
+    ConfigError(String),
+    NoCredentials,
+    ConnectError,
+    IOError,
+    InvalidReply,
+    Unauthorized,
+    RpcError(i64,String)
+}
+
+impl CliError
+{
+    fn get_message(&self) -> String
+    {
+        match *self
+        {
+            CliError::InvalidArgument(ref err) => err.clone(),
+            CliError::ConfigError(ref err)     => err.clone(),
+            CliError::NoCredentials            =>
+                format!("could not find the RPC credentials: no {} file in the directory of \
+                         the network and no rpc-password is set",common::COOKIE_FILE_NAME),
+            CliError::ConnectError             =>
+                "could not connect to the node, is it running?".to_string(),
+            CliError::IOError                  =>
+                "connection to the node failed".to_string(),
+            CliError::InvalidReply             =>
+                "invalid reply from the node".to_string(),
+            CliError::Unauthorized             =>
+                "authorization failed: wrong rpc-user or rpc-password".to_string(),
+            CliError::RpcError(code, ref msg)  =>
+                format!("error code: {}\nerror message:\n{}",code,msg)
+        }
+    }
+
+    /* Like the reference implementation, RPC errors exit with their code */
+    fn get_exit_status(&self) -> int
+    {
+        match *self
+        {
+            CliError::RpcError(code, _) => (code as int).abs(),
+            _                           => 1
+        }
+    }
+}
+
+macro_rules! try_io(
+    ($e:expr) => (match $e { Ok(e) => e, Err(_) => return Err(CliError::IOError) }))
+
+struct Options
+{
+    help     : bool,
+    version  : bool,
+    datadir  : Path,
+    network  : String,
+    host     : IpAddr,
+    port     : Option<u16>,
+    user     : Option<String>,
+    password : Option<String>,
+    named    : bool,
+    getinfo  : bool,
+    /* The method and its arguments */
+    args     : Vec<String>
+}
+
+#[allow(unused_must_use)]
+fn print_usage(out : &mut std::io::LineBufferedWriter<std::io::stdio::StdWriter>)
+{
+    let program : &String = &std::os::args()[0];
+
+    print_version(out);
+
+    write!(out,"Usage: {} [OPTIONS] METHOD [ARGS...]\n", program);
+    write!(out,"       {} [OPTIONS] --getinfo\n", program);
+    write!(out,"\n");
+    write!(out,"Options:\n");
+    print_option(out,"h","help","",OPT_DESC_HELP);
+    print_option(out,"v","version","",OPT_DESC_VERSION);
+    print_option(out,"d","datadir","DIR",OPT_DESC_DATADIR);
+    print_option(out,"n","network","NAME",OPT_DESC_NETWORK);
+    print_option(out,"","rpc-connect","IP",OPT_DESC_RPC_CONNECT);
+    print_option(out,"","rpc-port","PORT",OPT_DESC_RPC_PORT);
+    print_option(out,"","rpc-user","USER",OPT_DESC_RPC_USER);
+    print_option(out,"","rpc-password","PASS",OPT_DESC_RPC_PASSWORD);
+    print_option(out,"","named","",OPT_DESC_NAMED);
+    print_option(out,"","getinfo","",OPT_DESC_GETINFO);
+    write!(out,"\n");
+    write!(out,"The network and the rpc options are also read from {} in the data\n",
+           common::CONFIG_FILE_NAME);
+    write!(out,"directory.  The command line overrides them.\n");
+}
+
+#[allow(unused_must_use)]
+fn print_option(out   : &mut std::io::LineBufferedWriter<std::io::stdio::StdWriter>,
+                short : &str,
+                name  : &str,
+                hint  : &str,
+                desc  : &str)
+{
+    let mut opt : String = String::from_str("  ");
+
+    if short.is_empty()
+    {
+        opt.push_str("    ");
+    }
+    else
+    {
+        opt.push_str(format!("-{}, ",short).as_slice());
+    }
+
+    opt.push_str(format!("--{} {}",name,hint).as_slice());
+
+    if opt.len() < OPT_USAGE_WIDTH
+    {
+        opt.push_str(String::from_str(" ").repeat(OPT_USAGE_WIDTH-opt.len()).as_slice());
+    }
+    else
+    {
+        opt.push_str(" ");
+    }
+
+    write!(out,"{}{}\n",opt,desc);
+}
+
+#[allow(unused_must_use)]
+fn print_version(out : &mut std::io::LineBufferedWriter<std::io::stdio::StdWriter>)
+{
+    write!(out, "{} {}\n",NAME,option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"));
+}
+
+/* The settings of the node we care about */
+fn read_config_file(path : &Path, options : &mut Options) -> Result<(),CliError>
+{
+    let settings : Vec<(String,String)> = match common::read_config_file(path)
+    {
+        Ok(settings)                            => settings,
+        Err(ConfigFileError::ReadError)         =>
+            return Err(CliError::ConfigError(format!("cannot read {}",path.display()))),
+        Err(ConfigFileError::InvalidLine(line)) =>
+            return Err(CliError::ConfigError(format!("{}: invalid line {}",
+                                                     path.display(),line)))
+    };
+
+    for &(ref name, ref value) in settings.iter()
+    {
+        try!(set_option(options,name.as_slice(),value.as_slice()));
+    }
+
+    Ok(())
+}
+
+fn set_option(options : &mut Options, name : &str, value : &str) -> Result<(),CliError>
+{
+    let invalid = || CliError::ConfigError(format!("invalid value for {}: {}",name,value));
+
+    match name
+    {
+        "network"                     =>
+        {
+            if common::default_rpc_port(value).is_none()
+            {
+                return Err(invalid());
+            }
+
+            options.network = value.to_string();
+        },
+        "rpc-bind" | "rpc-connect"    =>
+            options.host = match from_str::<IpAddr>(value)
+            {
+                Some(host) => host,
+                None       => return Err(invalid())
+            },
+        "rpc-port"                    =>
+            options.port = match from_str::<u16>(value)
+            {
+                Some(port) => Some(port),
+                None       => return Err(invalid())
+            },
+        "rpc-user"                    => options.user = Some(value.to_string()),
+        "rpc-password"                => options.password = Some(value.to_string()),
+        _                             => ()
+    }
+
+    Ok(())
+}
+
+fn parse_options() -> Result<Options,CliError>
+{
+    let opts = vec![ optflag("h", "help",         OPT_DESC_HELP),
+                     optflag("v", "version",      OPT_DESC_VERSION),
+                     optopt("d",  "datadir",      OPT_DESC_DATADIR, "DIR"),
+                     optopt("n",  "network",      OPT_DESC_NETWORK, "NAME"),
+                     optopt("",   "rpc-connect",  OPT_DESC_RPC_CONNECT, "IP"),
+                     optopt("",   "rpc-port",     OPT_DESC_RPC_PORT, "PORT"),
+                     optopt("",   "rpc-user",     OPT_DESC_RPC_USER, "USER"),
+                     optopt("",   "rpc-password", OPT_DESC_RPC_PASSWORD, "PASS"),
+                     optflag("",  "named",        OPT_DESC_NAMED),
+                     optflag("",  "getinfo",      OPT_DESC_GETINFO) ];
+    let matches : getopts::Matches;
+    let mut options : Options;
+    let path : Path;
+
+    matches = match getopts::getopts(std::os::args().as_slice(), opts.as_slice())
+    {
+        Ok(m)  => m,
+        Err(e) => return Err(CliError::InvalidArgument(format!("{}",e)))
+    };
+
+    options = Options
+    {
+        help:     matches.opt_present("h"),
+        version:  matches.opt_present("v"),
+        datadir:  match matches.opt_str("d")
+                  {
+                      Some(dir) => Path::new(dir),
+                      None      => common::default_datadir()
+                  },
+        network:  DEFAULT_NETWORK.to_string(),
+        host:     from_str("127.0.0.1").unwrap(),
+        port:     None,
+        user:     None,
+        password: None,
+        named:    matches.opt_present("named"),
+        getinfo:  matches.opt_present("getinfo"),
+        args:     matches.free.iter().skip(1).map(|arg| arg.clone()).collect()
+    };
+
+    /* The configuration file first, then the command line overrides it */
+    path = options.datadir.join(common::CONFIG_FILE_NAME);
+
+    if path.exists()
+    {
+        try!(read_config_file(&path,&mut options));
+    }
+
+    for name in ["network", "rpc-connect", "rpc-port", "rpc-user", "rpc-password"].iter()
+    {
+        match matches.opt_str(*name)
+        {
+            Some(value) => try!(set_option(&mut options,*name,value.as_slice())),
+            None        => ()
+        }
+    }
+
+    Ok(options)
+}
+
+/* "user:password", from the options or from the cookie file of the node */
+fn get_credentials(options : &Options) -> Result<String,CliError>
+{
+    match options.password
+    {
+        Some(ref password) =>
+            return Ok(format!("{}:{}",options.user.clone().unwrap_or(String::new()),password)),
+        None               => ()
+    }
+
+    match File::open(&common::cookie_path(&options.datadir,options.network.as_slice()))
+              .read_to_string()
+    {
+        Ok(cookie) => Ok(cookie.as_slice().trim().to_string()),
+        Err(_)     => Err(CliError::NoCredentials)
+    }
+}
+
+fn is_json_param(method : &str, position : uint, name : &str) -> bool
+{
+    JSON_PARAMS.iter().any(|&(m, p, n)| m == method && (p == position || n == name))
+}
+
+fn parse_param(method : &str, position : uint, name : &str, arg : &str) -> Result<Json,CliError>
+{
+    if !is_json_param(method,position,name)
+    {
+        return Ok(arg.to_string().to_json());
+    }
+
+    match json::from_str(arg)
+    {
+        Ok(value) => Ok(value),
+        Err(_)    => Err(CliError::InvalidArgument(format!("invalid JSON in argument {}",
+                                                            arg)))
+    }
+}
+
+/* Positional arguments are an array, named ones an object */
+fn parse_params(method : &str, args : &[String], named : bool) -> Result<Json,CliError>
+{
+    let mut list : Vec<Json> = Vec::new();
+    let mut object : TreeMap<String,Json> = TreeMap::new();
+
+    for (i, arg) in args.iter().enumerate()
+    {
+        let sep : uint;
+        let name : &str;
+        let value : &str;
+
+        if !named
+        {
+            list.push(try!(parse_param(method,i,"",arg.as_slice())));
+            continue;
+        }
+
+        sep = match arg.as_slice().find('=')
+        {
+            Some(sep) => sep,
+            None      => return Err(CliError::InvalidArgument(
+                format!("no '=' in named argument {}",arg)))
+        };
+
+        name = arg.as_slice().slice_to(sep);
+        value = arg.as_slice().slice_from(sep+1);
+
+        object.insert(name.to_string(),try!(parse_param(method,::std::uint::MAX,name,value)));
+    }
+
+    if named
+    {
+        Ok(object.to_json())
+    }
+    else
+    {
+        Ok(list.to_json())
+    }
+}
+
+fn request(method : &str, params : Json, id : uint) -> Json
+{
+    let mut request : TreeMap<String,Json> = TreeMap::new();
+
+    request.insert("jsonrpc".to_string(),"1.0".to_string().to_json());
+    request.insert("method".to_string(),method.to_string().to_json());
+    request.insert("params".to_string(),params);
+    request.insert("id".to_string(),id.to_json());
+
+    request.to_json()
+}
+
+fn read_line(stream : &mut BufferedStream<TcpStream>) -> Result<String,CliError>
+{
+    let line : String = try_io!(stream.read_line());
+
+    Ok(line.as_slice().trim_right_chars(['\r','\n'].as_slice()).to_string())
+}
+
+/* Posts the request and returns the reply.  Errors have a reply too, only the
+ * failed authentication has not.
+ */
+fn post(options : &Options, credentials : &str, request : &Json) -> Result<Json,CliError>
+{
+    let address : SocketAddr;
+    let mut stream : BufferedStream<TcpStream>;
+    let body : String = format!("{}",request);
+    let status : String;
+    let mut length : Option<uint> = None;
+    let reply : Vec<u8>;
+
+    address = SocketAddr
+    {
+        ip:   options.host,
+        port: options.port.unwrap_or(common::default_rpc_port(options.network.as_slice())
+                                     .unwrap())
+    };
+
+    stream = match TcpStream::connect(address)
+    {
+        Ok(stream) => BufferedStream::new(stream),
+        Err(_)     => return Err(CliError::ConnectError)
+    };
+
+    try_io!(stream.write_str("POST / HTTP/1.1\r\n"));
+    try_io!(stream.write_str(format!("Host: {}\r\n",address).as_slice()));
+    try_io!(stream.write_str(format!("Authorization: Basic {}\r\n",
+                                     credentials.as_bytes().to_base64(STANDARD)).as_slice()));
+    try_io!(stream.write_str("Content-Type: application/json\r\n"));
+    try_io!(stream.write_str(format!("Content-Length: {}\r\n",body.len()).as_slice()));
+    try_io!(stream.write_str("Connection: close\r\n\r\n"));
+    try_io!(stream.write_str(body.as_slice()));
+    try_io!(stream.flush());
+
+    status = try!(read_line(&mut stream));
+
+    match status.as_slice().split(' ').nth(1)
+    {
+        Some("401") => return Err(CliError::Unauthorized),
+        Some(_)     => (),
+        None        => return Err(CliError::InvalidReply)
+    }
+
+    loop
+    {
+        let header : String = try!(read_line(&mut stream));
+        let lower : String = header.as_slice().chars().map(|c| c.to_lowercase()).collect();
+
+        if header.is_empty()
+        {
+            break;
+        }
+
+        if lower.as_slice().starts_with("content-length:")
+        {
+            length = from_str::<uint>(header.as_slice().slice_from(15).trim());
+        }
+    }
+
+    reply = match length
+    {
+        Some(length) => try_io!(stream.read_exact(length)),
+        None         => try_io!(stream.read_to_end())
+    };
+
+    match std::str::from_utf8(reply.as_slice()).and_then(|reply| json::from_str(reply).ok())
+    {
+        Some(reply) => Ok(reply),
+        None        => Err(CliError::InvalidReply)
+    }
+}
+
+fn get_result(reply : &Json) -> Result<Json,CliError>
+{
+    match reply.find("error")
+    {
+        Some(err) if !err.is_null() =>
+            return Err(CliError::RpcError(err.find("code").and_then(|c| c.as_i64())
+                                                         .unwrap_or(0),
+                                          err.find("message").and_then(|m| m.as_string())
+                                                             .unwrap_or("").to_string())),
+        _                           => ()
+    }
+
+    match reply.find("result")
+    {
+        Some(result) => Ok(result.clone()),
+        None         => Err(CliError::InvalidReply)
+    }
+}
+
+/* Strings are printed as they are, without quotes */
+fn print_result(result : &Json)
+{
+    match result.as_string()
+    {
+        Some(str)                   => println!("{}",str),
+        None if result.is_null()    => (),
+        None                        => println!("{}",result.to_pretty_str())
+    }
+}
+
+/* A field of an object, for people to read */
+fn field(object : &Json, name : &str) -> String
+{
+    match object.find(name)
+    {
+        Some(value) => match value.as_string()
+        {
+            Some(str) => str.to_string(),
+            None      => format!("{}",value)
+        },
+        None        => "-".to_string()
+    }
+}
+
+fn getinfo(options : &Options, credentials : &str) -> Result<(),CliError>
+{
+    let batch : Json = vec![request("getblockchaininfo",Vec::<Json>::new().to_json(),0),
+                            request("getnetworkinfo",Vec::<Json>::new().to_json(),1)].to_json();
+    let replies : Json = try!(post(options,credentials,&batch));
+    let reply = |id : u64| -> Result<Json,CliError>
+    {
+        match replies.as_list().and_then(|r| r.iter().find(|r| r.find("id")
+                                                               .and_then(|i| i.as_u64())
+                                                               == Some(id)))
+        {
+            Some(reply) => get_result(reply),
+            None        => Err(CliError::InvalidReply)
+        }
+    };
+    let chain : Json = try!(reply(0));
+    let network : Json = try!(reply(1));
+    let mut local : Vec<String> = Vec::new();
+
+    match network.find("localaddresses").and_then(|l| l.as_list())
+    {
+        Some(addrs) =>
+        {
+            for addr in addrs.iter()
+            {
+                local.push(format!("{}:{}",field(addr,"address"),field(addr,"port")));
+            }
+        },
+        None        => ()
+    }
+
+    println!("Chain: {}",field(&chain,"chain"));
+    println!("Blocks: {}",field(&chain,"blocks"));
+    println!("Best block: {}",field(&chain,"bestblockhash"));
+    println!("Difficulty: {}",field(&chain,"difficulty"));
+    println!("");
+    println!("Network: in {}, out {}, total {}",field(&network,"connections_in"),
+             field(&network,"connections_out"),field(&network,"connections"));
+    println!("Version: {} {}",field(&network,"version"),field(&network,"subversion"));
+    println!("Protocol version: {}",field(&network,"protocolversion"));
+    println!("Local addresses: {}",if local.is_empty() { "-".to_string() }
+                                   else { local.connect(", ") });
+
+    Ok(())
+}
+
+fn run(options : &Options) -> Result<(),CliError>
+{
+    let credentials : String = try!(get_credentials(options));
+    let method : &str;
+    let params : Json;
+    let reply : Json;
+
+    if options.getinfo
+    {
+        return getinfo(options,credentials.as_slice());
+    }
+
+    method = match options.args.as_slice().head()
+    {
+        Some(method) => method.as_slice(),
+        None         => return Err(CliError::InvalidArgument("no method given".to_string()))
+    };
+
+    params = try!(parse_params(method,options.args.as_slice().tail(),options.named));
+    reply = try!(post(options,credentials.as_slice(),&request(method,params,1)));
+
+    print_result(&try!(get_result(&reply)));
+
+    Ok(())
+}
+
+fn main()
+{
+    let options : Options;
+
+    options = match parse_options()
+    {
+        Ok(options) => options,
+        Err(err)    =>
+        {
+            (write!(&mut std::io::stderr(),"error: {}\n",err.get_message())).unwrap();
+            std::os::set_exit_status(1);
+            return;
+        }
+    };
+
+    if options.help
+    {
+        print_usage(&mut std::io::stdout());
+        return;
+    }
+    else if options.version
+    {
+        print_version(&mut std::io::stdout());
+        return;
+    }
+
+    match run(&options)
+    {
+        Ok(())   => (),
+        Err(err) =>
+        {
+            match err
+            {
+                CliError::RpcError(..) =>
+                    (write!(&mut std::io::stderr(),"{}\n",err.get_message())).unwrap(),
+                _                      =>
+                    (write!(&mut std::io::stderr(),"error: {}\n",err.get_message())).unwrap()
+            }
+
+            std::os::set_exit_status(err.get_exit_status());
+        }
+    }
+}
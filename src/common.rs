@@ -0,0 +1,91 @@
+use std::io::File;
+
+/* What the node and rustybit-cli both need to know to find each other: the
+ * data directory, the configuration file in it, the cookie file and the RPC
+ * ports.  The cli is a program of its own and only includes this module, so
+ * it must not use any other module of the node.
+ */
+
+/* Name of the configuration file in the data directory */
+pub const CONFIG_FILE_NAME : &'static str = "rustybit.conf";
+
+/* Name of the cookie file in the directory of the network */
+pub const COOKIE_FILE_NAME : &'static str = ".cookie";
+
+const DATADIR_NAME : &'static str = ".rustybit";
+
+pub const MAINNET_RPC_PORT : u16 = 8332;
+pub const TESTNET_RPC_PORT : u16 = 18332;
+pub const SIGNET_RPC_PORT : u16 = 38332;
+pub const REGTEST_RPC_PORT : u16 = 18443;
+
+#[deriving(Show)]
+pub enum ConfigFileError
+{
+    ReadError,
+    InvalidLine(uint)  /* Line number */
+}
+
+/* ~/.rustybit, or the current directory if we have no home */
+pub fn default_datadir() -> Path
+{
+    match ::std::os::homedir()
+    {
+        Some(home) => home.join(DATADIR_NAME),
+        None       => Path::new(".")
+    }
+}
+
+/* Each network has its own cookie, in a directory named after it */
+pub fn cookie_path(datadir : &Path, network : &str) -> Path
+{
+    datadir.join(network).join(COOKIE_FILE_NAME)
+}
+
+#[allow(dead_code)]
+pub fn default_rpc_port(network : &str) -> Option<u16>
+{
+    match network
+    {
+        "main"    => Some(MAINNET_RPC_PORT),
+        "test"    => Some(TESTNET_RPC_PORT),
+        "signet"  => Some(SIGNET_RPC_PORT),
+        "regtest" => Some(REGTEST_RPC_PORT),
+        _         => None
+    }
+}
+
+/* Lines are "name=value", blank or comments starting with a '#'.  Returns the
+ * names and values in the order they appear.
+ */
+pub fn read_config_file(path : &Path) -> Result<Vec<(String,String)>,ConfigFileError>
+{
+    let mut settings : Vec<(String,String)> = Vec::new();
+    let contents : String = match File::open(path).read_to_string()
+    {
+        Ok(contents) => contents,
+        Err(_)       => return Err(ConfigFileError::ReadError)
+    };
+
+    for (i, line) in contents.as_slice().lines().enumerate()
+    {
+        let line : &str = line.trim();
+        let sep : uint;
+
+        if line.is_empty() || line.starts_with("#")
+        {
+            continue;
+        }
+
+        sep = match line.find('=')
+        {
+            Some(sep) => sep,
+            None      => return Err(ConfigFileError::InvalidLine(i+1))
+        };
+
+        settings.push((line.slice_to(sep).trim().to_string(),
+                       line.slice_from(sep+1).trim().to_string()));
+    }
+
+    Ok(settings)
+}
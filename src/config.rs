@@ -1,3 +1,12 @@
+use std::io::net::ip::SocketAddr;
+use std::io::net::ip::IpAddr;
+use std::from_str::FromStr;
+
+use logger::LogLevel;
+use logger::LogFormat;
+
+use common::ConfigFileError;
+
 pub const NAME : &'static str = "rustybit";
 
 pub const VERSION_MAJOR : u8 = 0;
@@ -6,32 +15,600 @@ pub const VERSION_FIXES : u8 = 0;
 
 pub const VERSION_SUFIX : Option<&'static str> = Some("dev");
 
+#[deriving(Clone, PartialEq, Show)]
 pub enum Network
 {
-    MainNet = 0xD9B4BEF9
+    MainNet,
+    TestNet,
+    SigNet,
+    RegTest
+}
+
+/* Consensus rules that differ between networks */
+pub struct ConsensusParams
+{
+    pub pow_limit_bits           : u32,
+    /* Blocks found long after the previous one may have minimum difficulty */
+    pub pow_allow_min_difficulty : bool,
+    pub pow_no_retargeting       : bool,
+    pub subsidy_halving_interval : u32,
+    /* Height from which the coinbase must start with the block height */
+    pub bip34_height             : u32,
+    /* Script that signs the blocks of a signet, in hex */
+    pub signet_challenge         : Option<&'static str>
 }
 
-/* TODO This should be of type Network */
-pub const NETWORK : u32 = Network::MainNet as u32;
+pub struct NetworkParams
+{
+    pub network       : Network,
+    pub name          : &'static str,
+    pub magic         : u32,
+    pub default_port  : u16,
+    pub rpc_port      : u16,
+    /* The genesis blocks only differ in these fields of the header */
+    pub genesis_time  : u32,
+    pub genesis_bits  : u32,
+    pub genesis_nonce : u32,
+    pub dns_seeds     : &'static [&'static str],
+    /* Blocks at these heights must have these hashes */
+    pub checkpoints   : &'static [(u32, &'static str)],
+    pub consensus     : ConsensusParams,
+    /* Address prefixes: base58 versions and the bech32 human readable part */
+    pub pubkey_prefix : u8,
+    pub script_prefix : u8,
+    pub secret_prefix : u8,
+    pub bech32_hrp    : &'static str
+}
+
+pub static MAINNET_PARAMS : NetworkParams = NetworkParams
+{
+    network:       Network::MainNet,
+    name:          "main",
+    magic:         0xD9B4BEF9,
+    default_port:  8333,
+    rpc_port:      ::common::MAINNET_RPC_PORT,
+    genesis_time:  1231006505,
+    genesis_bits:  0x1d00ffff,
+    genesis_nonce: 2083236893,
+    dns_seeds:     &["seed.bitcoin.sipa.be",
+                     "dnsseed.bluematt.me",
+                     "dnsseed.bitcoin.dashjr.org",
+                     "seed.bitcoinstats.com",
+                     "seed.bitnodes.io",
+                     "bitseed.xf2.org"],
+    checkpoints:   &[( 11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+                     ( 33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+                     ( 74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+                     (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+                     (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+                     (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+                     (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+                     (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+                     (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+                     (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+                     (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+                     (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+                     (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983")],
+    consensus:     ConsensusParams
+    {
+        pow_limit_bits:           0x1d00ffff,
+        pow_allow_min_difficulty: false,
+        pow_no_retargeting:       false,
+        subsidy_halving_interval: 210000,
+        bip34_height:             227931,
+        signet_challenge:         None
+    },
+    pubkey_prefix: 0,
+    script_prefix: 5,
+    secret_prefix: 128,
+    bech32_hrp:    "bc"
+};
+
+pub static TESTNET_PARAMS : NetworkParams = NetworkParams
+{
+    network:       Network::TestNet,
+    name:          "test",
+    magic:         0x0709110B,
+    default_port:  18333,
+    rpc_port:      ::common::TESTNET_RPC_PORT,
+    genesis_time:  1296688602,
+    genesis_bits:  0x1d00ffff,
+    genesis_nonce: 414098458,
+    dns_seeds:     &["testnet-seed.bitcoin.jonasschnelli.ch",
+                     "seed.tbtc.petertodd.net",
+                     "seed.testnet.bitcoin.sprovoost.nl",
+                     "testnet-seed.bluematt.me"],
+    checkpoints:   &[(546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70")],
+    consensus:     ConsensusParams
+    {
+        pow_limit_bits:           0x1d00ffff,
+        pow_allow_min_difficulty: true,
+        pow_no_retargeting:       false,
+        subsidy_halving_interval: 210000,
+        bip34_height:             21111,
+        signet_challenge:         None
+    },
+    pubkey_prefix: 111,
+    script_prefix: 196,
+    secret_prefix: 239,
+    bech32_hrp:    "tb"
+};
+
+/* The default signet (BIP325), whose blocks are signed by a 1-of-2 multisig
+ * challenge.
+ */
+pub static SIGNET_PARAMS : NetworkParams = NetworkParams
+{
+    network:       Network::SigNet,
+    name:          "signet",
+    magic:         0x40CF030A,
+    default_port:  38333,
+    rpc_port:      ::common::SIGNET_RPC_PORT,
+    genesis_time:  1598918400,
+    genesis_bits:  0x1e0377ae,
+    genesis_nonce: 52613770,
+    dns_seeds:     &["seed.signet.bitcoin.sprovoost.nl"],
+    checkpoints:   &[],
+    consensus:     ConsensusParams
+    {
+        pow_limit_bits:           0x1e0377ae,
+        pow_allow_min_difficulty: false,
+        pow_no_retargeting:       false,
+        subsidy_halving_interval: 210000,
+        bip34_height:             1,
+        signet_challenge:         Some(concat!(
+            "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430",
+            "210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae"))
+    },
+    pubkey_prefix: 111,
+    script_prefix: 196,
+    secret_prefix: 239,
+    bech32_hrp:    "tb"
+};
+
+/* Local network for testing, blocks are mined instantly */
+pub static REGTEST_PARAMS : NetworkParams = NetworkParams
+{
+    network:       Network::RegTest,
+    name:          "regtest",
+    magic:         0xDAB5BFFA,
+    default_port:  18444,
+    rpc_port:      ::common::REGTEST_RPC_PORT,
+    genesis_time:  1296688602,
+    genesis_bits:  0x207fffff,
+    genesis_nonce: 2,
+    dns_seeds:     &[],
+    checkpoints:   &[],
+    consensus:     ConsensusParams
+    {
+        pow_limit_bits:           0x207fffff,
+        pow_allow_min_difficulty: true,
+        pow_no_retargeting:       true,
+        subsidy_halving_interval: 150,
+        bip34_height:             1,
+        signet_challenge:         None
+    },
+    pubkey_prefix: 111,
+    script_prefix: 196,
+    secret_prefix: 239,
+    bech32_hrp:    "bcrt"
+};
+
+impl Network
+{
+    pub fn from_name(name : &str) -> Option<Network>
+    {
+        match name
+        {
+            "main"    => Some(Network::MainNet),
+            "test"    => Some(Network::TestNet),
+            "signet"  => Some(Network::SigNet),
+            "regtest" => Some(Network::RegTest),
+            _         => None
+        }
+    }
+
+    pub fn get_params(&self) -> &'static NetworkParams
+    {
+        match *self
+        {
+            Network::MainNet => &MAINNET_PARAMS,
+            Network::TestNet => &TESTNET_PARAMS,
+            Network::SigNet  => &SIGNET_PARAMS,
+            Network::RegTest => &REGTEST_PARAMS
+        }
+    }
+}
 
-pub const PROTOCOL_VERSION : u32 = 70002;
+pub const PROTOCOL_VERSION : u32 = 70016;
 
 /* We reject peers with protocol versions smaller than this */
 pub const PROTOCOL_VERSION_MIN : u32 = 70002;
 
+/* First version with headers announcements (BIP130) */
+pub const SENDHEADERS_VERSION : u32 = 70012;
+
+/* First version with fee filters (BIP133) */
+pub const FEEFILTER_VERSION : u32 = 70013;
+
+/* First version with compact blocks (BIP152) */
+pub const SHORT_IDS_BLOCKS_VERSION : u32 = 70014;
+
+/* First version with tx relay by wtxid (BIP339) */
+pub const WTXID_RELAY_VERSION : u32 = 70016;
+
 pub enum Service
 {
-    NoService   = 0,
-    NodeNetwork = 1
+    NoService          = 0,
+    NodeNetwork        = 1 << 0,
+    NodeBloom          = 1 << 2,  /* BIP111 */
+    NodeCompactFilters = 1 << 6,  /* BIP157 */
+    NodeP2PV2          = 1 << 11  /* BIP324 */
 }
 
 pub type Services = u64;
 
-pub const SERVICES : Services = Service::NodeNetwork as Services;
 
-pub const INITIAL_DISCOVERY_PEERS : uint = 30;
+#[deriving(Show)]
+pub enum ConfigError
+{
+    ReadError,
+    InvalidLine(uint),         /* Line number */
+    UnknownKey(String),
+    InvalidValue(String,String)
+}
 
-pub const DEFAULT_PORT : u16 = 8333;
+/* What can be set in the configuration file, as "name=value" lines, and
+ * overridden in the command line, as "--name value".
+ */
+pub struct ConfigOption
+{
+    pub short : &'static str,
+    pub name  : &'static str,
+    pub hint  : &'static str,
+    pub desc  : &'static str
+}
+
+pub static CONFIG_OPTIONS : &'static [ConfigOption] = &[
+    ConfigOption { short: "n", name: "network", hint: "NAME",
+                   desc: "Network to use: main, test, signet or regtest (default main)" },
+    ConfigOption { short: "l", name: "listen", hint: "BOOL",
+                   desc: "Accept inbound connections (default false)" },
+    ConfigOption { short: "p", name: "port", hint: "PORT",
+                   desc: "Port to listen on (default the one of the network)" },
+    ConfigOption { short: "", name: "discovery-peers", hint: "N",
+                   desc: "Peers to connect to at startup (default 30)" },
+    ConfigOption { short: "", name: "max-addresses", hint: "N",
+                   desc: "Addresses of other peers we keep (default 2500)" },
+    ConfigOption { short: "", name: "max-inbound", hint: "N",
+                   desc: "Inbound peers we accept at the same time (default 117)" },
+    ConfigOption { short: "", name: "peer-timeout", hint: "S",
+                   desc: "Disconnect peers that do not answer a ping for this long (default 600)" },
+    ConfigOption { short: "", name: "connect-timeout", hint: "MS",
+                   desc: "Timeout to connect to a peer (default 10000)" },
+    ConfigOption { short: "", name: "write-timeout", hint: "MS",
+                   desc: "Timeout to write to a peer (default 300000)" },
+    ConfigOption { short: "", name: "read-timeout", hint: "MS",
+                   desc: "Timeout of each read from a peer (default 500)" },
+    ConfigOption { short: "", name: "v2-handshake-timeout", hint: "MS",
+                   desc: "Timeout of the v2 transport handshake (default 10000)" },
+    ConfigOption { short: "", name: "peer-bloom-filters", hint: "BOOL",
+                   desc: "Serve bloom filters and mempool requests to peers (default true)" },
+    ConfigOption { short: "", name: "peer-block-filters", hint: "BOOL",
+                   desc: "Serve compact block filters to peers (default true)" },
+    ConfigOption { short: "", name: "peer-v2-transport", hint: "BOOL",
+                   desc: "Use the encrypted v2 transport with peers that speak it (default true)" },
+    ConfigOption { short: "", name: "log-level", hint: "LEVEL",
+                   desc: "Log level: error, warn, info, debug or trace (default info)" },
+    ConfigOption { short: "", name: "log-format", hint: "FORMAT",
+                   desc: "Log format: text, or json with an object per line (default text)" },
+    ConfigOption { short: "", name: "log-categories", hint: "LIST",
+                   desc: "What to log, as category names separated by commas (default all)" },
+    ConfigOption { short: "", name: "log-peers", hint: "LIST",
+                   desc: "Only log about these peer IPs, separated by commas (default all)" },
+    ConfigOption { short: "", name: "log-file", hint: "FILE",
+                   desc: "Log to this file, relative to the data directory, not to stdout" },
+    ConfigOption { short: "", name: "log-file-max-size", hint: "BYTES",
+                   desc: "Rotate the log file at this size, 0 to never (default 10000000)" },
+    ConfigOption { short: "", name: "log-file-count", hint: "N",
+                   desc: "Rotated log files to keep (default 5)" },
+    ConfigOption { short: "", name: "proxy", hint: "ADDR",
+                   desc: "SOCKS5 proxy for outbound connections, i.e. 127.0.0.1:9050" },
+    ConfigOption { short: "", name: "proxy-username", hint: "USER",
+                   desc: "Username for the proxy" },
+    ConfigOption { short: "", name: "proxy-password", hint: "PASS",
+                   desc: "Password for the proxy" },
+    ConfigOption { short: "", name: "proxy-randomize-credentials", hint: "BOOL",
+                   desc: "Random proxy credentials for every connection (default true)" },
+    ConfigOption { short: "", name: "onion-service", hint: "BOOL",
+                   desc: "Accept connections through a tor onion service (default false)" },
+    ConfigOption { short: "", name: "tor-control", hint: "ADDR",
+                   desc: "Control port of tor (default 127.0.0.1:9051)" },
+    ConfigOption { short: "", name: "tor-control-password", hint: "PASS",
+                   desc: "Password for the control port of tor" },
+    ConfigOption { short: "", name: "onion-listen", hint: "ADDR",
+                   desc: "Where the onion service forwards to (default 127.0.0.1:8334)" },
+    ConfigOption { short: "", name: "rpc", hint: "BOOL",
+                   desc: "Accept JSON-RPC requests (default true)" },
+    ConfigOption { short: "", name: "rpc-bind", hint: "IP",
+                   desc: "Address to accept JSON-RPC requests on (default 127.0.0.1)" },
+    ConfigOption { short: "", name: "rpc-port", hint: "PORT",
+                   desc: "Port for JSON-RPC requests (default the one of the network)" },
+    ConfigOption { short: "", name: "rpc-user", hint: "USER",
+                   desc: "Username for JSON-RPC requests" },
+    ConfigOption { short: "", name: "rpc-password", hint: "PASS",
+                   desc: "Password for JSON-RPC requests, a cookie file is used if unset" },
+    ConfigOption { short: "", name: "rest", hint: "BOOL",
+                   desc: "Serve the REST interface on the RPC port (default false)" } ];
+
+pub struct Config
+{
+    pub datadir                     : Path,
+    pub network                     : Network,
+    pub listen                      : bool,
+    pub port                        : Option<u16>,
+    pub initial_discovery_peers     : uint,
+    pub max_addresses               : uint,
+    /* Further inbound connections are closed right away */
+    pub max_inbound                 : uint,
+    pub peer_timeout_s              : uint,
+    pub connect_timeout_ms          : uint,
+    pub write_timeout_ms            : uint,
+    pub read_timeout_ms             : uint,
+    pub v2_handshake_timeout_ms     : uint,
+    /* Serve bloom filters and mempool requests (BIP35 and BIP37) */
+    pub peer_bloom_filters          : bool,
+    /* Serve compact block filters (BIP157 and BIP158) */
+    pub peer_block_filters          : bool,
+    /* Encrypt the connections with the v2 transport (BIP324) when the peer
+     * speaks it.
+     */
+    pub peer_v2_transport           : bool,
+    pub log_level                   : LogLevel,
+    pub log_format                  : LogFormat,
+    pub log_categories              : u64,
+    pub log_peers                   : Vec<IpAddr>,
+    pub log_file                    : Option<Path>,
+    pub log_file_max_size           : u64,
+    pub log_file_count              : uint,
+    /* Route the outbound connections and DNS seed lookups through this SOCKS5
+     * proxy, i.e. a local tor daemon.
+     */
+    pub proxy                       : Option<SocketAddr>,
+    pub proxy_username              : Option<String>,
+    pub proxy_password              : Option<String>,
+    /* Authenticate to the proxy with fresh random credentials for each
+     * connection, so that tor isolates every connection in its own circuit.
+     */
+    pub proxy_randomize_credentials : bool,
+    /* Create an onion service through the control port of tor (ADD_ONION)
+     * and accept the connections it forwards to us.
+     */
+    pub onion_service               : bool,
+    pub tor_control                 : SocketAddr,
+    pub tor_control_password        : Option<String>,
+    pub onion_listen                : SocketAddr,
+    pub rpc                         : bool,
+    pub rpc_bind                    : IpAddr,
+    pub rpc_port                    : Option<u16>,
+    /* Without a password the clients authenticate with the cookie we write
+     * in the directory of the network.
+     */
+    pub rpc_user                    : Option<String>,
+    pub rpc_password                : Option<String>,
+    /* Read only and without authentication, only if rpc is set */
+    pub rest                        : bool
+}
+
+fn parse<T : FromStr>(key : &str, value : &str) -> Result<T,ConfigError>
+{
+    match from_str::<T>(value)
+    {
+        Some(v) => Ok(v),
+        None    => Err(ConfigError::InvalidValue(key.to_string(),value.to_string()))
+    }
+}
+
+/* Empty values unset the optional ones */
+fn parse_option<T : FromStr>(key : &str, value : &str) -> Result<Option<T>,ConfigError>
+{
+    if value.is_empty()
+    {
+        return Ok(None);
+    }
+
+    parse(key,value).map(|v| Some(v))
+}
+
+/* Comma separated, empty for none */
+fn parse_list<T : FromStr>(key : &str, value : &str) -> Result<Vec<T>,ConfigError>
+{
+    let mut list : Vec<T> = Vec::new();
+
+    for item in value.split(',').map(|i| i.trim()).filter(|i| !i.is_empty())
+    {
+        list.push(try!(parse(key,item)));
+    }
+
+    Ok(list)
+}
+
+#[allow(dead_code)]
+impl Config
+{
+    pub fn new(datadir : Path) -> Config
+    {
+        Config
+        {
+            datadir:                     datadir,
+            network:                     Network::MainNet,
+            listen:                      false,
+            port:                        None,
+            initial_discovery_peers:     30,
+            max_addresses:               2500,
+            max_inbound:                 117,
+            peer_timeout_s:              10*60,
+            connect_timeout_ms:          10000,
+            write_timeout_ms:            5*60*1000,
+            read_timeout_ms:             500,
+            v2_handshake_timeout_ms:     10000,
+            peer_bloom_filters:          true,
+            peer_block_filters:          true,
+            peer_v2_transport:           true,
+            log_level:                   ::logger::DEFAULT_LOG_LEVEL,
+            log_format:                  LogFormat::LogFormatText,
+            log_categories:              ::logger::DEFAULT_LOG_CATEGORIES,
+            log_peers:                   Vec::new(),
+            log_file:                    None,
+            log_file_max_size:           10000000,
+            log_file_count:              5,
+            proxy:                       None,
+            proxy_username:              None,
+            proxy_password:              None,
+            proxy_randomize_credentials: true,
+            onion_service:               false,
+            tor_control:                 from_str("127.0.0.1:9051").unwrap(),
+            tor_control_password:        None,
+            onion_listen:                from_str("127.0.0.1:8334").unwrap(),
+            rpc:                         true,
+            rpc_bind:                    from_str("127.0.0.1").unwrap(),
+            rpc_port:                    None,
+            rpc_user:                    None,
+            rpc_password:                None,
+            rest:                        false
+        }
+    }
+
+    /* The parameters of the network we are on */
+    pub fn get_params(&self) -> &'static NetworkParams
+    {
+        self.network.get_params()
+    }
+
+    pub fn get_port(&self) -> u16
+    {
+        self.port.unwrap_or(self.get_params().default_port)
+    }
+
+    pub fn get_rpc_port(&self) -> u16
+    {
+        self.rpc_port.unwrap_or(self.get_params().rpc_port)
+    }
+
+    /* What we offer to our peers */
+    pub fn get_services(&self) -> Services
+    {
+        let mut services : Services = Service::NodeNetwork as Services;
+
+        if self.peer_bloom_filters
+        {
+            services |= Service::NodeBloom as Services;
+        }
+
+        if self.peer_block_filters
+        {
+            services |= Service::NodeCompactFilters as Services;
+        }
+
+        if self.peer_v2_transport
+        {
+            services |= Service::NodeP2PV2 as Services;
+        }
+
+        services
+    }
+
+    pub fn set(&mut self, key : &str, value : &str) -> Result<(),ConfigError>
+    {
+        match key
+        {
+            "network" =>
+                self.network = match Network::from_name(value)
+                {
+                    Some(network) => network,
+                    None          =>
+                        return Err(ConfigError::InvalidValue(key.to_string(),value.to_string()))
+                },
+            "listen"                      => self.listen = try!(parse(key,value)),
+            "port"                        => self.port = try!(parse_option(key,value)),
+            "discovery-peers"             => self.initial_discovery_peers = try!(parse(key,value)),
+            "max-addresses"               => self.max_addresses = try!(parse(key,value)),
+            "max-inbound"                 => self.max_inbound = try!(parse(key,value)),
+            "peer-timeout"                => self.peer_timeout_s = try!(parse(key,value)),
+            "connect-timeout"             => self.connect_timeout_ms = try!(parse(key,value)),
+            "write-timeout"               => self.write_timeout_ms = try!(parse(key,value)),
+            "read-timeout"                => self.read_timeout_ms = try!(parse(key,value)),
+            "v2-handshake-timeout"        => self.v2_handshake_timeout_ms = try!(parse(key,value)),
+            "peer-bloom-filters"          => self.peer_bloom_filters = try!(parse(key,value)),
+            "peer-block-filters"          => self.peer_block_filters = try!(parse(key,value)),
+            "peer-v2-transport"           => self.peer_v2_transport = try!(parse(key,value)),
+            "log-level"                   =>
+                self.log_level = match LogLevel::from_name(value)
+                {
+                    Some(level) => level,
+                    None        =>
+                        return Err(ConfigError::InvalidValue(key.to_string(),value.to_string()))
+                },
+            "log-format"                  =>
+                self.log_format = match LogFormat::from_name(value)
+                {
+                    Some(format) => format,
+                    None         =>
+                        return Err(ConfigError::InvalidValue(key.to_string(),value.to_string()))
+                },
+            "log-categories"              =>
+                self.log_categories = match ::logger::parse_categories(value)
+                {
+                    Some(categories) => categories,
+                    None             =>
+                        return Err(ConfigError::InvalidValue(key.to_string(),value.to_string()))
+                },
+            "log-peers"                   => self.log_peers = try!(parse_list(key,value)),
+            "log-file"                    =>
+                self.log_file = if value.is_empty() { None }
+                                else { Some(self.datadir.join(value)) },
+            "log-file-max-size"           => self.log_file_max_size = try!(parse(key,value)),
+            "log-file-count"              => self.log_file_count = try!(parse(key,value)),
+            "proxy"                       => self.proxy = try!(parse_option(key,value)),
+            "proxy-username"              => self.proxy_username = try!(parse_option(key,value)),
+            "proxy-password"              => self.proxy_password = try!(parse_option(key,value)),
+            "proxy-randomize-credentials" =>
+                self.proxy_randomize_credentials = try!(parse(key,value)),
+            "onion-service"               => self.onion_service = try!(parse(key,value)),
+            "tor-control"                 => self.tor_control = try!(parse(key,value)),
+            "tor-control-password"        =>
+                self.tor_control_password = try!(parse_option(key,value)),
+            "onion-listen"                => self.onion_listen = try!(parse(key,value)),
+            "rpc"                         => self.rpc = try!(parse(key,value)),
+            "rpc-bind"                    => self.rpc_bind = try!(parse(key,value)),
+            "rpc-port"                    => self.rpc_port = try!(parse_option(key,value)),
+            "rpc-user"                    => self.rpc_user = try!(parse_option(key,value)),
+            "rpc-password"                => self.rpc_password = try!(parse_option(key,value)),
+            "rest"                        => self.rest = try!(parse(key,value)),
+            _                             => return Err(ConfigError::UnknownKey(key.to_string()))
+        }
+
+        Ok(())
+    }
+
+    /* The settings of the configuration file, see ::common::read_config_file */
+    pub fn read_file(&mut self, path : &Path) -> Result<(),ConfigError>
+    {
+        let settings : Vec<(String,String)> = match ::common::read_config_file(path)
+        {
+            Ok(settings)                            => settings,
+            Err(ConfigFileError::ReadError)         => return Err(ConfigError::ReadError),
+            Err(ConfigFileError::InvalidLine(line)) => return Err(ConfigError::InvalidLine(line))
+        };
+
+        for &(ref key, ref value) in settings.iter()
+        {
+            try!(self.set(key.as_slice(),value.as_slice()));
+        }
+
+        Ok(())
+    }
+}
 
 pub fn version() -> String
 {
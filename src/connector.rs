@@ -0,0 +1,403 @@
+use std::io::net::ip::SocketAddr;
+use std::io::net::ip::IpAddr;
+use std::io::net::ip::Ipv4Addr;
+use std::io::net::ip::Ipv6Addr;
+use std::io::TcpStream;
+
+use std::rand::Rng;
+
+use std::time::duration::Duration;
+
+use std::io::net::addrinfo::get_host_addresses;
+
+use config::Config;
+
+use datatype::netaddr::NetAddrV2;
+
+macro_rules! try_proxy(
+    ($e:expr) => (match $e { Ok(e) => e, Err(_) => return Err(ConnectorError::ProxyIOError) }))
+
+/* SOCKS5 (RFC1928) and username/password authentication (RFC1929) */
+const SOCKS5_VERSION : u8 = 0x05;
+const SOCKS5_AUTH_VERSION : u8 = 0x01;
+
+const SOCKS5_METHOD_NONE : u8 = 0x00;
+const SOCKS5_METHOD_USERPASS : u8 = 0x02;
+const SOCKS5_METHOD_NOT_ACCEPTABLE : u8 = 0xFF;
+
+const SOCKS5_CMD_CONNECT : u8 = 0x01;
+const SOCKS5_CMD_RESOLVE : u8 = 0xF0; /* Tor extension */
+
+const SOCKS5_ATYP_IPV4 : u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN : u8 = 0x03;
+const SOCKS5_ATYP_IPV6 : u8 = 0x04;
+
+const SOCKS5_REPLY_SUCCEEDED : u8 = 0x00;
+
+const SOCKS5_MAX_FIELD_SIZE : uint = 255;
+
+/* Time we allow the proxy to answer each step of the negotiation, Tor may
+ * take a while to build a circuit.
+ */
+const TIMEOUT_PROXY_MS : u64 = 20000;
+
+#[deriving(Show)]
+pub enum ConnectorError
+{
+    ConnectError,
+    ResolveError,
+    HostnameTooLong,
+    ProxyIOError,
+    ProxyNoAcceptableAuth,
+    ProxyAuthFailed,
+    ProxyInvalidReply,
+    ProxyReplyError(u8)
+}
+
+#[deriving(Clone, Show)]
+pub enum Destination
+{
+    Ip(SocketAddr),
+    Host(String,u16)  /* Hostnames, i.e. onion addresses, resolved by the proxy */
+}
+
+pub struct Socks5Proxy
+{
+    addr        : SocketAddr,
+    credentials : Option<(String,String)>,
+    randomize   : bool  /* Tor stream isolation (IsolateSOCKSAuth) */
+}
+
+pub enum Connector
+{
+    Direct,
+    Socks5(Socks5Proxy)
+}
+
+/* The connector for outbound connections, according to the configuration.
+ */
+pub fn connector(config : &Config) -> Connector
+{
+    let addr : SocketAddr = match config.proxy
+    {
+        Some(addr) => addr,
+        None       => return Connector::Direct
+    };
+    let credentials : Option<(String,String)>;
+
+    credentials = match (&config.proxy_username,&config.proxy_password)
+    {
+        (&Some(ref user),&Some(ref pass)) => Some((user.clone(),pass.clone())),
+        _                                 => None
+    };
+
+    Connector::Socks5(Socks5Proxy::new(addr,credentials,config.proxy_randomize_credentials))
+}
+
+/* Where to dial an address, None if we cannot reach its network.  Onion
+ * services are only reachable through the proxy, which we expect to be tor.
+ */
+pub fn destination(addr : &NetAddrV2, config : &Config) -> Option<Destination>
+{
+    match (addr.to_socket_addr(),addr.get_onion())
+    {
+        (Some(socketaddr),_)                          => Some(Destination::Ip(socketaddr)),
+        (_,Some(ref onion)) if config.proxy.is_some() =>
+            Some(Destination::Host(onion.clone(),addr.port)),
+        _                                             => None
+    }
+}
+
+impl Connector
+{
+    pub fn connect(&self, dest : &Destination, timeout : Duration)
+                   -> Result<TcpStream,ConnectorError>
+    {
+        match *self
+        {
+            Connector::Direct =>
+            {
+                match *dest
+                {
+                    Destination::Ip(addr) => connect_direct(addr,timeout),
+                    Destination::Host(ref host,port) =>
+                    {
+                        let addrs : Vec<IpAddr> = try!(resolve_direct(host.as_slice()));
+
+                        connect_direct(SocketAddr { ip: addrs[0], port: port },timeout)
+                    }
+                }
+            },
+            Connector::Socks5(ref proxy) => proxy.connect(dest,timeout)
+        }
+    }
+
+    /* Resolve a hostname, through the proxy if there is one so that the DNS
+     * lookups don't leak outside of it.
+     */
+    pub fn resolve(&self, host : &str) -> Result<Vec<IpAddr>,ConnectorError>
+    {
+        match *self
+        {
+            Connector::Direct            => resolve_direct(host),
+            Connector::Socks5(ref proxy) => proxy.resolve(host).map(|addr| vec![addr])
+        }
+    }
+}
+
+fn connect_direct(addr : SocketAddr, timeout : Duration) -> Result<TcpStream,ConnectorError>
+{
+    match TcpStream::connect_timeout(addr,timeout)
+    {
+        Ok(socket) => Ok(socket),
+        Err(_)     => Err(ConnectorError::ConnectError)
+    }
+}
+
+fn resolve_direct(host : &str) -> Result<Vec<IpAddr>,ConnectorError>
+{
+    match get_host_addresses(host)
+    {
+        Ok(ref addrs) if addrs.len() > 0 => Ok(addrs.clone()),
+        _                                => Err(ConnectorError::ResolveError)
+    }
+}
+
+#[allow(dead_code)]
+impl Socks5Proxy
+{
+    pub fn new(addr : SocketAddr, credentials : Option<(String,String)>, randomize : bool)
+               -> Socks5Proxy
+    {
+        Socks5Proxy { addr: addr, credentials: credentials, randomize: randomize }
+    }
+
+    pub fn get_addr(&self) -> SocketAddr
+    {
+        self.addr
+    }
+
+    pub fn connect(&self, dest : &Destination, timeout : Duration)
+                   -> Result<TcpStream,ConnectorError>
+    {
+        let mut socket : TcpStream = try!(connect_direct(self.addr,timeout));
+
+        try!(self.request(&mut socket,SOCKS5_CMD_CONNECT,dest));
+
+        socket.set_timeout(None);
+
+        Ok(socket)
+    }
+
+    /* Tor answers a RESOLVE with the address in the bound address field of
+     * the reply.
+     */
+    pub fn resolve(&self, host : &str) -> Result<IpAddr,ConnectorError>
+    {
+        let timeout : Duration = Duration::milliseconds(TIMEOUT_PROXY_MS as i64);
+        let mut socket : TcpStream = try!(connect_direct(self.addr,timeout));
+        let dest : Destination = Destination::Host(host.to_string(),0);
+
+        match try!(self.request(&mut socket,SOCKS5_CMD_RESOLVE,&dest))
+        {
+            Some(addr) => Ok(addr),
+            None       => Err(ConnectorError::ResolveError)
+        }
+    }
+
+    /* With IsolateSOCKSAuth (enabled by default) Tor uses a different circuit
+     * for each pair of credentials, so random credentials isolate every
+     * connection from the others.
+     */
+    fn get_credentials(&self) -> Option<(String,String)>
+    {
+        if self.randomize
+        {
+            let user : u64 = ::crypto::rng().gen();
+            let pass : u64 = ::crypto::rng().gen();
+
+            return Some((format!("{:016x}",user),format!("{:016x}",pass)));
+        }
+
+        self.credentials.clone()
+    }
+
+    fn request(&self, socket : &mut TcpStream, cmd : u8, dest : &Destination)
+               -> Result<Option<IpAddr>,ConnectorError>
+    {
+        let credentials : Option<(String,String)> = self.get_credentials();
+        let mut request : Vec<u8> = Vec::with_capacity(SOCKS5_MAX_FIELD_SIZE+7);
+        let method : u8;
+
+        socket.set_timeout(Some(TIMEOUT_PROXY_MS));
+
+        /* Greeting, offering the authentication methods we can do */
+        match credentials
+        {
+            Some(_) => try_proxy!(socket.write(&[SOCKS5_VERSION,2,SOCKS5_METHOD_NONE,
+                                                 SOCKS5_METHOD_USERPASS])),
+            None    => try_proxy!(socket.write(&[SOCKS5_VERSION,1,SOCKS5_METHOD_NONE]))
+        }
+
+        if try_proxy!(socket.read_u8()) != SOCKS5_VERSION
+        {
+            return Err(ConnectorError::ProxyInvalidReply);
+        }
+
+        method = try_proxy!(socket.read_u8());
+
+        match (method,credentials)
+        {
+            (SOCKS5_METHOD_NONE,_) => (),
+            (SOCKS5_METHOD_USERPASS,Some((ref user,ref pass))) =>
+                try!(authenticate(socket,user.as_slice(),pass.as_slice())),
+            (SOCKS5_METHOD_NOT_ACCEPTABLE,_) => return Err(ConnectorError::ProxyNoAcceptableAuth),
+            _ => return Err(ConnectorError::ProxyInvalidReply)
+        }
+
+        request.push_all(&[SOCKS5_VERSION,cmd,0x00]);
+
+        match *dest
+        {
+            Destination::Ip(addr) =>
+            {
+                push_ip(&mut request,addr.ip);
+                push_port(&mut request,addr.port);
+            },
+            Destination::Host(ref host,port) =>
+            {
+                if host.len() > SOCKS5_MAX_FIELD_SIZE
+                {
+                    return Err(ConnectorError::HostnameTooLong);
+                }
+
+                request.push(SOCKS5_ATYP_DOMAIN);
+                request.push(host.len() as u8);
+                request.push_all(host.as_bytes());
+                push_port(&mut request,port);
+            }
+        }
+
+        try_proxy!(socket.write(request.as_slice()));
+
+        read_reply(socket)
+    }
+}
+
+fn authenticate(socket : &mut TcpStream, user : &str, pass : &str) -> Result<(),ConnectorError>
+{
+    let mut request : Vec<u8> = Vec::with_capacity(3+user.len()+pass.len());
+    let status : u8;
+
+    if user.len() > SOCKS5_MAX_FIELD_SIZE || pass.len() > SOCKS5_MAX_FIELD_SIZE
+    {
+        return Err(ConnectorError::ProxyAuthFailed);
+    }
+
+    request.push(SOCKS5_AUTH_VERSION);
+    request.push(user.len() as u8);
+    request.push_all(user.as_bytes());
+    request.push(pass.len() as u8);
+    request.push_all(pass.as_bytes());
+
+    try_proxy!(socket.write(request.as_slice()));
+
+    if try_proxy!(socket.read_u8()) != SOCKS5_AUTH_VERSION
+    {
+        return Err(ConnectorError::ProxyInvalidReply);
+    }
+
+    status = try_proxy!(socket.read_u8());
+
+    if status != 0x00
+    {
+        return Err(ConnectorError::ProxyAuthFailed);
+    }
+
+    Ok(())
+}
+
+/* The reply carries the address bound by the proxy, which we return when it is
+ * an ip address.
+ */
+fn read_reply(socket : &mut TcpStream) -> Result<Option<IpAddr>,ConnectorError>
+{
+    let reply : u8;
+    let addr : Option<IpAddr>;
+
+    if try_proxy!(socket.read_u8()) != SOCKS5_VERSION
+    {
+        return Err(ConnectorError::ProxyInvalidReply);
+    }
+
+    reply = try_proxy!(socket.read_u8());
+
+    if reply != SOCKS5_REPLY_SUCCEEDED
+    {
+        return Err(ConnectorError::ProxyReplyError(reply));
+    }
+
+    try_proxy!(socket.read_u8()); /* Reserved */
+
+    addr = match try_proxy!(socket.read_u8())
+    {
+        SOCKS5_ATYP_IPV4 =>
+        {
+            let b : Vec<u8> = try_proxy!(socket.read_exact(4));
+
+            Some(Ipv4Addr(b[0],b[1],b[2],b[3]))
+        },
+        SOCKS5_ATYP_IPV6 =>
+        {
+            let mut s : [u16, ..8] = [0, ..8];
+
+            for i in range(0u,8)
+            {
+                s[i] = try_proxy!(socket.read_be_u16());
+            }
+
+            Some(Ipv6Addr(s[0],s[1],s[2],s[3],s[4],s[5],s[6],s[7]))
+        },
+        SOCKS5_ATYP_DOMAIN =>
+        {
+            let len : u8 = try_proxy!(socket.read_u8());
+
+            try_proxy!(socket.read_exact(len as uint));
+
+            None
+        },
+        _ => return Err(ConnectorError::ProxyInvalidReply)
+    };
+
+    try_proxy!(socket.read_be_u16()); /* Bound port */
+
+    Ok(addr)
+}
+
+fn push_ip(buf : &mut Vec<u8>, ip : IpAddr)
+{
+    match ip
+    {
+        Ipv4Addr(a,b,c,d) =>
+        {
+            buf.push(SOCKS5_ATYP_IPV4);
+            buf.push_all(&[a,b,c,d]);
+        },
+        Ipv6Addr(a,b,c,d,e,f,g,h) =>
+        {
+            buf.push(SOCKS5_ATYP_IPV6);
+
+            for s in [a,b,c,d,e,f,g,h].iter()
+            {
+                buf.push((*s >> 8) as u8);
+                buf.push(*s as u8);
+            }
+        }
+    }
+}
+
+fn push_port(buf : &mut Vec<u8>, port : u16)
+{
+    buf.push((port >> 8) as u8);
+    buf.push(port as u8);
+}
@@ -0,0 +1,863 @@
+extern crate time;
+
+use std::collections::HashSet;
+
+use self::time::Timespec;
+use std::time::duration::Duration;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::transaction::Transaction;
+use datatype::transaction::TxIn;
+use datatype::transaction::TxOut;
+use datatype::transaction::TxLock;
+use datatype::transaction::OutPoint;
+use datatype::hash::Hash;
+use datatype::value::Value;
+use datatype::script::Script;
+use datatype::script::{OP_0, OP_RETURN};
+use datatype::uint256::Uint256;
+
+use utxo::UtxoView;
+use utxo::Coin;
+
+use interpreter::ScriptError;
+
+use config::NetworkParams;
+use config::ConsensusParams;
+
+use marshalling::Marshalling;
+
+use message::reject::RejectType;
+
+/* Consensus rules.  Everything in here must match the reference
+ * implementation exactly, see the TODO in main.rs.
+ */
+
+pub const MAX_BLOCK_SIZE : uint = 1_000_000;
+
+/* We do not support segregated witness, so the weight of a block is always
+ * its size scaled by this factor.
+ */
+pub const WITNESS_SCALE_FACTOR : uint = 4;
+pub const MAX_BLOCK_WEIGHT : uint = 4_000_000;
+
+/* Signature operations are weighed like the bytes of a block */
+pub const MAX_BLOCK_SIGOPS_COST : uint = 80_000;
+
+pub const COIN : u64 = 100_000_000;
+pub const MAX_MONEY : u64 = 21_000_000*COIN;
+
+pub const COINBASE_MATURITY : u32 = 100;
+
+const COINBASE_SCRIPT_MIN_SIZE : uint = 2;
+const COINBASE_SCRIPT_MAX_SIZE : uint = 100;
+
+const INITIAL_SUBSIDY : u64 = 50*COIN;
+
+pub const RETARGET_INTERVAL : u32 = 2016;
+const TARGET_TIMESPAN_S : i64 = 14*24*60*60;
+const TARGET_SPACING_S : i64 = 10*60;
+
+/* The parameters that differ between networks are in ::config::NetworkParams */
+
+const MEDIAN_TIME_SPAN : u32 = 11;
+const MAX_FUTURE_BLOCK_TIME_S : i64 = 2*60*60;
+
+const SEQUENCE_FINAL : u32 = 0xffffffff;
+
+/* The two blocks that duplicate the coinbase of an earlier block, which the
+ * reference implementation lets through.
+ */
+const BIP30_EXCEPTIONS : &'static [(u32, &'static str)] = &[
+    (91842, "00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec"),
+    (91880, "00000000000743f190a18c5577a3c2d2a1f610ae9601ac046a38084ccb7cd721") ];
+
+/* The witness commitment of a coinbase (BIP141) is an output whose script
+ * starts like this.
+ */
+const WITNESS_COMMITMENT_HEADER : [u8, ..6] = [OP_RETURN, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+const WITNESS_COMMITMENT_MIN_SIZE : uint = 38;
+
+/* The push of the witness commitment with the signet solution starts like
+ * this (BIP325).
+ */
+pub const SIGNET_HEADER : [u8, ..4] = [0xec, 0xc7, 0xda, 0xa2];
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum ValidationError
+{
+    BlockHighHash,
+    BlockBadMerkleRoot,
+    BlockMutated,
+    BlockBadSize,
+    BlockNoCoinbase,
+    BlockMultipleCoinbase,
+    BlockBadCoinbaseHeight,
+    BlockBadCoinbaseValue,
+    BlockBadDifficulty,
+    BlockTimeTooOld,
+    BlockTimeTooNew,
+    BlockObsoleteVersion,
+    BlockNonFinalTx,
+    BlockPrevNotFound,
+    BlockDuplicate,
+    BlockCheckpointMismatch,
+    BlockBadSigops,
+    BlockForkBeforeCheckpoint,
+    BlockTooManySideBlocks,
+    BlockBadSignetSolution,
+    TxNoInputs,
+    TxNoOutputs,
+    TxTooLarge,
+    TxOutputValueOutOfRange,
+    TxDuplicateInputs,
+    TxBadCoinbaseLength,
+    TxNullPrevout,
+    TxMissingInputs,
+    TxPrematureCoinbaseSpend,
+    TxInputValueOutOfRange,
+    TxInputsBelowOutputs,
+    TxOutputsExist,
+    TxScriptFailure(ScriptError)
+}
+
+impl ValidationError
+{
+    pub fn get_reject_type(&self) -> RejectType
+    {
+        match *self
+        {
+            ValidationError::BlockDuplicate            => RejectType::RejectDuplicate,
+            ValidationError::BlockCheckpointMismatch   => RejectType::RejectCheckpoint,
+            ValidationError::BlockForkBeforeCheckpoint => RejectType::RejectCheckpoint,
+            ValidationError::BlockObsoleteVersion      => RejectType::RejectObsolete,
+            ValidationError::BlockMutated              => RejectType::RejectMalformed,
+            _                                          => RejectType::RejectInvalid
+        }
+    }
+
+    /* Reason as sent by the reference implementation in reject messages */
+    pub fn get_reason(&self) -> &'static str
+    {
+        match *self
+        {
+            ValidationError::BlockHighHash             => "high-hash",
+            ValidationError::BlockBadMerkleRoot        => "bad-txnmrklroot",
+            ValidationError::BlockMutated              => "bad-txns-duplicate",
+            ValidationError::BlockBadSize              => "bad-blk-length",
+            ValidationError::BlockNoCoinbase           => "bad-cb-missing",
+            ValidationError::BlockMultipleCoinbase     => "bad-cb-multiple",
+            ValidationError::BlockBadCoinbaseHeight    => "bad-cb-height",
+            ValidationError::BlockBadCoinbaseValue     => "bad-cb-amount",
+            ValidationError::BlockBadDifficulty        => "bad-diffbits",
+            ValidationError::BlockTimeTooOld           => "time-too-old",
+            ValidationError::BlockTimeTooNew           => "time-too-new",
+            ValidationError::BlockObsoleteVersion      => "bad-version",
+            ValidationError::BlockNonFinalTx           => "bad-txns-nonfinal",
+            ValidationError::BlockPrevNotFound         => "bad-prevblk",
+            ValidationError::BlockDuplicate            => "duplicate",
+            ValidationError::BlockCheckpointMismatch   => "checkpoint mismatch",
+            ValidationError::BlockBadSigops            => "bad-blk-sigops",
+            ValidationError::BlockForkBeforeCheckpoint => "bad-fork-prior-to-checkpoint",
+            ValidationError::BlockTooManySideBlocks    => "too-many-side-blocks",
+            ValidationError::BlockBadSignetSolution    => "bad-signet-blksig",
+            ValidationError::TxNoInputs                => "bad-txns-vin-empty",
+            ValidationError::TxNoOutputs               => "bad-txns-vout-empty",
+            ValidationError::TxTooLarge                => "bad-txns-oversize",
+            ValidationError::TxOutputValueOutOfRange   => "bad-txns-vout-toolarge",
+            ValidationError::TxDuplicateInputs         => "bad-txns-inputs-duplicate",
+            ValidationError::TxBadCoinbaseLength       => "bad-cb-length",
+            ValidationError::TxNullPrevout             => "bad-txns-prevout-null",
+            ValidationError::TxMissingInputs           => "bad-txns-inputs-missingorspent",
+            ValidationError::TxPrematureCoinbaseSpend  => "bad-txns-premature-spend-of-coinbase",
+            ValidationError::TxInputValueOutOfRange    => "bad-txns-inputvalues-outofrange",
+            ValidationError::TxInputsBelowOutputs      => "bad-txns-in-belowout",
+            ValidationError::TxOutputsExist            => "bad-txns-BIP30",
+            ValidationError::TxScriptFailure(_)        => "mandatory-script-verify-flag-failed"
+        }
+    }
+}
+
+/* Access to the chain a block is being connected to.
+ */
+pub trait ChainView
+{
+    /* Header of the block at the given height */
+    fn get_header_at(&self, height : u32) -> Option<&BlockHeader>;
+
+    /* The network the chain is on */
+    fn get_params(&self) -> &'static NetworkParams;
+}
+
+fn get_pow_limit(params : &ConsensusParams) -> Uint256
+{
+    Uint256::from_compact(params.pow_limit_bits).unwrap()
+}
+
+pub fn check_proof_of_work(hash : &Hash, bits : u32, params : &ConsensusParams) -> bool
+{
+    let limit : Uint256 = get_pow_limit(params);
+
+    match Uint256::from_compact(bits)
+    {
+        Some(ref target) if !target.is_zero() && *target <= limit =>
+            Uint256::from_hash(hash) <= *target,
+        _ => false
+    }
+}
+
+pub fn get_block_subsidy(height : u32, params : &ConsensusParams) -> Value
+{
+    let halvings : u32 = height/params.subsidy_halving_interval;
+
+    if halvings >= 64
+    {
+        return Value::zero();
+    }
+
+    Value::Satoshi(INITIAL_SUBSIDY >> halvings as uint)
+}
+
+pub fn is_final_tx(tx : &Transaction, height : u32, time : Timespec) -> bool
+{
+    let locked : bool = match tx.get_lock()
+    {
+        TxLock::LockLocked       => false,
+        TxLock::LockUnlocked     => true,
+        TxLock::LockBlock(block) => block >= height,
+        TxLock::LockTime(tm)     => tm >= time
+    };
+
+    !locked || tx.get_in_txs().iter().all(|i| i.get_sequence() == SEQUENCE_FINAL)
+}
+
+/* Context-free transaction checks.
+ */
+pub fn check_transaction(tx : &Transaction) -> Result<(),ValidationError>
+{
+    let mut total : u64 = 0;
+    let mut prev_outs : HashSet<&OutPoint> = HashSet::new();
+
+    if tx.get_in_txs().len() == 0
+    {
+        return Err(ValidationError::TxNoInputs);
+    }
+
+    if tx.get_out_txs().len() == 0
+    {
+        return Err(ValidationError::TxNoOutputs);
+    }
+
+    if tx.get_size()*WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+    {
+        return Err(ValidationError::TxTooLarge);
+    }
+
+    for out_tx in tx.get_out_txs().iter()
+    {
+        let value : u64 = out_tx.get_value().get_satoshis();
+
+        if value > MAX_MONEY || total+value > MAX_MONEY
+        {
+            return Err(ValidationError::TxOutputValueOutOfRange);
+        }
+
+        total += value;
+    }
+
+    for in_tx in tx.get_in_txs().iter()
+    {
+        if !prev_outs.insert(in_tx.get_prev_out())
+        {
+            return Err(ValidationError::TxDuplicateInputs);
+        }
+    }
+
+    if tx.is_coinbase()
+    {
+        let len : uint = tx.get_in_txs()[0].get_script().len();
+
+        if len < COINBASE_SCRIPT_MIN_SIZE || len > COINBASE_SCRIPT_MAX_SIZE
+        {
+            return Err(ValidationError::TxBadCoinbaseLength);
+        }
+    }
+    else if tx.get_in_txs().iter().any(|i| i.get_prev_out().is_null())
+    {
+        return Err(ValidationError::TxNullPrevout);
+    }
+
+    Ok(())
+}
+
+pub fn check_block_header(header : &BlockHeader,
+                          params : &ConsensusParams) -> Result<(),ValidationError>
+{
+    if !check_proof_of_work(&header.get_hash(),header.get_bits(),params)
+    {
+        return Err(ValidationError::BlockHighHash);
+    }
+
+    Ok(())
+}
+
+/* Context-free block checks.
+ */
+pub fn check_block(block : &Block, params : &ConsensusParams) -> Result<(),ValidationError>
+{
+    let txs : &Vec<Transaction> = block.get_txs();
+    let hashes : Vec<Hash>;
+
+    try!(check_block_header(block.get_header(),params));
+
+    hashes = txs.iter().map(|tx| tx.get_hash()).collect();
+
+    let (merkle_root, mutated) = ::datatype::merkle::compute_merkle_root(hashes.as_slice());
+
+    if merkle_root != *block.get_header().get_merkle_root()
+    {
+        return Err(ValidationError::BlockBadMerkleRoot);
+    }
+
+    if mutated
+    {
+        return Err(ValidationError::BlockMutated);
+    }
+
+    if txs.len() == 0
+        || txs.len()*WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+        || block.get_size()*WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+    {
+        return Err(ValidationError::BlockBadSize);
+    }
+
+    if !txs[0].is_coinbase()
+    {
+        return Err(ValidationError::BlockNoCoinbase);
+    }
+
+    if txs.iter().skip(1).any(|tx| tx.is_coinbase())
+    {
+        return Err(ValidationError::BlockMultipleCoinbase);
+    }
+
+    for tx in txs.iter()
+    {
+        try!(check_transaction(tx));
+    }
+
+    if txs.iter().fold(0u, |acc, tx| acc+get_legacy_sigop_count(tx))*WITNESS_SCALE_FACTOR
+        > MAX_BLOCK_SIGOPS_COST
+    {
+        return Err(ValidationError::BlockBadSigops);
+    }
+
+    match params.signet_challenge
+    {
+        Some(challenge) =>
+        {
+            let challenge : Script = Script::from_bytes(::crypto::from_hexstr(challenge).unwrap());
+
+            if !check_signet_solution(block,&challenge)
+            {
+                return Err(ValidationError::BlockBadSignetSolution);
+            }
+        },
+        None            => ()
+    }
+
+    Ok(())
+}
+
+fn is_witness_commitment(script : &Script) -> bool
+{
+    script.len() >= WITNESS_COMMITMENT_MIN_SIZE
+        && script.get_bytes().as_slice().starts_with(&WITNESS_COMMITMENT_HEADER)
+}
+
+/* Read a size the way the reference implementation does, which refuses the
+ * encodings that are not the shortest.
+ */
+fn read_compact_size(data : &[u8], pos : &mut uint) -> Option<uint>
+{
+    let first : u8;
+    let size : uint;
+    let mut v : u64 = 0;
+
+    if *pos >= data.len()
+    {
+        return None;
+    }
+
+    first = data[*pos];
+    *pos += 1;
+
+    size = match first
+    {
+        253 => 2,
+        254 => 4,
+        255 => 8,
+        _   => return Some(first as uint)
+    };
+
+    if *pos+size > data.len()
+    {
+        return None;
+    }
+
+    for i in range(0,size)
+    {
+        v |= (data[*pos+i] as u64) << 8*i;
+    }
+
+    *pos += size;
+
+    match size
+    {
+        2 if v < 253         => None,
+        4 if v < 0x10000     => None,
+        8 if v < 0x100000000 => None,
+        _                    => Some(v as uint)
+    }
+}
+
+/* The signature script of a signet solution, followed by the witness stack.
+ * Our challenges are not witness programs, so a solution with witness data
+ * fails like it does with the reference implementation.
+ */
+fn parse_signet_solution(solution : &[u8]) -> Option<Script>
+{
+    let mut pos : uint = 0;
+    let len : uint;
+
+    len = match read_compact_size(solution,&mut pos)
+    {
+        Some(len) if len <= solution.len()-pos => len,
+        _                                      => return None
+    };
+
+    pos += len;
+
+    /* An empty witness stack, and nothing after it */
+    if solution.len() != pos+1 || solution[pos] != 0
+    {
+        return None;
+    }
+
+    Some(Script::from_bytes(solution.slice(pos-len,pos).to_vec()))
+}
+
+/* The txs a signet block is signed with (BIP325): the first has an output of
+ * the challenge script and commits to the block without its solution, the
+ * second spends it with the solution.  None if the block has no witness
+ * commitment or the solution cannot be parsed.
+ */
+pub fn create_signet_txs(block : &Block, challenge : &Script) -> Option<(Transaction, Transaction)>
+{
+    let coinbase : &Transaction = &block.get_txs()[0];
+    let mut outs : Vec<TxOut> = coinbase.get_out_txs().clone();
+    let index : uint;
+    let mut commitment : Script = Script::new();
+    let mut solution : Option<Vec<u8>> = None;
+    let mut pc : uint = 0;
+    let script_sig : Script;
+    let mut hashes : Vec<Hash>;
+    let mut data : Marshalling = Marshalling::new();
+    let mut to_spend_script : Script = Script::new();
+    let to_spend : Transaction;
+    let to_sign : Transaction;
+
+    /* The last witness commitment is the one that counts */
+    index = match outs.iter().rposition(|out| is_witness_commitment(out.get_script()))
+    {
+        Some(index) => index,
+        None        => return None
+    };
+
+    /* The first push with the header and some data holds the solution, which
+     * is taken out leaving only the header.
+     */
+    loop
+    {
+        match outs[index].get_script().get_op(&mut pc)
+        {
+            Some((_, ref push)) if solution.is_none() && push.len() > SIGNET_HEADER.len()
+                                   && push.as_slice().starts_with(&SIGNET_HEADER) =>
+            {
+                solution = Some(push.slice_from(SIGNET_HEADER.len()).to_vec());
+                commitment.push_data(&SIGNET_HEADER);
+            },
+            Some((_, ref push)) if push.len() > 0 => commitment.push_data(push.as_slice()),
+            Some((opcode, _))                     => commitment.push_opcode(opcode),
+            None                                  => break
+        }
+    }
+
+    /* Without a solution the challenge must be satisfied by an empty script,
+     * and the commitment is left as it is.
+     */
+    match solution
+    {
+        Some(ref solution) =>
+        {
+            let out : TxOut = TxOut::new(outs[index].get_value().clone(),commitment);
+
+            script_sig = match parse_signet_solution(solution.as_slice())
+            {
+                Some(script) => script,
+                None         => return None
+            };
+
+            *outs.get_mut(index) = out;
+        },
+        None               => script_sig = Script::new()
+    }
+
+    hashes = block.get_txs().iter().map(|tx| tx.get_hash()).collect();
+    *hashes.get_mut(0) = Transaction::new(coinbase.get_version(),coinbase.get_in_txs().clone(),
+                                          outs,coinbase.get_lock()).get_hash();
+
+    let (merkle_root, _) = ::datatype::merkle::compute_merkle_root(hashes.as_slice());
+
+    data.write_uint32(block.get_header().get_version());
+    data.write_hash(block.get_header().get_prev_block());
+    data.write_hash(&merkle_root);
+    data.write_timestampu32(block.get_header().get_time());
+
+    to_spend_script.push_opcode(OP_0);
+    to_spend_script.push_data(data.get().as_slice());
+
+    to_spend = Transaction::new(0,
+                                vec![TxIn::new(OutPoint::new(Hash::zero(),0xffffffff),
+                                               to_spend_script,0)],
+                                vec![TxOut::new(Value::Satoshi(0),challenge.clone())],
+                                TxLock::from_u32(0));
+
+    to_sign = Transaction::new(0,
+                               vec![TxIn::new(OutPoint::new(to_spend.get_hash(),0),script_sig,0)],
+                               vec![TxOut::new(Value::Satoshi(0),
+                                               Script::from_bytes(vec![OP_RETURN]))],
+                               TxLock::from_u32(0));
+
+    Some((to_spend, to_sign))
+}
+
+/* Whether the block is signed by the challenge of the signet (BIP325) */
+pub fn check_signet_solution(block : &Block, challenge : &Script) -> bool
+{
+    match create_signet_txs(block,challenge)
+    {
+        Some((_, to_sign)) => ::interpreter::verify_script(to_sign.get_in_txs()[0].get_script(),
+                                                           challenge,&to_sign,0).is_ok(),
+        None               => false
+    }
+}
+
+/* Signature operations in the scripts of the tx itself, not counting those
+ * of the scripts its inputs run.
+ */
+pub fn get_legacy_sigop_count(tx : &Transaction) -> uint
+{
+    let inputs : uint = tx.get_in_txs().iter()
+        .fold(0u, |acc, i| acc+i.get_script().get_sigop_count());
+    let outputs : uint = tx.get_out_txs().iter()
+        .fold(0u, |acc, o| acc+o.get_script().get_sigop_count());
+
+    inputs+outputs
+}
+
+/* BIP30: a tx may not create outputs that already exist unspent, except in
+ * the two blocks that did so before the rule.
+ */
+pub fn check_tx_outputs_new<U : UtxoView>(tx     : &Transaction,
+                                          utxos  : &U,
+                                          block  : &Block,
+                                          height : u32) -> Result<(),ValidationError>
+{
+    let hash : Hash = tx.get_hash();
+
+    for &(h, exception) in BIP30_EXCEPTIONS.iter()
+    {
+        if h == height
+           && ::crypto::to_hexstr(block.get_hash().as_slice()).as_slice() == exception
+        {
+            return Ok(());
+        }
+    }
+
+    for i in range(0,tx.get_out_txs().len())
+    {
+        if utxos.get_coin(&OutPoint::new(hash.clone(),i as u32)).is_some()
+        {
+            return Err(ValidationError::TxOutputsExist);
+        }
+    }
+
+    Ok(())
+}
+
+/* Median time of the last MEDIAN_TIME_SPAN blocks up to (and including) the
+ * block at the given height.
+ */
+pub fn get_median_time_past<C : ChainView>(chain : &C, height : u32) -> Timespec
+{
+    let mut times : Vec<Timespec> = Vec::with_capacity(MEDIAN_TIME_SPAN as uint);
+    let first : u32 = if height+1 >= MEDIAN_TIME_SPAN { height+1-MEDIAN_TIME_SPAN }
+                      else { 0 };
+
+    for h in range(first,height+1)
+    {
+        times.push(chain.get_header_at(h).unwrap().get_time());
+    }
+
+    times.sort();
+
+    times[times.len()/2]
+}
+
+/* The target a block extending the block at the given height, with the given
+ * time, must have.
+ */
+pub fn get_next_work_required<C : ChainView>(chain : &C, height : u32, time : Timespec) -> u32
+{
+    let params : &ConsensusParams = &chain.get_params().consensus;
+    let prev : &BlockHeader = chain.get_header_at(height).unwrap();
+    let first : &BlockHeader;
+    let mut timespan : i64;
+    let mut target : Uint256;
+    let limit : Uint256 = get_pow_limit(params);
+
+    if (height+1)%RETARGET_INTERVAL != 0
+    {
+        if params.pow_allow_min_difficulty
+        {
+            return get_min_difficulty_work(chain,height,time);
+        }
+
+        return prev.get_bits();
+    }
+
+    if params.pow_no_retargeting
+    {
+        return prev.get_bits();
+    }
+
+    /* This is off-by-one relative to the ideal but it is what the reference
+     * implementation does, so we must do the same.
+     */
+    first = chain.get_header_at(height+1-RETARGET_INTERVAL).unwrap();
+
+    timespan = (prev.get_time()-first.get_time()).num_seconds();
+    timespan = ::std::cmp::max(timespan,TARGET_TIMESPAN_S/4);
+    timespan = ::std::cmp::min(timespan,TARGET_TIMESPAN_S*4);
+
+    target = Uint256::from_compact(prev.get_bits()).unwrap();
+    target = target.mul_u32(timespan as u32).div_u32(TARGET_TIMESPAN_S as u32);
+
+    if target > limit
+    {
+        target = limit;
+    }
+
+    target.to_compact()
+}
+
+/* On test networks a block more than twice the target spacing after the
+ * previous one can have the minimum difficulty.  Otherwise it has the
+ * difficulty of the last block that did not take advantage of that.
+ */
+fn get_min_difficulty_work<C : ChainView>(chain : &C, height : u32, time : Timespec) -> u32
+{
+    let limit_bits : u32 = chain.get_params().consensus.pow_limit_bits;
+    let prev : &BlockHeader = chain.get_header_at(height).unwrap();
+    let mut h : u32 = height;
+
+    if time > prev.get_time()+Duration::seconds(2*TARGET_SPACING_S)
+    {
+        return limit_bits;
+    }
+
+    while h > 0 && h%RETARGET_INTERVAL != 0
+        && chain.get_header_at(h).unwrap().get_bits() == limit_bits
+    {
+        h -= 1;
+    }
+
+    chain.get_header_at(h).unwrap().get_bits()
+}
+
+fn check_checkpoint(header : &BlockHeader, height : u32, params : &NetworkParams) -> bool
+{
+    for &(h, hash) in params.checkpoints.iter()
+    {
+        if h == height
+        {
+            return ::crypto::to_hexstr(header.get_hash().as_slice()).as_slice() == hash;
+        }
+    }
+
+    true
+}
+
+/* Checks of a header that depend on the chain it extends.  The header is to
+ * be at the given height.
+ */
+pub fn check_block_header_contextual<C : ChainView>(header : &BlockHeader,
+                                                    height : u32,
+                                                    chain  : &C,
+                                                    now    : Timespec)
+                                                    -> Result<(),ValidationError>
+{
+    assert!(height > 0);
+
+    if header.get_bits() != get_next_work_required(chain,height-1,header.get_time())
+    {
+        return Err(ValidationError::BlockBadDifficulty);
+    }
+
+    if !check_checkpoint(header,height,chain.get_params())
+    {
+        return Err(ValidationError::BlockCheckpointMismatch);
+    }
+
+    if header.get_time() <= get_median_time_past(chain,height-1)
+    {
+        return Err(ValidationError::BlockTimeTooOld);
+    }
+
+    if header.get_time() > now+Duration::seconds(MAX_FUTURE_BLOCK_TIME_S)
+    {
+        return Err(ValidationError::BlockTimeTooNew);
+    }
+
+    if height >= chain.get_params().consensus.bip34_height && header.get_version() < 2
+    {
+        return Err(ValidationError::BlockObsoleteVersion);
+    }
+
+    Ok(())
+}
+
+/* Checks of a block that depend on the chain it extends, but not on the UTXO
+ * set.
+ */
+pub fn check_block_contextual<C : ChainView>(block  : &Block,
+                                             height : u32,
+                                             chain  : &C) -> Result<(),ValidationError>
+{
+    let mtp : Timespec = get_median_time_past(chain,height-1);
+
+    for tx in block.get_txs().iter()
+    {
+        if !is_final_tx(tx,height,mtp)
+        {
+            return Err(ValidationError::BlockNonFinalTx);
+        }
+    }
+
+    if height >= chain.get_params().consensus.bip34_height
+    {
+        let mut expected : Script = Script::new();
+        let script : &Vec<u8> = block.get_txs()[0].get_in_txs()[0].get_script().get_bytes();
+
+        expected.push_int(height as i64);
+
+        if !script.as_slice().starts_with(expected.get_bytes().as_slice())
+        {
+            return Err(ValidationError::BlockBadCoinbaseHeight);
+        }
+    }
+
+    Ok(())
+}
+
+/* Check the inputs of a (non-coinbase) transaction to be included in a block
+ * at the given height.  Returns the fee.
+ */
+pub fn check_tx_inputs<U : UtxoView>(tx     : &Transaction,
+                                     utxos  : &U,
+                                     height : u32) -> Result<Value,ValidationError>
+{
+    let mut value_in : u64 = 0;
+    let value_out : u64;
+
+    assert!(!tx.is_coinbase());
+
+    for in_tx in tx.get_in_txs().iter()
+    {
+        let coin : Coin;
+        let value : u64;
+
+        coin = match utxos.get_coin(in_tx.get_prev_out())
+        {
+            Some(coin) => coin,
+            None       => return Err(ValidationError::TxMissingInputs)
+        };
+
+        if coin.is_coinbase() && height-coin.get_height() < COINBASE_MATURITY
+        {
+            return Err(ValidationError::TxPrematureCoinbaseSpend);
+        }
+
+        value = coin.get_output().get_value().get_satoshis();
+
+        if value > MAX_MONEY || value_in+value > MAX_MONEY
+        {
+            return Err(ValidationError::TxInputValueOutOfRange);
+        }
+
+        value_in += value;
+    }
+
+    value_out = tx.get_out_txs().iter()
+        .fold(0u64, |acc, o| acc+o.get_value().get_satoshis());
+
+    if value_in < value_out
+    {
+        return Err(ValidationError::TxInputsBelowOutputs);
+    }
+
+    Ok(Value::Satoshi(value_in-value_out))
+}
+
+pub fn check_tx_scripts<U : UtxoView>(tx    : &Transaction,
+                                      utxos : &U) -> Result<(),ValidationError>
+{
+    for (index, in_tx) in tx.get_in_txs().iter().enumerate()
+    {
+        let coin : Coin = utxos.get_coin(in_tx.get_prev_out()).unwrap();
+
+        match ::interpreter::verify_script(in_tx.get_script(),
+                                           coin.get_output().get_script(),
+                                           tx,index)
+        {
+            Ok(())   => (),
+            Err(err) => return Err(ValidationError::TxScriptFailure(err))
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_coinbase_value(block  : &Block,
+                            height : u32,
+                            fees   : &Value,
+                            params : &ConsensusParams) -> Result<(),ValidationError>
+{
+    let coinbase : &Transaction = &block.get_txs()[0];
+    let value_out : u64;
+    let max : Value;
+
+    value_out = coinbase.get_out_txs().iter()
+        .fold(0u64, |acc, o| acc+o.get_value().get_satoshis());
+
+    max = get_block_subsidy(height,params).checked_add(fees).unwrap();
+
+    if Value::Satoshi(value_out) > max
+    {
+        return Err(ValidationError::BlockBadCoinbaseValue);
+    }
+
+    Ok(())
+}
@@ -3,7 +3,7 @@ extern crate openssl;
 use std::rand::Rng;
 use std::rand::OsRng;
 
-use self::openssl::crypto::hash::{SHA256,Hasher};
+use self::openssl::crypto::hash::{SHA256,RIPEMD160,Hasher};
 
 pub fn sha256(data : &[u8]) -> [u8, ..32]
 {
@@ -28,6 +28,29 @@ pub fn dsha256(data : &[u8]) -> [u8, ..32]
     sha256(&sha256(data))
 }
 
+pub fn ripemd160(data : &[u8]) -> [u8, ..20]
+{
+    let mut hasher : Hasher = Hasher::new(RIPEMD160);
+    let mut hash : [u8, ..20] = [0u8, ..20];
+    let digest;
+
+    hasher.update(data);
+
+    digest = hasher.finalize();
+
+    for i in range(0,20)
+    {
+        hash[i] = digest[i];
+    }
+
+    hash
+}
+
+pub fn hash160(data : &[u8]) -> [u8, ..20]
+{
+    ripemd160(&sha256(data))
+}
+
 pub fn hash_first_u32(data : &[u8]) -> u32
 {
     let digest : [u8, ..32] = dsha256(data);
@@ -42,6 +65,575 @@ pub fn hash_first_u32(data : &[u8]) -> u32
     checksum
 }
 
+fn rotl32(x : u32, r : uint) -> u32
+{
+    (x << r) | (x >> (32-r))
+}
+
+/* MurmurHash3 (x86, 32 bits), used by bloom filters.
+ */
+pub fn murmur3(seed : u32, data : &[u8]) -> u32
+{
+    let c1 : u32 = 0xcc9e2d51;
+    let c2 : u32 = 0x1b873593;
+    let nblocks : uint = data.len()/4;
+    let tail : &[u8] = data.slice_from(nblocks*4);
+    let mut h1 : u32 = seed;
+    let mut k1 : u32;
+
+    for i in range(0,nblocks)
+    {
+        k1 = (data[4*i] as u32) | (data[4*i+1] as u32 << 8)
+           | (data[4*i+2] as u32 << 16) | (data[4*i+3] as u32 << 24);
+
+        k1 *= c1;
+        k1 = rotl32(k1,15);
+        k1 *= c2;
+
+        h1 ^= k1;
+        h1 = rotl32(h1,13);
+        h1 = h1*5+0xe6546b64;
+    }
+
+    k1 = 0;
+
+    if tail.len() >= 3 { k1 ^= (tail[2] as u32) << 16; }
+    if tail.len() >= 2 { k1 ^= (tail[1] as u32) << 8; }
+    if tail.len() >= 1
+    {
+        k1 ^= tail[0] as u32;
+        k1 *= c1;
+        k1 = rotl32(k1,15);
+        k1 *= c2;
+        h1 ^= k1;
+    }
+
+    h1 ^= data.len() as u32;
+    h1 ^= h1 >> 16;
+    h1 *= 0x85ebca6b;
+    h1 ^= h1 >> 13;
+    h1 *= 0xc2b2ae35;
+    h1 ^= h1 >> 16;
+
+    h1
+}
+
+fn rotl64(x : u64, r : uint) -> u64
+{
+    (x << r) | (x >> (64-r))
+}
+
+fn sipround(v : &mut [u64, ..4])
+{
+    v[0] += v[1]; v[1] = rotl64(v[1],13); v[1] ^= v[0]; v[0] = rotl64(v[0],32);
+    v[2] += v[3]; v[3] = rotl64(v[3],16); v[3] ^= v[2];
+    v[0] += v[3]; v[3] = rotl64(v[3],21); v[3] ^= v[0];
+    v[2] += v[1]; v[1] = rotl64(v[1],17); v[1] ^= v[2]; v[2] = rotl64(v[2],32);
+}
+
+/* SipHash-2-4, used by compact block filters (BIP158) and short ids (BIP152).
+ */
+pub fn siphash24(k0 : u64, k1 : u64, data : &[u8]) -> u64
+{
+    let mut v : [u64, ..4] = [0x736f6d6570736575 ^ k0, 0x646f72616e646f6d ^ k1,
+                              0x6c7967656e657261 ^ k0, 0x7465646279746573 ^ k1];
+    let nblocks : uint = data.len()/8;
+    let mut m : u64;
+
+    for i in range(0,nblocks)
+    {
+        m = 0;
+
+        for j in range(0u,8)
+        {
+            m |= (data[8*i+j] as u64) << 8*j;
+        }
+
+        v[3] ^= m;
+        sipround(&mut v);
+        sipround(&mut v);
+        v[0] ^= m;
+    }
+
+    m = (data.len() as u64) << 56;
+
+    for j in range(0,data.len()-nblocks*8)
+    {
+        m |= (data[8*nblocks+j] as u64) << 8*j;
+    }
+
+    v[3] ^= m;
+    sipround(&mut v);
+    sipround(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+
+    for _ in range(0u,4)
+    {
+        sipround(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+const KECCAK_ROUND_CONSTANTS : [u64, ..24] =
+    [0x0000000000000001, 0x0000000000008082, 0x800000000000808A, 0x8000000080008000,
+     0x000000000000808B, 0x0000000080000001, 0x8000000080008081, 0x8000000000008009,
+     0x000000000000008A, 0x0000000000000088, 0x0000000080008009, 0x000000008000000A,
+     0x000000008000808B, 0x800000000000008B, 0x8000000000008089, 0x8000000000008003,
+     0x8000000000008002, 0x8000000000000080, 0x000000000000800A, 0x800000008000000A,
+     0x8000000080008081, 0x8000000000008080, 0x0000000080000001, 0x8000000080008008];
+
+/* Rotation of the lane x+5*y */
+const KECCAK_ROTATIONS : [uint, ..25] = [ 0,  1, 62, 28, 27,
+                                         36, 44,  6, 55, 20,
+                                          3, 10, 43, 25, 39,
+                                         41, 45, 15, 21,  8,
+                                         18,  2, 61, 56, 14];
+
+const SHA3_256_RATE : uint = 136;
+
+fn keccak_f1600(s : &mut [u64, ..25])
+{
+    for round in range(0u,24)
+    {
+        let mut c : [u64, ..5] = [0, ..5];
+        let mut b : [u64, ..25] = [0, ..25];
+
+        /* theta */
+        for x in range(0u,5)
+        {
+            c[x] = s[x] ^ s[x+5] ^ s[x+10] ^ s[x+15] ^ s[x+20];
+        }
+
+        for x in range(0u,5)
+        {
+            let d : u64 = c[(x+4)%5] ^ rotl64(c[(x+1)%5],1);
+
+            for y in range(0u,5)
+            {
+                s[x+5*y] ^= d;
+            }
+        }
+
+        /* rho and pi */
+        for x in range(0u,5)
+        {
+            for y in range(0u,5)
+            {
+                let r : uint = KECCAK_ROTATIONS[x+5*y];
+
+                b[y+5*((2*x+3*y)%5)] = if r == 0 { s[x+5*y] } else { rotl64(s[x+5*y],r) };
+            }
+        }
+
+        /* chi */
+        for x in range(0u,5)
+        {
+            for y in range(0u,5)
+            {
+                s[x+5*y] = b[x+5*y] ^ (!b[(x+1)%5+5*y] & b[(x+2)%5+5*y]);
+            }
+        }
+
+        /* iota */
+        s[0] ^= KECCAK_ROUND_CONSTANTS[round];
+    }
+}
+
+/* SHA3-256 (FIPS 202), used by the checksum of onion addresses.
+ */
+pub fn sha3_256(data : &[u8]) -> [u8, ..32]
+{
+    let mut s : [u64, ..25] = [0, ..25];
+    let mut padded : Vec<u8> = data.to_vec();
+    let mut hash : [u8, ..32] = [0u8, ..32];
+    let len : uint;
+
+    padded.push(0x06);
+
+    while padded.len()%SHA3_256_RATE != 0
+    {
+        padded.push(0x00);
+    }
+
+    len = padded.len();
+    padded[len-1] |= 0x80;
+
+    for block in padded.as_slice().chunks(SHA3_256_RATE)
+    {
+        for i in range(0u,SHA3_256_RATE/8)
+        {
+            let mut lane : u64 = 0;
+
+            for j in range(0u,8)
+            {
+                lane |= (block[8*i+j] as u64) << 8*j;
+            }
+
+            s[i] ^= lane;
+        }
+
+        keccak_f1600(&mut s);
+    }
+
+    for i in range(0u,32)
+    {
+        hash[i] = (s[i/8] >> 8*(i%8)) as u8;
+    }
+
+    hash
+}
+
+/* HMAC with SHA256 (RFC 2104).
+ */
+pub fn hmac_sha256(key : &[u8], data : &[u8]) -> [u8, ..32]
+{
+    let mut block : [u8, ..64] = [0u8, ..64];
+    let mut inner : Vec<u8> = Vec::with_capacity(64+data.len());
+    let mut outer : Vec<u8> = Vec::with_capacity(64+32);
+
+    if key.len() > 64
+    {
+        let digest : [u8, ..32] = sha256(key);
+
+        for i in range(0u,32)
+        {
+            block[i] = digest[i];
+        }
+    }
+    else
+    {
+        for i in range(0,key.len())
+        {
+            block[i] = key[i];
+        }
+    }
+
+    for b in block.iter()
+    {
+        inner.push(*b ^ 0x36);
+        outer.push(*b ^ 0x5c);
+    }
+
+    inner.push_all(data);
+    outer.push_all(&sha256(inner.as_slice()));
+
+    sha256(outer.as_slice())
+}
+
+/* HKDF with SHA256 (RFC 5869).  We never need more than one block of output,
+ * so expand only returns the first 32 bytes.
+ */
+pub fn hkdf_sha256_extract(salt : &[u8], ikm : &[u8]) -> [u8, ..32]
+{
+    hmac_sha256(salt,ikm)
+}
+
+pub fn hkdf_sha256_expand32(prk : &[u8], info : &[u8]) -> [u8, ..32]
+{
+    let mut data : Vec<u8> = info.to_vec();
+
+    data.push(0x01);
+
+    hmac_sha256(prk,data.as_slice())
+}
+
+/* sha256(sha256(tag) || sha256(tag) || data), as defined in BIP340.
+ */
+pub fn tagged_sha256(tag : &str, data : &[u8]) -> [u8, ..32]
+{
+    let tag_hash : [u8, ..32] = sha256(tag.as_bytes());
+    let mut msg : Vec<u8> = Vec::with_capacity(64+data.len());
+
+    msg.push_all(&tag_hash);
+    msg.push_all(&tag_hash);
+    msg.push_all(data);
+
+    sha256(msg.as_slice())
+}
+
+fn read_le32(data : &[u8], pos : uint) -> u32
+{
+    (data[pos] as u32) | (data[pos+1] as u32 << 8)
+        | (data[pos+2] as u32 << 16) | (data[pos+3] as u32 << 24)
+}
+
+fn quarter_round(s : &mut [u32, ..16], a : uint, b : uint, c : uint, d : uint)
+{
+    s[a] += s[b]; s[d] = rotl32(s[d] ^ s[a],16);
+    s[c] += s[d]; s[b] = rotl32(s[b] ^ s[c],12);
+    s[a] += s[b]; s[d] = rotl32(s[d] ^ s[a],8);
+    s[c] += s[d]; s[b] = rotl32(s[b] ^ s[c],7);
+}
+
+/* One block of the ChaCha20 keystream (RFC 8439), with a 32 bytes key and a
+ * 12 bytes nonce.
+ */
+pub fn chacha20_block(key : &[u8], nonce : &[u8], counter : u32) -> [u8, ..64]
+{
+    let mut state : [u32, ..16] = [0u32, ..16];
+    let mut working : [u32, ..16];
+    let mut block : [u8, ..64] = [0u8, ..64];
+
+    assert!(key.len() == 32 && nonce.len() == 12);
+
+    state[0] = 0x61707865;
+    state[1] = 0x3320646e;
+    state[2] = 0x79622d32;
+    state[3] = 0x6b206574;
+
+    for i in range(0u,8)
+    {
+        state[4+i] = read_le32(key,4*i);
+    }
+
+    state[12] = counter;
+
+    for i in range(0u,3)
+    {
+        state[13+i] = read_le32(nonce,4*i);
+    }
+
+    working = state;
+
+    for _ in range(0u,10)
+    {
+        quarter_round(&mut working,0,4, 8,12);
+        quarter_round(&mut working,1,5, 9,13);
+        quarter_round(&mut working,2,6,10,14);
+        quarter_round(&mut working,3,7,11,15);
+        quarter_round(&mut working,0,5,10,15);
+        quarter_round(&mut working,1,6,11,12);
+        quarter_round(&mut working,2,7, 8,13);
+        quarter_round(&mut working,3,4, 9,14);
+    }
+
+    for i in range(0u,16)
+    {
+        let word : u32 = working[i]+state[i];
+
+        for j in range(0u,4)
+        {
+            block[4*i+j] = (word >> 8*j) as u8;
+        }
+    }
+
+    block
+}
+
+/* Encrypt or decrypt the data in place, starting at the given block.
+ */
+pub fn chacha20_xor(key : &[u8], nonce : &[u8], counter : u32, data : &mut [u8])
+{
+    for (i, chunk) in data.chunks_mut(64).enumerate()
+    {
+        let keystream : [u8, ..64] = chacha20_block(key,nonce,counter+i as u32);
+
+        for j in range(0,chunk.len())
+        {
+            chunk[j] ^= keystream[j];
+        }
+    }
+}
+
+/* Poly1305 one-time authenticator (RFC 8439), with 26 bits limbs so the
+ * products fit in 64 bits.
+ */
+fn poly1305(key : &[u8], msg : &[u8]) -> [u8, ..16]
+{
+    let mask : u32 = 0x3ffffff;
+    let r0 : u32 = read_le32(key, 0)&0x3ffffff;
+    let r1 : u32 = (read_le32(key, 3)>>2)&0x3ffff03;
+    let r2 : u32 = (read_le32(key, 6)>>4)&0x3ffc0ff;
+    let r3 : u32 = (read_le32(key, 9)>>6)&0x3f03fff;
+    let r4 : u32 = (read_le32(key,12)>>8)&0x00fffff;
+    let (s1, s2, s3, s4) = (r1*5, r2*5, r3*5, r4*5);
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
+    let (mut g0, mut g1, mut g2, mut g3, mut g4) : (u32, u32, u32, u32, u32);
+    let mut c : u32;
+    let mut f : u64;
+    let mut tag : [u8, ..16] = [0u8, ..16];
+
+    for chunk in msg.chunks(16)
+    {
+        let mut block : [u8, ..16] = [0u8, ..16];
+        let mut hibit : u32 = 1 << 24;
+        let (mut d0, mut d1, mut d2, mut d3, mut d4) : (u64, u64, u64, u64, u64);
+
+        for i in range(0,chunk.len())
+        {
+            block[i] = chunk[i];
+        }
+
+        /* The last block is padded with a one and zeros instead of the
+         * implicit high bit.
+         */
+        if chunk.len() < 16
+        {
+            block[chunk.len()] = 1;
+            hibit = 0;
+        }
+
+        h0 += read_le32(&block, 0)&mask;
+        h1 += (read_le32(&block, 3)>>2)&mask;
+        h2 += (read_le32(&block, 6)>>4)&mask;
+        h3 += (read_le32(&block, 9)>>6)&mask;
+        h4 += (read_le32(&block,12)>>8) | hibit;
+
+        d0 = h0 as u64*r0 as u64 + h1 as u64*s4 as u64 + h2 as u64*s3 as u64
+           + h3 as u64*s2 as u64 + h4 as u64*s1 as u64;
+        d1 = h0 as u64*r1 as u64 + h1 as u64*r0 as u64 + h2 as u64*s4 as u64
+           + h3 as u64*s3 as u64 + h4 as u64*s2 as u64;
+        d2 = h0 as u64*r2 as u64 + h1 as u64*r1 as u64 + h2 as u64*r0 as u64
+           + h3 as u64*s4 as u64 + h4 as u64*s3 as u64;
+        d3 = h0 as u64*r3 as u64 + h1 as u64*r2 as u64 + h2 as u64*r1 as u64
+           + h3 as u64*r0 as u64 + h4 as u64*s4 as u64;
+        d4 = h0 as u64*r4 as u64 + h1 as u64*r3 as u64 + h2 as u64*r2 as u64
+           + h3 as u64*r1 as u64 + h4 as u64*r0 as u64;
+
+        c = (d0>>26) as u32; h0 = d0 as u32&mask; d1 += c as u64;
+        c = (d1>>26) as u32; h1 = d1 as u32&mask; d2 += c as u64;
+        c = (d2>>26) as u32; h2 = d2 as u32&mask; d3 += c as u64;
+        c = (d3>>26) as u32; h3 = d3 as u32&mask; d4 += c as u64;
+        c = (d4>>26) as u32; h4 = d4 as u32&mask;
+        h0 += c*5; c = h0>>26; h0 &= mask; h1 += c;
+    }
+
+    /* Fully carry h */
+    c = h1>>26; h1 &= mask; h2 += c;
+    c = h2>>26; h2 &= mask; h3 += c;
+    c = h3>>26; h3 &= mask; h4 += c;
+    c = h4>>26; h4 &= mask; h0 += c*5;
+    c = h0>>26; h0 &= mask; h1 += c;
+
+    /* g = h - (2^130-5), used instead of h if it does not underflow */
+    g0 = h0+5; c = g0>>26; g0 &= mask;
+    g1 = h1+c; c = g1>>26; g1 &= mask;
+    g2 = h2+c; c = g2>>26; g2 &= mask;
+    g3 = h3+c; c = g3>>26; g3 &= mask;
+    g4 = h4+c-(1 << 26);
+
+    c = (g4>>31)-1;
+    h0 = (h0&!c) | (g0&c);
+    h1 = (h1&!c) | (g1&c);
+    h2 = (h2&!c) | (g2&c);
+    h3 = (h3&!c) | (g3&c);
+    h4 = (h4&!c) | (g4&c);
+
+    /* h = (h + s) % 2^128 */
+    h0 = h0 | (h1<<26);
+    h1 = (h1>>6) | (h2<<20);
+    h2 = (h2>>12) | (h3<<14);
+    h3 = (h3>>18) | (h4<<8);
+
+    f = h0 as u64+read_le32(key,16) as u64;           h0 = f as u32;
+    f = h1 as u64+read_le32(key,20) as u64+(f>>32);   h1 = f as u32;
+    f = h2 as u64+read_le32(key,24) as u64+(f>>32);   h2 = f as u32;
+    f = h3 as u64+read_le32(key,28) as u64+(f>>32);   h3 = f as u32;
+
+    for (i, word) in [h0, h1, h2, h3].iter().enumerate()
+    {
+        for j in range(0u,4)
+        {
+            tag[4*i+j] = (*word >> 8*j) as u8;
+        }
+    }
+
+    tag
+}
+
+fn aead_tag(key : &[u8], nonce : &[u8], aad : &[u8], ciphertext : &[u8]) -> [u8, ..16]
+{
+    let poly_key : [u8, ..64] = chacha20_block(key,nonce,0);
+    let mut mac_data : Vec<u8> = Vec::with_capacity(aad.len()+ciphertext.len()+48);
+
+    mac_data.push_all(aad);
+    mac_data.grow((16-aad.len()%16)%16,0u8);
+    mac_data.push_all(ciphertext);
+    mac_data.grow((16-ciphertext.len()%16)%16,0u8);
+
+    for j in range(0u,8)
+    {
+        mac_data.push(((aad.len() as u64) >> 8*j) as u8);
+    }
+
+    for j in range(0u,8)
+    {
+        mac_data.push(((ciphertext.len() as u64) >> 8*j) as u8);
+    }
+
+    poly1305(poly_key.slice_to(32),mac_data.as_slice())
+}
+
+/* ChaCha20-Poly1305 AEAD (RFC 8439).  Returns the ciphertext followed by the
+ * 16 bytes tag.
+ */
+pub fn aead_chacha20_poly1305_encrypt(key   : &[u8],
+                                      nonce : &[u8],
+                                      aad   : &[u8],
+                                      plain : &[u8]) -> Vec<u8>
+{
+    let mut data : Vec<u8> = plain.to_vec();
+    let tag : [u8, ..16];
+
+    chacha20_xor(key,nonce,1,data.as_mut_slice());
+
+    tag = aead_tag(key,nonce,aad,data.as_slice());
+
+    data.push_all(&tag);
+
+    data
+}
+
+/* Returns None if the data is too short or the tag does not match.
+ */
+pub fn aead_chacha20_poly1305_decrypt(key   : &[u8],
+                                      nonce : &[u8],
+                                      aad   : &[u8],
+                                      data  : &[u8]) -> Option<Vec<u8>>
+{
+    let mut plain : Vec<u8>;
+    let tag : [u8, ..16];
+
+    if data.len() < 16
+    {
+        return None;
+    }
+
+    tag = aead_tag(key,nonce,aad,data.slice_to(data.len()-16));
+
+    if !constant_time_eq(&tag,data.slice_from(data.len()-16))
+    {
+        return None;
+    }
+
+    plain = data.slice_to(data.len()-16).to_vec();
+
+    chacha20_xor(key,nonce,1,plain.as_mut_slice());
+
+    Some(plain)
+}
+
+/* Whether a and b are equal, in a time that only depends on their length */
+pub fn constant_time_eq(a : &[u8], b : &[u8]) -> bool
+{
+    let mut diff : u8 = 0;
+
+    if a.len() != b.len()
+    {
+        return false;
+    }
+
+    for i in range(0,a.len())
+    {
+        diff |= a[i] ^ b[i];
+    }
+
+    diff == 0
+}
+
 pub fn checksum(data : &[u8]) -> u32
 {
     hash_first_u32(data)
@@ -51,8 +643,6 @@ pub fn to_hexstr(data : &[u8]) -> String
 {
     let mut str : String = String::new();
 
-    assert!(data.len() == 32);
-
     for b in data.iter()
     {
         str.push_str(format!("{:02x}",*b).as_slice());
@@ -61,6 +651,34 @@ pub fn to_hexstr(data : &[u8]) -> String
     str
 }
 
+/* Returns None if the string is not a valid hexadecimal string.
+ */
+pub fn from_hexstr(str : &str) -> Option<Vec<u8>>
+{
+    let mut data : Vec<u8> = Vec::with_capacity(str.len()/2);
+    let digits : Vec<char> = str.chars().collect();
+
+    if digits.len()%2 != 0
+    {
+        return None;
+    }
+
+    for pair in digits.as_slice().chunks(2)
+    {
+        let high = pair[0].to_digit(16);
+        let low  = pair[1].to_digit(16);
+
+        if high.is_none() || low.is_none()
+        {
+            return None;
+        }
+
+        data.push(((high.unwrap()<<4) | low.unwrap()) as u8);
+    }
+
+    Some(data)
+}
+
 pub fn rng() -> OsRng
 {
     let rng = OsRng::new();
@@ -0,0 +1,170 @@
+extern crate time;
+
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use datatype::hash::Hash;
+use datatype::transaction::Transaction;
+
+pub const BLOCK_HEADER_SIZE : uint = 80;
+
+#[deriving(Clone)]
+pub struct BlockHeader
+{
+    version     : u32,
+    prev_block  : Hash,
+    merkle_root : Hash,
+    time        : time::Timespec,
+    bits        : u32,        /* Target in compact form */
+    nounce      : u32
+}
+
+#[allow(dead_code)]
+impl BlockHeader
+{
+    pub fn new(version     : u32,
+               prev_block  : Hash,
+               merkle_root : Hash,
+               time        : time::Timespec,
+               bits        : u32,
+               nounce      : u32) -> BlockHeader
+    {
+        BlockHeader
+        {
+            version:     version,
+            prev_block:  prev_block,
+            merkle_root: merkle_root,
+            time:        time,
+            bits:        bits,
+            nounce:      nounce
+        }
+    }
+
+    pub fn get_version(&self) -> u32
+    {
+        self.version
+    }
+
+    pub fn get_prev_block(&self) -> &Hash
+    {
+        &self.prev_block
+    }
+
+    pub fn get_merkle_root(&self) -> &Hash
+    {
+        &self.merkle_root
+    }
+
+    pub fn get_time(&self) -> time::Timespec
+    {
+        self.time
+    }
+
+    pub fn get_bits(&self) -> u32
+    {
+        self.bits
+    }
+
+    pub fn get_nounce(&self) -> u32
+    {
+        self.nounce
+    }
+
+    pub fn set_nounce(&mut self, nounce : u32)
+    {
+        self.nounce = nounce;
+    }
+
+    pub fn get_hash(&self) -> Hash
+    {
+        let mut marshalling = ::marshalling::Marshalling::new();
+
+        marshalling.write_block_header(self);
+
+        assert!(marshalling.len() == BLOCK_HEADER_SIZE);
+
+        Hash::from_digest(::crypto::dsha256(marshalling.get().as_slice()))
+    }
+}
+
+impl Show for BlockHeader
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}Hash       : {}\n",space,self.get_hash()));
+        try!(write!(f,"{}Version    : {}\n",space,self.version));
+        try!(write!(f,"{}Prev block : {}\n",space,self.prev_block));
+        try!(write!(f,"{}Merkle root: {}\n",space,self.merkle_root));
+        try!(write!(f,"{}Time       : {}\n",space,self.time.sec));
+        try!(write!(f,"{}Bits       : {:08x}\n",space,self.bits));
+        try!(write!(f,"{}Nounce     : {}",space,self.nounce));
+
+        Ok(())
+    }
+}
+
+#[deriving(Clone)]
+pub struct Block
+{
+    header : BlockHeader,
+    txs    : Vec<Transaction>
+}
+
+#[allow(dead_code)]
+impl Block
+{
+    pub fn new(header : BlockHeader, txs : Vec<Transaction>) -> Block
+    {
+        Block
+        {
+            header: header,
+            txs:    txs
+        }
+    }
+
+    pub fn get_header(&self) -> &BlockHeader
+    {
+        &self.header
+    }
+
+    pub fn get_txs(&self) -> &Vec<Transaction>
+    {
+        &self.txs
+    }
+
+    pub fn get_hash(&self) -> Hash
+    {
+        self.header.get_hash()
+    }
+
+    pub fn serialize(&self) -> Vec<u8>
+    {
+        let mut marshalling = ::marshalling::Marshalling::new();
+
+        marshalling.write_block(self);
+
+        marshalling.get()
+    }
+
+    /* Size in bytes of the serialized block */
+    pub fn get_size(&self) -> uint
+    {
+        self.serialize().len()
+    }
+}
+
+impl Show for Block
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        // TODO this should be "{:width}"
+        try!(write!(f,"{:4}\n",self.header));
+        write!(f,"{}Txs        : {}",space,self.txs.len())
+    }
+}
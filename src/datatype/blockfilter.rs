@@ -0,0 +1,308 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use std::collections::HashSet;
+
+use datatype::block::Block;
+use datatype::script::Script;
+use datatype::script::OP_RETURN;
+use datatype::hash::Hash;
+
+/* Filter types (BIP158) */
+pub const FILTER_TYPE_BASIC : u8 = 0;
+
+/* Golomb-Rice parameters of the basic filter */
+const BASIC_FILTER_P : uint = 19;
+const BASIC_FILTER_M : u64 = 784931;
+
+/* High 64 bits of the 128 bits product */
+fn mul_high(a : u64, b : u64) -> u64
+{
+    let a_lo : u64 = a & 0xffffffff;
+    let a_hi : u64 = a >> 32;
+    let b_lo : u64 = b & 0xffffffff;
+    let b_hi : u64 = b >> 32;
+    let lo_lo : u64 = a_lo*b_lo;
+    let hi_lo : u64 = a_hi*b_lo;
+    let lo_hi : u64 = a_lo*b_hi;
+    let hi_hi : u64 = a_hi*b_hi;
+    let cross : u64 = (lo_lo >> 32) + (hi_lo & 0xffffffff) + lo_hi;
+
+    hi_hi + (hi_lo >> 32) + (cross >> 32)
+}
+
+struct BitWriter
+{
+    bytes : Vec<u8>,
+    bits  : uint      /* Bits used in the last byte */
+}
+
+impl BitWriter
+{
+    fn new() -> BitWriter
+    {
+        BitWriter { bytes: Vec::new(), bits: 8 }
+    }
+
+    fn write_bit(&mut self, bit : bool)
+    {
+        if self.bits == 8
+        {
+            self.bytes.push(0);
+            self.bits = 0;
+        }
+
+        if bit
+        {
+            let last : uint = self.bytes.len()-1;
+
+            self.bytes[last] |= 0x80 >> self.bits;
+        }
+
+        self.bits += 1;
+    }
+
+    /* Most significant bit first */
+    fn write_bits(&mut self, v : u64, n : uint)
+    {
+        for i in range(0,n).rev()
+        {
+            self.write_bit((v >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a>
+{
+    bytes : &'a [u8],
+    pos   : uint      /* In bits */
+}
+
+impl<'a> BitReader<'a>
+{
+    fn read_bit(&mut self) -> Option<bool>
+    {
+        let byte : uint = self.pos/8;
+
+        if byte >= self.bytes.len()
+        {
+            return None;
+        }
+
+        self.pos += 1;
+
+        Some(self.bytes[byte] & (0x80 >> ((self.pos-1)%8)) != 0)
+    }
+
+    fn read_bits(&mut self, n : uint) -> Option<u64>
+    {
+        let mut v : u64 = 0;
+
+        for _ in range(0,n)
+        {
+            v = (v << 1) | match self.read_bit() { Some(b) => b as u64, None => return None };
+        }
+
+        Some(v)
+    }
+}
+
+/* Compact block filter (BIP158): a Golomb-coded set of the scripts a block
+ * creates and spends, so clients can find out whether a block is relevant
+ * to them without telling us what they look for.
+ */
+#[deriving(Clone)]
+pub struct BlockFilter
+{
+    filter_type : u8,
+    block_hash  : Hash,
+    encoded     : Vec<u8>
+}
+
+#[allow(dead_code)]
+impl BlockFilter
+{
+    pub fn new(filter_type : u8, block_hash : Hash, encoded : Vec<u8>) -> BlockFilter
+    {
+        BlockFilter
+        {
+            filter_type: filter_type,
+            block_hash:  block_hash,
+            encoded:     encoded
+        }
+    }
+
+    /* The basic filter contains the output scripts of every tx in the block
+     * (but OP_RETURN ones) and the scripts of the outputs they spend.
+     */
+    pub fn build_basic(block : &Block, spent : &[Script]) -> BlockFilter
+    {
+        let mut elements : HashSet<Vec<u8>> = HashSet::new();
+        let block_hash : Hash = block.get_hash();
+        let (k0, k1) = BlockFilter::get_keys(&block_hash);
+        let f : u64;
+        let mut values : Vec<u64>;
+        let mut writer : BitWriter = BitWriter::new();
+        let mut encoded = ::marshalling::Marshalling::new();
+        let mut last : u64 = 0;
+
+        for tx in block.get_txs().iter()
+        {
+            for out_tx in tx.get_out_txs().iter()
+            {
+                let bytes : &Vec<u8> = out_tx.get_script().get_bytes();
+
+                if bytes.len() > 0 && bytes[0] != OP_RETURN
+                {
+                    elements.insert(bytes.clone());
+                }
+            }
+        }
+
+        for script in spent.iter()
+        {
+            if script.len() > 0
+            {
+                elements.insert(script.get_bytes().clone());
+            }
+        }
+
+        f = (elements.len() as u64)*BASIC_FILTER_M;
+
+        values = elements.iter()
+            .map(|e| mul_high(::crypto::siphash24(k0,k1,e.as_slice()),f))
+            .collect();
+        values.sort();
+
+        for v in values.iter()
+        {
+            let delta : u64 = *v-last;
+
+            for _ in range(0,delta >> BASIC_FILTER_P)
+            {
+                writer.write_bit(true);
+            }
+
+            writer.write_bit(false);
+            writer.write_bits(delta,BASIC_FILTER_P);
+
+            last = *v;
+        }
+
+        encoded.write_varint(values.len() as u64);
+        encoded.write(writer.bytes.as_slice());
+
+        BlockFilter::new(FILTER_TYPE_BASIC,block_hash,encoded.get())
+    }
+
+    /* The siphash key is the first 16 bytes of the block hash */
+    fn get_keys(block_hash : &Hash) -> (u64, u64)
+    {
+        let digest : [u8, ..32] = block_hash.to_digest();
+        let mut k0 : u64 = 0;
+        let mut k1 : u64 = 0;
+
+        for i in range(0u,8)
+        {
+            k0 |= (digest[i] as u64) << 8*i;
+            k1 |= (digest[8+i] as u64) << 8*i;
+        }
+
+        (k0, k1)
+    }
+
+    pub fn get_filter_type(&self) -> u8
+    {
+        self.filter_type
+    }
+
+    pub fn get_block_hash(&self) -> &Hash
+    {
+        &self.block_hash
+    }
+
+    pub fn get_encoded(&self) -> &Vec<u8>
+    {
+        &self.encoded
+    }
+
+    pub fn get_hash(&self) -> Hash
+    {
+        Hash::from_digest(::crypto::dsha256(self.encoded.as_slice()))
+    }
+
+    /* Filter headers chain the filters like block headers chain blocks */
+    pub fn compute_header(&self, prev_header : &Hash) -> Hash
+    {
+        let mut data : Vec<u8> = Vec::with_capacity(64);
+
+        data.push_all(&self.get_hash().to_digest());
+        data.push_all(&prev_header.to_digest());
+
+        Hash::from_digest(::crypto::dsha256(data.as_slice()))
+    }
+
+    /* Whether the element may be in the filter (false positives happen with
+     * probability 1/M).
+     */
+    pub fn contains(&self, element : &[u8]) -> bool
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(&self.encoded);
+        let n : u64 = unmarshalling.read_varint();
+        let (k0, k1) = BlockFilter::get_keys(&self.block_hash);
+        let target : u64 = mul_high(::crypto::siphash24(k0,k1,element),n*BASIC_FILTER_M);
+        let mut reader : BitReader;
+        let mut value : u64 = 0;
+
+        reader = BitReader
+        {
+            bytes: self.encoded.slice_from(self.encoded.len()-unmarshalling.remaining()),
+            pos:   0
+        };
+
+        for _ in range(0,n)
+        {
+            let mut q : u64 = 0;
+
+            loop
+            {
+                match reader.read_bit()
+                {
+                    Some(true)  => q += 1,
+                    Some(false) => break,
+                    None        => return false
+                }
+            }
+
+            value += (q << BASIC_FILTER_P) + match reader.read_bits(BASIC_FILTER_P)
+            {
+                Some(r) => r,
+                None    => return false
+            };
+
+            if value == target
+            {
+                return true;
+            }
+
+            if value > target
+            {
+                return false;
+            }
+        }
+
+        false
+    }
+}
+
+impl Show for BlockFilter
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f,"{}BlockFilter type {} block {} ({} bytes)",
+               space, self.filter_type, self.block_hash, self.encoded.len())
+    }
+}
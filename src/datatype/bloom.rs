@@ -0,0 +1,212 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use std::num::Float;
+
+use datatype::transaction::Transaction;
+use datatype::transaction::OutPoint;
+
+/* Limits from BIP37 */
+pub const MAX_BLOOM_FILTER_SIZE : uint = 36000;
+pub const MAX_HASH_FUNCS : u32 = 50;
+/* filteradd data is limited to the maximum size of a script element */
+pub const MAX_FILTERADD_SIZE : uint = 520;
+
+const BLOOM_HASH_SEED_MULT : u32 = 0xfba4c795;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum BloomUpdate
+{
+    BloomUpdateNone         = 0,
+    BloomUpdateAll          = 1,
+    BloomUpdateP2PubkeyOnly = 2
+}
+
+const BLOOM_UPDATE_MASK : u8 = 3;
+
+#[deriving(Clone)]
+pub struct BloomFilter
+{
+    data       : Vec<u8>,
+    hash_funcs : u32,
+    tweak      : u32,
+    flags      : u8
+}
+
+#[allow(dead_code)]
+impl BloomFilter
+{
+    pub fn new(data : Vec<u8>, hash_funcs : u32, tweak : u32, flags : u8) -> BloomFilter
+    {
+        BloomFilter
+        {
+            data:       data,
+            hash_funcs: hash_funcs,
+            tweak:      tweak,
+            flags:      flags
+        }
+    }
+
+    /* Create an empty filter sized for the given number of elements and false
+     * positive rate, like a lightweight client does.
+     */
+    pub fn with_rate(elements : uint, fp_rate : f64, tweak : u32, flags : u8) -> BloomFilter
+    {
+        let ln2 : f64 = 2.0f64.ln();
+        let bits : f64 = -1.0/(ln2*ln2)*(elements as f64)*fp_rate.ln();
+        let size : uint = ::std::cmp::min((bits/8.0) as uint,MAX_BLOOM_FILTER_SIZE);
+        let funcs : u32 = ((size*8) as f64/(elements as f64)*ln2) as u32;
+
+        BloomFilter::new(Vec::from_elem(::std::cmp::max(size,1),0u8),
+                         ::std::cmp::min(funcs,MAX_HASH_FUNCS),
+                         tweak,
+                         flags)
+    }
+
+    pub fn get_data(&self) -> &Vec<u8>
+    {
+        &self.data
+    }
+
+    pub fn get_hash_funcs(&self) -> u32
+    {
+        self.hash_funcs
+    }
+
+    pub fn get_tweak(&self) -> u32
+    {
+        self.tweak
+    }
+
+    pub fn get_flags(&self) -> u8
+    {
+        self.flags
+    }
+
+    pub fn get_update(&self) -> BloomUpdate
+    {
+        match self.flags & BLOOM_UPDATE_MASK
+        {
+            1 => BloomUpdate::BloomUpdateAll,
+            2 => BloomUpdate::BloomUpdateP2PubkeyOnly,
+            _ => BloomUpdate::BloomUpdateNone
+        }
+    }
+
+    pub fn is_within_limits(&self) -> bool
+    {
+        self.data.len() <= MAX_BLOOM_FILTER_SIZE && self.hash_funcs <= MAX_HASH_FUNCS
+    }
+
+    fn bit(&self, n : u32, data : &[u8]) -> uint
+    {
+        let seed : u32 = n*BLOOM_HASH_SEED_MULT+self.tweak;
+
+        (::crypto::murmur3(seed,data) as uint) % (self.data.len()*8)
+    }
+
+    pub fn insert(&mut self, data : &[u8])
+    {
+        if self.data.is_empty()
+        {
+            return;
+        }
+
+        for n in range(0,self.hash_funcs)
+        {
+            let bit : uint = self.bit(n,data);
+
+            self.data[bit>>3] |= 1 << (bit&7);
+        }
+    }
+
+    pub fn contains(&self, data : &[u8]) -> bool
+    {
+        if self.data.is_empty()
+        {
+            return false;
+        }
+
+        range(0,self.hash_funcs).all(|n|
+        {
+            let bit : uint = self.bit(n,data);
+
+            self.data[bit>>3] & (1 << (bit&7)) != 0
+        })
+    }
+
+    fn serialize_outpoint(outpoint : &OutPoint) -> Vec<u8>
+    {
+        let mut m = ::marshalling::Marshalling::new();
+
+        m.write_outpoint(outpoint);
+
+        m.get()
+    }
+
+    pub fn insert_outpoint(&mut self, outpoint : &OutPoint)
+    {
+        self.insert(BloomFilter::serialize_outpoint(outpoint).as_slice());
+    }
+
+    pub fn contains_outpoint(&self, outpoint : &OutPoint) -> bool
+    {
+        self.contains(BloomFilter::serialize_outpoint(outpoint).as_slice())
+    }
+
+    /* Whether the tx matches the filter, i.e. its hash, any data pushed by
+     * its output scripts, any outpoint it spends or any data pushed by its
+     * input scripts.  Depending on the flags, matched outputs are added to the
+     * filter so the txs spending them match too.
+     */
+    pub fn is_relevant_and_update(&mut self, tx : &Transaction) -> bool
+    {
+        let hash = tx.get_hash();
+        let mut found : bool = self.contains(&hash.to_digest());
+
+        for (i, out_tx) in tx.get_out_txs().iter().enumerate()
+        {
+            let script = out_tx.get_script();
+
+            if !script.get_pushes().iter().any(|data| self.contains(data.as_slice()))
+            {
+                continue;
+            }
+
+            found = true;
+
+            match self.get_update()
+            {
+                BloomUpdate::BloomUpdateAll =>
+                    self.insert_outpoint(&OutPoint::new(hash.clone(),i as u32)),
+                BloomUpdate::BloomUpdateP2PubkeyOnly =>
+                    if script.is_pay_to_pubkey() || script.is_multisig()
+                    {
+                        self.insert_outpoint(&OutPoint::new(hash.clone(),i as u32))
+                    },
+                BloomUpdate::BloomUpdateNone => ()
+            }
+        }
+
+        if found
+        {
+            return true;
+        }
+
+        tx.get_in_txs().iter().any(|in_tx|
+            self.contains_outpoint(in_tx.get_prev_out()) ||
+            in_tx.get_script().get_pushes().iter().any(|data| self.contains(data.as_slice())))
+    }
+}
+
+impl Show for BloomFilter
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f,"{}BloomFilter {} bytes, {} hash funcs, tweak {}, flags {}",
+               space, self.data.len(), self.hash_funcs, self.tweak, self.flags)
+    }
+}
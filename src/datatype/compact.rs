@@ -0,0 +1,399 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use std::collections::HashMap;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::transaction::Transaction;
+use datatype::hash::Hash;
+
+/* Short transaction ids are the 6 lower bytes of the siphash of the txid */
+pub const SHORTID_SIZE : uint = 6;
+const SHORTID_MASK : u64 = 0xffffffffffff;
+
+/* Compact blocks version we speak (BIP152, txids instead of wtxids).
+ *
+ * Version 2 uses the wtxids for the short ids and sends the txs with their
+ * witness.  We do not support segregated witness: we could not parse those
+ * txs nor check them, so we neither offer version 2 nor accept it.  Peers
+ * that only speak version 2 send us inv and headers as usual, and we fetch
+ * whole blocks from them.
+ */
+pub const CMPCT_VERSION : u64 = 1;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum CompactError
+{
+    CompactIndexOverflow,     /* Prefilled tx index past the end of the block */
+    CompactTooManyTxs,
+    CompactDuplicateShortId,
+    CompactMerkleMismatch,    /* Reconstructed block does not match the header */
+    CompactWrongTxCount       /* blocktxn with more or less txs than requested */
+}
+
+#[deriving(Clone)]
+pub struct PrefilledTx
+{
+    pub index : uint,   /* Absolute index in the block */
+    pub tx    : Transaction
+}
+
+/* The contents of a cmpctblock message: the header, the short ids of the
+ * txs the receiver is likely to have and the txs it is likely not to have
+ * (at least the coinbase).
+ */
+#[deriving(Clone)]
+pub struct HeaderAndShortIds
+{
+    header    : BlockHeader,
+    nonce     : u64,
+    short_ids : Vec<u64>,
+    prefilled : Vec<PrefilledTx>
+}
+
+#[allow(dead_code)]
+impl HeaderAndShortIds
+{
+    pub fn new(header    : BlockHeader,
+               nonce     : u64,
+               short_ids : Vec<u64>,
+               prefilled : Vec<PrefilledTx>) -> HeaderAndShortIds
+    {
+        HeaderAndShortIds
+        {
+            header:    header,
+            nonce:     nonce,
+            short_ids: short_ids,
+            prefilled: prefilled
+        }
+    }
+
+    /* Only the coinbase is prefilled, we do not know what the peer has */
+    pub fn from_block(block : &Block, nonce : u64) -> HeaderAndShortIds
+    {
+        let mut cmpct : HeaderAndShortIds;
+        let txs : &Vec<Transaction> = block.get_txs();
+        let keys : (u64, u64);
+
+        cmpct = HeaderAndShortIds::new(block.get_header().clone(),nonce,Vec::new(),
+                                       vec![PrefilledTx { index: 0, tx: txs[0].clone() }]);
+
+        keys = cmpct.get_keys();
+
+        for tx in txs.iter().skip(1)
+        {
+            cmpct.short_ids.push(short_id(keys,&tx.get_hash()));
+        }
+
+        cmpct
+    }
+
+    pub fn get_header(&self) -> &BlockHeader
+    {
+        &self.header
+    }
+
+    pub fn get_nonce(&self) -> u64
+    {
+        self.nonce
+    }
+
+    pub fn get_short_ids(&self) -> &Vec<u64>
+    {
+        &self.short_ids
+    }
+
+    pub fn get_prefilled(&self) -> &Vec<PrefilledTx>
+    {
+        &self.prefilled
+    }
+
+    pub fn get_tx_count(&self) -> uint
+    {
+        self.short_ids.len()+self.prefilled.len()
+    }
+
+    /* The siphash keys are the first 16 bytes of sha256(header || nonce), so
+     * they are different for every block and every sender.
+     */
+    pub fn get_keys(&self) -> (u64, u64)
+    {
+        let mut m = ::marshalling::Marshalling::new();
+        let digest : [u8, ..32];
+        let mut k0 : u64 = 0;
+        let mut k1 : u64 = 0;
+
+        m.write_block_header(&self.header);
+        m.write_uint64(self.nonce);
+
+        digest = ::crypto::sha256(m.get().as_slice());
+
+        for i in range(0u,8)
+        {
+            k0 |= (digest[i] as u64) << 8*i;
+            k1 |= (digest[8+i] as u64) << 8*i;
+        }
+
+        (k0, k1)
+    }
+}
+
+pub fn short_id((k0, k1) : (u64, u64), txid : &Hash) -> u64
+{
+    ::crypto::siphash24(k0,k1,&txid.to_digest()) & SHORTID_MASK
+}
+
+impl Show for HeaderAndShortIds
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}HeaderAndShortIds:\n", space));
+        try!(write!(f,"{}  block:     {}\n", space, self.header.get_hash()));
+        try!(write!(f,"{}  nonce:     {}\n", space, self.nonce));
+        try!(write!(f,"{}  short ids: {}\n", space, self.short_ids.len()));
+        write!(f,"{}  prefilled: {}", space, self.prefilled.len())
+    }
+}
+
+/* The txs of a block we could not find when reconstructing it */
+#[deriving(Clone)]
+pub struct BlockTxRequest
+{
+    block_hash : Hash,
+    indexes    : Vec<uint>   /* Absolute and increasing */
+}
+
+#[allow(dead_code)]
+impl BlockTxRequest
+{
+    pub fn new(block_hash : Hash, indexes : Vec<uint>) -> BlockTxRequest
+    {
+        BlockTxRequest
+        {
+            block_hash: block_hash,
+            indexes:    indexes
+        }
+    }
+
+    pub fn get_block_hash(&self) -> &Hash
+    {
+        &self.block_hash
+    }
+
+    pub fn get_indexes(&self) -> &Vec<uint>
+    {
+        &self.indexes
+    }
+}
+
+impl Show for BlockTxRequest
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f,"{}BlockTxRequest {} txs of {}", space, self.indexes.len(), self.block_hash)
+    }
+}
+
+/* The txs requested with a BlockTxRequest, in the same order */
+#[deriving(Clone)]
+pub struct BlockTransactions
+{
+    block_hash : Hash,
+    txs        : Vec<Transaction>
+}
+
+#[allow(dead_code)]
+impl BlockTransactions
+{
+    pub fn new(block_hash : Hash, txs : Vec<Transaction>) -> BlockTransactions
+    {
+        BlockTransactions
+        {
+            block_hash: block_hash,
+            txs:        txs
+        }
+    }
+
+    pub fn get_block_hash(&self) -> &Hash
+    {
+        &self.block_hash
+    }
+
+    pub fn get_txs(&self) -> &Vec<Transaction>
+    {
+        &self.txs
+    }
+}
+
+impl Show for BlockTransactions
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f,"{}BlockTransactions {} txs of {}", space, self.txs.len(), self.block_hash)
+    }
+}
+
+/* A block being reconstructed from a cmpctblock */
+#[deriving(Clone)]
+pub struct PartialBlock
+{
+    cmpct     : HeaderAndShortIds,
+    keys      : (u64, u64),
+    txs       : Vec<Option<Transaction>>,
+    positions : HashMap<u64,uint>,        /* Short id to index in the block */
+    collided  : Vec<uint>                 /* Indexes with more than one candidate */
+}
+
+#[allow(dead_code)]
+impl PartialBlock
+{
+    pub fn new(cmpct : HeaderAndShortIds) -> Result<PartialBlock,CompactError>
+    {
+        let count : uint = cmpct.get_tx_count();
+        let mut txs : Vec<Option<Transaction>>;
+        let mut positions : HashMap<u64,uint> = HashMap::new();
+
+        if count == 0 || count > ::consensus::MAX_BLOCK_SIZE/60
+        {
+            return Err(CompactError::CompactTooManyTxs);
+        }
+
+        txs = Vec::from_fn(count,|_| None);
+
+        for prefilled in cmpct.get_prefilled().iter()
+        {
+            if prefilled.index >= count || txs[prefilled.index].is_some()
+            {
+                return Err(CompactError::CompactIndexOverflow);
+            }
+
+            txs[prefilled.index] = Some(prefilled.tx.clone());
+        }
+
+        /* Short ids fill the gaps between the prefilled txs in order */
+        {
+            let mut short_ids = cmpct.get_short_ids().iter();
+
+            for i in range(0,count)
+            {
+                if txs[i].is_some()
+                {
+                    continue;
+                }
+
+                if positions.insert(*short_ids.next().unwrap(),i).is_some()
+                {
+                    return Err(CompactError::CompactDuplicateShortId);
+                }
+            }
+        }
+
+        Ok(PartialBlock
+        {
+            keys:      cmpct.get_keys(),
+            cmpct:     cmpct,
+            txs:       txs,
+            positions: positions,
+            collided:  Vec::new()
+        })
+    }
+
+    pub fn get_hash(&self) -> Hash
+    {
+        self.cmpct.get_header().get_hash()
+    }
+
+    /* Fill the slot of the tx if the block has it.  If two candidates have
+     * the same short id we cannot tell which one is in the block, so the
+     * slot is requested from the peer.
+     */
+    pub fn add_candidate(&mut self, tx : &Transaction)
+    {
+        let hash : Hash = tx.get_hash();
+        let collision : bool;
+        let index : uint = match self.positions.get(&short_id(self.keys,&hash))
+        {
+            Some(index) => *index,
+            None        => return
+        };
+
+        if self.collided.contains(&index)
+        {
+            return;
+        }
+
+        collision = match self.txs[index]
+        {
+            Some(ref other) => other.get_hash() != hash,
+            None            => false
+        };
+
+        if collision
+        {
+            self.txs[index] = None;
+            self.collided.push(index);
+        }
+        else
+        {
+            self.txs[index] = Some(tx.clone());
+        }
+    }
+
+    /* Indexes of the txs we still need */
+    pub fn get_missing(&self) -> Vec<uint>
+    {
+        range(0,self.txs.len()).filter(|i| self.txs[*i].is_none()).collect()
+    }
+
+    /* Complete the block with the missing txs, in the order returned by
+     * get_missing, and check it matches the header.
+     */
+    pub fn fill(&self, missing : &[Transaction]) -> Result<Block,CompactError>
+    {
+        let mut txs : Vec<Transaction> = Vec::with_capacity(self.txs.len());
+        let mut missing_iter = missing.iter();
+        let hashes : Vec<Hash>;
+        let block : Block;
+
+        for tx in self.txs.iter()
+        {
+            match *tx
+            {
+                Some(ref tx) => txs.push(tx.clone()),
+                None         =>
+                    match missing_iter.next()
+                    {
+                        Some(tx) => txs.push(tx.clone()),
+                        None     => return Err(CompactError::CompactWrongTxCount)
+                    }
+            }
+        }
+
+        if missing_iter.next().is_some()
+        {
+            return Err(CompactError::CompactWrongTxCount);
+        }
+
+        hashes = txs.iter().map(|tx| tx.get_hash()).collect();
+
+        match ::datatype::merkle::compute_merkle_root(hashes.as_slice())
+        {
+            (ref root, false) if root == self.cmpct.get_header().get_merkle_root() => (),
+            _ => return Err(CompactError::CompactMerkleMismatch)
+        }
+
+        block = Block::new(self.cmpct.get_header().clone(),txs);
+
+        Ok(block)
+    }
+}
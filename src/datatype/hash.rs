@@ -1,13 +1,20 @@
 use std::fmt::Show;
 use std::fmt::Formatter;
 
+use std::cmp::Eq;
+use std::cmp::PartialEq;
+
 use std::clone::Clone;
 
+/* The hash is stored in the order it is displayed, i.e. reversed relative to
+ * the digest and to the wire format.
+ */
 pub struct Hash
 {
     hash : [u8, ..32]
 }
 
+#[allow(dead_code)]
 impl Hash
 {
     pub fn new(hash : [u8, ..32]) -> Hash
@@ -17,6 +24,65 @@ impl Hash
             hash: hash
         }
     }
+
+    pub fn zero() -> Hash
+    {
+        Hash::new([0u8, ..32])
+    }
+
+    /* Build a hash from a digest (as returned by ::crypto::dsha256()).
+     */
+    pub fn from_digest(digest : [u8, ..32]) -> Hash
+    {
+        let mut hash : [u8, ..32] = [0u8, ..32];
+
+        for i in range(0u,32)
+        {
+            hash[i] = digest[31-i];
+        }
+
+        Hash::new(hash)
+    }
+
+    pub fn to_digest(&self) -> [u8, ..32]
+    {
+        let mut digest : [u8, ..32] = [0u8, ..32];
+
+        for i in range(0u,32)
+        {
+            digest[i] = self.hash[31-i];
+        }
+
+        digest
+    }
+
+    /* From the hexadecimal string it is displayed as */
+    pub fn from_hexstr(str : &str) -> Option<Hash>
+    {
+        let mut hash : [u8, ..32] = [0u8, ..32];
+        let bytes : Vec<u8> = match ::crypto::from_hexstr(str)
+        {
+            Some(ref bytes) if bytes.len() == 32 => bytes.clone(),
+            _                                    => return None
+        };
+
+        for i in range(0u,32)
+        {
+            hash[i] = bytes[i];
+        }
+
+        Some(Hash::new(hash))
+    }
+
+    pub fn is_zero(&self) -> bool
+    {
+        self.hash.iter().all(|b| *b == 0u8)
+    }
+
+    pub fn as_slice(&self) -> &[u8]
+    {
+        self.hash.as_slice()
+    }
 }
 
 impl Index<uint, u8> for Hash
@@ -42,3 +108,23 @@ impl Clone for Hash
         Hash::new(self.hash)
     }
 }
+
+impl<S : Writer> ::std::hash::Hash<S> for Hash
+{
+    fn hash(&self, state: &mut S)
+    {
+        use std::hash::Hash;
+
+        self.hash.as_slice().hash(state);
+    }
+}
+
+impl PartialEq for Hash
+{
+    fn eq(&self, other: &Hash) -> bool
+    {
+        self.hash.as_slice() == other.hash.as_slice()
+    }
+}
+
+impl Eq for Hash {}
@@ -8,9 +8,15 @@ pub enum InvEntryType
 {
     Error,
     MsgTx,
-    MsgBlock
+    MsgBlock,
+    MsgFilteredBlock,
+    MsgCmpctBlock,     /* BIP152 */
+    MsgWitnessTx       /* BIP339, the hash is a wtxid */
 }
 
+/* Set in the type of getdata entries to ask for the witness data (BIP144) */
+pub const MSG_WITNESS_FLAG : u32 = 1 << 30;
+
 #[deriving(Clone)]
 pub struct InvEntry
 {
@@ -24,9 +30,12 @@ impl Show for InvEntry
     {
         match self.typ
         {
-            InvEntryType::Error    => try!(write!(f, "ERROR ")),
-            InvEntryType::MsgTx    => try!(write!(f, "TX    ")),
-            InvEntryType::MsgBlock => try!(write!(f, "BLOCK "))
+            InvEntryType::Error            => try!(write!(f, "ERROR  ")),
+            InvEntryType::MsgTx            => try!(write!(f, "TX     ")),
+            InvEntryType::MsgBlock         => try!(write!(f, "BLOCK  ")),
+            InvEntryType::MsgFilteredBlock => try!(write!(f, "FBLOCK ")),
+            InvEntryType::MsgCmpctBlock    => try!(write!(f, "CBLOCK ")),
+            InvEntryType::MsgWitnessTx     => try!(write!(f, "WTX    "))
         }
 
         write!(f,"{}",self.hash)
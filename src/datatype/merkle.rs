@@ -0,0 +1,325 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use std::iter::range_step;
+
+use datatype::hash::Hash;
+use datatype::block::BlockHeader;
+
+/* A block cannot have more txs than fit with the minimum tx size (60 bytes) */
+const MAX_TXS : u32 = 1_000_000/60;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum MerkleError
+{
+    MerkleNoTxs,
+    MerkleTooManyTxs,
+    MerkleTooManyHashes,
+    MerkleNotEnoughFlags,
+    MerkleOverflow,        /* Ran out of flags or hashes */
+    MerkleUnusedData,      /* Flags or hashes left after the traversal */
+    MerkleDuplicateHash    /* CVE-2012-2459 */
+}
+
+fn hash_pair(left : &Hash, right : &Hash) -> Hash
+{
+    let mut data : Vec<u8> = Vec::with_capacity(64);
+
+    data.push_all(&left.to_digest());
+    data.push_all(&right.to_digest());
+
+    Hash::from_digest(::crypto::dsha256(data.as_slice()))
+}
+
+/* Returns the merkle root and whether the tree is mutated, i.e. if it
+ * contains two identical consecutive hashes at some level, which would allow
+ * for a different list of transactions with the same merkle root
+ * (CVE-2012-2459).
+ */
+pub fn compute_merkle_root(hashes : &[Hash]) -> (Hash, bool)
+{
+    let mut level : Vec<Hash> = hashes.to_vec();
+    let mut mutated : bool = false;
+
+    if level.len() == 0
+    {
+        return (Hash::zero(), false);
+    }
+
+    while level.len() > 1
+    {
+        let mut next : Vec<Hash> = Vec::with_capacity((level.len()+1)/2);
+
+        for i in range_step(0,level.len(),2)
+        {
+            let right : uint = ::std::cmp::min(i+1,level.len()-1);
+
+            if right != i && level[i] == level[right]
+            {
+                mutated = true;
+            }
+
+            next.push(hash_pair(&level[i],&level[right]));
+        }
+
+        level = next;
+    }
+
+    (level[0].clone(), mutated)
+}
+
+/* A merkle tree with only the branches needed to prove that some txs are in
+ * a block, as sent in merkleblock messages (BIP37).  The tree is traversed
+ * depth first: each flag tells whether the node is the parent of a matched tx,
+ * and the hashes are those of the nodes that are not descended into.
+ */
+#[deriving(Clone)]
+pub struct PartialMerkleTree
+{
+    tx_count : u32,
+    hashes   : Vec<Hash>,
+    flags    : Vec<bool>
+}
+
+#[allow(dead_code)]
+impl PartialMerkleTree
+{
+    pub fn new(tx_count : u32, hashes : Vec<Hash>, flags : Vec<bool>) -> PartialMerkleTree
+    {
+        PartialMerkleTree
+        {
+            tx_count: tx_count,
+            hashes:   hashes,
+            flags:    flags
+        }
+    }
+
+    /* Build the tree for the block with the given txids, including the
+     * branches of the txs that matched.
+     */
+    pub fn from_txids(txids : &[Hash], matches : &[bool]) -> PartialMerkleTree
+    {
+        let mut tree : PartialMerkleTree;
+        let height : uint;
+
+        assert_eq!(txids.len(),matches.len());
+
+        tree = PartialMerkleTree::new(txids.len() as u32,Vec::new(),Vec::new());
+
+        height = tree.calc_height();
+
+        tree.traverse_and_build(height,0,txids,matches);
+
+        tree
+    }
+
+    pub fn get_tx_count(&self) -> u32
+    {
+        self.tx_count
+    }
+
+    pub fn get_hashes(&self) -> &Vec<Hash>
+    {
+        &self.hashes
+    }
+
+    pub fn get_flags(&self) -> &Vec<bool>
+    {
+        &self.flags
+    }
+
+    fn calc_height(&self) -> uint
+    {
+        let mut height : uint = 0;
+
+        while self.calc_tree_width(height) > 1
+        {
+            height += 1;
+        }
+
+        height
+    }
+
+    /* Number of nodes at the given height, leaves being at height 0 */
+    fn calc_tree_width(&self, height : uint) -> uint
+    {
+        ((self.tx_count as uint)+(1 << height)-1) >> height
+    }
+
+    fn calc_hash(&self, height : uint, pos : uint, txids : &[Hash]) -> Hash
+    {
+        let left : Hash;
+        let right : Hash;
+
+        if height == 0
+        {
+            return txids[pos].clone();
+        }
+
+        left = self.calc_hash(height-1,pos*2,txids);
+
+        right = if pos*2+1 < self.calc_tree_width(height-1)
+                    { self.calc_hash(height-1,pos*2+1,txids) }
+                else
+                    { left.clone() };
+
+        hash_pair(&left,&right)
+    }
+
+    fn traverse_and_build(&mut self, height : uint, pos : uint,
+                          txids : &[Hash], matches : &[bool])
+    {
+        let mut parent_of_match : bool = false;
+
+        for p in range(pos << height,::std::cmp::min((pos+1) << height,self.tx_count as uint))
+        {
+            parent_of_match |= matches[p];
+        }
+
+        self.flags.push(parent_of_match);
+
+        if height == 0 || !parent_of_match
+        {
+            let hash : Hash = self.calc_hash(height,pos,txids);
+
+            self.hashes.push(hash);
+        }
+        else
+        {
+            self.traverse_and_build(height-1,pos*2,txids,matches);
+
+            if pos*2+1 < self.calc_tree_width(height-1)
+            {
+                self.traverse_and_build(height-1,pos*2+1,txids,matches);
+            }
+        }
+    }
+
+    fn traverse_and_extract(&self, height : uint, pos : uint,
+                            bits_used : &mut uint, hashes_used : &mut uint,
+                            matches : &mut Vec<(Hash, uint)>) -> Result<Hash,MerkleError>
+    {
+        let parent_of_match : bool;
+        let left : Hash;
+        let right : Hash;
+
+        if *bits_used >= self.flags.len()
+        {
+            return Err(MerkleError::MerkleOverflow);
+        }
+
+        parent_of_match = self.flags[*bits_used];
+        *bits_used += 1;
+
+        if height == 0 || !parent_of_match
+        {
+            let hash : Hash;
+
+            if *hashes_used >= self.hashes.len()
+            {
+                return Err(MerkleError::MerkleOverflow);
+            }
+
+            hash = self.hashes[*hashes_used].clone();
+            *hashes_used += 1;
+
+            if height == 0 && parent_of_match
+            {
+                matches.push((hash.clone(), pos));
+            }
+
+            return Ok(hash);
+        }
+
+        left = try!(self.traverse_and_extract(height-1,pos*2,bits_used,hashes_used,matches));
+
+        if pos*2+1 < self.calc_tree_width(height-1)
+        {
+            right = try!(self.traverse_and_extract(height-1,pos*2+1,bits_used,hashes_used,
+                                                   matches));
+
+            /* A right node equal to the left one means the tree was built
+             * with a duplicated tx (CVE-2012-2459)
+             */
+            if right == left
+            {
+                return Err(MerkleError::MerkleDuplicateHash);
+            }
+        }
+        else
+        {
+            right = left.clone();
+        }
+
+        Ok(hash_pair(&left,&right))
+    }
+
+    /* Check the tree is well formed and return its merkle root together with
+     * the matched txids and their positions in the block.
+     */
+    pub fn extract_matches(&self) -> Result<(Hash, Vec<(Hash, uint)>),MerkleError>
+    {
+        let mut matches : Vec<(Hash, uint)> = Vec::new();
+        let mut bits_used : uint = 0;
+        let mut hashes_used : uint = 0;
+        let root : Hash;
+
+        if self.tx_count == 0
+        {
+            return Err(MerkleError::MerkleNoTxs);
+        }
+
+        if self.tx_count > MAX_TXS
+        {
+            return Err(MerkleError::MerkleTooManyTxs);
+        }
+
+        if self.hashes.len() > self.tx_count as uint
+        {
+            return Err(MerkleError::MerkleTooManyHashes);
+        }
+
+        if self.flags.len() < self.hashes.len()
+        {
+            return Err(MerkleError::MerkleNotEnoughFlags);
+        }
+
+        root = try!(self.traverse_and_extract(self.calc_height(),0,&mut bits_used,
+                                              &mut hashes_used,&mut matches));
+
+        /* Flags are sent as bytes, so up to 7 trailing bits can be unused */
+        if (bits_used+7)/8 != (self.flags.len()+7)/8 || hashes_used != self.hashes.len()
+        {
+            return Err(MerkleError::MerkleUnusedData);
+        }
+
+        Ok((root, matches))
+    }
+}
+
+/* Whether the tree proves that the tx is in the block with the given header.
+ */
+#[allow(dead_code)]
+pub fn verify_proof(header : &BlockHeader, tree : &PartialMerkleTree, txid : &Hash) -> bool
+{
+    match tree.extract_matches()
+    {
+        Ok((root, matches)) =>
+            root == *header.get_merkle_root() && matches.iter().any(|&(ref h, _)| h == txid),
+        Err(_) => false
+    }
+}
+
+impl Show for PartialMerkleTree
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}PartialMerkleTree:\n", space));
+        try!(write!(f,"{}  txs:    {}\n", space, self.tx_count));
+        try!(write!(f,"{}  hashes: {}\n", space, self.hashes.len()));
+        write!(f,"{}  flags:  {}", space, self.flags.len())
+    }
+}
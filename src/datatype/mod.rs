@@ -4,3 +4,9 @@ pub mod script;
 pub mod value;
 pub mod transaction;
 pub mod hash;
+pub mod block;
+pub mod uint256;
+pub mod bloom;
+pub mod merkle;
+pub mod blockfilter;
+pub mod compact;
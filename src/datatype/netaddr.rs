@@ -33,11 +33,12 @@ impl NetAddr
     {
         match self.addr
         {
-            Some(SocketAddr { ip: _, port: 0 })                 => false,
-            Some(SocketAddr { ip: Ipv6Addr(..), port: _ })      => false, /* TODO */
-            Some(SocketAddr { ip: Ipv4Addr(0,0,0,0), port: _ }) => false,
-            Some(SocketAddr { ip: Ipv4Addr(..), port: _ })      => true,
-            None                                                => false
+            Some(SocketAddr { ip: _, port: 0 })                               => false,
+            Some(SocketAddr { ip: Ipv6Addr(0,0,0,0,0,0,0,0), port: _ })       => false,
+            Some(SocketAddr { ip: Ipv6Addr(..), port: _ })                    => true,
+            Some(SocketAddr { ip: Ipv4Addr(0,0,0,0), port: _ })               => false,
+            Some(SocketAddr { ip: Ipv4Addr(..), port: _ })                    => true,
+            None                                                              => false
         }
     }
 }
@@ -55,3 +56,285 @@ impl Show for NetAddr
         Ok(())
     }
 }
+
+/* Networks of the addresses of addrv2 messages (BIP155) */
+pub enum NetworkId
+{
+    NetIPv4  = 1,
+    NetIPv6  = 2,
+    NetTorV2 = 3,
+    NetTorV3 = 4,
+    NetI2P   = 5,
+    NetCJDNS = 6
+}
+
+/* Longest address we accept in an addrv2 message */
+pub const MAX_ADDRV2_SIZE : uint = 512;
+
+const ONION_VERSION : u8 = 3;
+const ONION_PUBKEY_SIZE : uint = 32;
+const ONION_CHECKSUM_SIZE : uint = 2;
+const ONION_ENCODED_SIZE : uint = 56;
+
+const BASE32_ALPHABET : &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/* An address of any of the networks of BIP155.  The address bytes are the
+ * ip for IPv4 and IPv6, and the public key for onion services.
+ */
+#[deriving(Clone)]
+pub struct NetAddrV2
+{
+    pub time     : Option<time::Timespec>,
+    pub services : ::config::Services,
+    pub network  : u8,
+    pub addr     : Vec<u8>,
+    pub port     : u16
+}
+
+#[allow(dead_code)]
+impl NetAddrV2
+{
+    pub fn new(time     : Option<time::Timespec>,
+               services : ::config::Services,
+               network  : u8,
+               addr     : Vec<u8>,
+               port     : u16) -> NetAddrV2
+    {
+        NetAddrV2
+        {
+            time:     time,
+            services: services,
+            network:  network,
+            addr:     addr,
+            port:     port
+        }
+    }
+
+    pub fn from_netaddr(netaddr : &NetAddr) -> Option<NetAddrV2>
+    {
+        match netaddr.addr
+        {
+            Some(SocketAddr { ip: Ipv4Addr(b3,b2,b1,b0), port }) =>
+                Some(NetAddrV2::new(netaddr.time,netaddr.services,NetworkId::NetIPv4 as u8,
+                                    vec![b3,b2,b1,b0],port)),
+            Some(SocketAddr { ip: Ipv6Addr(s0,s1,s2,s3,s4,s5,s6,s7), port }) =>
+            {
+                let mut addr : Vec<u8> = Vec::with_capacity(16);
+
+                for s in [s0,s1,s2,s3,s4,s5,s6,s7].iter()
+                {
+                    addr.push((*s >> 8) as u8);
+                    addr.push(*s as u8);
+                }
+
+                Some(NetAddrV2::new(netaddr.time,netaddr.services,NetworkId::NetIPv6 as u8,
+                                    addr,port))
+            },
+            None => None
+        }
+    }
+
+    /* For IPv4 and IPv6 addresses */
+    pub fn to_socket_addr(&self) -> Option<SocketAddr>
+    {
+        let a : &[u8] = self.addr.as_slice();
+        let s = |i : uint| ((a[2*i] as u16) << 8) | (a[2*i+1] as u16);
+
+        if self.network == NetworkId::NetIPv4 as u8 && a.len() == 4
+        {
+            return Some(SocketAddr { ip: Ipv4Addr(a[0],a[1],a[2],a[3]), port: self.port });
+        }
+
+        if self.network == NetworkId::NetIPv6 as u8 && a.len() == 16
+        {
+            return Some(SocketAddr { ip: Ipv6Addr(s(0),s(1),s(2),s(3),s(4),s(5),s(6),s(7)),
+                                     port: self.port });
+        }
+
+        None
+    }
+
+    /* Only the addresses that fit in an addr message */
+    pub fn to_netaddr(&self) -> Option<NetAddr>
+    {
+        self.to_socket_addr().map(|addr| NetAddr::new(self.time,self.services,Some(addr)))
+    }
+
+    /* Whether the address manager keeps it: the networks we may reach, some
+     * day for I2P and CJDNS, and not the obsolete v2 onion services.
+     */
+    pub fn is_valid_addr(&self) -> bool
+    {
+        if self.port == 0 || !self.has_valid_size()
+        {
+            return false;
+        }
+
+        match self.network
+        {
+            1 | 2 => self.to_netaddr().map_or(false,|netaddr| netaddr.is_valid_addr()),
+            4 | 5 => true,
+            6     => self.addr[0] == 0xfc,
+            _     => false
+        }
+    }
+
+    /* Size of the addresses of the network, None for unknown networks */
+    pub fn expected_size(network : u8) -> Option<uint>
+    {
+        match network
+        {
+            1 => Some(4),
+            2 => Some(16),
+            3 => Some(10),
+            4 => Some(ONION_PUBKEY_SIZE),
+            5 => Some(32),
+            6 => Some(16),
+            _ => None
+        }
+    }
+
+    pub fn has_valid_size(&self) -> bool
+    {
+        match NetAddrV2::expected_size(self.network)
+        {
+            Some(size) => self.addr.len() == size,
+            None       => self.addr.len() <= MAX_ADDRV2_SIZE
+        }
+    }
+
+    /* From the hostname of a v3 onion service, i.e. the service id returned
+     * by tor with or without the ".onion" suffix.
+     */
+    pub fn from_onion(host : &str, port : u16, services : ::config::Services,
+                      time : Option<time::Timespec>) -> Option<NetAddrV2>
+    {
+        let id : &str = if host.ends_with(".onion") { host.slice_to(host.len()-6) }
+                        else { host };
+        let decoded : Vec<u8>;
+        let pubkey : Vec<u8>;
+
+        if id.len() != ONION_ENCODED_SIZE
+        {
+            return None;
+        }
+
+        decoded = match base32_decode(id)
+        {
+            Some(decoded) => decoded,
+            None          => return None
+        };
+
+        if decoded[ONION_PUBKEY_SIZE+ONION_CHECKSUM_SIZE] != ONION_VERSION
+        {
+            return None;
+        }
+
+        pubkey = decoded.slice_to(ONION_PUBKEY_SIZE).to_vec();
+
+        if onion_checksum(pubkey.as_slice()).as_slice()
+            != decoded.slice(ONION_PUBKEY_SIZE,ONION_PUBKEY_SIZE+ONION_CHECKSUM_SIZE)
+        {
+            return None;
+        }
+
+        Some(NetAddrV2::new(time,services,NetworkId::NetTorV3 as u8,pubkey,port))
+    }
+
+    /* The hostname, for v3 onion services */
+    pub fn get_onion(&self) -> Option<String>
+    {
+        let mut data : Vec<u8>;
+
+        if self.network != NetworkId::NetTorV3 as u8 || self.addr.len() != ONION_PUBKEY_SIZE
+        {
+            return None;
+        }
+
+        data = self.addr.clone();
+        data.push_all(onion_checksum(self.addr.as_slice()).as_slice());
+        data.push(ONION_VERSION);
+
+        Some(base32_encode(data.as_slice())+".onion")
+    }
+}
+
+/* CHECKSUM = H(".onion checksum" | PUBKEY | VERSION)[:2] */
+fn onion_checksum(pubkey : &[u8]) -> Vec<u8>
+{
+    let mut data : Vec<u8> = ".onion checksum".as_bytes().to_vec();
+
+    data.push_all(pubkey);
+    data.push(ONION_VERSION);
+
+    ::crypto::sha3_256(data.as_slice()).slice_to(ONION_CHECKSUM_SIZE).to_vec()
+}
+
+/* RFC 4648 lowercase base32, without padding */
+fn base32_encode(data : &[u8]) -> String
+{
+    let mut str : String = String::new();
+    let mut acc : uint = 0;
+    let mut bits : uint = 0;
+
+    for b in data.iter()
+    {
+        acc = ((acc << 8) | (*b as uint)) & 0xfff;
+        bits += 8;
+
+        while bits >= 5
+        {
+            bits -= 5;
+            str.push(BASE32_ALPHABET[(acc >> bits) & 0x1f] as char);
+        }
+    }
+
+    if bits > 0
+    {
+        str.push(BASE32_ALPHABET[(acc << (5-bits)) & 0x1f] as char);
+    }
+
+    str
+}
+
+fn base32_decode(str : &str) -> Option<Vec<u8>>
+{
+    let mut data : Vec<u8> = Vec::with_capacity(str.len()*5/8);
+    let mut acc : uint = 0;
+    let mut bits : uint = 0;
+
+    for c in str.bytes()
+    {
+        let lower : u8 = if c >= b'A' && c <= b'Z' { c+32 } else { c };
+        let value : uint = match BASE32_ALPHABET.iter().position(|a| *a == lower)
+        {
+            Some(value) => value,
+            None        => return None
+        };
+
+        acc = ((acc << 5) | value) & 0xfff;
+        bits += 5;
+
+        if bits >= 8
+        {
+            bits -= 8;
+            data.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(data)
+}
+
+impl Show for NetAddrV2
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        match (self.to_netaddr(),self.get_onion())
+        {
+            (Some(netaddr),_) => write!(f, "{}", netaddr),
+            (_,Some(onion))   => write!(f, "{}:{}", onion, self.port),
+            _                 => write!(f, "network {} {}:{}", self.network,
+                                        ::crypto::to_hexstr(self.addr.as_slice()), self.port)
+        }
+    }
+}
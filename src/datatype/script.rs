@@ -1,21 +1,303 @@
 use std::fmt::Show;
 use std::fmt::Formatter;
 
-/* TODO */
-pub struct Script;
+pub const OP_0                   : u8 = 0x00;
+pub const OP_PUSHDATA1           : u8 = 0x4c;
+pub const OP_PUSHDATA2           : u8 = 0x4d;
+pub const OP_PUSHDATA4           : u8 = 0x4e;
+pub const OP_1NEGATE             : u8 = 0x4f;
+pub const OP_1                   : u8 = 0x51;
+pub const OP_16                  : u8 = 0x60;
+pub const OP_NOP                 : u8 = 0x61;
+pub const OP_VERIFY              : u8 = 0x69;
+pub const OP_RETURN              : u8 = 0x6a;
+pub const OP_DROP                : u8 = 0x75;
+pub const OP_DUP                 : u8 = 0x76;
+pub const OP_EQUAL               : u8 = 0x87;
+pub const OP_EQUALVERIFY         : u8 = 0x88;
+pub const OP_SHA256              : u8 = 0xa8;
+pub const OP_HASH160             : u8 = 0xa9;
+pub const OP_HASH256             : u8 = 0xaa;
+pub const OP_CHECKSIG            : u8 = 0xac;
+pub const OP_CHECKSIGVERIFY      : u8 = 0xad;
+pub const OP_CHECKMULTISIG       : u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY : u8 = 0xaf;
 
+pub const OP_TRUE : u8 = OP_1;
+
+pub const MAX_PUBKEYS_PER_MULTISIG : uint = 20;
+
+#[deriving(Clone, PartialEq, Eq)]
+pub struct Script
+{
+    bytes : Vec<u8>
+}
+
+#[allow(dead_code)]
 impl Script
 {
     pub fn new() -> Script
     {
         Script
+        {
+            bytes: Vec::new()
+        }
     }
+
+    pub fn from_bytes(bytes : Vec<u8>) -> Script
+    {
+        Script
+        {
+            bytes: bytes
+        }
+    }
+
+    pub fn get_bytes(&self) -> &Vec<u8>
+    {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> uint
+    {
+        self.bytes.len()
+    }
+
+    pub fn push_opcode(&mut self, opcode : u8)
+    {
+        self.bytes.push(opcode);
+    }
+
+    pub fn push_data(&mut self, data : &[u8])
+    {
+        let len = data.len();
+
+        match len
+        {
+            0             => self.bytes.push(OP_0),
+            1     ... 75  => self.bytes.push(len as u8),
+            76    ... 255 =>
+            {
+                self.bytes.push(OP_PUSHDATA1);
+                self.bytes.push(len as u8);
+            },
+            256 ... 65535 =>
+            {
+                self.bytes.push(OP_PUSHDATA2);
+                self.bytes.push((len&0xff) as u8);
+                self.bytes.push((len>>8) as u8);
+            },
+            _             =>
+            {
+                self.bytes.push(OP_PUSHDATA4);
+
+                for i in range(0u,4)
+                {
+                    self.bytes.push(((len>>8*i)&0xff) as u8);
+                }
+            }
+        }
+
+        self.bytes.push_all(data);
+    }
+
+    /* Push an integer the same way the reference implementation does, i.e.
+     * small integers use the OP_N opcodes and everything else is pushed as a
+     * minimally encoded number.
+     */
+    pub fn push_int(&mut self, v : i64)
+    {
+        match v
+        {
+            -1       => self.bytes.push(OP_1NEGATE),
+            0        => self.bytes.push(OP_0),
+            1 ... 16 => self.bytes.push(OP_1+(v as u8)-1),
+            _        => self.push_data(encode_num(v).as_slice())
+        }
+    }
+
+    /* Read the opcode at pc and the data it pushes, if any, advancing pc.
+     * Returns None at the end of the script or on a truncated push.
+     */
+    pub fn get_op(&self, pc : &mut uint) -> Option<(u8, Vec<u8>)>
+    {
+        let bytes : &Vec<u8> = &self.bytes;
+        let opcode : u8;
+        let mut len : uint = 0;
+        let size : uint;
+
+        if *pc >= bytes.len()
+        {
+            return None;
+        }
+
+        opcode = bytes[*pc];
+        *pc += 1;
+
+        size = match opcode
+        {
+            OP_PUSHDATA1 => 1,
+            OP_PUSHDATA2 => 2,
+            OP_PUSHDATA4 => 4,
+            0x01 ... 0x4b =>
+            {
+                len = opcode as uint;
+                0
+            },
+            _ => return Some((opcode, Vec::new()))
+        };
+
+        if *pc+size > bytes.len()
+        {
+            return None;
+        }
+
+        for i in range(0,size)
+        {
+            len |= (bytes[*pc+i] as uint) << 8*i;
+        }
+
+        *pc += size;
+
+        if *pc+len > bytes.len()
+        {
+            return None;
+        }
+
+        *pc += len;
+
+        Some((opcode, bytes.slice(*pc-len,*pc).to_vec()))
+    }
+
+    /* All the data pushed by the script, stopping at the first bad push */
+    pub fn get_pushes(&self) -> Vec<Vec<u8>>
+    {
+        let mut pushes : Vec<Vec<u8>> = Vec::new();
+        let mut pc : uint = 0;
+
+        loop
+        {
+            match self.get_op(&mut pc)
+            {
+                Some((_, data)) => if data.len() > 0 { pushes.push(data) },
+                None            => break
+            }
+        }
+
+        pushes
+    }
+
+    /* Signature operations, counted the legacy way: a multisig always counts
+     * as the most keys it can have.  Stops at the first bad push.
+     */
+    pub fn get_sigop_count(&self) -> uint
+    {
+        let mut count : uint = 0;
+        let mut pc : uint = 0;
+
+        loop
+        {
+            match self.get_op(&mut pc)
+            {
+                Some((OP_CHECKSIG, _))            => count += 1,
+                Some((OP_CHECKSIGVERIFY, _))      => count += 1,
+                Some((OP_CHECKMULTISIG, _))       => count += MAX_PUBKEYS_PER_MULTISIG,
+                Some((OP_CHECKMULTISIGVERIFY, _)) => count += MAX_PUBKEYS_PER_MULTISIG,
+                Some(_)                           => (),
+                None                              => break
+            }
+        }
+
+        count
+    }
+
+    /* <pubkey> OP_CHECKSIG */
+    pub fn is_pay_to_pubkey(&self) -> bool
+    {
+        let len : uint = self.bytes.len();
+
+        (len == 35 && self.bytes[0] == 33 || len == 67 && self.bytes[0] == 65)
+            && self.bytes[len-1] == OP_CHECKSIG
+    }
+
+    /* OP_m <pubkey>... OP_n OP_CHECKMULTISIG */
+    pub fn is_multisig(&self) -> bool
+    {
+        let mut opcodes : Vec<u8> = Vec::new();
+        let mut pushes : Vec<Vec<u8>> = Vec::new();
+        let mut pc : uint = 0;
+        let len : uint;
+        let m : u8;
+        let n : u8;
+
+        loop
+        {
+            match self.get_op(&mut pc)
+            {
+                Some((opcode, data)) =>
+                {
+                    opcodes.push(opcode);
+                    pushes.push(data);
+                },
+                None => break
+            }
+        }
+
+        len = opcodes.len();
+
+        if pc != self.bytes.len() || len < 4
+        {
+            return false;
+        }
+
+        m = opcodes[0];
+        n = opcodes[len-2];
+
+        if m < OP_1 || m > OP_16 || n < m || n > OP_16 ||
+           opcodes[len-1] != OP_CHECKMULTISIG ||
+           (n-OP_1+1) as uint != len-3
+        {
+            return false;
+        }
+
+        pushes.slice(1,len-2).iter().all(|p| p.len() == 33 || p.len() == 65)
+    }
+}
+
+/* Minimal little endian encoding with a sign bit, as used by script numbers.
+ */
+pub fn encode_num(v : i64) -> Vec<u8>
+{
+    let mut r : Vec<u8> = Vec::new();
+    let negative : bool = v < 0;
+    let mut abs : u64 = if negative { -v as u64 } else { v as u64 };
+
+    while abs > 0
+    {
+        r.push((abs&0xff) as u8);
+        abs >>= 8;
+    }
+
+    if r.len() > 0
+    {
+        if r[r.len()-1]&0x80 != 0
+        {
+            r.push(if negative { 0x80 } else { 0x00 });
+        }
+        else if negative
+        {
+            let last = r.len()-1;
+
+            r[last] |= 0x80;
+        }
+    }
+
+    r
 }
 
 impl Show for Script
 {
-    fn fmt(&self, _f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
     {
-        Ok(()) // XXX TODO
+        write!(f,"{}",::crypto::to_hexstr(self.bytes.as_slice()))
     }
 }
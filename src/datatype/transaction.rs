@@ -7,6 +7,7 @@ use datatype::value::Value;
 use datatype::script::Script;
 use datatype::hash::Hash;
 
+#[deriving(Clone, PartialEq, Eq, Hash)]
 pub struct OutPoint
 {
     hash  : Hash,
@@ -34,6 +35,12 @@ impl OutPoint
     {
         self.index
     }
+
+    /* The previous output of a coinbase input */
+    pub fn is_null(&self) -> bool
+    {
+        self.hash.is_zero() && self.index == 0xffffffff
+    }
 }
 
 impl Show for OutPoint
@@ -47,6 +54,7 @@ impl Show for OutPoint
     }
 }
 
+#[deriving(Clone)]
 pub struct TxOut
 {
     value  : Value,
@@ -90,6 +98,7 @@ impl Show for TxOut
     }
 }
 
+#[deriving(Clone)]
 pub struct TxIn
 {
     prev_out   : OutPoint,
@@ -143,7 +152,7 @@ impl Show for TxIn
     }
 }
 
-#[deriving(Show)]
+#[deriving(Show, Clone)]
 pub enum TxLock
 {
     LockLocked,               /* Always locked */
@@ -170,6 +179,7 @@ impl TxLock
 
 /* TODO impl Show for TemporalLock */
 
+#[deriving(Clone)]
 pub struct Transaction
 {
     version : u32,
@@ -197,7 +207,35 @@ impl Transaction
 
     pub fn get_hash(&self) -> Hash
     {
-        Hash::new([0u8, ..32]) // TODO
+        Hash::from_digest(::crypto::dsha256(self.serialize().as_slice()))
+    }
+
+    /* The hash including the witness data (BIP141).  We do not support
+     * segregated witness, so our txs have no witness and it is the txid.
+     */
+    pub fn get_witness_hash(&self) -> Hash
+    {
+        self.get_hash()
+    }
+
+    pub fn serialize(&self) -> Vec<u8>
+    {
+        let mut marshalling = ::marshalling::Marshalling::new();
+
+        marshalling.write_transaction(self);
+
+        marshalling.get()
+    }
+
+    /* Size in bytes of the serialized transaction */
+    pub fn get_size(&self) -> uint
+    {
+        self.serialize().len()
+    }
+
+    pub fn is_coinbase(&self) -> bool
+    {
+        self.txs_in.len() == 1 && self.txs_in[0].get_prev_out().is_null()
     }
 
     pub fn get_version(&self) -> u32
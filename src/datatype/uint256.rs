@@ -0,0 +1,241 @@
+use std::cmp::Ordering;
+
+use datatype::hash::Hash;
+
+/* Unsigned 256 bit integer.  This only implements what we need to deal with
+ * proof of work targets.
+ */
+#[deriving(Clone, PartialEq, Eq)]
+pub struct Uint256
+{
+    limbs : [u32, ..8] /* Least significant limb first */
+}
+
+#[allow(dead_code)]
+impl Uint256
+{
+    pub fn zero() -> Uint256
+    {
+        Uint256 { limbs: [0u32, ..8] }
+    }
+
+    pub fn from_u64(v : u64) -> Uint256
+    {
+        let mut r = Uint256::zero();
+
+        r.limbs[0] = (v&0xffffffff) as u32;
+        r.limbs[1] = (v>>32) as u32;
+
+        r
+    }
+
+    /* Interpret the hash as a (big endian) number, the way the reference
+     * implementation does when comparing it against the target.
+     */
+    pub fn from_hash(hash : &Hash) -> Uint256
+    {
+        let mut r = Uint256::zero();
+
+        for i in range(0u,32)
+        {
+            r.limbs[(31-i)/4] |= (hash[i] as u32) << 8*((31-i)%4);
+        }
+
+        r
+    }
+
+    /* Decode a target from the compact representation.  Returns None if the
+     * compact value is negative or overflows 256 bits.
+     */
+    pub fn from_compact(compact : u32) -> Option<Uint256>
+    {
+        let size : uint = (compact>>24) as uint;
+        let mut mantissa : u32 = compact&0x007fffff;
+        let negative : bool = compact&0x00800000 != 0;
+        let r : Uint256;
+
+        if size <= 3
+        {
+            mantissa >>= 8*(3-size);
+            r = Uint256::from_u64(mantissa as u64);
+        }
+        else
+        {
+            r = Uint256::from_u64(mantissa as u64).shl(8*(size-3));
+        }
+
+        if negative && mantissa != 0
+        {
+            return None;
+        }
+
+        if mantissa != 0 && (size > 34
+                             || (mantissa > 0xff && size > 33)
+                             || (mantissa > 0xffff && size > 32))
+        {
+            return None;
+        }
+
+        Some(r)
+    }
+
+    pub fn to_compact(&self) -> u32
+    {
+        let mut size : uint = (self.bits()+7)/8;
+        let mut compact : u32;
+
+        if size <= 3
+        {
+            compact = (self.low_u64() << 8*(3-size)) as u32;
+        }
+        else
+        {
+            compact = self.shr(8*(size-3)).low_u64() as u32;
+        }
+
+        /* The 0x00800000 bit denotes the sign, so if it is already set we
+         * divide the mantissa by 256 and increase the exponent.
+         */
+        if compact&0x00800000 != 0
+        {
+            compact >>= 8;
+            size += 1;
+        }
+
+        compact | (size as u32) << 24
+    }
+
+    pub fn low_u64(&self) -> u64
+    {
+        (self.limbs[0] as u64) | (self.limbs[1] as u64) << 32
+    }
+
+    pub fn is_zero(&self) -> bool
+    {
+        self.limbs.iter().all(|l| *l == 0)
+    }
+
+    /* Number of significant bits */
+    pub fn bits(&self) -> uint
+    {
+        for i in range(0u,8).rev()
+        {
+            if self.limbs[i] != 0
+            {
+                for b in range(0u,32).rev()
+                {
+                    if self.limbs[i] & (1<<b) != 0
+                    {
+                        return 32*i+b+1;
+                    }
+                }
+            }
+        }
+
+        0
+    }
+
+    pub fn shl(&self, shift : uint) -> Uint256
+    {
+        let mut r = Uint256::zero();
+        let limb_shift = shift/32;
+        let bit_shift = shift%32;
+
+        for i in range(0u,8)
+        {
+            if i+limb_shift+1 < 8 && bit_shift != 0
+            {
+                r.limbs[i+limb_shift+1] |= self.limbs[i] >> (32-bit_shift);
+            }
+
+            if i+limb_shift < 8
+            {
+                r.limbs[i+limb_shift] |= self.limbs[i] << bit_shift;
+            }
+        }
+
+        r
+    }
+
+    pub fn shr(&self, shift : uint) -> Uint256
+    {
+        let mut r = Uint256::zero();
+        let limb_shift = shift/32;
+        let bit_shift = shift%32;
+
+        for i in range(0u,8)
+        {
+            if i >= limb_shift+1 && bit_shift != 0
+            {
+                r.limbs[i-limb_shift-1] |= self.limbs[i] << (32-bit_shift);
+            }
+
+            if i >= limb_shift
+            {
+                r.limbs[i-limb_shift] |= self.limbs[i] >> bit_shift;
+            }
+        }
+
+        r
+    }
+
+    /* Multiplication by a small number.  Overflow is discarded. */
+    pub fn mul_u32(&self, v : u32) -> Uint256
+    {
+        let mut r = Uint256::zero();
+        let mut carry : u64 = 0;
+
+        for i in range(0u,8)
+        {
+            let n : u64 = (self.limbs[i] as u64)*(v as u64)+carry;
+
+            r.limbs[i] = (n&0xffffffff) as u32;
+            carry = n>>32;
+        }
+
+        r
+    }
+
+    /* Division by a small number */
+    pub fn div_u32(&self, v : u32) -> Uint256
+    {
+        let mut r = Uint256::zero();
+        let mut rem : u64 = 0;
+
+        assert!(v != 0);
+
+        for i in range(0u,8).rev()
+        {
+            let n : u64 = (rem<<32) | (self.limbs[i] as u64);
+
+            r.limbs[i] = (n/(v as u64)) as u32;
+            rem = n%(v as u64);
+        }
+
+        r
+    }
+}
+
+impl PartialOrd for Uint256
+{
+    fn partial_cmp(&self, other : &Uint256) -> Option<Ordering>
+    {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Uint256
+{
+    fn cmp(&self, other : &Uint256) -> Ordering
+    {
+        for i in range(0u,8).rev()
+        {
+            if self.limbs[i] != other.limbs[i]
+            {
+                return self.limbs[i].cmp(&other.limbs[i]);
+            }
+        }
+
+        Ordering::Equal
+    }
+}
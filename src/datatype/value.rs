@@ -5,11 +5,41 @@ use std::fmt::Formatter;
  * creating nasty bugs, because type system.
  */
 #[allow(dead_code)]
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Value
 {
     Satoshi(u64)
 }
 
+#[allow(dead_code)]
+impl Value
+{
+    pub fn zero() -> Value
+    {
+        Value::Satoshi(0)
+    }
+
+    pub fn get_satoshis(&self) -> u64
+    {
+        match *self
+        {
+            Value::Satoshi(v) => v
+        }
+    }
+
+    /* Returns None on overflow */
+    pub fn checked_add(&self, other : &Value) -> Option<Value>
+    {
+        self.get_satoshis().checked_add(other.get_satoshis()).map(Value::Satoshi)
+    }
+
+    /* Returns None on underflow */
+    pub fn checked_sub(&self, other : &Value) -> Option<Value>
+    {
+        self.get_satoshis().checked_sub(other.get_satoshis()).map(Value::Satoshi)
+    }
+}
+
 impl Show for Value
 {
     fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
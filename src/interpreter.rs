@@ -0,0 +1,648 @@
+use datatype::script::Script;
+use datatype::script::{OP_0, OP_PUSHDATA1, OP_PUSHDATA2, OP_PUSHDATA4,
+                       OP_1NEGATE, OP_1, OP_16, OP_NOP, OP_VERIFY, OP_RETURN,
+                       OP_DROP, OP_DUP, OP_EQUAL, OP_EQUALVERIFY, OP_SHA256,
+                       OP_HASH160, OP_HASH256, OP_CHECKSIG, OP_CHECKSIGVERIFY,
+                       OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY};
+use datatype::script::MAX_PUBKEYS_PER_MULTISIG;
+use datatype::transaction::Transaction;
+use datatype::transaction::TxIn;
+use datatype::transaction::TxOut;
+use datatype::value::Value;
+
+use marshalling::Marshalling;
+
+use secp256k1::Point;
+
+const MAX_SCRIPT_SIZE : uint = 10000;
+const MAX_STACK_SIZE : uint = 1000;
+const MAX_ELEMENT_SIZE : uint = 520;
+const MAX_NUM_SIZE : uint = 4;
+
+/* Signature hash types, the last byte of a signature */
+#[cfg(test)]
+pub const SIGHASH_ALL : u8 = 0x01;
+const SIGHASH_NONE : u8 = 0x02;
+const SIGHASH_SINGLE : u8 = 0x03;
+const SIGHASH_ANYONECANPAY : u8 = 0x80;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum ScriptError
+{
+    ScriptTooBig,
+    ScriptBadPush,
+    ScriptStackOverflow,
+    ScriptStackUnderflow,
+    ScriptPushTooBig,
+    ScriptOpReturn,
+    ScriptVerifyFailed,
+    ScriptEvalFalse,
+    ScriptUnknownOpcode(u8),
+    ScriptBadNumber,
+    ScriptPubkeyCount,
+    ScriptSigCount
+}
+
+type Stack = Vec<Vec<u8>>;
+
+fn cast_to_bool(v : &Vec<u8>) -> bool
+{
+    for i in range(0,v.len())
+    {
+        if v[i] != 0
+        {
+            /* Negative zero is still zero */
+            return !(i == v.len()-1 && v[i] == 0x80);
+        }
+    }
+
+    false
+}
+
+/* Script numbers are little endian with a sign bit, at most 4 bytes long */
+fn decode_num(v : &Vec<u8>) -> Result<i64,ScriptError>
+{
+    let mut r : i64 = 0;
+    let last : uint;
+
+    if v.len() > MAX_NUM_SIZE
+    {
+        return Err(ScriptError::ScriptBadNumber);
+    }
+
+    if v.is_empty()
+    {
+        return Ok(0);
+    }
+
+    for i in range(0,v.len())
+    {
+        r |= (v[i] as i64) << 8*i;
+    }
+
+    last = v.len()-1;
+
+    if v[last] & 0x80 != 0
+    {
+        Ok(-(r & !(0x80i64 << 8*last)))
+    }
+    else
+    {
+        Ok(r)
+    }
+}
+
+fn pop(stack : &mut Stack) -> Result<Vec<u8>,ScriptError>
+{
+    match stack.pop()
+    {
+        Some(v) => Ok(v),
+        None    => Err(ScriptError::ScriptStackUnderflow)
+    }
+}
+
+fn push(stack : &mut Stack, v : Vec<u8>) -> Result<(),ScriptError>
+{
+    if v.len() > MAX_ELEMENT_SIZE
+    {
+        return Err(ScriptError::ScriptPushTooBig);
+    }
+
+    stack.push(v);
+
+    if stack.len() > MAX_STACK_SIZE
+    {
+        return Err(ScriptError::ScriptStackOverflow);
+    }
+
+    Ok(())
+}
+
+/* Read the little endian length that follows a OP_PUSHDATAn opcode.
+ */
+fn read_push_len(bytes : &Vec<u8>, pc : &mut uint, size : uint)
+                 -> Result<uint,ScriptError>
+{
+    let mut len : uint = 0;
+
+    if *pc+size > bytes.len()
+    {
+        return Err(ScriptError::ScriptBadPush);
+    }
+
+    for i in range(0,size)
+    {
+        len |= (bytes[*pc+i] as uint) << 8*i;
+    }
+
+    *pc += size;
+
+    Ok(len)
+}
+
+/* Read a DER integer at pos, returning where its value starts and its length.
+ */
+fn read_der_integer(der : &[u8], pos : &mut uint) -> Option<(uint, uint)>
+{
+    let mut lenbyte : uint;
+    let mut len : uint = 0;
+    let start : uint;
+
+    if *pos == der.len() || der[*pos] != 0x02
+    {
+        return None;
+    }
+
+    *pos += 1;
+
+    if *pos == der.len()
+    {
+        return None;
+    }
+
+    lenbyte = der[*pos] as uint;
+    *pos += 1;
+
+    if lenbyte & 0x80 != 0
+    {
+        lenbyte -= 0x80;
+
+        if lenbyte > der.len()-*pos
+        {
+            return None;
+        }
+
+        while lenbyte > 0 && der[*pos] == 0
+        {
+            *pos += 1;
+            lenbyte -= 1;
+        }
+
+        if lenbyte >= 4
+        {
+            return None;
+        }
+
+        while lenbyte > 0
+        {
+            len = (len << 8)+(der[*pos] as uint);
+            *pos += 1;
+            lenbyte -= 1;
+        }
+    }
+    else
+    {
+        len = lenbyte;
+    }
+
+    if len > der.len()-*pos
+    {
+        return None;
+    }
+
+    start = *pos;
+    *pos += len;
+
+    Some((start, len))
+}
+
+/* Right aligned in 32 bytes, without the leading zeroes */
+fn der_integer_bytes(integer : &[u8]) -> Option<[u8, ..32]>
+{
+    let mut bytes : [u8, ..32] = [0u8, ..32];
+    let mut start : uint = 0;
+    let len : uint;
+
+    while start < integer.len() && integer[start] == 0
+    {
+        start += 1;
+    }
+
+    len = integer.len()-start;
+
+    if len > 32
+    {
+        return None;
+    }
+
+    for i in range(0,len)
+    {
+        bytes[32-len+i] = integer[start+i];
+    }
+
+    Some(bytes)
+}
+
+/* The lax DER parser of the reference implementation, which also accepts
+ * the badly encoded signatures of before BIP66.  Returns r and s, or None
+ * if the signature cannot be valid.
+ */
+fn parse_der_lax(der : &[u8]) -> Option<([u8, ..32], [u8, ..32])>
+{
+    let mut pos : uint = 0;
+    let mut lenbyte : uint;
+    let rpos : uint;
+    let rlen : uint;
+    let spos : uint;
+    let slen : uint;
+
+    if der.len() == 0 || der[0] != 0x30
+    {
+        return None;
+    }
+
+    pos += 1;
+
+    if pos == der.len()
+    {
+        return None;
+    }
+
+    /* The length of the sequence is skipped */
+    lenbyte = der[pos] as uint;
+    pos += 1;
+
+    if lenbyte & 0x80 != 0
+    {
+        lenbyte -= 0x80;
+
+        if lenbyte > der.len()-pos
+        {
+            return None;
+        }
+
+        pos += lenbyte;
+    }
+
+    match read_der_integer(der,&mut pos)
+    {
+        Some((start, len)) => { rpos = start; rlen = len; },
+        None               => return None
+    }
+
+    match read_der_integer(der,&mut pos)
+    {
+        Some((start, len)) => { spos = start; slen = len; },
+        None               => return None
+    }
+
+    match (der_integer_bytes(der.slice(rpos,rpos+rlen)),
+           der_integer_bytes(der.slice(spos,spos+slen)))
+    {
+        (Some(r), Some(s)) => Some((r, s)),
+        _                  => None
+    }
+}
+
+/* The hash a signature commits to, with the legacy (non segwit) scheme.
+ * script_code is the script being executed, without the signatures.
+ */
+pub fn signature_hash(script_code : &Script,
+                      tx          : &Transaction,
+                      index       : uint,
+                      hash_type   : u8) -> [u8, ..32]
+{
+    let mut ins : Vec<TxIn> = Vec::new();
+    let mut outs : Vec<TxOut> = Vec::new();
+    let mut marshalling : Marshalling = Marshalling::new();
+    let base : u8 = hash_type & 0x1f;
+
+    /* A bug of the reference implementation, the number one is signed */
+    if base == SIGHASH_SINGLE && index >= tx.get_out_txs().len()
+    {
+        let mut one : [u8, ..32] = [0u8, ..32];
+
+        one[0] = 1;
+
+        return one;
+    }
+
+    for (i, in_tx) in tx.get_in_txs().iter().enumerate()
+    {
+        if i == index
+        {
+            ins.push(TxIn::new(in_tx.get_prev_out().clone(),script_code.clone(),
+                               in_tx.get_sequence()));
+        }
+        else if hash_type & SIGHASH_ANYONECANPAY == 0
+        {
+            ins.push(TxIn::new(in_tx.get_prev_out().clone(),Script::new(),
+                               if base == SIGHASH_NONE || base == SIGHASH_SINGLE { 0 }
+                               else { in_tx.get_sequence() }));
+        }
+    }
+
+    match base
+    {
+        SIGHASH_NONE   => (),
+        SIGHASH_SINGLE =>
+        {
+            for _ in range(0,index)
+            {
+                outs.push(TxOut::new(Value::Satoshi(0xffffffffffffffff),Script::new()));
+            }
+
+            outs.push(tx.get_out_txs()[index].clone());
+        },
+        _              => outs = tx.get_out_txs().clone()
+    }
+
+    marshalling.write_transaction(&Transaction::new(tx.get_version(),ins,outs,tx.get_lock()));
+    marshalling.write_uint32(hash_type as u32);
+
+    ::crypto::dsha256(marshalling.get().as_slice())
+}
+
+/* The script without any push of the data, like FindAndDelete() of the
+ * reference implementation does for signatures.
+ */
+fn find_and_delete(script : &Script, data : &Vec<u8>) -> Script
+{
+    let bytes : &Vec<u8> = script.get_bytes();
+    let mut pattern : Script = Script::new();
+    let mut result : Vec<u8> = Vec::new();
+    let mut pc : uint = 0;
+    let mut start : uint = 0;
+
+    pattern.push_data(data.as_slice());
+
+    loop
+    {
+        result.push_all(bytes.slice(start,pc));
+
+        while bytes.len()-pc >= pattern.len() &&
+              bytes.slice(pc,pc+pattern.len()) == pattern.get_bytes().as_slice()
+        {
+            pc += pattern.len();
+        }
+
+        start = pc;
+
+        if script.get_op(&mut pc).is_none()
+        {
+            break;
+        }
+    }
+
+    result.push_all(bytes.slice(start,bytes.len()));
+
+    Script::from_bytes(result)
+}
+
+/* A signature with its hash type byte appended */
+fn check_sig(sig         : &Vec<u8>,
+             pubkey      : &Vec<u8>,
+             script_code : &Script,
+             tx          : &Transaction,
+             index       : uint) -> bool
+{
+    let hash_type : u8;
+    let point : Point;
+
+    if sig.is_empty()
+    {
+        return false;
+    }
+
+    hash_type = sig[sig.len()-1];
+
+    point = match Point::from_bytes(pubkey.as_slice())
+    {
+        Some(point) => point,
+        None        => return false
+    };
+
+    match parse_der_lax(sig.slice_to(sig.len()-1))
+    {
+        Some((r, s)) => ::secp256k1::ecdsa_verify(&point,&r,&s,
+                                                  &signature_hash(script_code,tx,index,
+                                                                  hash_type)),
+        None         => false
+    }
+}
+
+/* Pops the keys, the signatures and the extra element that an off by one of
+ * the reference implementation consumes.  The signatures must be in the
+ * order of their keys.
+ */
+fn check_multisig(script : &Script,
+                  stack  : &mut Stack,
+                  tx     : &Transaction,
+                  index  : uint) -> Result<bool,ScriptError>
+{
+    let mut pubkeys : Vec<Vec<u8>> = Vec::new();
+    let mut sigs : Vec<Vec<u8>> = Vec::new();
+    let mut script_code : Script = script.clone();
+    let mut ok : bool = true;
+    let mut k : uint = 0;
+    let mut s : uint = 0;
+    let n_keys : i64;
+    let n_sigs : i64;
+
+    n_keys = try!(decode_num(&try!(pop(stack))));
+
+    if n_keys < 0 || n_keys > MAX_PUBKEYS_PER_MULTISIG as i64
+    {
+        return Err(ScriptError::ScriptPubkeyCount);
+    }
+
+    for _ in range(0,n_keys)
+    {
+        pubkeys.push(try!(pop(stack)));
+    }
+
+    n_sigs = try!(decode_num(&try!(pop(stack))));
+
+    if n_sigs < 0 || n_sigs > n_keys
+    {
+        return Err(ScriptError::ScriptSigCount);
+    }
+
+    for _ in range(0,n_sigs)
+    {
+        sigs.push(try!(pop(stack)));
+    }
+
+    try!(pop(stack));
+
+    for sig in sigs.iter()
+    {
+        script_code = find_and_delete(&script_code,sig);
+    }
+
+    while ok && s < sigs.len()
+    {
+        if check_sig(&sigs[s],&pubkeys[k],&script_code,tx,index)
+        {
+            s += 1;
+        }
+
+        k += 1;
+
+        /* Not enough keys left for the signatures left */
+        if sigs.len()-s > pubkeys.len()-k
+        {
+            ok = false;
+        }
+    }
+
+    Ok(ok)
+}
+
+fn eval(script : &Script,
+        stack  : &mut Stack,
+        tx     : &Transaction,
+        index  : uint) -> Result<(),ScriptError>
+{
+    let bytes : &Vec<u8> = script.get_bytes();
+    let mut pc : uint = 0;
+
+    if bytes.len() > MAX_SCRIPT_SIZE
+    {
+        return Err(ScriptError::ScriptTooBig);
+    }
+
+    while pc < bytes.len()
+    {
+        let opcode : u8 = bytes[pc];
+
+        pc += 1;
+
+        match opcode
+        {
+            OP_0 => try!(push(stack,Vec::new())),
+            0x01 ... 0x4b | OP_PUSHDATA1 | OP_PUSHDATA2 | OP_PUSHDATA4 =>
+            {
+                let len : uint = match opcode
+                {
+                    OP_PUSHDATA1 => try!(read_push_len(bytes,&mut pc,1)),
+                    OP_PUSHDATA2 => try!(read_push_len(bytes,&mut pc,2)),
+                    OP_PUSHDATA4 => try!(read_push_len(bytes,&mut pc,4)),
+                    _            => opcode as uint
+                };
+
+                if pc+len > bytes.len()
+                {
+                    return Err(ScriptError::ScriptBadPush);
+                }
+
+                try!(push(stack,bytes.slice(pc,pc+len).to_vec()));
+
+                pc += len;
+            },
+            OP_1NEGATE => try!(push(stack,vec![0x81u8])),
+            OP_1 ... OP_16 => try!(push(stack,vec![opcode-OP_1+1])),
+            OP_NOP => (),
+            OP_VERIFY =>
+            {
+                if !cast_to_bool(&try!(pop(stack)))
+                {
+                    return Err(ScriptError::ScriptVerifyFailed);
+                }
+            },
+            OP_RETURN => return Err(ScriptError::ScriptOpReturn),
+            OP_DROP =>
+            {
+                try!(pop(stack));
+            },
+            OP_DUP =>
+            {
+                let top : Vec<u8> = try!(pop(stack));
+
+                try!(push(stack,top.clone()));
+                try!(push(stack,top));
+            },
+            OP_EQUAL | OP_EQUALVERIFY =>
+            {
+                let a : Vec<u8> = try!(pop(stack));
+                let b : Vec<u8> = try!(pop(stack));
+
+                if opcode == OP_EQUALVERIFY
+                {
+                    if a != b
+                    {
+                        return Err(ScriptError::ScriptVerifyFailed);
+                    }
+                }
+                else
+                {
+                    try!(push(stack,if a == b { vec![1u8] } else { Vec::new() }));
+                }
+            },
+            OP_SHA256 =>
+            {
+                let v : Vec<u8> = try!(pop(stack));
+
+                try!(push(stack,::crypto::sha256(v.as_slice()).to_vec()));
+            },
+            OP_HASH160 =>
+            {
+                let v : Vec<u8> = try!(pop(stack));
+
+                try!(push(stack,::crypto::hash160(v.as_slice()).to_vec()));
+            },
+            OP_HASH256 =>
+            {
+                let v : Vec<u8> = try!(pop(stack));
+
+                try!(push(stack,::crypto::dsha256(v.as_slice()).to_vec()));
+            },
+            OP_CHECKSIG | OP_CHECKSIGVERIFY | OP_CHECKMULTISIG
+                | OP_CHECKMULTISIGVERIFY =>
+            {
+                let ok : bool;
+
+                if opcode == OP_CHECKSIG || opcode == OP_CHECKSIGVERIFY
+                {
+                    let pubkey : Vec<u8> = try!(pop(stack));
+                    let sig : Vec<u8> = try!(pop(stack));
+
+                    ok = check_sig(&sig,&pubkey,&find_and_delete(script,&sig),tx,index);
+                }
+                else
+                {
+                    ok = try!(check_multisig(script,stack,tx,index));
+                }
+
+                if opcode == OP_CHECKSIGVERIFY || opcode == OP_CHECKMULTISIGVERIFY
+                {
+                    if !ok
+                    {
+                        return Err(ScriptError::ScriptVerifyFailed);
+                    }
+                }
+                else
+                {
+                    try!(push(stack,if ok { vec![1u8] } else { Vec::new() }));
+                }
+            },
+            _ => return Err(ScriptError::ScriptUnknownOpcode(opcode))
+        }
+    }
+
+    Ok(())
+}
+
+/* Check that the signature script of the input at index of tx satisfies the
+ * script of the output it is spending.
+ *
+ * TODO: P2SH, and the flow control, stack, arithmetic and locktime opcodes.
+ *       Until then only the mempool runs scripts, blocks are connected
+ *       without them.
+ */
+pub fn verify_script(script_sig    : &Script,
+                     script_pubkey : &Script,
+                     tx            : &Transaction,
+                     index         : uint) -> Result<(),ScriptError>
+{
+    let mut stack : Stack = Vec::new();
+
+    try!(eval(script_sig,&mut stack,tx,index));
+    try!(eval(script_pubkey,&mut stack,tx,index));
+
+    match stack.last()
+    {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _                              => Err(ScriptError::ScriptEvalFalse)
+    }
+}
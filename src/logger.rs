@@ -1,238 +1,846 @@
 extern crate time;
+extern crate serialize;
 
 use std::io::net::ip::SocketAddr;
+use std::io::net::ip::IpAddr;
+use std::io::File;
+use std::io::IoResult;
+use std::io::Append;
+use std::io::Write;
+
+use std::collections::TreeMap;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use std::num::from_str_radix;
 
 use message::Message;
 
+use datatype::hash::Hash;
+
 use std::time::duration::Duration;
 use self::time::Timespec;
 
+use self::serialize::json::Json;
+use self::serialize::json::ToJson;
+
 enum LogFlag
 {
-    LogFlagPeerError  = 1 <<  0,
-    LogFlagMsgVersion = 1 <<  1,
-    LogFlagMsgVerAck  = 1 <<  2,
-    LogFlagMsgPing    = 1 <<  3,
-    LogFlagMsgPong    = 1 <<  4,
-    LogFlagMsgAddr    = 1 <<  5,
-    LogFlagMsgInv     = 1 <<  6,
-    LogFlagMsgGetData = 1 <<  7,
-    LogFlagMsgReject  = 1 <<  8,
-    LogFlagMsgTx      = 1 <<  9,
-    LogFlagMsgGetAddr = 1 << 10,
-    LogFlagLag        = 1 << 11,
-    LogFlagAddrMng    = 1 << 12
-}
-
-const LOG_FLAGS : u64 =
-    0
-    | LogFlag::LogFlagPeerError as u64
-//    | LogFlag::LogFlagMsgVersion as u64
-//    | LogFlag::LogFlagMsgVerAck as u64
-//    | LogFlag::LogFlagMsgPing as u64
-//    | LogFlag::LogFlagMsgPong as u64
-    | LogFlag::LogFlagMsgAddr as u64
-//    | LogFlag::LogFlagMsgInv as u64
-//    | LogFlag::LogFlagMsgGetData as u64
-    | LogFlag::LogFlagMsgReject as u64
-//    | LogFlag::LogFlagMsgTx as u64
-    | LogFlag::LogFlagMsgGetAddr as u64
-//    | LogFlag::LogFlagLag as u64
-    | LogFlag::LogFlagAddrMng as u64
-    ;
+    LogFlagPeerError      = 1 <<  0,
+    LogFlagMsgVersion     = 1 <<  1,
+    LogFlagMsgVerAck      = 1 <<  2,
+    LogFlagMsgPing        = 1 <<  3,
+    LogFlagMsgPong        = 1 <<  4,
+    LogFlagMsgAddr        = 1 <<  5,
+    LogFlagMsgInv         = 1 <<  6,
+    LogFlagMsgGetData     = 1 <<  7,
+    LogFlagMsgReject      = 1 <<  8,
+    LogFlagMsgTx          = 1 <<  9,
+    LogFlagMsgGetAddr     = 1 << 10,
+    LogFlagLag            = 1 << 11,
+    LogFlagAddrMng        = 1 << 12,
+    LogFlagMsgBlock       = 1 << 13,
+    LogFlagChain          = 1 << 14,
+    LogFlagMempool        = 1 << 15,
+    LogFlagMsgNotFound    = 1 << 16,
+    LogFlagMsgMemPool     = 1 << 17,
+    LogFlagMsgFilter      = 1 << 18,
+    LogFlagMsgMerkleBlock = 1 << 19,
+    LogFlagMsgCFilter     = 1 << 20,
+    LogFlagMsgCmpct       = 1 << 21,
+    LogFlagMsgHeaders     = 1 << 22,
+    LogFlagMsgFeeFilter   = 1 << 23,
+    LogFlagMsgWtxidRelay  = 1 << 24,
+    LogFlagTransport      = 1 << 25,
+    LogFlagTor            = 1 << 26
+}
 
-fn msg_to_command(msg : &Message) -> &str
+/* Names of the categories, for the configuration */
+static LOG_CATEGORIES : &'static [(&'static str, u64)] = &[
+    ("peer-error",      LogFlag::LogFlagPeerError as u64),
+    ("msg-version",     LogFlag::LogFlagMsgVersion as u64),
+    ("msg-verack",      LogFlag::LogFlagMsgVerAck as u64),
+    ("msg-ping",        LogFlag::LogFlagMsgPing as u64),
+    ("msg-pong",        LogFlag::LogFlagMsgPong as u64),
+    ("msg-addr",        LogFlag::LogFlagMsgAddr as u64),
+    ("msg-inv",         LogFlag::LogFlagMsgInv as u64),
+    ("msg-getdata",     LogFlag::LogFlagMsgGetData as u64),
+    ("msg-reject",      LogFlag::LogFlagMsgReject as u64),
+    ("msg-tx",          LogFlag::LogFlagMsgTx as u64),
+    ("msg-getaddr",     LogFlag::LogFlagMsgGetAddr as u64),
+    ("lag",             LogFlag::LogFlagLag as u64),
+    ("addrmng",         LogFlag::LogFlagAddrMng as u64),
+    ("msg-block",       LogFlag::LogFlagMsgBlock as u64),
+    ("chain",           LogFlag::LogFlagChain as u64),
+    ("mempool",         LogFlag::LogFlagMempool as u64),
+    ("msg-notfound",    LogFlag::LogFlagMsgNotFound as u64),
+    ("msg-mempool",     LogFlag::LogFlagMsgMemPool as u64),
+    ("msg-filter",      LogFlag::LogFlagMsgFilter as u64),
+    ("msg-merkleblock", LogFlag::LogFlagMsgMerkleBlock as u64),
+    ("msg-cfilter",     LogFlag::LogFlagMsgCFilter as u64),
+    ("msg-cmpct",       LogFlag::LogFlagMsgCmpct as u64),
+    ("msg-headers",     LogFlag::LogFlagMsgHeaders as u64),
+    ("msg-feefilter",   LogFlag::LogFlagMsgFeeFilter as u64),
+    ("msg-wtxidrelay",  LogFlag::LogFlagMsgWtxidRelay as u64),
+    ("transport",       LogFlag::LogFlagTransport as u64),
+    ("tor",             LogFlag::LogFlagTor as u64) ];
+
+pub const LOG_CATEGORIES_ALL : u64 = (1 << 27)-1;
+
+/* What gets logged is decided by the level, the categories just narrow it */
+pub const DEFAULT_LOG_CATEGORIES : u64 = LOG_CATEGORIES_ALL;
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum LogLevel
 {
-    match *msg
+    LogLevelError = 0,
+    LogLevelWarn  = 1,
+    LogLevelInfo  = 2,
+    LogLevelDebug = 3,
+    LogLevelTrace = 4
+}
+
+pub const DEFAULT_LOG_LEVEL : LogLevel = LogLevel::LogLevelInfo;
+
+#[allow(dead_code)]
+impl LogLevel
+{
+    pub fn from_name(name : &str) -> Option<LogLevel>
+    {
+        match name
+        {
+            "error" => Some(LogLevel::LogLevelError),
+            "warn"  => Some(LogLevel::LogLevelWarn),
+            "info"  => Some(LogLevel::LogLevelInfo),
+            "debug" => Some(LogLevel::LogLevelDebug),
+            "trace" => Some(LogLevel::LogLevelTrace),
+            _       => None
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str
     {
-        Message::MsgVersion(_) => "version",
-        Message::MsgVerAck(_)  => "verack",
-        Message::MsgPing(_)    => "ping",
-        Message::MsgPong(_)    => "pong",
-        Message::MsgAddr(_)    => "addr",
-        Message::MsgInv(_)     => "Message::inv",
-        Message::MsgGetData(_) => "getdata",
-        Message::MsgReject(_)  => "reject",
-        Message::MsgTx(_)      => "tx",
-        Message::MsgGetAddr(_) => "getaddr",
+        match *self
+        {
+            LogLevel::LogLevelError => "error",
+            LogLevel::LogLevelWarn  => "warn",
+            LogLevel::LogLevelInfo  => "info",
+            LogLevel::LogLevelDebug => "debug",
+            LogLevel::LogLevelTrace => "trace"
+        }
+    }
+
+    fn get_label(&self) -> &'static str
+    {
+        match *self
+        {
+            LogLevel::LogLevelError => "ERROR",
+            LogLevel::LogLevelWarn  => "WARN",
+            LogLevel::LogLevelInfo  => "INFO",
+            LogLevel::LogLevelDebug => "DEBUG",
+            LogLevel::LogLevelTrace => "TRACE"
+        }
     }
 }
 
-pub fn log_received_msg(addr : &SocketAddr, msg : &Message)
+/* Text is for people, JSON lines, with an object per event, for programs */
+#[deriving(Clone, PartialEq, Show)]
+pub enum LogFormat
 {
-    match *msg
+    LogFormatText,
+    LogFormatJson
+}
+
+impl LogFormat
+{
+    pub fn from_name(name : &str) -> Option<LogFormat>
+    {
+        match name
+        {
+            "text" => Some(LogFormat::LogFormatText),
+            "json" => Some(LogFormat::LogFormatJson),
+            _      => None
+        }
+    }
+}
+
+/* Categories are given as a comma separated list of names, "all", "none" or
+ * a bitmask in decimal or 0x hexadecimal.
+ */
+pub fn parse_categories(str : &str) -> Option<u64>
+{
+    let mut categories : u64 = 0;
+
+    if str.starts_with("0x")
     {
-        Message::MsgVersion(_) =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgVersion as u64 == 0 { return; },
-        Message::MsgVerAck(_)  =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgVerAck as u64 == 0  { return; },
-        Message::MsgPing(_)    =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgPing as u64 == 0    { return; },
-        Message::MsgPong(_)    =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgPong as u64 == 0    { return; },
-        Message::MsgAddr(_)    =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgAddr as u64 == 0    { return; },
-        Message::MsgInv(_)     =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgInv as u64 == 0     { return; },
-        Message::MsgGetData(_) =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgGetData as u64 == 0 { return; },
-        Message::MsgReject(_)  =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgReject as u64 == 0  { return; },
-        Message::MsgTx(_)      =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgTx as u64 == 0      { return; },
-        Message::MsgGetAddr(_) =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgGetAddr as u64 == 0 { return; },
-    }
-
-    println!(">>> {}  {} command: {:9}",
-             time::now().rfc822z(),addr,msg_to_command(msg));
+        return from_str_radix::<u64>(str.slice_from(2),16);
+    }
 
-    match *msg
+    match from_str::<u64>(str)
     {
-        Message::MsgVersion(ref version) => println!("{:4}",version),
-        Message::MsgVerAck(ref verack)   => println!("{:4}",verack),
-        Message::MsgPing(ref ping)       => println!("{:4}",ping),
-        Message::MsgPong(ref pong)       => println!("{:4}",pong),
-        Message::MsgAddr(ref addrs)      => println!("{:4}",addrs),
-        Message::MsgInv(ref inv)         => println!("{:4}",inv),
-        Message::MsgGetData(ref getdata) => println!("{:4}",getdata),
-        Message::MsgReject(ref reject)   => println!("{:4}",reject),
-        Message::MsgTx(ref tx)           => println!("{:4}",tx),
-        Message::MsgGetAddr(ref getaddr) => println!("{:4}",getaddr),
+        Some(categories) => return Some(categories),
+        None             => ()
     }
+
+    for name in str.split(',').map(|n| n.trim())
+    {
+        categories |= match name
+        {
+            "all"  => LOG_CATEGORIES_ALL,
+            "none" => 0,
+            _      => match LOG_CATEGORIES.iter().find(|&&(n, _)| n == name)
+            {
+                Some(&(_, flag)) => flag,
+                None             => return None
+            }
+        };
+    }
+
+    Some(categories)
 }
 
-pub fn log_sent_msg(addr : &SocketAddr, msg : &Message)
+fn category_name(flag : LogFlag) -> &'static str
 {
-    match *msg
+    match LOG_CATEGORIES.iter().find(|&&(_, f)| f == flag as u64)
+    {
+        Some(&(name, _)) => name,
+        None             => unreachable!()
+    }
+}
+
+#[allow(dead_code)]
+pub fn category_names(categories : u64) -> Vec<&'static str>
+{
+    LOG_CATEGORIES.iter().filter(|&&(_, flag)| categories & flag != 0)
+                         .map(|&(name, _)| name)
+                         .collect()
+}
+
+/* A log file that is renamed to <path>.1 when it gets to its maximum size,
+ * shifting the older ones up to <path>.<count>.
+ */
+struct LogFile
+{
+    path     : Path,
+    file     : File,
+    size     : u64,
+    max_size : u64,
+    count    : uint
+}
+
+impl LogFile
+{
+    fn open(path : &Path, max_size : u64, count : uint) -> IoResult<LogFile>
     {
-        Message::MsgVersion(_) =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgVersion as u64 == 0 { return; },
-        Message::MsgVerAck(_)  =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgVerAck as u64 == 0  { return; },
-        Message::MsgPing(_)    =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgPing as u64 == 0    { return; },
-        Message::MsgPong(_)    =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgPong as u64 == 0    { return; },
-        Message::MsgAddr(_)    =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgAddr as u64 == 0    { return; },
-        Message::MsgInv(_)     =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgInv as u64 == 0     { return; },
-        Message::MsgGetData(_) =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgGetData as u64 == 0 { return; },
-        Message::MsgReject(_)  =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgReject as u64 == 0  { return; },
-        Message::MsgTx(_)      =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgTx as u64 == 0      { return; },
-        Message::MsgGetAddr(_) =>
-            if LOG_FLAGS & LogFlag::LogFlagMsgGetAddr as u64 == 0 { return; },
-    }
-
-    println!("<<< {}  {} command: {:9}",
-             time::now().rfc822z(),addr,msg_to_command(msg));
+        let file : File = try!(File::open_mode(path,Append,Write));
+        let size : u64 = try!(file.stat()).size;
 
-    match *msg
+        Ok(LogFile { path: path.clone(), file: file, size: size, max_size: max_size,
+                     count: count })
+    }
+
+    fn rotated_path(&self, n : uint) -> Path
     {
-        Message::MsgVersion(ref version) => println!("{:4}",version),
-        Message::MsgVerAck(ref verack)   => println!("{:4}",verack),
-        Message::MsgPing(ref ping)       => println!("{:4}",ping),
-        Message::MsgPong(ref pong)       => println!("{:4}",pong),
-        Message::MsgAddr(ref addrs)      => println!("{:4}",addrs),
-        Message::MsgInv(ref inv)         => println!("{:4}",inv),
-        Message::MsgGetData(ref getdata) => println!("{:4}",getdata),
-        Message::MsgReject(ref reject)   => println!("{:4}",reject),
-        Message::MsgTx(ref tx)           => println!("{:4}",tx),
-        Message::MsgGetAddr(ref getaddr) => println!("{:4}",getaddr),
+        Path::new(format!("{}.{}",self.path.display(),n))
+    }
+
+    #[allow(unused_must_use)]
+    fn rotate(&mut self) -> IoResult<()>
+    {
+        if self.count > 0
+        {
+            for n in range(1,self.count).rev()
+            {
+                /* The older files may not be there yet */
+                ::std::io::fs::rename(&self.rotated_path(n),&self.rotated_path(n+1));
+            }
+
+            try!(::std::io::fs::rename(&self.path,&self.rotated_path(1)));
+        }
+
+        self.file = try!(File::create(&self.path));
+        self.size = 0;
+
+        Ok(())
+    }
+
+    fn write(&mut self, text : &str) -> IoResult<()>
+    {
+        if self.max_size > 0 && self.size > 0 && self.size+text.len() as u64 > self.max_size
+        {
+            try!(self.rotate());
+        }
+
+        try!(self.file.write_str(text));
+        try!(self.file.flush());
+
+        self.size += text.len() as u64;
+
+        Ok(())
     }
 }
 
-pub fn log_lag(addr : &SocketAddr, lag : &Duration)
+struct Logger
+{
+    level      : LogLevel,
+    format     : LogFormat,
+    categories : u64,
+    /* Only log about these peers, or about all of them if empty */
+    peers      : Vec<IpAddr>,
+    file       : Option<LogFile>
+}
+
+impl Logger
 {
-    if LOG_FLAGS & LogFlag::LogFlagLag as u64 != 0
+    fn enabled(&self, level : LogLevel, flag : LogFlag, addr : Option<&SocketAddr>) -> bool
     {
-        println!("{}  Lag: {} ms",addr,lag.num_milliseconds());
+        if level as uint > self.level as uint || self.categories & flag as u64 == 0
+        {
+            return false;
+        }
+
+        match addr
+        {
+            Some(addr) => self.peers.is_empty() || self.peers.contains(&addr.ip),
+            None       => true
+        }
+    }
+
+    fn write_text(&mut self, level : LogLevel, text : &str)
+    {
+        let line : String = format!("{} {:5} {}\n",
+                                    time::now().rfc822z(),
+                                    level.get_label(),
+                                    text);
+
+        self.write(level,line.as_slice());
+    }
+
+    /* The fields of every event come with the time, level, category and event
+     * name, and the peer address if it is about a peer.
+     */
+    fn write_json(&mut self,
+                  level  : LogLevel,
+                  flag   : LogFlag,
+                  addr   : Option<&SocketAddr>,
+                  event  : &str,
+                  fields : TreeMap<String,Json>)
+    {
+        let mut fields : TreeMap<String,Json> = fields;
+
+        fields.insert("time".to_string(),time::now_utc().rfc3339().to_json());
+        fields.insert("level".to_string(),level.get_name().to_string().to_json());
+        fields.insert("category".to_string(),category_name(flag).to_string().to_json());
+        fields.insert("event".to_string(),event.to_string().to_json());
+
+        match addr
+        {
+            Some(addr) => { fields.insert("peer".to_string(),addr.to_string().to_json()); },
+            None       => ()
+        }
+
+        self.write(level,format!("{}\n",fields.to_json()).as_slice());
+    }
+
+    #[allow(unused_must_use)]
+    fn write(&mut self, level : LogLevel, line : &str)
+    {
+        match self.file
+        {
+            Some(ref mut file) =>
+            {
+                file.write(line);
+            },
+            None if level as uint <= LogLevel::LogLevelWarn as uint =>
+            {
+                ::std::io::stderr().write_str(line);
+            },
+            None =>
+            {
+                ::std::io::stdout().write_str(line);
+            }
+        }
     }
 }
 
-pub fn log_peer_error_fatal(addr : &SocketAddr, err : ::peer::PeerError)
+/* The logger of a node, shared by the threads of the node.  Peers and
+ * managers log from their own threads, the lock keeps the lines of each of
+ * them together.  Every node has its own, so the nodes running in the same
+ * process do not change each other's level, file or filters.
+ */
+#[deriving(Clone)]
+pub struct NodeLogger
 {
-    assert!(err.is_fatal());
+    logger : Arc<Mutex<Logger>>
+}
+
+/* The logger of the node the thread works for */
+local_data_key!(THREAD_LOGGER: NodeLogger)
 
-    if LOG_FLAGS & LogFlag::LogFlagPeerError as u64 != 0
+impl NodeLogger
+{
+    pub fn new(config : &::config::Config) -> IoResult<NodeLogger>
     {
-        (write!(&mut ::std::io::stderr(),"{} Fatal Error: {}\n",addr,err)).unwrap();
+        let file : Option<LogFile> = match config.log_file
+        {
+            Some(ref path) => Some(try!(LogFile::open(path,config.log_file_max_size,
+                                                      config.log_file_count))),
+            None           => None
+        };
+
+        Ok(NodeLogger::from_logger(Logger {
+            level:      config.log_level.clone(),
+            format:     config.log_format.clone(),
+            categories: config.log_categories,
+            peers:      config.log_peers.clone(),
+            file:       file }))
+    }
+
+    fn from_logger(logger : Logger) -> NodeLogger
+    {
+        NodeLogger { logger: Arc::new(Mutex::new(logger)) }
+    }
+
+    /* Log what the calling thread logs with this logger.  The threads of a
+     * node all do it first thing.
+     */
+    pub fn install(&self)
+    {
+        THREAD_LOGGER.replace(Some(self.clone()));
+    }
+
+    /* These change the logging of a running node */
+
+    pub fn get_level(&self) -> LogLevel
+    {
+        self.logger.lock().level.clone()
+    }
+
+    pub fn set_level(&self, level : LogLevel)
+    {
+        self.logger.lock().level = level;
+    }
+
+    pub fn get_categories(&self) -> u64
+    {
+        self.logger.lock().categories
+    }
+
+    pub fn set_categories(&self, categories : u64)
+    {
+        self.logger.lock().categories = categories;
+    }
+
+    pub fn get_peers(&self) -> Vec<IpAddr>
+    {
+        self.logger.lock().peers.clone()
+    }
+
+    pub fn set_peers(&self, peers : Vec<IpAddr>)
+    {
+        self.logger.lock().peers = peers;
     }
 }
 
-pub fn log_addr_mng_request(request : &::addrmng::AddrManagerRequest)
+/* Threads that are not part of a node, like the main one before it starts
+ * it, get the defaults: text on the standard output.
+ */
+fn current() -> NodeLogger
 {
-    if LOG_FLAGS & LogFlag::LogFlagAddrMng as u64 != 0
+    let logger : NodeLogger;
+
+    match THREAD_LOGGER.get()
     {
-        println!("Address Manager: Request: {}",request);
+        Some(logger) => return logger.clone(),
+        None         => ()
     }
+
+    logger = NodeLogger::from_logger(Logger {
+        level:      DEFAULT_LOG_LEVEL,
+        format:     LogFormat::LogFormatText,
+        categories: DEFAULT_LOG_CATEGORIES,
+        peers:      Vec::new(),
+        file:       None });
+
+    logger.install();
+
+    logger
 }
 
-pub fn log_addr_mng_reply(reply : &::addrmng::AddrManagerReply)
+fn json_fields(fields : Vec<(&str, Json)>) -> TreeMap<String,Json>
+{
+    fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
+
+fn is_enabled(level : LogLevel, flag : LogFlag, addr : Option<&SocketAddr>) -> bool
 {
-    if LOG_FLAGS & LogFlag::LogFlagAddrMng as u64 != 0
+    let current : NodeLogger = current();
+    let enabled : bool = current.logger.lock().enabled(level,flag,addr);
+
+    enabled
+}
+
+/* The text is only built if it is going to be logged.  In JSON it is the
+ * message of a "log" event.
+ */
+fn log(level : LogLevel, flag : LogFlag, addr : Option<&SocketAddr>, text : || -> String)
+{
+    let current : NodeLogger = current();
+    let mut logger = current.logger.lock();
+    let message : String;
+
+    if !logger.enabled(level.clone(),flag,addr)
+    {
+        return;
+    }
+
+    message = text();
+
+    match logger.format
     {
-        println!("Address Manager: Reply: {}",reply);
+        LogFormat::LogFormatText => logger.write_text(level,message.as_slice()),
+        LogFormat::LogFormatJson =>
+            logger.write_json(level,flag,addr,"log",
+                              json_fields(vec![("message", message.to_json())]))
     }
 }
 
-pub fn log_addr_mng_disconnect()
+/* Events that have fields of their own in JSON */
+fn log_event(level  : LogLevel,
+             flag   : LogFlag,
+             addr   : Option<&SocketAddr>,
+             event  : &str,
+             text   : || -> String,
+             fields : || -> TreeMap<String,Json>)
 {
-    if LOG_FLAGS & LogFlag::LogFlagAddrMng as u64 != 0
+    let current : NodeLogger = current();
+    let mut logger = current.logger.lock();
+
+    if !logger.enabled(level.clone(),flag,addr)
+    {
+        return;
+    }
+
+    match logger.format
     {
-        println!("Address Manager: Channel disconnected");
+        LogFormat::LogFormatText => logger.write_text(level,text().as_slice()),
+        LogFormat::LogFormatJson => logger.write_json(level,flag,addr,event,fields())
     }
 }
 
+fn msg_to_command(msg : &Message) -> &str
+{
+    match *msg
+    {
+        Message::MsgVersion(_)      => "version",
+        Message::MsgVerAck(_)       => "verack",
+        Message::MsgPing(_)         => "ping",
+        Message::MsgPong(_)         => "pong",
+        Message::MsgAddr(_)         => "addr",
+        Message::MsgInv(_)          => "inv",
+        Message::MsgGetData(_)      => "getdata",
+        Message::MsgReject(_)       => "reject",
+        Message::MsgTx(_)           => "tx",
+        Message::MsgGetAddr(_)      => "getaddr",
+        Message::MsgBlock(_)        => "block",
+        Message::MsgNotFound(_)     => "notfound",
+        Message::MsgMemPool(_)      => "mempool",
+        Message::MsgFilterLoad(_)   => "filterload",
+        Message::MsgFilterAdd(_)    => "filteradd",
+        Message::MsgFilterClear(_)  => "filterclear",
+        Message::MsgMerkleBlock(_)  => "merkleblock",
+        Message::MsgGetCFilters(_)  => "getcfilters",
+        Message::MsgCFilter(_)      => "cfilter",
+        Message::MsgGetCFHeaders(_) => "getcfheaders",
+        Message::MsgCFHeaders(_)    => "cfheaders",
+        Message::MsgGetCFCheckpt(_) => "getcfcheckpt",
+        Message::MsgCFCheckpt(_)    => "cfcheckpt",
+        Message::MsgSendCmpct(_)    => "sendcmpct",
+        Message::MsgCmpctBlock(_)   => "cmpctblock",
+        Message::MsgGetBlockTxn(_)  => "getblocktxn",
+        Message::MsgBlockTxn(_)     => "blocktxn",
+        Message::MsgSendHeaders(_)  => "sendheaders",
+        Message::MsgGetHeaders(_)   => "getheaders",
+        Message::MsgHeaders(_)      => "headers",
+        Message::MsgFeeFilter(_)    => "feefilter",
+        Message::MsgWtxidRelay(_)   => "wtxidrelay",
+        Message::MsgSendAddrV2(_)   => "sendaddrv2",
+        Message::MsgAddrV2(_)       => "addrv2",
+    }
+}
+
+fn msg_to_flag(msg : &Message) -> LogFlag
+{
+    match *msg
+    {
+        Message::MsgVersion(_)      => LogFlag::LogFlagMsgVersion,
+        Message::MsgVerAck(_)       => LogFlag::LogFlagMsgVerAck,
+        Message::MsgPing(_)         => LogFlag::LogFlagMsgPing,
+        Message::MsgPong(_)         => LogFlag::LogFlagMsgPong,
+        Message::MsgAddr(_)         => LogFlag::LogFlagMsgAddr,
+        Message::MsgInv(_)          => LogFlag::LogFlagMsgInv,
+        Message::MsgGetData(_)      => LogFlag::LogFlagMsgGetData,
+        Message::MsgReject(_)       => LogFlag::LogFlagMsgReject,
+        Message::MsgTx(_)           => LogFlag::LogFlagMsgTx,
+        Message::MsgGetAddr(_)      => LogFlag::LogFlagMsgGetAddr,
+        Message::MsgBlock(_)        => LogFlag::LogFlagMsgBlock,
+        Message::MsgNotFound(_)     => LogFlag::LogFlagMsgNotFound,
+        Message::MsgMemPool(_)      => LogFlag::LogFlagMsgMemPool,
+        Message::MsgFilterLoad(_)   => LogFlag::LogFlagMsgFilter,
+        Message::MsgFilterAdd(_)    => LogFlag::LogFlagMsgFilter,
+        Message::MsgFilterClear(_)  => LogFlag::LogFlagMsgFilter,
+        Message::MsgMerkleBlock(_)  => LogFlag::LogFlagMsgMerkleBlock,
+        Message::MsgGetCFilters(_)  => LogFlag::LogFlagMsgCFilter,
+        Message::MsgCFilter(_)      => LogFlag::LogFlagMsgCFilter,
+        Message::MsgGetCFHeaders(_) => LogFlag::LogFlagMsgCFilter,
+        Message::MsgCFHeaders(_)    => LogFlag::LogFlagMsgCFilter,
+        Message::MsgGetCFCheckpt(_) => LogFlag::LogFlagMsgCFilter,
+        Message::MsgCFCheckpt(_)    => LogFlag::LogFlagMsgCFilter,
+        Message::MsgSendCmpct(_)    => LogFlag::LogFlagMsgCmpct,
+        Message::MsgCmpctBlock(_)   => LogFlag::LogFlagMsgCmpct,
+        Message::MsgGetBlockTxn(_)  => LogFlag::LogFlagMsgCmpct,
+        Message::MsgBlockTxn(_)     => LogFlag::LogFlagMsgCmpct,
+        Message::MsgSendHeaders(_)  => LogFlag::LogFlagMsgHeaders,
+        Message::MsgGetHeaders(_)   => LogFlag::LogFlagMsgHeaders,
+        Message::MsgHeaders(_)      => LogFlag::LogFlagMsgHeaders,
+        Message::MsgFeeFilter(_)    => LogFlag::LogFlagMsgFeeFilter,
+        Message::MsgWtxidRelay(_)   => LogFlag::LogFlagMsgWtxidRelay,
+        Message::MsgSendAddrV2(_)   => LogFlag::LogFlagMsgAddr,
+        Message::MsgAddrV2(_)       => LogFlag::LogFlagMsgAddr,
+    }
+}
+
+fn msg_to_string(msg : &Message) -> String
+{
+    match *msg
+    {
+        Message::MsgVersion(ref version)           => format!("{:4}",version),
+        Message::MsgVerAck(ref verack)             => format!("{:4}",verack),
+        Message::MsgPing(ref ping)                 => format!("{:4}",ping),
+        Message::MsgPong(ref pong)                 => format!("{:4}",pong),
+        Message::MsgAddr(ref addrs)                => format!("{:4}",addrs),
+        Message::MsgInv(ref inv)                   => format!("{:4}",inv),
+        Message::MsgGetData(ref getdata)           => format!("{:4}",getdata),
+        Message::MsgReject(ref reject)             => format!("{:4}",reject),
+        Message::MsgTx(ref tx)                     => format!("{:4}",tx),
+        Message::MsgGetAddr(ref getaddr)           => format!("{:4}",getaddr),
+        Message::MsgBlock(ref block)               => format!("{:4}",block),
+        Message::MsgNotFound(ref notfound)         => format!("{:4}",notfound),
+        Message::MsgMemPool(ref mempool)           => format!("{:4}",mempool),
+        Message::MsgFilterLoad(ref filterload)     => format!("{:4}",filterload),
+        Message::MsgFilterAdd(ref filteradd)       => format!("{:4}",filteradd),
+        Message::MsgFilterClear(ref filterclear)   => format!("{:4}",filterclear),
+        Message::MsgMerkleBlock(ref merkleblock)   => format!("{:4}",merkleblock),
+        Message::MsgGetCFilters(ref getcfilters)   => format!("{:4}",getcfilters),
+        Message::MsgCFilter(ref cfilter)           => format!("{:4}",cfilter),
+        Message::MsgGetCFHeaders(ref getcfheaders) => format!("{:4}",getcfheaders),
+        Message::MsgCFHeaders(ref cfheaders)       => format!("{:4}",cfheaders),
+        Message::MsgGetCFCheckpt(ref getcfcheckpt) => format!("{:4}",getcfcheckpt),
+        Message::MsgCFCheckpt(ref cfcheckpt)       => format!("{:4}",cfcheckpt),
+        Message::MsgSendCmpct(ref sendcmpct)       => format!("{:4}",sendcmpct),
+        Message::MsgCmpctBlock(ref cmpctblock)     => format!("{:4}",cmpctblock),
+        Message::MsgGetBlockTxn(ref getblocktxn)   => format!("{:4}",getblocktxn),
+        Message::MsgBlockTxn(ref blocktxn)         => format!("{:4}",blocktxn),
+        Message::MsgSendHeaders(ref sendheaders)   => format!("{:4}",sendheaders),
+        Message::MsgGetHeaders(ref getheaders)     => format!("{:4}",getheaders),
+        Message::MsgHeaders(ref headers)           => format!("{:4}",headers),
+        Message::MsgFeeFilter(ref feefilter)       => format!("{:4}",feefilter),
+        Message::MsgWtxidRelay(ref wtxidrelay)     => format!("{:4}",wtxidrelay),
+        Message::MsgSendAddrV2(ref sendaddrv2)     => format!("{:4}",sendaddrv2),
+        Message::MsgAddrV2(ref addrv2)             => format!("{:4}",addrv2),
+    }
+}
+
+/* The size is of the message on the wire, with its v1 header or v2 packet */
+fn msg_fields(msg : &Message, size : uint) -> TreeMap<String,Json>
+{
+    json_fields(vec![("command", msg_to_command(msg).to_string().to_json()),
+                     ("size",    size.to_json())])
+}
+
+pub fn log_received_msg(addr : &SocketAddr, msg : &Message, size : uint)
+{
+    log_event(LogLevel::LogLevelDebug,msg_to_flag(msg),Some(addr),"msg_received",
+              || format!(">>> {} command: {:9}\n{}",addr,msg_to_command(msg),msg_to_string(msg)),
+              || msg_fields(msg,size));
+}
+
+pub fn log_sent_msg(addr : &SocketAddr, msg : &Message, size : uint)
+{
+    log_event(LogLevel::LogLevelDebug,msg_to_flag(msg),Some(addr),"msg_sent",
+              || format!("<<< {} command: {:9}\n{}",addr,msg_to_command(msg),msg_to_string(msg)),
+              || msg_fields(msg,size));
+}
+
+pub fn log_lag(addr : &SocketAddr, lag : &Duration)
+{
+    log_event(LogLevel::LogLevelTrace,LogFlag::LogFlagLag,Some(addr),"lag",
+              || format!("{}  Lag: {} ms",addr,lag.num_milliseconds()),
+              || json_fields(vec![("lag_ms", lag.num_milliseconds().to_json())]));
+}
+
+pub fn log_peer_error_fatal(addr : &SocketAddr, err : ::peer::PeerError)
+{
+    assert!(err.is_fatal());
+
+    log_event(LogLevel::LogLevelWarn,LogFlag::LogFlagPeerError,Some(addr),"peer_error",
+              || format!("{} Fatal Error: {}",addr,err),
+              || json_fields(vec![("error", format!("{}",err).to_json())]));
+}
+
+pub fn log_listen_error(addr : &SocketAddr)
+{
+    log(LogLevel::LogLevelError,LogFlag::LogFlagPeerError,None,
+        || format!("Cannot listen on {}",addr));
+}
+
+pub fn log_inbound_full(addr : &SocketAddr)
+{
+    log(LogLevel::LogLevelInfo,LogFlag::LogFlagPeerError,Some(addr),
+        || format!("{}  Too many inbound peers, connection closed",addr));
+}
+
+pub fn log_proxy_connected(addr : &SocketAddr, host : &str, port : u16)
+{
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagTor,Some(addr),
+        || format!("{}  Connected to {}:{} through the proxy",addr,host,port));
+}
+
+pub fn log_v2_session(addr : &SocketAddr, session_id : &[u8, ..32])
+{
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagTransport,Some(addr),
+        || format!("{}  v2 transport, session id {}",addr,::crypto::to_hexstr(session_id)));
+}
+
+pub fn log_v2_fallback(addr : &SocketAddr, err : &::peer::PeerError)
+{
+    log(LogLevel::LogLevelInfo,LogFlag::LogFlagTransport,Some(addr),
+        || format!("{}  v2 handshake failed ({}), falling back to v1",addr,err));
+}
+
+pub fn log_onion_service(addr : &::datatype::netaddr::NetAddrV2)
+{
+    log(LogLevel::LogLevelInfo,LogFlag::LogFlagTor,None,
+        || format!("Tor: reachable at {}",addr));
+}
+
+pub fn log_tor_control_error(err : &::torcontrol::TorControlError)
+{
+    log(LogLevel::LogLevelError,LogFlag::LogFlagTor,None,
+        || format!("Tor: onion service failed: {}",err));
+}
+
+pub fn log_addr_mng_request(request : &::addrmng::AddrManagerRequest)
+{
+    log(LogLevel::LogLevelTrace,LogFlag::LogFlagAddrMng,None,
+        || format!("Address Manager: Request: {}",request));
+}
+
+pub fn log_addr_mng_reply(reply : &::addrmng::AddrManagerReply)
+{
+    log(LogLevel::LogLevelTrace,LogFlag::LogFlagAddrMng,None,
+        || format!("Address Manager: Reply: {}",reply));
+}
+
+pub fn log_addr_mng_disconnect()
+{
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagAddrMng,None,
+        || format!("Address Manager: Channel disconnected"));
+}
+
 pub fn log_addr_mng_buckets<T : Iterator<uint>>(buckets : &mut T)
 {
-    if LOG_FLAGS & LogFlag::LogFlagAddrMng as u64 != 0
+    let sizes : Vec<uint>;
+
+    if !is_enabled(LogLevel::LogLevelTrace,LogFlag::LogFlagAddrMng,None)
     {
-        let mut count : uint = 0;
+        return;
+    }
+
+    sizes = buckets.collect();
+
+    log_event(LogLevel::LogLevelTrace,LogFlag::LogFlagAddrMng,None,"addrmng_buckets",|| {
+        let mut text : String = String::new();
 
-        for b in *buckets
+        for (i, b) in sizes.iter().enumerate()
         {
-            if count%8 == 0
+            if i%8 == 0
             {
-                if count > 0 { println!(""); }
-                print!("Address Manager: Buckets {}:",count/8);
+                if i > 0 { text.push('\n'); }
+                text.push_str(format!("Address Manager: Buckets {}:",i/8).as_slice());
             }
 
-            print!(" {:2}",b);
-            count += 1;
+            text.push_str(format!(" {:2}",b).as_slice());
         }
 
-        println!("");
-    }
+        text
+    }, || {
+        json_fields(vec![("buckets",   sizes.to_json()),
+                         ("addresses", sizes.iter().fold(0,|sum, b| sum+*b).to_json()),
+                         ("empty",     sizes.iter().filter(|&&b| b == 0).count().to_json()),
+                         ("max",       sizes.iter().max().map(|b| *b).unwrap_or(0).to_json())])
+    });
 }
 
-pub fn log_addr_mng_timestamp_update(addr : &SocketAddr,
-                                     old : &Timespec,
-                                     new : &Timespec)
+pub fn log_addr_mng_timestamp_update(addr : &::datatype::netaddr::NetAddrV2,
+                                     old  : &Timespec,
+                                     new  : &Timespec)
 {
-    if LOG_FLAGS & LogFlag::LogFlagAddrMng as u64 != 0
-    {
-        println!("Address Manager: Updated address {} from {} to {}",
-                 addr,old.sec,new.sec);
-    }
+    log(LogLevel::LogLevelTrace,LogFlag::LogFlagAddrMng,addr.to_socket_addr().as_ref(),
+        || format!("Address Manager: Updated address {} from {} to {}",addr,old.sec,new.sec));
 }
 
 pub fn log_addr_mng_address_count(count : uint)
 {
-    if LOG_FLAGS & LogFlag::LogFlagAddrMng as u64 != 0
-    {
-        println!("Address Manager: Address count: {}",count);
-    }
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagAddrMng,None,
+        || format!("Address Manager: Address count: {}",count));
 }
 
 pub fn log_addr_mng_cleanup(count_before : uint, count_after : uint)
 {
-    if LOG_FLAGS & LogFlag::LogFlagAddrMng as u64 != 0
-    {
-        println!("Address Manager: Cleanup: {} -> {}",count_before,count_after);
-    }
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagAddrMng,None,
+        || format!("Address Manager: Cleanup: {} -> {}",count_before,count_after));
+}
+
+pub fn log_chain_new_tip(hash : &Hash, height : u32)
+{
+    log(LogLevel::LogLevelInfo,LogFlag::LogFlagChain,None,
+        || format!("Chain: New tip {} at height {}",hash,height));
+}
+
+pub fn log_chain_block_rejected(hash : &Hash, err : &::consensus::ValidationError)
+{
+    log(LogLevel::LogLevelWarn,LogFlag::LogFlagChain,None,
+        || format!("Chain: Rejected block {}: {} ({})",hash,err,err.get_reason()));
+}
+
+pub fn log_mempool_accepted(hash : &Hash, count : uint, size : uint)
+{
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagMempool,None,
+        || format!("Mempool: Accepted {} ({} txs, {} bytes)",hash,count,size));
+}
+
+pub fn log_mempool_rejected(hash : &Hash, err : &::mempool::MempoolError)
+{
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagMempool,None,
+        || format!("Mempool: Rejected {}: {} ({})",hash,err,err.get_reason()));
+}
+
+pub fn log_mempool_removed(hash : &Hash)
+{
+    log(LogLevel::LogLevelTrace,LogFlag::LogFlagMempool,None,
+        || format!("Mempool: Removed {}",hash));
+}
+
+pub fn log_orphan_added(hash : &Hash, peer : &SocketAddr, count : uint)
+{
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagMempool,Some(peer),
+        || format!("Orphanage: Added {} from {} ({} orphans)",hash,peer,count));
+}
+
+pub fn log_orphan_evicted(hash : &Hash)
+{
+    log(LogLevel::LogLevelDebug,LogFlag::LogFlagMempool,None,
+        || format!("Orphanage: Evicted {}",hash));
 }
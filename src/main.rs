@@ -1,43 +1,71 @@
 #![feature(macro_rules)]
 
 extern crate getopts;
+extern crate libc;
 
 use std::io::net::ip::SocketAddr;
-use std::io::timer::sleep;
-use std::time::duration::Duration;
+use std::sync::Arc;
 
 use getopts::optflag;
+use getopts::optopt;
 use peerdiscovery::discover_peers;
 
-use comm::DuplexChannel;
-
 use addrmng::AddrManagerChannel;
-use addrmng::AddrManager;
-use addrmng::AddrManagerRequest;
-use addrmng::AddrManagerReply;
 
+use config::Config;
+
+use logger::NodeLogger;
+
+use node::Node;
+
+mod common;
 mod config;
 mod datatype;
 mod marshalling;
 mod crypto;
 mod comm;
+mod connector;
 mod msgbuffer;
 mod message;
 mod logger;
 mod peer;
 mod peerdiscovery;
 mod addrmng;
+mod interpreter;
+mod consensus;
+mod utxo;
+mod chain;
+mod chainmng;
+mod mempool;
+mod orphanage;
+mod relay;
+mod secp256k1;
+mod v2transport;
+mod torcontrol;
+mod miner;
+mod node;
+mod rpc;
+mod rest;
+
+#[cfg(test)]
+mod tests;
 
 struct Options
 {
     help    : bool,
-    version : bool
+    version : bool,
+    config  : Config
 }
 
 pub const OPT_DESC_HELP : &'static str
     = "Display this help and exit";
 pub const OPT_DESC_VERSION : &'static str
     = "Output version information and exit";
+pub const OPT_DESC_DATADIR : &'static str
+    = "Directory of the configuration file (default ~/.rustybit)";
+
+/* Width of the option column in the usage */
+const OPT_USAGE_WIDTH : uint = 38;
 
 #[allow(unused_must_use)]
 fn print_usage(out : &mut std::io::LineBufferedWriter<std::io::stdio::StdWriter>)
@@ -49,8 +77,51 @@ fn print_usage(out : &mut std::io::LineBufferedWriter<std::io::stdio::StdWriter>
     write!(out,"Usage: {} [OPTIONS]\n", program);
     write!(out,"\n");
     write!(out,"Options:\n");
-    write!(out,"  -h, --help     {}\n",OPT_DESC_HELP);
-    write!(out,"  -v, --version  {}\n",OPT_DESC_VERSION);
+    print_option(out,"h","help","",OPT_DESC_HELP);
+    print_option(out,"v","version","",OPT_DESC_VERSION);
+    print_option(out,"d","datadir","DIR",OPT_DESC_DATADIR);
+
+    for opt in config::CONFIG_OPTIONS.iter()
+    {
+        print_option(out,opt.short,opt.name,opt.hint,opt.desc);
+    }
+
+    write!(out,"\n");
+    write!(out,"The options but help, version and datadir can also be set in {} in the\n",
+           common::CONFIG_FILE_NAME);
+    write!(out,"data directory, as \"name=value\" lines.\n");
+}
+
+#[allow(unused_must_use)]
+fn print_option(out   : &mut std::io::LineBufferedWriter<std::io::stdio::StdWriter>,
+                short : &str,
+                name  : &str,
+                hint  : &str,
+                desc  : &str)
+{
+    let mut opt : String = String::from_str("  ");
+
+    if short.is_empty()
+    {
+        opt.push_str("    ");
+    }
+    else
+    {
+        opt.push_str(format!("-{}, ",short).as_slice());
+    }
+
+    opt.push_str(format!("--{} {}",name,hint).as_slice());
+
+    if opt.len() < OPT_USAGE_WIDTH
+    {
+        opt.push_str(String::from_str(" ").repeat(OPT_USAGE_WIDTH-opt.len()).as_slice());
+    }
+    else
+    {
+        opt.push_str(" ");
+    }
+
+    write!(out,"{}{}\n",opt,desc);
 }
 
 #[allow(unused_must_use)]
@@ -61,11 +132,19 @@ fn print_version(out : &mut std::io::LineBufferedWriter<std::io::stdio::StdWrite
 
 fn parse_options() -> Option<Options>
 {
-    let opts = [ optflag("h", "help",    OPT_DESC_HELP),
-                 optflag("v", "version", OPT_DESC_VERSION) ];
+    let mut opts = vec![ optflag("h", "help",    OPT_DESC_HELP),
+                         optflag("v", "version", OPT_DESC_VERSION),
+                         optopt("d",  "datadir", OPT_DESC_DATADIR, "DIR") ];
     let matches : getopts::Matches;
+    let mut config : Config;
+    let path : Path;
+
+    for opt in config::CONFIG_OPTIONS.iter()
+    {
+        opts.push(optopt(opt.short,opt.name,opt.desc,opt.hint));
+    }
 
-    matches = match getopts::getopts(std::os::args().as_slice(), &opts)
+    matches = match getopts::getopts(std::os::args().as_slice(), opts.as_slice())
     {
         Ok(m)  => m,
         Err(e) =>
@@ -81,86 +160,136 @@ fn parse_options() -> Option<Options>
         return None;
     }
 
-    Some(Options { help:    matches.opt_present("h"),
-                   version: matches.opt_present("v") })
-}
+    config = Config::new(match matches.opt_str("d")
+                         {
+                             Some(dir) => Path::new(dir),
+                             None      => common::default_datadir()
+                         });
 
-fn run_peer(address      : SocketAddr,
-            addr_channel : AddrManagerChannel) -> Result<(),peer::PeerError>
-{
-    let mut peer : peer::Peer = peer::Peer::new(address,addr_channel);
+    /* The configuration file first, then the command line overrides it */
+    path = config.datadir.join(common::CONFIG_FILE_NAME);
 
-    try!(peer.connect());
-    try!(peer.send_version());
-
-    peer.read_loop()
-}
-
-fn spawn_thread_run_peer(address      : SocketAddr,
-                         addr_channel : AddrManagerChannel)
-{
-    spawn(proc() {
-        match run_peer(address,addr_channel)
+    if path.exists()
+    {
+        match config.read_file(&path)
         {
+            Ok(())   => (),
             Err(err) =>
             {
-                logger::log_peer_error_fatal(&address, err);
-            },
-            _        => unreachable!()
+                (write!(&mut std::io::stderr(),"error: {}: {}\n",path.display(),err)).unwrap();
+                return None;
+            }
         }
-    });
+    }
+
+    for opt in config::CONFIG_OPTIONS.iter()
+    {
+        for value in matches.opt_strs(opt.name).iter()
+        {
+            match config.set(opt.name,value.as_slice())
+            {
+                Ok(())   => (),
+                Err(err) =>
+                {
+                    (write!(&mut std::io::stderr(),"error: {}\n",err)).unwrap();
+                    return None;
+                }
+            }
+        }
+    }
+
+    Some(Options { help:    matches.opt_present("h"),
+                   version: matches.opt_present("v"),
+                   config:  config })
 }
 
-fn spawn_thread_run_address_manager(orchestrator : DuplexChannel<AddrManagerReply,
-                                                                 AddrManagerRequest>)
+fn spawn_thread_run_onion_service(config       : Arc<Config>,
+                                  logger       : NodeLogger,
+                                  addr_channel : AddrManagerChannel)
 {
     spawn(proc() {
-        let mut addr_mng : AddrManager;
+        logger.install();
 
-        addr_mng = AddrManager::new(orchestrator);
-
-        addr_mng.read_loop();
+        match torcontrol::run_onion_service(&*config,addr_channel)
+        {
+            Err(err) => logger::log_tor_control_error(&err),
+            Ok(())   => ()
+        }
     });
 }
 
-fn run_peers()
+/* Returns when the node is told to stop through the RPC server */
+fn run_peers(config : Arc<Config>, logger : NodeLogger)
 {
     let mut addrs : Vec<SocketAddr>;
-    let (channel_us, channel_addrmng)
-        = comm::sync_duplex_channel(addrmng::ADDRMNG_CHANNEL_BUF_CAP);
+    let node : Node = Node::start(config.clone(),logger.clone());
+    let (stop_sender, stop) = channel::<()>();
 
-    addrs = discover_peers(config::INITIAL_DISCOVERY_PEERS);
+    addrs = discover_peers(&*config);
 
     /* For testing */
-    addrs.push(SocketAddr { ip: std::io::net::ip::Ipv4Addr(127,0,0,1),   port: 8333 });
-    addrs.push(SocketAddr { ip: std::io::net::ip::Ipv4Addr(192,168,1,2), port: 8333 });
+    addrs.push(SocketAddr { ip: std::io::net::ip::Ipv4Addr(127,0,0,1),
+                            port: config.get_params().default_port });
+    addrs.push(SocketAddr { ip: std::io::net::ip::Ipv4Addr(192,168,1,2),
+                            port: config.get_params().default_port });
     addrs.reverse();
 
-    spawn_thread_run_address_manager(channel_addrmng);
+    if config.onion_service
+    {
+        spawn_thread_run_onion_service(config.clone(),logger.clone(),node.new_addr_channel());
+    }
 
     for addrs in addrs.iter()
     {
-        let (channel_peer, channel_addrmng)
-            = comm::sync_duplex_channel(addrmng::ADDRMNG_CHANNEL_BUF_CAP);
+        node.connect(*addrs);
+    }
 
-        channel_us.sender.send(AddrManagerRequest::AddrMngAddPeerChannel(channel_addrmng));
+    /* Accept the connections the onion service forwards to us.  Tor connects
+     * from the loopback, so all the inbound peers have the same address.
+     */
+    if config.onion_service && node.listen(config.onion_listen).is_err()
+    {
+        logger::log_tor_control_error(&torcontrol::TorControlError::InvalidListenAddress);
+    }
 
-        spawn_thread_run_peer(*addrs,channel_peer);
+    if config.listen
+    {
+        let addr : SocketAddr = SocketAddr { ip: std::io::net::ip::Ipv4Addr(0,0,0,0),
+                                             port: config.get_port() };
+
+        if node.listen(addr).is_err()
+        {
+            logger::log_listen_error(&addr);
+        }
     }
 
-    /* Loop while we occasionally add more peers */
-    loop
+    if config.rpc
     {
-        /* TODO We should at least try to get a few peers for the same
-         * /12 subnetwork, to reduce latency.
-         */
-        sleep(Duration::seconds(60)); // XXX TODO
+        match rpc::start(&node,stop_sender.clone())
+        {
+            Ok(_)    => (),
+            Err(err) =>
+            {
+                (write!(&mut std::io::stderr(),"error: cannot start the RPC server: {}\n",
+                        err)).unwrap();
+                std::os::set_exit_status(-1);
+                return;
+            }
+        }
     }
+
+    /* TODO We should at least try to get a few peers for the same /12
+     * subnetwork, to reduce latency.
+     */
+    stop.recv();
+
+    rpc::remove_cookie(&*config);
 }
 
 fn main()
 {
     let options : Options;
+    let logger : NodeLogger;
 
     options = match parse_options() {
         Some(opt) => opt,
@@ -182,7 +311,23 @@ fn main()
         return;
     }
 
-    run_peers();
+    logger = match NodeLogger::new(&options.config)
+    {
+        Ok(logger) => logger,
+        Err(err)   =>
+        {
+            (write!(&mut std::io::stderr(),"error: cannot open the log file: {}\n",err)).unwrap();
+            std::os::set_exit_status(-1);
+            return;
+        }
+    };
+
+    logger.install();
+
+    run_peers(Arc::new(options.config),logger);
+
+    /* The runtime would wait for the peers and the managers, which never end */
+    unsafe { libc::exit(std::os::get_exit_status() as libc::c_int); }
 }
 
 /* TODO:
@@ -190,7 +335,8 @@ fn main()
  *  * There are asserts that need to be verified in runtime and handled
  *    gracefully instead of terminating the task
  *    (eg. Unmarshalling::read_strvar()).
- *  * Carefuly audit block consensus to be 100% equal to the core implementation
+ *  * Carefuly audit block consensus (consensus.rs) to be 100% equal to the core
+ *    implementation
  *     * Test block acceptence: https://github.com/TheBlueMatt/test-scripts
  *     * There will be an official concesus library. When that's ready, use it.
  *
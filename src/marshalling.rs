@@ -4,6 +4,8 @@ use std::io::net::ip::SocketAddr;
 use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use datatype::netaddr::NetAddr;
+use datatype::netaddr::NetAddrV2;
+use datatype::netaddr::MAX_ADDRV2_SIZE;
 use datatype::invvect::InvVect;
 use datatype::transaction::Transaction;
 use datatype::transaction::TxLock;
@@ -13,10 +15,23 @@ use datatype::transaction::OutPoint;
 use datatype::value::Value;
 use datatype::script::Script;
 use datatype::hash::Hash;
+use datatype::block::BlockHeader;
+use datatype::block::Block;
+use datatype::bloom::BloomFilter;
+use datatype::merkle::PartialMerkleTree;
+use datatype::blockfilter::BlockFilter;
+use datatype::compact::HeaderAndShortIds;
+use datatype::compact::PrefilledTx;
+use datatype::compact::BlockTxRequest;
+use datatype::compact::BlockTransactions;
+use datatype::compact::SHORTID_SIZE;
 
 const VARSTR_MAX_LENGTH : uint = 256;
+/* The same as the reference implementation, whose reject reasons and user
+ * agents use '-' and parentheses.
+ */
 const VARSTR_SAFE_CHARS : &'static str
-    = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ01234567890 .,;_/:?@";
+    = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ01234567890 .,;-_/:?@()";
 
 /* TODO don't use Vec<u8>. use &[u8]
  */
@@ -177,7 +192,13 @@ impl Marshalling
                         self.write(&[0xffu8, ..2]);
                         self.write(&[b3,b2,b1,b0]);
                     },
-                    Ipv6Addr(..) => unimplemented!() /* TODO ipv6 */
+                    Ipv6Addr(s0, s1, s2, s3, s4, s5, s6, s7) =>
+                    {
+                        for s in [s0,s1,s2,s3,s4,s5,s6,s7].iter()
+                        {
+                            self.write(&[(*s>>8) as u8, (*s&0xff) as u8]);
+                        }
+                    }
                 };
 
                 /* port is encoded in network order (big endian) */
@@ -194,6 +215,24 @@ impl Marshalling
         };
     }
 
+    /* BIP155: the services are a varint and the address has a network id */
+    pub fn write_netaddrv2(&mut self, netaddr : &NetAddrV2)
+    {
+        match netaddr.time
+        {
+            Some(ts) => self.write_timestampu32(ts),
+            None     => self.write_uint32(0),
+        }
+
+        self.write_varint(netaddr.services as u64);
+        self.write_uint8(netaddr.network);
+        self.write_varbytes(netaddr.addr.as_slice());
+
+        /* port is encoded in network order (big endian) */
+        self.write(&[(netaddr.port>>8) as u8,
+                     (netaddr.port&0xff) as u8]);
+    }
+
     pub fn write_hash(&mut self, hash : &Hash)
     {
         for i in range(0u,32).rev()
@@ -213,10 +252,22 @@ impl Marshalling
         }
     }
 
-    pub fn write_script(&mut self, _s : &Script)
+    pub fn write_outpoint(&mut self, outpoint : &OutPoint)
+    {
+        self.write_hash(outpoint.get_hash());
+        self.write_uint32(outpoint.get_index());
+    }
+
+    pub fn write_varbytes(&mut self, bytes : &[u8])
     {
-        self.write_varint(0u64);
-        /* TODO write script */
+        self.write_varint(bytes.len() as u64);
+        self.write(bytes);
+    }
+
+    pub fn write_script(&mut self, s : &Script)
+    {
+        self.write_varint(s.len() as u64);
+        self.write(s.get_bytes().as_slice());
     }
 
     pub fn write_value(&mut self, v : &Value)
@@ -235,10 +286,7 @@ impl Marshalling
 
         for in_tx in tx.get_in_txs().iter()
         {
-            let prev_out : &OutPoint = in_tx.get_prev_out();
-
-            self.write_hash(prev_out.get_hash());
-            self.write_uint32(prev_out.get_index());
+            self.write_outpoint(in_tx.get_prev_out());
             self.write_script(in_tx.get_script());
             self.write_uint32(in_tx.get_sequence());
         }
@@ -248,9 +296,7 @@ impl Marshalling
         for out_tx in tx.get_out_txs().iter()
         {
             self.write_value(out_tx.get_value());
-
-            self.write_varint(0u64); /* TODO XXX write script */
-            /* TODO XXX write script */
+            self.write_script(out_tx.get_script());
         }
 
         match tx.get_lock()
@@ -262,6 +308,144 @@ impl Marshalling
         }
     }
 
+    pub fn write_block_header(&mut self, header : &BlockHeader)
+    {
+        self.write_uint32(header.get_version());
+        self.write_hash(header.get_prev_block());
+        self.write_hash(header.get_merkle_root());
+        self.write_timestampu32(header.get_time());
+        self.write_uint32(header.get_bits());
+        self.write_uint32(header.get_nounce());
+    }
+
+    pub fn write_block(&mut self, block : &Block)
+    {
+        self.write_block_header(block.get_header());
+
+        self.write_varint(block.get_txs().len() as u64);
+
+        for tx in block.get_txs().iter()
+        {
+            self.write_transaction(tx);
+        }
+    }
+
+    pub fn write_bloom_filter(&mut self, filter : &BloomFilter)
+    {
+        self.write_varbytes(filter.get_data().as_slice());
+        self.write_uint32(filter.get_hash_funcs());
+        self.write_uint32(filter.get_tweak());
+        self.write_uint8(filter.get_flags());
+    }
+
+    pub fn write_partial_merkle_tree(&mut self, tree : &PartialMerkleTree)
+    {
+        let flags : &Vec<bool> = tree.get_flags();
+        let mut bytes : Vec<u8> = Vec::from_elem((flags.len()+7)/8,0u8);
+
+        self.write_uint32(tree.get_tx_count());
+
+        self.write_varint(tree.get_hashes().len() as u64);
+
+        for hash in tree.get_hashes().iter()
+        {
+            self.write_hash(hash);
+        }
+
+        for (i, flag) in flags.iter().enumerate()
+        {
+            if *flag
+            {
+                bytes[i/8] |= 1 << (i%8);
+            }
+        }
+
+        self.write_varbytes(bytes.as_slice());
+    }
+
+    pub fn write_hashes(&mut self, hashes : &Vec<Hash>)
+    {
+        self.write_varint(hashes.len() as u64);
+
+        for hash in hashes.iter()
+        {
+            self.write_hash(hash);
+        }
+    }
+
+    pub fn write_block_filter(&mut self, filter : &BlockFilter)
+    {
+        self.write_uint8(filter.get_filter_type());
+        self.write_hash(filter.get_block_hash());
+        self.write_varbytes(filter.get_encoded().as_slice());
+    }
+
+    /* Indexes are sent as the difference with the previous one minus one */
+    fn write_diff_index(&mut self, index : uint, last : &mut Option<uint>)
+    {
+        let diff : uint = match *last
+        {
+            Some(l) => index-l-1,
+            None    => index
+        };
+
+        self.write_varint(diff as u64);
+
+        *last = Some(index);
+    }
+
+    pub fn write_header_and_short_ids(&mut self, cmpct : &HeaderAndShortIds)
+    {
+        let mut last : Option<uint> = None;
+
+        self.write_block_header(cmpct.get_header());
+        self.write_uint64(cmpct.get_nonce());
+
+        self.write_varint(cmpct.get_short_ids().len() as u64);
+
+        for short_id in cmpct.get_short_ids().iter()
+        {
+            for i in range(0,SHORTID_SIZE)
+            {
+                self.buf.push(((*short_id>>8*i)&0xff) as u8);
+            }
+        }
+
+        self.write_varint(cmpct.get_prefilled().len() as u64);
+
+        for prefilled in cmpct.get_prefilled().iter()
+        {
+            self.write_diff_index(prefilled.index,&mut last);
+            self.write_transaction(&prefilled.tx);
+        }
+    }
+
+    pub fn write_block_tx_request(&mut self, request : &BlockTxRequest)
+    {
+        let mut last : Option<uint> = None;
+
+        self.write_hash(request.get_block_hash());
+
+        self.write_varint(request.get_indexes().len() as u64);
+
+        for index in request.get_indexes().iter()
+        {
+            self.write_diff_index(*index,&mut last);
+        }
+    }
+
+    pub fn write_block_transactions(&mut self, txs : &BlockTransactions)
+    {
+        self.write_hash(txs.get_block_hash());
+
+        self.write_varint(txs.get_txs().len() as u64);
+
+        for tx in txs.get_txs().iter()
+        {
+            self.write_transaction(tx);
+        }
+    }
+
     pub fn get(&self) -> Vec<u8>
     {
         self.buf.clone()
@@ -307,6 +491,26 @@ impl Unmarshalling
         self.pos += s;
     }
 
+    /* Number of bytes left to read */
+    pub fn remaining(&self) -> uint
+    {
+        self.buf.len()-self.pos
+    }
+
+    pub fn read_bytes(&mut self, len : uint) -> Vec<u8>
+    {
+        let mut bytes : Vec<u8>;
+
+        assert!(self.pos+len <= self.buf.len());
+
+        bytes = Vec::with_capacity(len);
+        bytes.push_all(self.buf.slice(self.pos,self.pos+len));
+
+        self.pos += len;
+
+        bytes
+    }
+
     pub fn read_uint8(&mut self) -> u8
     {
         let v : u8;
@@ -496,30 +700,31 @@ impl Unmarshalling
 
         services = self.read_uint64();
 
-        for i in range(0u,10)
+        /* IPv4 addresses are mapped to ::ffff:0:0/96 */
+        if self.buf.slice(self.pos,self.pos+10).iter().all(|b| *b == 0x00u8)
+            && self.buf[self.pos+10] == 0xffu8 && self.buf[self.pos+11] == 0xffu8
         {
-            if self.buf[self.pos] != 0x00u8
-            {
-                println!("unimplemented: read_netaddr() IPv6"); /* TODO */
+            self.pos += 12;
 
-                self.skip(10-i+8);
+            addr = Ipv4Addr(self.buf[self.pos],
+                            self.buf[self.pos+1],
+                            self.buf[self.pos+2],
+                            self.buf[self.pos+3]);
 
-                return NetAddr::new(time,services,None);
-            }
-
-            self.pos += 1;
+            self.pos += 4;
         }
+        else
+        {
+            let mut s : [u16, ..8] = [0u16, ..8];
 
-        assert!(self.buf[self.pos] == 0xffu8 && self.buf[self.pos+1] == 0xffu8);
-
-        self.pos += 2; /* skip FF FF */
-
-        addr = Ipv4Addr(self.buf[self.pos],
-                        self.buf[self.pos+1],
-                        self.buf[self.pos+2],
-                        self.buf[self.pos+3]);
+            for i in range(0u,8)
+            {
+                s[i] = ((self.buf[self.pos] as u16)<<8) | (self.buf[self.pos+1] as u16);
+                self.pos += 2;
+            }
 
-        self.pos += 4;
+            addr = Ipv6Addr(s[0],s[1],s[2],s[3],s[4],s[5],s[6],s[7]);
+        }
 
         /* port is encoded in network order (big endian) */
         port = ((self.buf[self.pos] as u16)<<8) | (self.buf[self.pos+1] as u16);
@@ -528,7 +733,7 @@ impl Unmarshalling
 
         socketaddr = None;
 
-        if addr != Ipv4Addr(0,0,0,0)
+        if addr != Ipv4Addr(0,0,0,0) && addr != Ipv6Addr(0,0,0,0,0,0,0,0)
         {
             socketaddr = Some(SocketAddr {
                 ip:   addr,
@@ -539,6 +744,38 @@ impl Unmarshalling
         NetAddr::new(time,services,socketaddr)
     }
 
+    /* None if the address is longer than BIP155 allows, the whole message
+     * is then refused.
+     */
+    pub fn read_netaddrv2(&mut self) -> Option<NetAddrV2>
+    {
+        let time : time::Timespec;
+        let services : ::config::Services;
+        let network : u8;
+        let addr : Vec<u8>;
+        let port : u16;
+
+        time = self.read_timestampu32();
+        services = self.read_varint() as ::config::Services;
+        network = self.read_uint8();
+
+        addr = self.read_varbytes();
+
+        if addr.len() > MAX_ADDRV2_SIZE
+        {
+            return None;
+        }
+
+        assert!(self.pos+2 <= self.buf.len());
+
+        /* port is encoded in network order (big endian) */
+        port = ((self.buf[self.pos] as u16)<<8) | (self.buf[self.pos+1] as u16);
+
+        self.pos += 2;
+
+        Some(NetAddrV2::new(Some(time),services,network,addr,port))
+    }
+
     pub fn read_hash(&mut self) -> Hash
     {
         let mut hash : [u8, ..32] = [0, ..32];
@@ -561,17 +798,23 @@ impl Unmarshalling
 
         count = self.read_varint();
 
-        assert!(count <= 50000);
+        assert!(count <= ::message::inv::MSG_INV_MAX as u64);
 
         for _ in range(0,count)
         {
             let typ : ::datatype::invvect::InvEntryType;
 
-            typ = match self.read_uint32() {
-                0 => ::datatype::invvect::InvEntryType::Error,
+            /* We have no witness data, so asking for it is the same as
+             * asking for the plain tx or block.  Unknown types are read as
+             * errors and ignored.
+             */
+            typ = match self.read_uint32() & !::datatype::invvect::MSG_WITNESS_FLAG {
                 1 => ::datatype::invvect::InvEntryType::MsgTx,
                 2 => ::datatype::invvect::InvEntryType::MsgBlock,
-                _ => panic!("invalid type of inventory entry")
+                3 => ::datatype::invvect::InvEntryType::MsgFilteredBlock,
+                4 => ::datatype::invvect::InvEntryType::MsgCmpctBlock,
+                5 => ::datatype::invvect::InvEntryType::MsgWitnessTx,
+                _ => ::datatype::invvect::InvEntryType::Error
             };
 
             invvec.add(::datatype::invvect::InvEntry {
@@ -588,9 +831,8 @@ impl Unmarshalling
         let script_len;
 
         script_len = self.read_varint();
-        self.skip(script_len as uint); /* TODO read script */
 
-        Script::new()
+        Script::from_bytes(self.read_bytes(script_len as uint))
     }
 
     pub fn read_value(&mut self) -> Value
@@ -625,14 +867,10 @@ impl Unmarshalling
         for _ in range(0,self.read_varint())
         {
             let value : Value;
-            let script_len : u64;
             let script : Script;
 
             value = self.read_value();
-
-            script_len = self.read_varint(); /* XXX read script */
-            self.skip(script_len as uint); /* XXX read script */
-            script = Script::new(); /* XXX read script */
+            script = self.read_script();
 
             txs_out.push(TxOut::new(value,script));
         }
@@ -642,6 +880,224 @@ impl Unmarshalling
         Transaction::new(version,txs_in,txs_out,lock)
     }
 
+    pub fn read_varbytes(&mut self) -> Vec<u8>
+    {
+        let len : uint = self.read_varint() as uint;
+
+        self.read_bytes(len)
+    }
+
+    pub fn read_bloom_filter(&mut self) -> BloomFilter
+    {
+        let data : Vec<u8>;
+        let hash_funcs : u32;
+        let tweak : u32;
+        let flags : u8;
+
+        data = self.read_varbytes();
+        hash_funcs = self.read_uint32();
+        tweak = self.read_uint32();
+        flags = self.read_uint8();
+
+        BloomFilter::new(data,hash_funcs,tweak,flags)
+    }
+
+    pub fn read_partial_merkle_tree(&mut self) -> PartialMerkleTree
+    {
+        let tx_count : u32;
+        let mut hashes : Vec<Hash> = Vec::new();
+        let mut flags : Vec<bool> = Vec::new();
+        let bytes : Vec<u8>;
+
+        tx_count = self.read_uint32();
+
+        for _ in range(0,self.read_varint())
+        {
+            hashes.push(self.read_hash());
+        }
+
+        bytes = self.read_varbytes();
+
+        for i in range(0,bytes.len()*8)
+        {
+            flags.push(bytes[i/8] & (1 << (i%8)) != 0);
+        }
+
+        PartialMerkleTree::new(tx_count,hashes,flags)
+    }
+
+    pub fn read_hashes(&mut self, max : uint) -> Vec<Hash>
+    {
+        let mut hashes : Vec<Hash> = Vec::new();
+        let count : u64 = self.read_varint();
+
+        assert!(count <= max as u64);
+
+        for _ in range(0,count)
+        {
+            hashes.push(self.read_hash());
+        }
+
+        hashes
+    }
+
+    /* None if the index is past the txs fitting in a block */
+    fn read_diff_index(&mut self, last : &mut Option<uint>) -> Option<uint>
+    {
+        let max : u64 = (::consensus::MAX_BLOCK_SIZE/60) as u64;
+        let diff : u64 = self.read_varint();
+        let index : u64;
+
+        if diff >= max
+        {
+            return None;
+        }
+
+        index = match *last
+        {
+            Some(l) => (l as u64)+diff+1,
+            None    => diff
+        };
+
+        if index >= max
+        {
+            return None;
+        }
+
+        *last = Some(index as uint);
+
+        Some(index as uint)
+    }
+
+    /* None if the short ids or the prefilled indexes are malformed */
+    pub fn read_header_and_short_ids(&mut self) -> Option<HeaderAndShortIds>
+    {
+        let header : BlockHeader;
+        let nonce : u64;
+        let mut short_ids : Vec<u64> = Vec::new();
+        let mut prefilled : Vec<PrefilledTx> = Vec::new();
+        let mut last : Option<uint> = None;
+
+        header = self.read_block_header();
+        nonce = self.read_uint64();
+
+        for _ in range(0,self.read_varint())
+        {
+            let mut short_id : u64 = 0;
+
+            if self.pos+SHORTID_SIZE > self.buf.len()
+            {
+                return None;
+            }
+
+            for i in range(0,SHORTID_SIZE)
+            {
+                short_id |= self.buf[self.pos] as u64 << 8*i;
+                self.pos += 1;
+            }
+
+            short_ids.push(short_id);
+        }
+
+        for _ in range(0,self.read_varint())
+        {
+            let index : uint = match self.read_diff_index(&mut last)
+            {
+                Some(index) => index,
+                None        => return None
+            };
+
+            prefilled.push(PrefilledTx { index: index, tx: self.read_transaction() });
+        }
+
+        Some(HeaderAndShortIds::new(header,nonce,short_ids,prefilled))
+    }
+
+    /* None if an index is past the txs fitting in a block */
+    pub fn read_block_tx_request(&mut self) -> Option<BlockTxRequest>
+    {
+        let block_hash : Hash;
+        let mut indexes : Vec<uint> = Vec::new();
+        let mut last : Option<uint> = None;
+
+        block_hash = self.read_hash();
+
+        for _ in range(0,self.read_varint())
+        {
+            match self.read_diff_index(&mut last)
+            {
+                Some(index) => indexes.push(index),
+                None        => return None
+            }
+        }
+
+        Some(BlockTxRequest::new(block_hash,indexes))
+    }
+
+    pub fn read_block_transactions(&mut self) -> BlockTransactions
+    {
+        let block_hash : Hash;
+        let mut txs : Vec<Transaction> = Vec::new();
+
+        block_hash = self.read_hash();
+
+        for _ in range(0,self.read_varint())
+        {
+            txs.push(self.read_transaction());
+        }
+
+        BlockTransactions::new(block_hash,txs)
+    }
+
+    pub fn read_block_filter(&mut self) -> BlockFilter
+    {
+        let filter_type : u8;
+        let block_hash : Hash;
+        let encoded : Vec<u8>;
+
+        filter_type = self.read_uint8();
+        block_hash = self.read_hash();
+        encoded = self.read_varbytes();
+
+        BlockFilter::new(filter_type,block_hash,encoded)
+    }
+
+    pub fn read_block_header(&mut self) -> BlockHeader
+    {
+        let version : u32;
+        let prev_block : Hash;
+        let merkle_root : Hash;
+        let time : time::Timespec;
+        let bits : u32;
+        let nounce : u32;
+
+        assert!(self.pos+::datatype::block::BLOCK_HEADER_SIZE <= self.buf.len());
+
+        version = self.read_uint32();
+        prev_block = self.read_hash();
+        merkle_root = self.read_hash();
+        time = self.read_timestampu32();
+        bits = self.read_uint32();
+        nounce = self.read_uint32();
+
+        BlockHeader::new(version,prev_block,merkle_root,time,bits,nounce)
+    }
+
+    pub fn read_block(&mut self) -> Block
+    {
+        let header : BlockHeader;
+        let mut txs : Vec<Transaction> = Vec::new();
+
+        header = self.read_block_header();
+
+        for _ in range(0,self.read_varint())
+        {
+            txs.push(self.read_transaction());
+        }
+
+        Block::new(header,txs)
+    }
+
     pub fn consumed(&self) -> uint
     {
         self.pos
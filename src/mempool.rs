@@ -0,0 +1,679 @@
+extern crate time;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use self::time::Timespec;
+use std::time::duration::Duration;
+
+use datatype::block::Block;
+use datatype::transaction::Transaction;
+use datatype::transaction::OutPoint;
+use datatype::hash::Hash;
+use datatype::value::Value;
+
+use consensus::ValidationError;
+
+use chain::Chain;
+
+use utxo::UtxoView;
+use utxo::Coin;
+
+use message::reject::RejectType;
+
+pub const MAX_MEMPOOL_SIZE : uint = 300*(1<<20); /* 300MB */
+const MEMPOOL_EXPIRY_H : i64 = 14*24;
+
+/* Satoshis per 1000 bytes */
+pub const MIN_RELAY_FEE_RATE : u64 = 1000;
+
+/* When the mempool is full, the minimum fee rate is raised above the fee
+ * rate of the evicted txs, and it halves every this many hours.
+ */
+const MEMPOOL_MIN_FEE_HALFLIFE_H : i64 = 12;
+
+const MAX_ANCESTORS : uint = 25;
+const MAX_DESCENDANTS : uint = 25;
+
+/* Most txs a replacement can evict, with their descendants */
+const MAX_REPLACEMENT_CANDIDATES : uint = 100;
+
+/* Height we give to coins created by transactions in the mempool */
+const MEMPOOL_HEIGHT : u32 = 0x7fffffff;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum MempoolError
+{
+    MempoolInvalid(ValidationError),
+    MempoolCoinbase,
+    MempoolDuplicate,
+    MempoolSpendsConflicting,
+    MempoolTooManyReplacements,
+    MempoolReplacementFee,
+    MempoolNonFinal,
+    MempoolInsufficientFee,
+    MempoolTooLongChain,
+    MempoolFull
+}
+
+impl MempoolError
+{
+    pub fn get_reject_type(&self) -> RejectType
+    {
+        match *self
+        {
+            MempoolError::MempoolInvalid(ref err)    => err.get_reject_type(),
+            MempoolError::MempoolCoinbase            => RejectType::RejectInvalid,
+            MempoolError::MempoolDuplicate           => RejectType::RejectDuplicate,
+            MempoolError::MempoolSpendsConflicting   => RejectType::RejectInvalid,
+            MempoolError::MempoolTooManyReplacements => RejectType::RejectNonstandard,
+            MempoolError::MempoolReplacementFee      => RejectType::RejectInsufficientFee,
+            MempoolError::MempoolNonFinal            => RejectType::RejectNonstandard,
+            MempoolError::MempoolInsufficientFee     => RejectType::RejectInsufficientFee,
+            MempoolError::MempoolTooLongChain        => RejectType::RejectNonstandard,
+            MempoolError::MempoolFull                => RejectType::RejectInsufficientFee
+        }
+    }
+
+    /* Reason as sent by the reference implementation in reject messages */
+    pub fn get_reason(&self) -> &'static str
+    {
+        match *self
+        {
+            MempoolError::MempoolInvalid(ref err)    => err.get_reason(),
+            MempoolError::MempoolCoinbase            => "coinbase",
+            MempoolError::MempoolDuplicate           => "txn-already-in-mempool",
+            MempoolError::MempoolSpendsConflicting   => "bad-txns-spends-conflicting-tx",
+            MempoolError::MempoolTooManyReplacements => "too many potential replacements",
+            MempoolError::MempoolReplacementFee      => "insufficient fee",
+            MempoolError::MempoolNonFinal            => "non-final",
+            MempoolError::MempoolInsufficientFee     => "min relay fee not met",
+            MempoolError::MempoolTooLongChain        => "too-long-mempool-chain",
+            MempoolError::MempoolFull                => "mempool full"
+        }
+    }
+}
+
+struct MempoolEntry
+{
+    tx       : Transaction,
+    fee      : Value,
+    size     : uint,
+    sigops   : uint,
+    time     : Timespec,   /* When it entered the mempool */
+    parents  : HashSet<Hash>,
+    children : HashSet<Hash>
+}
+
+impl MempoolEntry
+{
+    /* Satoshis per 1000 bytes */
+    fn get_fee_rate(&self) -> u64
+    {
+        self.fee.get_satoshis()*1000/(self.size as u64)
+    }
+}
+
+/* The UTXO set as seen with the mempool on top of it.
+ */
+struct MempoolView<'a>
+{
+    mempool : &'a Mempool,
+    chain   : &'a Chain
+}
+
+impl<'a> UtxoView for MempoolView<'a>
+{
+    fn get_coin(&self, outpoint : &OutPoint) -> Option<Coin>
+    {
+        match self.mempool.entries.get(outpoint.get_hash())
+        {
+            Some(entry) =>
+            {
+                let outs = entry.tx.get_out_txs();
+
+                if (outpoint.get_index() as uint) < outs.len()
+                {
+                    let out = outs[outpoint.get_index() as uint].clone();
+
+                    Some(Coin::new(out,MEMPOOL_HEIGHT,false))
+                }
+                else
+                {
+                    None
+                }
+            },
+            None => self.chain.get_utxos().get_coin(outpoint)
+        }
+    }
+}
+
+pub struct Mempool
+{
+    entries      : HashMap<Hash,MempoolEntry>,
+    spent        : HashMap<OutPoint,Hash>,   /* Outpoints spent by the mempool */
+    total_size   : uint,
+    min_fee_rate : u64,                      /* Raised when evicting */
+    min_fee_time : Timespec                  /* When it was raised */
+}
+
+#[allow(dead_code)]
+impl Mempool
+{
+    pub fn new() -> Mempool
+    {
+        Mempool
+        {
+            entries:      HashMap::new(),
+            spent:        HashMap::new(),
+            total_size:   0,
+            min_fee_rate: 0,
+            min_fee_time: time::now_utc().to_timespec()
+        }
+    }
+
+    pub fn len(&self) -> uint
+    {
+        self.entries.len()
+    }
+
+    /* Total size in bytes of the transactions in the mempool */
+    pub fn get_size(&self) -> uint
+    {
+        self.total_size
+    }
+
+    pub fn contains(&self, hash : &Hash) -> bool
+    {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash : &Hash) -> Option<&Transaction>
+    {
+        self.entries.get(hash).map(|e| &e.tx)
+    }
+
+    pub fn get_txs(&self) -> Vec<&Transaction>
+    {
+        self.entries.values().map(|e| &e.tx).collect()
+    }
+
+    pub fn get_fee_rate(&self, hash : &Hash) -> Option<u64>
+    {
+        self.entries.get(hash).map(|e| e.get_fee_rate())
+    }
+
+    /* The coin as seen with the mempool on top of the chain: the outputs the
+     * mempool spends are spent, the ones it creates are not.
+     */
+    pub fn get_coin(&self, outpoint : &OutPoint, chain : &Chain) -> Option<Coin>
+    {
+        if self.spent.contains_key(outpoint)
+        {
+            return None;
+        }
+
+        MempoolView { mempool: self, chain: chain }.get_coin(outpoint)
+    }
+
+    /* Minimum fee rate to enter the mempool */
+    pub fn get_min_fee_rate(&self) -> u64
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let halvings : i64 = (now-self.min_fee_time).num_hours()/MEMPOOL_MIN_FEE_HALFLIFE_H;
+        let rate : u64 = if halvings < 64 { self.min_fee_rate >> halvings as uint } else { 0 };
+
+        ::std::cmp::max(rate,MIN_RELAY_FEE_RATE)
+    }
+
+    /* Hashes of all transactions, highest fee rate first */
+    pub fn get_hashes_by_fee_rate(&self) -> Vec<Hash>
+    {
+        let mut rates : Vec<(u64, &Hash)>;
+
+        rates = self.entries.iter().map(|(h, e)| (e.get_fee_rate(), h)).collect();
+
+        rates.sort_by(|&(a, _), &(b, _)| b.cmp(&a));
+
+        rates.iter().map(|&(_, h)| h.clone()).collect()
+    }
+
+    /* Transactions for a new block of up to max_size bytes and max_sigops
+     * signature operations, highest fee rate first but always after their
+     * parents, and their total fee.
+     */
+    pub fn select_for_block(&self, max_size : uint, max_sigops : uint)
+                            -> (Vec<Transaction>, Value)
+    {
+        let mut selected : HashSet<Hash> = HashSet::new();
+        let mut txs : Vec<Transaction> = Vec::new();
+        let mut fees : Value = Value::zero();
+        let mut size : uint = 0;
+        let mut sigops : uint = 0;
+        let mut todo : Vec<Hash> = self.get_hashes_by_fee_rate();
+
+        loop
+        {
+            let count : uint = selected.len();
+            let mut waiting : Vec<Hash> = Vec::new();
+
+            for hash in todo.into_iter()
+            {
+                let entry : &MempoolEntry = self.entries.get(&hash).unwrap();
+
+                if !entry.parents.iter().all(|p| selected.contains(p))
+                {
+                    waiting.push(hash);
+                    continue;
+                }
+
+                if size+entry.size > max_size || sigops+entry.sigops > max_sigops
+                {
+                    continue;
+                }
+
+                size += entry.size;
+                sigops += entry.sigops;
+                fees = fees.checked_add(&entry.fee).unwrap();
+                txs.push(entry.tx.clone());
+                selected.insert(hash);
+            }
+
+            /* Children of txs that did not fit wait forever */
+            if waiting.is_empty() || selected.len() == count
+            {
+                break;
+            }
+
+            todo = waiting;
+        }
+
+        (txs, fees)
+    }
+
+    fn get_ancestors(&self, hash : &Hash) -> HashSet<Hash>
+    {
+        let mut ancestors : HashSet<Hash> = HashSet::new();
+        let mut todo : Vec<Hash> = vec![hash.clone()];
+
+        loop
+        {
+            let h : Hash = match todo.pop() { Some(h) => h, None => break };
+
+            for parent in self.entries.get(&h).unwrap().parents.iter()
+            {
+                if ancestors.insert(parent.clone())
+                {
+                    todo.push(parent.clone());
+                }
+            }
+        }
+
+        ancestors
+    }
+
+    fn get_descendants(&self, hash : &Hash) -> HashSet<Hash>
+    {
+        let mut descendants : HashSet<Hash> = HashSet::new();
+        let mut todo : Vec<Hash> = vec![hash.clone()];
+
+        loop
+        {
+            let h : Hash = match todo.pop() { Some(h) => h, None => break };
+
+            for child in self.entries.get(&h).unwrap().children.iter()
+            {
+                if descendants.insert(child.clone())
+                {
+                    todo.push(child.clone());
+                }
+            }
+        }
+
+        descendants
+    }
+
+    /* Validate the transaction against the UTXO set plus the mempool and add
+     * it.
+     */
+    pub fn accept(&mut self, tx : Transaction, chain : &Chain) -> Result<(),MempoolError>
+    {
+        let hash : Hash = tx.get_hash();
+        let now : Timespec = time::now_utc().to_timespec();
+        let height : u32 = chain.get_height()+1;
+        let mtp : Timespec = ::consensus::get_median_time_past(chain,height-1);
+        let fee : Value;
+        let entry : MempoolEntry;
+        let mut parents : HashSet<Hash> = HashSet::new();
+        let conflicts : HashSet<Hash>;
+
+        match ::consensus::check_transaction(&tx)
+        {
+            Err(err) => return Err(MempoolError::MempoolInvalid(err)),
+            Ok(())   => ()
+        }
+
+        if tx.is_coinbase()
+        {
+            return Err(MempoolError::MempoolCoinbase);
+        }
+
+        if self.contains(&hash)
+        {
+            return Err(MempoolError::MempoolDuplicate);
+        }
+
+        /* The txs spending the same outputs, which it may replace */
+        conflicts = tx.get_in_txs().iter()
+            .filter_map(|i| self.spent.get(i.get_prev_out()))
+            .map(|h| h.clone())
+            .collect();
+
+        /* BIP113: the txs of the next block are checked against the median
+         * time past, not the time of the block.
+         */
+        if !::consensus::is_final_tx(&tx,height,mtp)
+        {
+            return Err(MempoolError::MempoolNonFinal);
+        }
+
+        {
+            let view = MempoolView { mempool: self, chain: chain };
+
+            fee = match ::consensus::check_tx_inputs(&tx,&view,height)
+            {
+                Ok(fee)  => fee,
+                Err(err) => return Err(MempoolError::MempoolInvalid(err))
+            };
+
+            match ::consensus::check_tx_scripts(&tx,&view)
+            {
+                Err(err) => return Err(MempoolError::MempoolInvalid(err)),
+                Ok(())   => ()
+            }
+        }
+
+        for in_tx in tx.get_in_txs().iter()
+        {
+            let parent : &Hash = in_tx.get_prev_out().get_hash();
+
+            if self.contains(parent)
+            {
+                parents.insert(parent.clone());
+            }
+        }
+
+        entry = MempoolEntry
+        {
+            size:     tx.get_size(),
+            sigops:   ::consensus::get_legacy_sigop_count(&tx),
+            tx:       tx,
+            fee:      fee,
+            time:     now,
+            parents:  parents,
+            children: HashSet::new()
+        };
+
+        if entry.get_fee_rate() < self.get_min_fee_rate()
+        {
+            return Err(MempoolError::MempoolInsufficientFee);
+        }
+
+        if !conflicts.is_empty()
+        {
+            try!(self.check_replacement(&entry,&conflicts));
+        }
+
+        try!(self.check_chain_limits(&entry));
+
+        for conflict in conflicts.iter()
+        {
+            self.remove_recursive(conflict);
+        }
+
+        self.add_entry(hash.clone(),entry);
+
+        self.trim_to_size(MAX_MEMPOOL_SIZE);
+
+        if !self.contains(&hash)
+        {
+            return Err(MempoolError::MempoolFull);
+        }
+
+        Ok(())
+    }
+
+    /* Replace by fee, whether or not the conflicting txs signal it (BIP125
+     * without its first rule, like the reference implementation does now).
+     * The replacement must pay a higher fee rate than each tx it conflicts
+     * with, and more fees than all the txs it evicts, plus its own relay.
+     */
+    fn check_replacement(&self, entry     : &MempoolEntry,
+                                conflicts : &HashSet<Hash>) -> Result<(),MempoolError>
+    {
+        let mut evicted : HashSet<Hash> = HashSet::new();
+        let mut evicted_fees : u64 = 0;
+        let fee : u64 = entry.fee.get_satoshis();
+
+        for conflict in conflicts.iter()
+        {
+            if entry.get_fee_rate() <= self.entries.get(conflict).unwrap().get_fee_rate()
+            {
+                return Err(MempoolError::MempoolReplacementFee);
+            }
+
+            evicted.insert(conflict.clone());
+            evicted.extend(self.get_descendants(conflict).into_iter());
+        }
+
+        if evicted.len() > MAX_REPLACEMENT_CANDIDATES
+        {
+            return Err(MempoolError::MempoolTooManyReplacements);
+        }
+
+        if entry.parents.iter().any(|p| evicted.contains(p))
+        {
+            return Err(MempoolError::MempoolSpendsConflicting);
+        }
+
+        for hash in evicted.iter()
+        {
+            evicted_fees += self.entries.get(hash).unwrap().fee.get_satoshis();
+        }
+
+        if fee < evicted_fees
+            || fee-evicted_fees < MIN_RELAY_FEE_RATE*(entry.size as u64)/1000
+        {
+            return Err(MempoolError::MempoolReplacementFee);
+        }
+
+        Ok(())
+    }
+
+    fn check_chain_limits(&self, entry : &MempoolEntry) -> Result<(),MempoolError>
+    {
+        let mut ancestors : HashSet<Hash> = HashSet::new();
+
+        for parent in entry.parents.iter()
+        {
+            ancestors.insert(parent.clone());
+            ancestors.extend(self.get_ancestors(parent).into_iter());
+        }
+
+        if ancestors.len()+1 > MAX_ANCESTORS
+        {
+            return Err(MempoolError::MempoolTooLongChain);
+        }
+
+        /* Like in the reference implementation, the descendants of a tx count
+         * the tx itself, and the new one is added to them.
+         */
+        for ancestor in ancestors.iter()
+        {
+            if self.get_descendants(ancestor).len()+2 > MAX_DESCENDANTS
+            {
+                return Err(MempoolError::MempoolTooLongChain);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_entry(&mut self, hash : Hash, entry : MempoolEntry)
+    {
+        for parent in entry.parents.iter()
+        {
+            self.entries.get_mut(parent).unwrap().children.insert(hash.clone());
+        }
+
+        for in_tx in entry.tx.get_in_txs().iter()
+        {
+            self.spent.insert(in_tx.get_prev_out().clone(),hash.clone());
+        }
+
+        self.total_size += entry.size;
+
+        self.entries.insert(hash,entry);
+    }
+
+    /* Remove a single transaction.  Its children (if any) stay in the mempool,
+     * so this is only correct if the transaction was confirmed or if the
+     * children are also being removed.
+     */
+    fn remove_entry(&mut self, hash : &Hash)
+    {
+        let entry : MempoolEntry = match self.entries.remove(hash)
+        {
+            Some(entry) => entry,
+            None        => return
+        };
+
+        for parent in entry.parents.iter()
+        {
+            match self.entries.get_mut(parent)
+            {
+                Some(p) => { p.children.remove(hash); },
+                None    => ()
+            }
+        }
+
+        for child in entry.children.iter()
+        {
+            match self.entries.get_mut(child)
+            {
+                Some(c) => { c.parents.remove(hash); },
+                None    => ()
+            }
+        }
+
+        for in_tx in entry.tx.get_in_txs().iter()
+        {
+            self.spent.remove(in_tx.get_prev_out());
+        }
+
+        self.total_size -= entry.size;
+
+        ::logger::log_mempool_removed(hash);
+    }
+
+    /* Remove a transaction along with everything that depends on it */
+    fn remove_recursive(&mut self, hash : &Hash)
+    {
+        let descendants : HashSet<Hash>;
+
+        if !self.contains(hash)
+        {
+            return;
+        }
+
+        descendants = self.get_descendants(hash);
+
+        for descendant in descendants.iter()
+        {
+            self.remove_entry(descendant);
+        }
+
+        self.remove_entry(hash);
+    }
+
+    /* Evict the transactions with the lowest fee rate (and whatever depends
+     * on them) until the mempool fits in the given size.
+     */
+    pub fn trim_to_size(&mut self, max_size : uint)
+    {
+        let mut hashes : Vec<Hash>;
+
+        if self.total_size <= max_size
+        {
+            return;
+        }
+
+        hashes = self.get_hashes_by_fee_rate();
+
+        while self.total_size > max_size
+        {
+            let lowest : Hash = hashes.pop().unwrap();
+            let rate : u64;
+
+            /* Already evicted along with an ancestor */
+            if !self.contains(&lowest)
+            {
+                continue;
+            }
+
+            rate = self.entries.get(&lowest).unwrap().get_fee_rate()+MIN_RELAY_FEE_RATE;
+
+            if rate > self.get_min_fee_rate()
+            {
+                self.min_fee_rate = rate;
+                self.min_fee_time = time::now_utc().to_timespec();
+            }
+
+            self.remove_recursive(&lowest);
+        }
+    }
+
+    /* Remove transactions that have been in the mempool for too long */
+    pub fn expire(&mut self)
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let limit : Timespec = now-Duration::hours(MEMPOOL_EXPIRY_H);
+        let expired : Vec<Hash>;
+
+        expired = self.entries.iter()
+            .filter(|&(_, e)| e.time < limit)
+            .map(|(h, _)| h.clone())
+            .collect();
+
+        for hash in expired.iter()
+        {
+            self.remove_recursive(hash);
+        }
+    }
+
+    /* Remove the transactions confirmed by a newly connected block and the
+     * ones that conflict with it.
+     */
+    pub fn remove_for_block(&mut self, block : &Block)
+    {
+        for tx in block.get_txs().iter()
+        {
+            let hash : Hash = tx.get_hash();
+
+            self.remove_entry(&hash);
+
+            for in_tx in tx.get_in_txs().iter()
+            {
+                let conflict : Option<Hash>;
+
+                conflict = self.spent.get(in_tx.get_prev_out()).map(|h| h.clone());
+
+                match conflict
+                {
+                    Some(ref h) if *h != hash => self.remove_recursive(h),
+                    _                         => ()
+                }
+            }
+        }
+    }
+}
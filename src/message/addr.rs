@@ -43,7 +43,7 @@ impl Addr
         &self.addresses
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let mut msg = ::marshalling::Marshalling::new();
         let header : Header;
@@ -55,7 +55,7 @@ impl Addr
             msg.write_netaddr(addr,true);
         }
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "addr".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
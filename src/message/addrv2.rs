@@ -0,0 +1,112 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+use message::addr::MSG_ADDR_MAX;
+
+use datatype::netaddr::NetAddrV2;
+
+/* BIP155: like addr, but the addresses can be of networks other than IPv4 and
+ * IPv6.
+ */
+pub struct AddrV2
+{
+    addresses : Vec<NetAddrV2>
+}
+
+#[allow(dead_code)]
+impl AddrV2
+{
+    pub fn new() -> AddrV2
+    {
+        AddrV2
+        {
+            addresses: Vec::new()
+        }
+    }
+
+    pub fn from_addrs(addrs : &Vec<NetAddrV2>) -> AddrV2
+    {
+        AddrV2
+        {
+            addresses: addrs.clone()
+        }
+    }
+
+    pub fn add(&mut self, addr : NetAddrV2)
+    {
+        self.addresses.push(addr);
+
+        assert!(self.addresses.len() <= MSG_ADDR_MAX);
+    }
+
+    pub fn get_addresses(&self) -> &Vec<NetAddrV2>
+    {
+        &self.addresses
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_varint(self.addresses.len() as u64);
+
+        for addr in self.addresses.iter()
+        {
+            msg.write_netaddrv2(addr);
+        }
+
+        header = Header::new(magic,
+                             "addrv2".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    /* None if there are too many addresses or one is too long */
+    pub fn unserialize(data : &Vec<u8>) -> Option<AddrV2>
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let mut addresses : AddrV2 = AddrV2::new();
+        let count : u64;
+
+        count = unmarshalling.read_varint();
+
+        if count > MSG_ADDR_MAX as u64
+        {
+            return None;
+        }
+
+        for _ in range(0,count)
+        {
+            match unmarshalling.read_netaddrv2()
+            {
+                Some(netaddr) => addresses.add(netaddr),
+                None          => return None
+            }
+        }
+
+        Some(addresses)
+    }
+}
+
+impl Show for AddrV2
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}AddrV2:\n", space));
+
+        for i in range(0,self.addresses.len())
+        {
+            try!(write!(f,"{}    Address #{} {}{}",space,i+1,self.addresses[i],
+                 if i == self.addresses.len()-1 { "" } else { "\n" }));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+pub struct Block
+{
+    block : ::datatype::block::Block
+}
+
+#[allow(dead_code)]
+impl Block
+{
+    pub fn new(block : ::datatype::block::Block) -> Block
+    {
+        Block
+        {
+            block: block
+        }
+    }
+
+    pub fn get_block(&self) -> &::datatype::block::Block
+    {
+        &self.block
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_block(&self.block);
+
+        header = Header::new(magic,
+                             "block".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> Block
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+
+        Block::new(unmarshalling.read_block())
+    }
+}
+
+impl Show for Block
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}Block:\n", space));
+
+        // TODO this should be "{:2+space}"
+        write!(f,"{:6}", self.block)
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::compact::BlockTransactions;
+
+pub struct BlockTxn
+{
+    txs : BlockTransactions
+}
+
+#[allow(dead_code)]
+impl BlockTxn
+{
+    pub fn new(txs : BlockTransactions) -> BlockTxn
+    {
+        BlockTxn
+        {
+            txs: txs
+        }
+    }
+
+    pub fn get_txs(&self) -> &BlockTransactions
+    {
+        &self.txs
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_block_transactions(&self.txs);
+
+        header = Header::new(magic,
+                             "blocktxn".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> BlockTxn
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+
+        BlockTxn::new(unmarshalling.read_block_transactions())
+    }
+}
+
+impl Show for BlockTxn
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}BlockTxn:\n", space));
+
+        // TODO this should be "{:2+space}"
+        write!(f,"{:6}", self.txs)
+    }
+}
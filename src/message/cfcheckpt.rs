@@ -0,0 +1,90 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::hash::Hash;
+
+/* Enough checkpoints for a chain of 10M blocks */
+const MAX_CFCHECKPT_HEADERS : uint = 10000;
+
+pub struct CFCheckpt
+{
+    filter_type    : u8,
+    stop_hash      : Hash,
+    filter_headers : Vec<Hash>
+}
+
+#[allow(dead_code)]
+impl CFCheckpt
+{
+    pub fn new(filter_type : u8, stop_hash : Hash, filter_headers : Vec<Hash>) -> CFCheckpt
+    {
+        CFCheckpt
+        {
+            filter_type:    filter_type,
+            stop_hash:      stop_hash,
+            filter_headers: filter_headers
+        }
+    }
+
+    pub fn get_filter_type(&self) -> u8
+    {
+        self.filter_type
+    }
+
+    pub fn get_stop_hash(&self) -> &Hash
+    {
+        &self.stop_hash
+    }
+
+    pub fn get_filter_headers(&self) -> &Vec<Hash>
+    {
+        &self.filter_headers
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_uint8(self.filter_type);
+        msg.write_hash(&self.stop_hash);
+        msg.write_hashes(&self.filter_headers);
+
+        header = Header::new(magic,
+                             "cfcheckpt".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> CFCheckpt
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let filter_type : u8;
+        let stop_hash : Hash;
+        let filter_headers : Vec<Hash>;
+
+        filter_type = unmarshalling.read_uint8();
+        stop_hash = unmarshalling.read_hash();
+        filter_headers = unmarshalling.read_hashes(MAX_CFCHECKPT_HEADERS);
+
+        CFCheckpt::new(filter_type,stop_hash,filter_headers)
+    }
+}
+
+impl Show for CFCheckpt
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}CFCheckpt:\n", space));
+        try!(write!(f,"{}  type:      {}\n", space, self.filter_type));
+        try!(write!(f,"{}  stop hash: {}\n", space, self.stop_hash));
+        write!(f,"{}  headers:   {}", space, self.filter_headers.len())
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::hash::Hash;
+
+pub struct CFHeaders
+{
+    filter_type        : u8,
+    stop_hash          : Hash,
+    prev_filter_header : Hash,
+    filter_hashes      : Vec<Hash>
+}
+
+#[allow(dead_code)]
+impl CFHeaders
+{
+    pub fn new(filter_type : u8, stop_hash : Hash, prev_filter_header : Hash,
+               filter_hashes : Vec<Hash>) -> CFHeaders
+    {
+        CFHeaders
+        {
+            filter_type:        filter_type,
+            stop_hash:          stop_hash,
+            prev_filter_header: prev_filter_header,
+            filter_hashes:      filter_hashes
+        }
+    }
+
+    pub fn get_filter_type(&self) -> u8
+    {
+        self.filter_type
+    }
+
+    pub fn get_stop_hash(&self) -> &Hash
+    {
+        &self.stop_hash
+    }
+
+    pub fn get_prev_filter_header(&self) -> &Hash
+    {
+        &self.prev_filter_header
+    }
+
+    pub fn get_filter_hashes(&self) -> &Vec<Hash>
+    {
+        &self.filter_hashes
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_uint8(self.filter_type);
+        msg.write_hash(&self.stop_hash);
+        msg.write_hash(&self.prev_filter_header);
+        msg.write_hashes(&self.filter_hashes);
+
+        header = Header::new(magic,
+                             "cfheaders".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> CFHeaders
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let filter_type : u8;
+        let stop_hash : Hash;
+        let prev_filter_header : Hash;
+        let filter_hashes : Vec<Hash>;
+
+        filter_type = unmarshalling.read_uint8();
+        stop_hash = unmarshalling.read_hash();
+        prev_filter_header = unmarshalling.read_hash();
+        filter_hashes = unmarshalling.read_hashes(
+            ::message::getcfheaders::MAX_GETCFHEADERS_SIZE as uint);
+
+        CFHeaders::new(filter_type,stop_hash,prev_filter_header,filter_hashes)
+    }
+}
+
+impl Show for CFHeaders
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}CFHeaders:\n", space));
+        try!(write!(f,"{}  type:        {}\n", space, self.filter_type));
+        try!(write!(f,"{}  stop hash:   {}\n", space, self.stop_hash));
+        try!(write!(f,"{}  prev header: {}\n", space, self.prev_filter_header));
+        write!(f,"{}  hashes:      {}", space, self.filter_hashes.len())
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::blockfilter::BlockFilter;
+
+pub struct CFilter
+{
+    filter : BlockFilter
+}
+
+#[allow(dead_code)]
+impl CFilter
+{
+    pub fn new(filter : BlockFilter) -> CFilter
+    {
+        CFilter
+        {
+            filter: filter
+        }
+    }
+
+    pub fn get_filter(&self) -> &BlockFilter
+    {
+        &self.filter
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_block_filter(&self.filter);
+
+        header = Header::new(magic,
+                             "cfilter".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> CFilter
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+
+        CFilter::new(unmarshalling.read_block_filter())
+    }
+}
+
+impl Show for CFilter
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}CFilter:\n", space));
+
+        // TODO this should be "{:2+space}"
+        write!(f,"{:6}", self.filter)
+    }
+}
@@ -0,0 +1,65 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::compact::HeaderAndShortIds;
+
+pub struct CmpctBlock
+{
+    block : HeaderAndShortIds
+}
+
+#[allow(dead_code)]
+impl CmpctBlock
+{
+    pub fn new(block : HeaderAndShortIds) -> CmpctBlock
+    {
+        CmpctBlock
+        {
+            block: block
+        }
+    }
+
+    pub fn get_block(&self) -> &HeaderAndShortIds
+    {
+        &self.block
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_header_and_short_ids(&self.block);
+
+        header = Header::new(magic,
+                             "cmpctblock".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    /* None if the message is malformed */
+    pub fn unserialize(data : &Vec<u8>) -> Option<CmpctBlock>
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+
+        unmarshalling.read_header_and_short_ids().map(|x| CmpctBlock::new(x))
+    }
+}
+
+impl Show for CmpctBlock
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}CmpctBlock:\n", space));
+
+        // TODO this should be "{:2+space}"
+        write!(f,"{:6}", self.block)
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+/* BIP133: Tells the peer not to announce txs paying less than this fee rate
+ * (in satoshis per 1000 bytes).
+ */
+pub struct FeeFilter
+{
+    fee_rate : u64
+}
+
+impl FeeFilter
+{
+    pub fn new(fee_rate : u64) -> FeeFilter
+    {
+        FeeFilter
+        {
+            fee_rate: fee_rate
+        }
+    }
+
+    pub fn get_fee_rate(&self) -> u64
+    {
+        self.fee_rate
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_uint64(self.fee_rate);
+
+        header = Header::new(magic,
+                             "feefilter".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> FeeFilter
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let fee_rate : u64;
+
+        fee_rate = unmarshalling.read_uint64();
+
+        FeeFilter { fee_rate: fee_rate }
+    }
+}
+
+impl Show for FeeFilter
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f, "{}FeeFilter {}", space, self.fee_rate)
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+pub struct FilterAdd
+{
+    data : Vec<u8>
+}
+
+#[allow(dead_code)]
+impl FilterAdd
+{
+    pub fn new(data : Vec<u8>) -> FilterAdd
+    {
+        FilterAdd
+        {
+            data: data
+        }
+    }
+
+    pub fn get_data(&self) -> &Vec<u8>
+    {
+        &self.data
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_varbytes(self.data.as_slice());
+
+        header = Header::new(magic,
+                             "filteradd".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> FilterAdd
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+
+        FilterAdd::new(unmarshalling.read_varbytes())
+    }
+}
+
+impl Show for FilterAdd
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f,"{}FilterAdd {}", space, ::crypto::to_hexstr(self.data.as_slice()))
+    }
+}
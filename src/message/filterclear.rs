@@ -0,0 +1,44 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+pub struct FilterClear;
+
+#[allow(dead_code)]
+impl FilterClear
+{
+    pub fn new() -> FilterClear
+    {
+        FilterClear
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        header = Header::new(magic,
+                             "filterclear".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize()
+    }
+
+    pub fn unserialize(_data : &Vec<u8>) -> FilterClear
+    {
+        FilterClear::new()
+    }
+}
+
+impl Show for FilterClear
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f,"{}FilterClear", space)
+    }
+}
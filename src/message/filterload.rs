@@ -0,0 +1,64 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::bloom::BloomFilter;
+
+pub struct FilterLoad
+{
+    filter : BloomFilter
+}
+
+#[allow(dead_code)]
+impl FilterLoad
+{
+    pub fn new(filter : BloomFilter) -> FilterLoad
+    {
+        FilterLoad
+        {
+            filter: filter
+        }
+    }
+
+    pub fn get_filter(&self) -> &BloomFilter
+    {
+        &self.filter
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_bloom_filter(&self.filter);
+
+        header = Header::new(magic,
+                             "filterload".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> FilterLoad
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+
+        FilterLoad::new(unmarshalling.read_bloom_filter())
+    }
+}
+
+impl Show for FilterLoad
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}FilterLoad:\n", space));
+
+        // TODO this should be "{:2+space}"
+        write!(f,"{:6}", self.filter)
+    }
+}
@@ -13,12 +13,12 @@ impl GetAddr
         GetAddr
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let msg = ::marshalling::Marshalling::new();
         let header : Header;
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "getaddr".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
@@ -0,0 +1,65 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::compact::BlockTxRequest;
+
+pub struct GetBlockTxn
+{
+    request : BlockTxRequest
+}
+
+#[allow(dead_code)]
+impl GetBlockTxn
+{
+    pub fn new(request : BlockTxRequest) -> GetBlockTxn
+    {
+        GetBlockTxn
+        {
+            request: request
+        }
+    }
+
+    pub fn get_request(&self) -> &BlockTxRequest
+    {
+        &self.request
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_block_tx_request(&self.request);
+
+        header = Header::new(magic,
+                             "getblocktxn".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    /* None if the message is malformed */
+    pub fn unserialize(data : &Vec<u8>) -> Option<GetBlockTxn>
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+
+        unmarshalling.read_block_tx_request().map(|x| GetBlockTxn::new(x))
+    }
+}
+
+impl Show for GetBlockTxn
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}GetBlockTxn:\n", space));
+
+        // TODO this should be "{:2+space}"
+        write!(f,"{:6}", self.request)
+    }
+}
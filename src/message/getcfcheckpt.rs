@@ -0,0 +1,79 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::hash::Hash;
+
+/* cfcheckpt replies carry the filter header of every this many blocks */
+pub const CFCHECKPT_INTERVAL : u32 = 1000;
+
+pub struct GetCFCheckpt
+{
+    filter_type : u8,
+    stop_hash   : Hash
+}
+
+#[allow(dead_code)]
+impl GetCFCheckpt
+{
+    pub fn new(filter_type : u8, stop_hash : Hash) -> GetCFCheckpt
+    {
+        GetCFCheckpt
+        {
+            filter_type: filter_type,
+            stop_hash:   stop_hash
+        }
+    }
+
+    pub fn get_filter_type(&self) -> u8
+    {
+        self.filter_type
+    }
+
+    pub fn get_stop_hash(&self) -> &Hash
+    {
+        &self.stop_hash
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_uint8(self.filter_type);
+        msg.write_hash(&self.stop_hash);
+
+        header = Header::new(magic,
+                             "getcfcheckpt".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> GetCFCheckpt
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let filter_type : u8;
+        let stop_hash : Hash;
+
+        filter_type = unmarshalling.read_uint8();
+        stop_hash = unmarshalling.read_hash();
+
+        GetCFCheckpt::new(filter_type,stop_hash)
+    }
+}
+
+impl Show for GetCFCheckpt
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}GetCFCheckpt:\n", space));
+        try!(write!(f,"{}  type:      {}\n", space, self.filter_type));
+        write!(f,"{}  stop hash: {}", space, self.stop_hash)
+    }
+}
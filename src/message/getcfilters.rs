@@ -0,0 +1,90 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::hash::Hash;
+
+/* Maximum number of filters requested with a getcfilters (BIP157) */
+pub const MAX_GETCFILTERS_SIZE : u32 = 1000;
+
+pub struct GetCFilters
+{
+    filter_type  : u8,
+    start_height : u32,
+    stop_hash    : Hash
+}
+
+#[allow(dead_code)]
+impl GetCFilters
+{
+    pub fn new(filter_type : u8, start_height : u32, stop_hash : Hash) -> GetCFilters
+    {
+        GetCFilters
+        {
+            filter_type:  filter_type,
+            start_height: start_height,
+            stop_hash:    stop_hash
+        }
+    }
+
+    pub fn get_filter_type(&self) -> u8
+    {
+        self.filter_type
+    }
+
+    pub fn get_start_height(&self) -> u32
+    {
+        self.start_height
+    }
+
+    pub fn get_stop_hash(&self) -> &Hash
+    {
+        &self.stop_hash
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_uint8(self.filter_type);
+        msg.write_uint32(self.start_height);
+        msg.write_hash(&self.stop_hash);
+
+        header = Header::new(magic,
+                             "getcfilters".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> GetCFilters
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let filter_type : u8;
+        let start_height : u32;
+        let stop_hash : Hash;
+
+        filter_type = unmarshalling.read_uint8();
+        start_height = unmarshalling.read_uint32();
+        stop_hash = unmarshalling.read_hash();
+
+        GetCFilters::new(filter_type,start_height,stop_hash)
+    }
+}
+
+impl Show for GetCFilters
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}GetCFilters:\n", space));
+        try!(write!(f,"{}  type:         {}\n", space, self.filter_type));
+        try!(write!(f,"{}  start height: {}\n", space, self.start_height));
+        write!(f,"{}  stop hash:    {}", space, self.stop_hash)
+    }
+}
@@ -30,21 +30,26 @@ impl GetData
         }
     }
 
+    pub fn get_invvect(&self) -> &InvVect
+    {
+        &self.vect
+    }
+
     pub fn add(&mut self, entry : InvEntry)
     {
         self.vect.add(entry);
 
-        assert!(self.vect.len() <= 50000);
+        assert!(self.vect.len() <= ::message::inv::MSG_INV_MAX);
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let mut msg = ::marshalling::Marshalling::new();
         let header : Header;
 
         msg.write_invvect(&self.vect);
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "getdata".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
@@ -0,0 +1,114 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::hash::Hash;
+
+/* Maximum number of hashes in a block locator */
+pub const MAX_LOCATOR_SIZE : uint = 101;
+
+/* Asks for the headers after the first block of the locator the peer has in
+ * its active chain, up to the stop hash (all zeros for as many as fit in a
+ * headers message).
+ */
+pub struct GetHeaders
+{
+    version   : u32,
+    locator   : Vec<Hash>,
+    hash_stop : Hash
+}
+
+#[allow(dead_code)]
+impl GetHeaders
+{
+    pub fn new(version : u32, locator : Vec<Hash>, hash_stop : Hash) -> GetHeaders
+    {
+        assert!(locator.len() <= MAX_LOCATOR_SIZE);
+
+        GetHeaders
+        {
+            version:   version,
+            locator:   locator,
+            hash_stop: hash_stop
+        }
+    }
+
+    pub fn get_version(&self) -> u32
+    {
+        self.version
+    }
+
+    pub fn get_locator(&self) -> &Vec<Hash>
+    {
+        &self.locator
+    }
+
+    pub fn get_hash_stop(&self) -> &Hash
+    {
+        &self.hash_stop
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_uint32(self.version);
+        msg.write_hashes(&self.locator);
+        msg.write_hash(&self.hash_stop);
+
+        header = Header::new(magic,
+                             "getheaders".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    /* None if the locator is too long */
+    pub fn unserialize(data : &Vec<u8>) -> Option<GetHeaders>
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let mut locator : Vec<Hash> = Vec::new();
+        let version : u32;
+        let count : u64;
+        let hash_stop : Hash;
+
+        version = unmarshalling.read_uint32();
+        count = unmarshalling.read_varint();
+
+        if count > MAX_LOCATOR_SIZE as u64
+        {
+            return None;
+        }
+
+        for _ in range(0,count)
+        {
+            locator.push(unmarshalling.read_hash());
+        }
+
+        hash_stop = unmarshalling.read_hash();
+
+        Some(GetHeaders::new(version,locator,hash_stop))
+    }
+}
+
+impl Show for GetHeaders
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}GetHeaders:\n", space));
+        try!(write!(f,"{}  version:   {}\n", space, self.version));
+
+        for hash in self.locator.iter()
+        {
+            try!(write!(f,"{}  locator:   {}\n", space, hash));
+        }
+
+        write!(f,"{}  hash stop: {}", space, self.hash_stop)
+    }
+}
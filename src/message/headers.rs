@@ -0,0 +1,96 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::block::BlockHeader;
+
+/* Maximum number of headers in a headers message */
+pub const MAX_HEADERS_RESULTS : uint = 2000;
+
+pub struct Headers
+{
+    headers : Vec<BlockHeader>
+}
+
+#[allow(dead_code)]
+impl Headers
+{
+    pub fn new(headers : Vec<BlockHeader>) -> Headers
+    {
+        assert!(headers.len() <= MAX_HEADERS_RESULTS);
+
+        Headers
+        {
+            headers: headers
+        }
+    }
+
+    pub fn get_headers(&self) -> &Vec<BlockHeader>
+    {
+        &self.headers
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_varint(self.headers.len() as u64);
+
+        /* Each header is followed by the number of txs, which is always 0 */
+        for h in self.headers.iter()
+        {
+            msg.write_block_header(h);
+            msg.write_varint(0);
+        }
+
+        header = Header::new(magic,
+                             "headers".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    /* None if there are more headers than allowed */
+    pub fn unserialize(data : &Vec<u8>) -> Option<Headers>
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let mut headers : Vec<BlockHeader> = Vec::new();
+        let count : u64;
+
+        count = unmarshalling.read_varint();
+
+        if count > MAX_HEADERS_RESULTS as u64
+        {
+            return None;
+        }
+
+        for _ in range(0,count)
+        {
+            headers.push(unmarshalling.read_block_header());
+            unmarshalling.read_varint();
+        }
+
+        Some(Headers::new(headers))
+    }
+}
+
+impl Show for Headers
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}Headers:\n", space));
+
+        for h in self.headers.iter()
+        {
+            try!(write!(f,"{}  {}\n", space, h.get_hash()));
+        }
+
+        Ok(())
+    }
+}
@@ -6,6 +6,8 @@ use message::header::Header;
 use datatype::invvect::InvEntry;
 use datatype::invvect::InvVect;
 
+pub const MSG_INV_MAX : uint = 50000;
+
 pub struct Inv
 {
     vect : InvVect
@@ -31,17 +33,17 @@ impl Inv
     {
         self.vect.add(entry);
 
-        assert!(self.vect.len() <= 50000);
+        assert!(self.vect.len() <= MSG_INV_MAX);
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let mut msg = ::marshalling::Marshalling::new();
         let header : Header;
 
         msg.write_invvect(&self.vect);
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "inv".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
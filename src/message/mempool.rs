@@ -0,0 +1,44 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+pub struct MemPool;
+
+#[allow(dead_code)]
+impl MemPool
+{
+    pub fn new() -> MemPool
+    {
+        MemPool
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        header = Header::new(magic,
+                             "mempool".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize()
+    }
+
+    pub fn unserialize(_data : &Vec<u8>) -> MemPool
+    {
+        MemPool::new()
+    }
+}
+
+impl Show for MemPool
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f,"{}MemPool", space)
+    }
+}
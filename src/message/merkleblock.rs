@@ -0,0 +1,79 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::block::BlockHeader;
+use datatype::merkle::PartialMerkleTree;
+
+pub struct MerkleBlock
+{
+    header : BlockHeader,
+    tree   : PartialMerkleTree
+}
+
+#[allow(dead_code)]
+impl MerkleBlock
+{
+    pub fn new(header : BlockHeader, tree : PartialMerkleTree) -> MerkleBlock
+    {
+        MerkleBlock
+        {
+            header: header,
+            tree:   tree
+        }
+    }
+
+    pub fn get_header(&self) -> &BlockHeader
+    {
+        &self.header
+    }
+
+    pub fn get_tree(&self) -> &PartialMerkleTree
+    {
+        &self.tree
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_block_header(&self.header);
+        msg.write_partial_merkle_tree(&self.tree);
+
+        header = Header::new(magic,
+                             "merkleblock".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> MerkleBlock
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let header : BlockHeader;
+        let tree : PartialMerkleTree;
+
+        header = unmarshalling.read_block_header();
+        tree = unmarshalling.read_partial_merkle_tree();
+
+        MerkleBlock::new(header,tree)
+    }
+}
+
+impl Show for MerkleBlock
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}MerkleBlock:\n", space));
+
+        // TODO this should be "{:2+space}"
+        try!(write!(f,"{:6}\n", self.header));
+        write!(f,"{:6}", self.tree)
+    }
+}
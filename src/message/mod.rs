@@ -9,6 +9,30 @@ pub mod getdata;
 pub mod reject;
 pub mod tx;
 pub mod getaddr;
+pub mod block;
+pub mod notfound;
+pub mod mempool;
+pub mod filterload;
+pub mod filteradd;
+pub mod filterclear;
+pub mod merkleblock;
+pub mod getcfilters;
+pub mod cfilter;
+pub mod getcfheaders;
+pub mod cfheaders;
+pub mod getcfcheckpt;
+pub mod cfcheckpt;
+pub mod sendcmpct;
+pub mod cmpctblock;
+pub mod getblocktxn;
+pub mod blocktxn;
+pub mod sendheaders;
+pub mod getheaders;
+pub mod headers;
+pub mod feefilter;
+pub mod wtxidrelay;
+pub mod sendaddrv2;
+pub mod addrv2;
 
 pub enum Message
 {
@@ -21,5 +45,29 @@ pub enum Message
     MsgGetData(getdata::GetData),
     MsgReject(reject::Reject),
     MsgTx(tx::Tx),
-    MsgGetAddr(getaddr::GetAddr)
+    MsgGetAddr(getaddr::GetAddr),
+    MsgBlock(block::Block),
+    MsgNotFound(notfound::NotFound),
+    MsgMemPool(mempool::MemPool),
+    MsgFilterLoad(filterload::FilterLoad),
+    MsgFilterAdd(filteradd::FilterAdd),
+    MsgFilterClear(filterclear::FilterClear),
+    MsgMerkleBlock(merkleblock::MerkleBlock),
+    MsgGetCFilters(getcfilters::GetCFilters),
+    MsgCFilter(cfilter::CFilter),
+    MsgGetCFHeaders(getcfheaders::GetCFHeaders),
+    MsgCFHeaders(cfheaders::CFHeaders),
+    MsgGetCFCheckpt(getcfcheckpt::GetCFCheckpt),
+    MsgCFCheckpt(cfcheckpt::CFCheckpt),
+    MsgSendCmpct(sendcmpct::SendCmpct),
+    MsgCmpctBlock(cmpctblock::CmpctBlock),
+    MsgGetBlockTxn(getblocktxn::GetBlockTxn),
+    MsgBlockTxn(blocktxn::BlockTxn),
+    MsgSendHeaders(sendheaders::SendHeaders),
+    MsgGetHeaders(getheaders::GetHeaders),
+    MsgHeaders(headers::Headers),
+    MsgFeeFilter(feefilter::FeeFilter),
+    MsgWtxidRelay(wtxidrelay::WtxidRelay),
+    MsgSendAddrV2(sendaddrv2::SendAddrV2),
+    MsgAddrV2(addrv2::AddrV2)
 }
@@ -0,0 +1,88 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+use datatype::invvect::InvVect;
+use datatype::invvect::InvEntry;
+
+pub struct NotFound
+{
+    vect : InvVect
+}
+
+#[allow(dead_code)]
+impl NotFound
+{
+    pub fn new() -> NotFound
+    {
+        NotFound
+        {
+            vect: InvVect::new()
+        }
+    }
+
+    pub fn from_inv(invvect : &InvVect) -> NotFound
+    {
+        NotFound
+        {
+            vect: invvect.clone()
+        }
+    }
+
+    pub fn get_invvect(&self) -> &InvVect
+    {
+        &self.vect
+    }
+
+    pub fn add(&mut self, entry : InvEntry)
+    {
+        self.vect.add(entry);
+
+        assert!(self.vect.len() <= ::message::inv::MSG_INV_MAX);
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_invvect(&self.vect);
+
+        header = Header::new(magic,
+                             "notfound".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> NotFound
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let vect : InvVect;
+
+        vect = unmarshalling.read_invvect();
+
+        NotFound
+        {
+            vect: vect
+        }
+    }
+}
+
+impl Show for NotFound
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}NotFound:\n", space));
+
+        // TODO this should be "{:2+space}"
+        try!(write!(f,"{:6}", self.vect));
+
+        Ok(())
+    }
+}
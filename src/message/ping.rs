@@ -24,14 +24,14 @@ impl Ping
         self.nounce
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let mut msg = ::marshalling::Marshalling::new();
         let header : Header;
 
         msg.write_uint64(self.nounce);
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "ping".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
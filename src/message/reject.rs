@@ -3,8 +3,10 @@ use std::fmt::Formatter;
 
 use message::header::Header;
 
-#[deriving(Show)]
-enum RejectType
+use datatype::hash::Hash;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum RejectType
 {
     RejectMalformed       = 0x01,
     RejectInvalid         = 0x10,
@@ -39,44 +41,76 @@ pub struct Reject
 {
     msg    : String, /* message that cause this reject */
     typ    : RejectType,
-    reason : String
+    reason : String,
+    hash   : Option<Hash> /* tx or block rejected */
 }
 
 #[allow(dead_code)]
 impl Reject
 {
-    pub fn new(msg : String, typ : RejectType, reason : String) -> Reject
+    pub fn new(msg : String, typ : RejectType, reason : String, hash : Option<Hash>) -> Reject
     {
         Reject
         {
             msg:    msg,
             typ:    typ,
-            reason: reason
+            reason: reason,
+            hash:   hash
         }
     }
 
+    pub fn get_msg(&self) -> &String
+    {
+        &self.msg
+    }
+
+    pub fn get_type(&self) -> RejectType
+    {
+        self.typ.clone()
+    }
+
+    pub fn get_reason(&self) -> &String
+    {
+        &self.reason
+    }
+
+    pub fn get_hash(&self) -> Option<&Hash>
+    {
+        self.hash.as_ref()
+    }
+
     pub fn unserialize(data : &Vec<u8>) -> Reject
     {
         let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
         let msg : String;
         let typ : u8;
         let reason : String;
+        let mut hash : Option<Hash> = None;
 
         msg = unmarshalling.read_varstr();
         typ = unmarshalling.read_uint8();
         reason = unmarshalling.read_varstr();
 
+        /* Rejects of txs and blocks are followed by the hash of the rejected
+         * object, but older nodes do not send it.
+         */
+        if (msg.as_slice() == "tx" || msg.as_slice() == "block") && unmarshalling.remaining() >= 32
+        {
+            hash = Some(unmarshalling.read_hash());
+        }
+
         assert!(RejectType::from_u8(typ).is_some());
 
         Reject
         {
             msg:    msg,
             typ:    RejectType::from_u8(typ).unwrap(),
-            reason: reason
+            reason: reason,
+            hash:   hash
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let mut msg = ::marshalling::Marshalling::new();
         let header : Header;
@@ -85,7 +119,13 @@ impl Reject
         msg.write_uint8(self.typ as u8);
         msg.write_varstr(&self.reason);
 
-        header = Header::new(::config::NETWORK,
+        match self.hash
+        {
+            Some(ref hash) => msg.write_hash(hash),
+            None           => ()
+        }
+
+        header = Header::new(magic,
                              "reject".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
@@ -101,6 +141,12 @@ impl Show for Reject
         let width = f.width().unwrap_or(0);
         let space = String::from_str(" ").repeat(width);
 
-        write!(f,"{}Reject {} {} \"{}\"", space, self.msg, self.typ, self.reason)
+        try!(write!(f,"{}Reject {} {} \"{}\"", space, self.msg, self.typ, self.reason));
+
+        match self.hash
+        {
+            Some(ref hash) => write!(f," {}", hash),
+            None           => Ok(())
+        }
     }
 }
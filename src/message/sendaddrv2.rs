@@ -0,0 +1,45 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+/* BIP155: Tells the peer we want addresses in addrv2 messages, which can
+ * carry addresses of other networks, i.e. onion services.
+ */
+pub struct SendAddrV2;
+
+impl SendAddrV2
+{
+    pub fn new() -> SendAddrV2
+    {
+        SendAddrV2
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let header : Header;
+
+        header = Header::new(magic,
+                             "sendaddrv2".to_string(),
+                             0u32,
+                             ::crypto::checksum(&[]));
+
+        header.serialize()
+    }
+
+    pub fn unserialize(_data : &Vec<u8>) -> SendAddrV2
+    {
+        SendAddrV2::new()
+    }
+}
+
+impl Show for SendAddrV2
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f, "{}SendAddrV2", space)
+    }
+}
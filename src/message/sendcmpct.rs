@@ -0,0 +1,78 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+/* BIP152: Tells the peer we speak compact blocks of the given version and
+ * whether we want new blocks announced with cmpctblock directly (high
+ * bandwidth mode) or with inv/headers (low bandwidth mode).
+ */
+pub struct SendCmpct
+{
+    announce : bool,
+    version  : u64
+}
+
+#[allow(dead_code)]
+impl SendCmpct
+{
+    pub fn new(announce : bool, version : u64) -> SendCmpct
+    {
+        SendCmpct
+        {
+            announce: announce,
+            version:  version
+        }
+    }
+
+    pub fn get_announce(&self) -> bool
+    {
+        self.announce
+    }
+
+    pub fn get_version(&self) -> u64
+    {
+        self.version
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let mut msg = ::marshalling::Marshalling::new();
+        let header : Header;
+
+        msg.write_bool(self.announce);
+        msg.write_uint64(self.version);
+
+        header = Header::new(magic,
+                             "sendcmpct".to_string(),
+                             msg.len() as u32,
+                             ::crypto::checksum(msg.get().as_slice()));
+
+        header.serialize() + msg.get()
+    }
+
+    pub fn unserialize(data : &Vec<u8>) -> SendCmpct
+    {
+        let mut unmarshalling = ::marshalling::Unmarshalling::new(data);
+        let announce : bool;
+        let version : u64;
+
+        announce = unmarshalling.read_bool();
+        version = unmarshalling.read_uint64();
+
+        SendCmpct::new(announce,version)
+    }
+}
+
+impl Show for SendCmpct
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        try!(write!(f,"{}SendCmpct:\n", space));
+        try!(write!(f,"{}  announce: {}\n", space, self.announce));
+        write!(f,"{}  version:  {}", space, self.version)
+    }
+}
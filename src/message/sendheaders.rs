@@ -0,0 +1,45 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+/* BIP130: Tells the peer we prefer new blocks announced with headers rather
+ * than inv.
+ */
+pub struct SendHeaders;
+
+impl SendHeaders
+{
+    pub fn new() -> SendHeaders
+    {
+        SendHeaders
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let header : Header;
+
+        header = Header::new(magic,
+                             "sendheaders".to_string(),
+                             0u32,
+                             ::crypto::checksum(&[]));
+
+        header.serialize()
+    }
+
+    pub fn unserialize(_data : &Vec<u8>) -> SendHeaders
+    {
+        SendHeaders::new()
+    }
+}
+
+impl Show for SendHeaders
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f, "{}SendHeaders", space)
+    }
+}
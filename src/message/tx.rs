@@ -21,14 +21,19 @@ impl Tx
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn get_transaction(&self) -> &Transaction
+    {
+        &self.tx
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let mut msg = ::marshalling::Marshalling::new();
         let header : Header;
 
         msg.write_transaction(&self.tx);
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "tx".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
@@ -12,11 +12,11 @@ impl VerAck
         VerAck
     }
 
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let header : Header;
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "verack".to_string(),
                              0u32,
                              ::crypto::checksum(&[]));
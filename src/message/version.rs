@@ -24,16 +24,16 @@ pub struct Version
 
 impl Version
 {
-    pub fn new(version : String, best_height : u32) -> Version
+    pub fn new(version : String, services : ::config::Services, best_height : u32) -> Version
     {
         Version
         {
             proto_ver:   ::config::PROTOCOL_VERSION,
-            services:    ::config::SERVICES,
+            services:    services,
             version:     version,
             time:        time::now_utc().to_timespec(),
-            addr_recv:   NetAddr::new(None,::config::SERVICES,None),
-            addr_send:   NetAddr::new(None,::config::SERVICES,None),
+            addr_recv:   NetAddr::new(None,services,None),
+            addr_send:   NetAddr::new(None,services,None),
             best_height: best_height,
             nounce:      ::crypto::rng().gen(),
             relay:       true
@@ -45,13 +45,34 @@ impl Version
         self.proto_ver
     }
 
+    pub fn get_services(&self) -> ::config::Services
+    {
+        self.services
+    }
+
+    /* The user agent (BIP14) */
+    pub fn get_version(&self) -> &String
+    {
+        &self.version
+    }
+
+    pub fn get_best_height(&self) -> u32
+    {
+        self.best_height
+    }
+
     pub fn get_addr_send(&self) -> &NetAddr
     {
         &self.addr_send
     }
 
+    pub fn get_relay(&self) -> bool
+    {
+        self.relay
+    }
+
     // TODO: create a trait for serialization (serialize::Encodable?)
-    pub fn serialize(&self) -> Vec<u8>
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
     {
         let mut msg = ::marshalling::Marshalling::new();
         let header : Header;
@@ -66,7 +87,7 @@ impl Version
         msg.write_uint32(self.best_height);
         msg.write_bool(self.relay);
 
-        header = Header::new(::config::NETWORK,
+        header = Header::new(magic,
                              "version".to_string(),
                              msg.len() as u32,
                              ::crypto::checksum(msg.get().as_slice()));
@@ -99,8 +120,7 @@ impl Version
         relay = if unmarshalling.consumed() < size { unmarshalling.read_bool() }
                                                    else { true };
 
-        assert!(services == ::config::Service::NoService as u64
-                || services == ::config::Service::NodeNetwork as u64);
+        /* We ignore the service bits we do not know about */
 
         Version
         {
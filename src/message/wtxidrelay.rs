@@ -0,0 +1,47 @@
+use std::fmt::Show;
+use std::fmt::Formatter;
+
+use message::header::Header;
+
+/* BIP339: Tells the peer we want txs announced and requested by wtxid.  It
+ * must be sent after version and before verack.  We only receive it, see
+ * Peer::handle_wtxidrelay.
+ */
+pub struct WtxidRelay;
+
+#[allow(dead_code)]
+impl WtxidRelay
+{
+    pub fn new() -> WtxidRelay
+    {
+        WtxidRelay
+    }
+
+    pub fn serialize(&self, magic : u32) -> Vec<u8>
+    {
+        let header : Header;
+
+        header = Header::new(magic,
+                             "wtxidrelay".to_string(),
+                             0u32,
+                             ::crypto::checksum(&[]));
+
+        header.serialize()
+    }
+
+    pub fn unserialize(_data : &Vec<u8>) -> WtxidRelay
+    {
+        WtxidRelay::new()
+    }
+}
+
+impl Show for WtxidRelay
+{
+    fn fmt(&self, f : &mut Formatter) -> Result<(), ::std::fmt::Error>
+    {
+        let width = f.width().unwrap_or(0);
+        let space = String::from_str(" ").repeat(width);
+
+        write!(f, "{}WtxidRelay", space)
+    }
+}
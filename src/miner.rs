@@ -0,0 +1,133 @@
+extern crate time;
+
+use self::time::Timespec;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::transaction::Transaction;
+use datatype::transaction::TxIn;
+use datatype::transaction::TxOut;
+use datatype::transaction::TxLock;
+use datatype::transaction::OutPoint;
+use datatype::hash::Hash;
+use datatype::script::Script;
+use datatype::value::Value;
+
+use chain::Chain;
+
+use consensus::ChainView;
+
+use config::ConsensusParams;
+
+use mempool::Mempool;
+
+/* A CPU miner.  It is only of any use with the regtest difficulty, where
+ * about every other nonce gives a valid block.
+ */
+
+const BLOCK_VERSION : u32 = 4;
+
+/* Room left in the block for the header and the coinbase */
+const COINBASE_RESERVED_SIZE : uint = 1000;
+const COINBASE_RESERVED_SIGOPS : uint = 100;
+
+/* The coinbase pays the subsidy and the fees to the script.  The height is
+ * required by BIP34 and the extra nonce gives us more block hashes to try
+ * once the nonces run out.
+ */
+fn create_coinbase(height : u32, extra_nonce : u32, value : Value, script : &Script)
+                   -> Transaction
+{
+    let mut sig_script : Script = Script::new();
+
+    sig_script.push_int(height as i64);
+    sig_script.push_int(extra_nonce as i64);
+
+    Transaction::new(1,
+                     vec![TxIn::new(OutPoint::new(Hash::zero(),0xffffffff),sig_script,0xffffffff)],
+                     vec![TxOut::new(value,script.clone())],
+                     TxLock::from_u32(0))
+}
+
+/* A block on top of the tip of the chain with the best txs of the mempool,
+ * not yet mined.
+ */
+fn create_block(chain       : &Chain,
+                mempool     : &Mempool,
+                script      : &Script,
+                extra_nonce : u32) -> Block
+{
+    let height : u32 = chain.get_height()+1;
+    let now : Timespec = time::now_utc().to_timespec();
+    let mtp : Timespec = ::consensus::get_median_time_past(chain,height-1);
+    let time : Timespec;
+    let value : Value;
+    let hashes : Vec<Hash>;
+    let header : BlockHeader;
+
+    let (mut txs, fees) = mempool.select_for_block(::consensus::MAX_BLOCK_SIZE
+                                                   -COINBASE_RESERVED_SIZE,
+                                                   ::consensus::MAX_BLOCK_SIGOPS_COST
+                                                   /::consensus::WITNESS_SCALE_FACTOR
+                                                   -COINBASE_RESERVED_SIGOPS);
+
+    /* Blocks mined in a burst would not be past the median time otherwise */
+    time = Timespec { sec: ::std::cmp::max(now.sec,mtp.sec+1), nsec: 0 };
+
+    value = ::consensus::get_block_subsidy(height,&chain.get_params().consensus)
+        .checked_add(&fees).unwrap();
+
+    txs.insert(0,create_coinbase(height,extra_nonce,value,script));
+
+    hashes = txs.iter().map(|tx| tx.get_hash()).collect();
+
+    let (merkle_root, _) = ::datatype::merkle::compute_merkle_root(hashes.as_slice());
+
+    header = BlockHeader::new(BLOCK_VERSION,chain.get_tip().clone(),merkle_root,time,
+                              ::consensus::get_next_work_required(chain,height-1,time),0);
+
+    Block::new(header,txs)
+}
+
+/* Try every nonce until the hash of the header meets its target */
+pub fn solve(header : &mut BlockHeader, params : &ConsensusParams) -> bool
+{
+    let mut nounce : u32 = 0;
+
+    loop
+    {
+        header.set_nounce(nounce);
+
+        if ::consensus::check_proof_of_work(&header.get_hash(),header.get_bits(),params)
+        {
+            return true;
+        }
+
+        if nounce == 0xffffffff
+        {
+            return false;
+        }
+
+        nounce += 1;
+    }
+}
+
+/* Mine a block on top of the chain that pays to the given script.
+ */
+pub fn mine_block(chain : &Chain, mempool : &Mempool, script : &Script) -> Block
+{
+    let mut extra_nonce : u32 = 0;
+
+    loop
+    {
+        let block : Block = create_block(chain,mempool,script,extra_nonce);
+        let mut header : BlockHeader = block.get_header().clone();
+
+        if solve(&mut header,&chain.get_params().consensus)
+        {
+            return Block::new(header,block.get_txs().clone());
+        }
+
+        extra_nonce += 1;
+    }
+}
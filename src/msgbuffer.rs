@@ -1,5 +1,9 @@
+extern crate time;
+
 use std::io::TcpStream;
 
+use self::time::Timespec;
+
 use message::Message;
 
 use message::version::Version;
@@ -12,31 +16,94 @@ use message::getdata::GetData;
 use message::reject::Reject;
 use message::tx::Tx;
 use message::getaddr::GetAddr;
+use message::block::Block;
+use message::notfound::NotFound;
+use message::mempool::MemPool;
+use message::filterload::FilterLoad;
+use message::filteradd::FilterAdd;
+use message::filterclear::FilterClear;
+use message::merkleblock::MerkleBlock;
+use message::getcfilters::GetCFilters;
+use message::cfilter::CFilter;
+use message::getcfheaders::GetCFHeaders;
+use message::cfheaders::CFHeaders;
+use message::getcfcheckpt::GetCFCheckpt;
+use message::cfcheckpt::CFCheckpt;
+use message::sendcmpct::SendCmpct;
+use message::cmpctblock::CmpctBlock;
+use message::getblocktxn::GetBlockTxn;
+use message::blocktxn::BlockTxn;
+use message::sendheaders::SendHeaders;
+use message::getheaders::GetHeaders;
+use message::headers::Headers;
+use message::feefilter::FeeFilter;
+use message::wtxidrelay::WtxidRelay;
+use message::sendaddrv2::SendAddrV2;
+use message::addrv2::AddrV2;
 
 use message::header::Header;
 use message::header::HEADER_SIZE;
 
 use peer::PeerError;
 
+use config::Config;
+
+use v2transport::V2Cipher;
+use v2transport::LENGTH_SIZE;
+use v2transport::PACKET_OVERHEAD;
+use v2transport::MAX_MSG_TYPE_SIZE;
+use v2transport::GARBAGE_TERMINATOR_SIZE;
+use v2transport::MAX_GARBAGE_SIZE;
+use v2transport::V1_PREFIX_SIZE;
+use v2transport::v1_prefix;
+
 const PAYLOAD_MAX_SIZE : uint = 4*(1<<20); /* 4MB */
 
-const TIMEOUT_READ_MS : uint = 500;
+/* Large enough for a message with its v1 header or in a v2 packet */
+const BUFFER_SIZE : uint = PAYLOAD_MAX_SIZE+MAX_MSG_TYPE_SIZE+PACKET_OVERHEAD;
 
 pub struct MsgBuffer
 {
-    buf : Vec<u8>
+    buf             : Vec<u8>,
+    /* Receiving cipher, once the v2 handshake is done */
+    v2              : Option<V2Cipher>,
+    /* Length of the v2 packet being read, it can only be decrypted once */
+    v2_length       : Option<uint>,
+    read_timeout_ms : u64,
+    /* Of the network we are on, messages of other networks are rejected */
+    magic           : u32,
+    /* Read from the socket so far */
+    bytes_read      : u64,
+    /* Size on the wire of the last message read */
+    msg_size        : uint
 }
 
 impl MsgBuffer
 {
-    pub fn new() -> MsgBuffer
+    pub fn new(config : &Config) -> MsgBuffer
     {
         MsgBuffer
         {
-            buf: Vec::with_capacity(PAYLOAD_MAX_SIZE+HEADER_SIZE)
+            buf:             Vec::with_capacity(BUFFER_SIZE),
+            v2:              None,
+            v2_length:       None,
+            read_timeout_ms: config.read_timeout_ms as u64,
+            magic:           config.get_params().magic,
+            bytes_read:      0,
+            msg_size:        0
         }
     }
 
+    pub fn get_bytes_read(&self) -> u64
+    {
+        self.bytes_read
+    }
+
+    pub fn get_msg_size(&self) -> uint
+    {
+        self.msg_size
+    }
+
     fn drop(&mut self, n : uint)
     {
         let len = self.buf.len();
@@ -59,10 +126,14 @@ impl MsgBuffer
         if self.buf.len() < size
         {
             let result;
+            let before : uint = self.buf.len();
 
-            socket.set_read_timeout(Some(TIMEOUT_READ_MS as u64));
+            socket.set_read_timeout(Some(self.read_timeout_ms));
             result = socket.push(size-self.buf.len(),&mut self.buf);
 
+            /* Even if it failed, part of it may have been read */
+            self.bytes_read += (self.buf.len()-before) as u64;
+
             if result.is_err()
             {
                 match result.err().unwrap().kind
@@ -89,55 +160,94 @@ impl MsgBuffer
         Ok(())
     }
 
-    pub fn read_message(&mut self, socket : &mut TcpStream)
-                        -> Result<Message,PeerError>
+    /* Read exactly size bytes for the v2 handshake, which blocks until it is
+     * done or the deadline passes.
+     */
+    fn read_until(&mut self, size : uint, socket : &mut TcpStream, deadline : Timespec)
+                  -> Result<(),PeerError>
     {
-        let header : Header;
-        let msg : Result<Message,PeerError>;
+        loop
+        {
+            match self.read_ensure_size(size,socket)
+            {
+                Err(ref err) if !err.is_fatal() && time::now_utc().to_timespec() < deadline => (),
+                Err(ref err) if !err.is_fatal() => return Err(PeerError::V2HandshakeTimeout),
+                result                          => return result
+            }
+        }
+    }
 
-        /* We should never have to expand */
-        assert!(self.buf.capacity() == PAYLOAD_MAX_SIZE+HEADER_SIZE);
+    /* As the responder, whether the peer started with a v1 version message.
+     * The bytes stay in the buffer either way, they are the start of the
+     * message or of the key.
+     */
+    pub fn read_v1_prefix(&mut self, socket : &mut TcpStream, deadline : Timespec)
+                          -> Result<bool,PeerError>
+    {
+        try!(self.read_until(V1_PREFIX_SIZE,socket,deadline));
 
-        /* Read enoght to have a header */
-        try!(self.read_ensure_size(HEADER_SIZE,socket));
+        Ok(self.buf.as_slice() == v1_prefix(self.magic).as_slice())
+    }
 
-        assert!(self.buf.len() >= HEADER_SIZE);
+    pub fn read_v2_key(&mut self, socket : &mut TcpStream, deadline : Timespec)
+                       -> Result<Vec<u8>,PeerError>
+    {
+        let key : Vec<u8>;
 
-        header = Header::unserialize(&self.buf);
+        try!(self.read_until(::secp256k1::ELLSWIFT_SIZE,socket,deadline));
 
-        if header.get_payload_size() > PAYLOAD_MAX_SIZE
-        {
-            return Err(PeerError::ReadMsgPayloadTooBig);
-        }
+        key = self.buf.clone();
 
-        /* Read enoght to have the message payload */
-        try!(self.read_ensure_size(HEADER_SIZE+header.get_payload_size(),socket));
+        self.buf.clear();
 
-        assert!(self.buf.len() == HEADER_SIZE+header.get_payload_size());
+        Ok(key)
+    }
 
-        /* We can now safely drop the header, since we have a complete message */
-        self.drop(HEADER_SIZE);
+    /* Read the garbage of the peer up to the terminator, which is not part of
+     * the garbage returned.
+     */
+    pub fn read_v2_garbage(&mut self, terminator : &[u8], socket : &mut TcpStream,
+                           deadline : Timespec) -> Result<Vec<u8>,PeerError>
+    {
+        let garbage : Vec<u8>;
 
-        assert!(self.buf.len() == header.get_payload_size());
+        try!(self.read_until(GARBAGE_TERMINATOR_SIZE,socket,deadline));
 
-        if ::crypto::checksum(self.buf.as_slice()) != header.get_checksum()
+        while !self.buf.as_slice().ends_with(terminator)
         {
-            return Err(PeerError::ReadMsgInvalidChecksum);
-        }
+            let len : uint = self.buf.len();
 
-        if header.get_network() != ::config::NETWORK
-        {
-            return Err(PeerError::ReadMsgWrongNetwork);
+            if len == MAX_GARBAGE_SIZE+GARBAGE_TERMINATOR_SIZE
+            {
+                return Err(PeerError::V2HandshakeFailed);
+            }
+
+            try!(self.read_until(len+1,socket,deadline));
         }
 
-        msg = match header.get_command().as_slice()
+        garbage = self.buf.slice_to(self.buf.len()-GARBAGE_TERMINATOR_SIZE).to_vec();
+
+        self.buf.clear();
+
+        Ok(garbage)
+    }
+
+    /* All the messages after this are read from v2 packets */
+    pub fn start_v2(&mut self, cipher : V2Cipher)
+    {
+        self.v2 = Some(cipher);
+    }
+
+    /* Build the message from the payload in the buffer */
+    fn parse_message(&self, command : &str) -> Result<Message,PeerError>
+    {
+        match command
         {
             "version" =>
             {
                 let version : Version;
 
-                version = Version::unserialize(&self.buf,
-                                               header.get_payload_size());
+                version = Version::unserialize(&self.buf,self.buf.len());
 
                 Ok(Message::MsgVersion(version))
             },
@@ -213,11 +323,336 @@ impl MsgBuffer
 
                 Ok(Message::MsgGetAddr(getaddr))
             },
+            "block" =>
+            {
+                let block : Block;
+
+                block = Block::unserialize(&self.buf);
+
+                Ok(Message::MsgBlock(block))
+            },
+            "notfound" =>
+            {
+                let notfound : NotFound;
+
+                notfound = NotFound::unserialize(&self.buf);
+
+                Ok(Message::MsgNotFound(notfound))
+            },
+            "mempool" =>
+            {
+                let mempool : MemPool;
+
+                mempool = MemPool::unserialize(&self.buf);
+
+                Ok(Message::MsgMemPool(mempool))
+            },
+            "filterload" =>
+            {
+                let filterload : FilterLoad;
+
+                filterload = FilterLoad::unserialize(&self.buf);
+
+                Ok(Message::MsgFilterLoad(filterload))
+            },
+            "filteradd" =>
+            {
+                let filteradd : FilterAdd;
+
+                filteradd = FilterAdd::unserialize(&self.buf);
+
+                Ok(Message::MsgFilterAdd(filteradd))
+            },
+            "filterclear" =>
+            {
+                let filterclear : FilterClear;
+
+                filterclear = FilterClear::unserialize(&self.buf);
+
+                Ok(Message::MsgFilterClear(filterclear))
+            },
+            "merkleblock" =>
+            {
+                let merkleblock : MerkleBlock;
+
+                merkleblock = MerkleBlock::unserialize(&self.buf);
+
+                Ok(Message::MsgMerkleBlock(merkleblock))
+            },
+            "getcfilters" =>
+            {
+                let getcfilters : GetCFilters;
+
+                getcfilters = GetCFilters::unserialize(&self.buf);
+
+                Ok(Message::MsgGetCFilters(getcfilters))
+            },
+            "cfilter" =>
+            {
+                let cfilter : CFilter;
+
+                cfilter = CFilter::unserialize(&self.buf);
+
+                Ok(Message::MsgCFilter(cfilter))
+            },
+            "getcfheaders" =>
+            {
+                let getcfheaders : GetCFHeaders;
+
+                getcfheaders = GetCFHeaders::unserialize(&self.buf);
+
+                Ok(Message::MsgGetCFHeaders(getcfheaders))
+            },
+            "cfheaders" =>
+            {
+                let cfheaders : CFHeaders;
+
+                cfheaders = CFHeaders::unserialize(&self.buf);
+
+                Ok(Message::MsgCFHeaders(cfheaders))
+            },
+            "getcfcheckpt" =>
+            {
+                let getcfcheckpt : GetCFCheckpt;
+
+                getcfcheckpt = GetCFCheckpt::unserialize(&self.buf);
+
+                Ok(Message::MsgGetCFCheckpt(getcfcheckpt))
+            },
+            "cfcheckpt" =>
+            {
+                let cfcheckpt : CFCheckpt;
+
+                cfcheckpt = CFCheckpt::unserialize(&self.buf);
+
+                Ok(Message::MsgCFCheckpt(cfcheckpt))
+            },
+            "sendcmpct" =>
+            {
+                let sendcmpct : SendCmpct;
+
+                sendcmpct = SendCmpct::unserialize(&self.buf);
+
+                Ok(Message::MsgSendCmpct(sendcmpct))
+            },
+            "cmpctblock" =>
+            {
+                let cmpctblock : CmpctBlock;
+
+                cmpctblock = match CmpctBlock::unserialize(&self.buf)
+                {
+                    Some(cmpctblock) => cmpctblock,
+                    None             => return Err(PeerError::ReadMsgMalformed)
+                };
+
+                Ok(Message::MsgCmpctBlock(cmpctblock))
+            },
+            "getblocktxn" =>
+            {
+                let getblocktxn : GetBlockTxn;
+
+                getblocktxn = match GetBlockTxn::unserialize(&self.buf)
+                {
+                    Some(getblocktxn) => getblocktxn,
+                    None              => return Err(PeerError::ReadMsgMalformed)
+                };
+
+                Ok(Message::MsgGetBlockTxn(getblocktxn))
+            },
+            "blocktxn" =>
+            {
+                let blocktxn : BlockTxn;
+
+                blocktxn = BlockTxn::unserialize(&self.buf);
+
+                Ok(Message::MsgBlockTxn(blocktxn))
+            },
+            "sendheaders" =>
+            {
+                let sendheaders : SendHeaders;
+
+                sendheaders = SendHeaders::unserialize(&self.buf);
+
+                Ok(Message::MsgSendHeaders(sendheaders))
+            },
+            "getheaders" =>
+            {
+                let getheaders : GetHeaders;
+
+                getheaders = match GetHeaders::unserialize(&self.buf)
+                {
+                    Some(getheaders) => getheaders,
+                    None             => return Err(PeerError::ReadMsgMalformed)
+                };
+
+                Ok(Message::MsgGetHeaders(getheaders))
+            },
+            "headers" =>
+            {
+                let headers : Headers;
+
+                headers = match Headers::unserialize(&self.buf)
+                {
+                    Some(headers) => headers,
+                    None          => return Err(PeerError::ReadMsgMalformed)
+                };
+
+                Ok(Message::MsgHeaders(headers))
+            },
+            "feefilter" =>
+            {
+                let feefilter : FeeFilter;
+
+                feefilter = FeeFilter::unserialize(&self.buf);
+
+                Ok(Message::MsgFeeFilter(feefilter))
+            },
+            "wtxidrelay" =>
+            {
+                let wtxidrelay : WtxidRelay;
+
+                wtxidrelay = WtxidRelay::unserialize(&self.buf);
+
+                Ok(Message::MsgWtxidRelay(wtxidrelay))
+            },
+            "sendaddrv2" =>
+            {
+                let sendaddrv2 : SendAddrV2;
+
+                sendaddrv2 = SendAddrV2::unserialize(&self.buf);
+
+                Ok(Message::MsgSendAddrV2(sendaddrv2))
+            },
+            "addrv2" =>
+            {
+                let addrv2 : AddrV2;
+
+                addrv2 = match AddrV2::unserialize(&self.buf)
+                {
+                    Some(addrv2) => addrv2,
+                    None         => return Err(PeerError::ReadMsgMalformed)
+                };
+
+                Ok(Message::MsgAddrV2(addrv2))
+            },
             _ => Err(PeerError::ReadMsgUnknownCommand)
-        };
+        }
+    }
+
+    pub fn read_message(&mut self, socket : &mut TcpStream)
+                        -> Result<Message,PeerError>
+    {
+        let header : Header;
+        let msg : Result<Message,PeerError>;
+
+        /* We should never have to expand */
+        assert!(self.buf.capacity() == BUFFER_SIZE);
+
+        if self.v2.is_some()
+        {
+            return self.read_v2_message(socket);
+        }
+
+        /* Read enoght to have a header */
+        try!(self.read_ensure_size(HEADER_SIZE,socket));
+
+        assert!(self.buf.len() >= HEADER_SIZE);
+
+        header = Header::unserialize(&self.buf);
+
+        if header.get_payload_size() > PAYLOAD_MAX_SIZE
+        {
+            return Err(PeerError::ReadMsgPayloadTooBig);
+        }
+
+        /* Read enoght to have the message payload */
+        try!(self.read_ensure_size(HEADER_SIZE+header.get_payload_size(),socket));
+
+        assert!(self.buf.len() == HEADER_SIZE+header.get_payload_size());
+
+        self.msg_size = self.buf.len();
+
+        /* We can now safely drop the header, since we have a complete message */
+        self.drop(HEADER_SIZE);
+
+        assert!(self.buf.len() == header.get_payload_size());
+
+        if ::crypto::checksum(self.buf.as_slice()) != header.get_checksum()
+        {
+            return Err(PeerError::ReadMsgInvalidChecksum);
+        }
+
+        if header.get_network() != self.magic
+        {
+            return Err(PeerError::ReadMsgWrongNetwork);
+        }
+
+        msg = self.parse_message(header.get_command().as_slice());
 
         self.buf.clear();
 
         msg
     }
+
+    /* Decoys and the version packet are skipped */
+    fn read_v2_message(&mut self, socket : &mut TcpStream) -> Result<Message,PeerError>
+    {
+        loop
+        {
+            let length : uint;
+            let packet : Option<(bool, Vec<u8>)>;
+            let contents : Vec<u8>;
+            let msg : Result<Message,PeerError>;
+
+            if self.v2_length.is_none()
+            {
+                let contents_length : uint;
+
+                try!(self.read_ensure_size(LENGTH_SIZE,socket));
+
+                contents_length = self.v2.as_mut().unwrap().decrypt_length(self.buf.as_slice());
+
+                if contents_length > PAYLOAD_MAX_SIZE+MAX_MSG_TYPE_SIZE
+                {
+                    return Err(PeerError::ReadMsgPayloadTooBig);
+                }
+
+                self.v2_length = Some(contents_length);
+                self.buf.clear();
+            }
+
+            length = self.v2_length.unwrap()+PACKET_OVERHEAD-LENGTH_SIZE;
+
+            try!(self.read_ensure_size(length,socket));
+
+            self.v2_length = None;
+            self.msg_size = LENGTH_SIZE+length;
+
+            packet = self.v2.as_mut().unwrap().decrypt_packet(self.buf.as_slice());
+
+            self.buf.clear();
+
+            contents = match packet
+            {
+                Some((false, contents)) => contents,
+                Some((true, _))         => continue,
+                None                    => return Err(PeerError::ReadMsgNotAuthentic)
+            };
+
+            msg = match ::v2transport::decode_contents(contents.as_slice())
+            {
+                Some((command, offset)) =>
+                {
+                    self.buf.push_all(contents.slice_from(offset));
+
+                    self.parse_message(command.as_slice())
+                },
+                None => Err(PeerError::ReadMsgUnknownCommand)
+            };
+
+            self.buf.clear();
+
+            return msg;
+        }
+    }
 }
@@ -0,0 +1,545 @@
+extern crate time;
+
+use std::io::net::ip::SocketAddr;
+use std::io::net::ip::IpAddr;
+use std::io::TcpStream;
+use std::io::TcpListener;
+use std::io::TcpAcceptor;
+use std::io::Listener;
+use std::io::Acceptor;
+use std::io::IoResult;
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicUint;
+use std::sync::atomic::SeqCst;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::hash::Hash;
+use datatype::netaddr::NetAddrV2;
+use datatype::script::Script;
+use datatype::transaction::Transaction;
+use datatype::transaction::OutPoint;
+
+use self::time::Timespec;
+
+use utxo::Coin;
+
+use comm::DuplexChannel;
+
+use config::Config;
+
+use connector::Destination;
+
+use logger::NodeLogger;
+
+use addrmng::AddrManagerChannel;
+use addrmng::AddrManager;
+use addrmng::AddrManagerRequest;
+use addrmng::AddrManagerReply;
+
+use chainmng::ChainManagerChannel;
+use chainmng::ChainManager;
+use chainmng::ChainManagerRequest;
+use chainmng::ChainManagerReply;
+use chainmng::ChainInfo;
+
+use peer::Peer;
+use peer::PeerError;
+use peer::PeerInfo;
+
+/* A node is the address manager, the chain manager and the peers talking to
+ * them, each in its own thread.  Nothing is global, every node has its
+ * configuration and its logger, so many nodes can run in the same process,
+ * which is what the tests do.
+ */
+pub struct Node
+{
+    config        : Arc<Config>,
+    logger        : NodeLogger,
+    addr_channel  : AddrManagerChannel,
+    chain_channel : ChainManagerChannel,
+    /* Inbound peers connected, on all the addresses we listen on */
+    inbound       : Arc<AtomicUint>
+}
+
+fn run_peer(peer : &mut Peer) -> Result<(),PeerError>
+{
+    let result : Result<(),PeerError>;
+
+    try!(peer.connect());
+    try!(peer.send_version());
+
+    result = peer.read_loop();
+    peer.remove();
+
+    result
+}
+
+fn spawn_thread_run_peer(dest          : Destination,
+                         config        : Arc<Config>,
+                         logger        : NodeLogger,
+                         addr_channel  : AddrManagerChannel,
+                         chain_channel : ChainManagerChannel)
+{
+    spawn(proc() {
+        let mut peer : Peer;
+
+        logger.install();
+
+        peer = Peer::new_outbound(dest,config,addr_channel,chain_channel);
+
+        match run_peer(&mut peer)
+        {
+            Err(err) =>
+            {
+                ::logger::log_peer_error_fatal(&peer.get_addr(), err);
+            },
+            _        => unreachable!()
+        }
+    });
+}
+
+fn run_inbound_peer(socket        : TcpStream,
+                    address       : SocketAddr,
+                    config        : Arc<Config>,
+                    addr_channel  : AddrManagerChannel,
+                    chain_channel : ChainManagerChannel) -> Result<(),PeerError>
+{
+    let mut peer : Peer;
+    let result : Result<(),PeerError>;
+
+    peer = Peer::new_inbound(socket,address,config,addr_channel,chain_channel);
+
+    try!(peer.accept());
+
+    result = peer.read_loop();
+    peer.remove();
+
+    result
+}
+
+fn spawn_thread_run_inbound_peer(socket        : TcpStream,
+                                 address       : SocketAddr,
+                                 config        : Arc<Config>,
+                                 logger        : NodeLogger,
+                                 addr_channel  : AddrManagerChannel,
+                                 chain_channel : ChainManagerChannel,
+                                 inbound       : Arc<AtomicUint>)
+{
+    spawn(proc() {
+        logger.install();
+
+        match run_inbound_peer(socket,address,config,addr_channel,chain_channel)
+        {
+            Err(err) =>
+            {
+                ::logger::log_peer_error_fatal(&address, err);
+            },
+            _        => unreachable!()
+        }
+
+        inbound.fetch_sub(1,SeqCst);
+    });
+}
+
+fn spawn_thread_run_address_manager(orchestrator : DuplexChannel<AddrManagerReply,
+                                                                 AddrManagerRequest>,
+                                    config       : Arc<Config>,
+                                    logger       : NodeLogger)
+{
+    spawn(proc() {
+        let mut addr_mng : AddrManager;
+
+        logger.install();
+
+        addr_mng = AddrManager::new(orchestrator,&*config);
+
+        addr_mng.read_loop();
+    });
+}
+
+fn spawn_thread_run_chain_manager(orchestrator : DuplexChannel<ChainManagerReply,
+                                                               ChainManagerRequest>,
+                                  config       : Arc<Config>,
+                                  logger       : NodeLogger)
+{
+    spawn(proc() {
+        let mut chain_mng : ChainManager;
+
+        logger.install();
+
+        chain_mng = ChainManager::new(orchestrator,&*config);
+
+        chain_mng.read_loop();
+    });
+}
+
+/* Give every inbound connection a peer of its own, as long as there are less
+ * than the maximum.
+ */
+fn spawn_thread_accept_peers(acceptor         : TcpAcceptor,
+                             config           : Arc<Config>,
+                             logger           : NodeLogger,
+                             channel_us       : AddrManagerChannel,
+                             channel_chain_us : ChainManagerChannel,
+                             inbound          : Arc<AtomicUint>)
+{
+    spawn(proc() {
+        let mut acceptor : TcpAcceptor = acceptor;
+
+        logger.install();
+
+        for maybesocket in acceptor.incoming()
+        {
+            let mut socket : TcpStream = match maybesocket
+            {
+                Ok(socket) => socket,
+                Err(_)     => continue
+            };
+            let address : SocketAddr = match socket.peer_name()
+            {
+                Ok(address) => address,
+                Err(_)      => continue
+            };
+
+            /* Dropping the socket closes it */
+            if inbound.load(SeqCst) >= config.max_inbound
+            {
+                ::logger::log_inbound_full(&address);
+                continue;
+            }
+
+            let (channel_peer, channel_addrmng)
+                = ::comm::sync_duplex_channel(::addrmng::ADDRMNG_CHANNEL_BUF_CAP);
+            let (channel_chain_peer, channel_chainmng)
+                = ::comm::sync_duplex_channel(::chainmng::CHAINMNG_CHANNEL_BUF_CAP);
+
+            channel_us.sender.send(AddrManagerRequest::AddrMngIsBanned(address.ip));
+
+            match channel_us.receiver.recv()
+            {
+                AddrManagerReply::AddrMngResult(true)  => continue,
+                AddrManagerReply::AddrMngResult(false) => (),
+                _                                      => unreachable!()
+            }
+
+            channel_us.sender.send(AddrManagerRequest::AddrMngAddPeerChannel(channel_addrmng));
+            channel_chain_us.sender.send(
+                ChainManagerRequest::ChainMngAddPeerChannel(channel_chainmng));
+
+            inbound.fetch_add(1,SeqCst);
+
+            spawn_thread_run_inbound_peer(socket,address,config.clone(),logger.clone(),
+                                          channel_peer,channel_chain_peer,inbound.clone());
+        }
+    });
+}
+
+#[allow(dead_code)]
+impl Node
+{
+    pub fn start(config : Arc<Config>, logger : NodeLogger) -> Node
+    {
+        let (channel_us, channel_addrmng)
+            = ::comm::sync_duplex_channel(::addrmng::ADDRMNG_CHANNEL_BUF_CAP);
+        let (channel_chain_us, channel_chainmng)
+            = ::comm::sync_duplex_channel(::chainmng::CHAINMNG_CHANNEL_BUF_CAP);
+
+        spawn_thread_run_address_manager(channel_addrmng,config.clone(),logger.clone());
+        spawn_thread_run_chain_manager(channel_chainmng,config.clone(),logger.clone());
+
+        Node
+        {
+            config:        config,
+            logger:        logger,
+            addr_channel:  channel_us,
+            chain_channel: channel_chain_us,
+            inbound:       Arc::new(AtomicUint::new(0))
+        }
+    }
+
+    pub fn get_config(&self) -> &Config
+    {
+        &*self.config
+    }
+
+    pub fn get_logger(&self) -> &NodeLogger
+    {
+        &self.logger
+    }
+
+    /* The same node, for another thread */
+    pub fn handle(&self) -> Node
+    {
+        Node
+        {
+            config:        self.config.clone(),
+            logger:        self.logger.clone(),
+            addr_channel:  self.new_addr_channel(),
+            chain_channel: self.new_chain_channel(),
+            inbound:       self.inbound.clone()
+        }
+    }
+
+    /* A channel to the address manager for another thread */
+    pub fn new_addr_channel(&self) -> AddrManagerChannel
+    {
+        let (channel, channel_addrmng)
+            = ::comm::sync_duplex_channel(::addrmng::ADDRMNG_CHANNEL_BUF_CAP);
+
+        self.addr_channel.sender.send(AddrManagerRequest::AddrMngAddPeerChannel(channel_addrmng));
+
+        channel
+    }
+
+    /* A channel to the chain manager for another thread */
+    pub fn new_chain_channel(&self) -> ChainManagerChannel
+    {
+        let (channel, channel_chainmng)
+            = ::comm::sync_duplex_channel(::chainmng::CHAINMNG_CHANNEL_BUF_CAP);
+
+        self.chain_channel.sender.send(
+            ChainManagerRequest::ChainMngAddPeerChannel(channel_chainmng));
+
+        channel
+    }
+
+    pub fn connect(&self, address : SocketAddr)
+    {
+        self.connect_to(Destination::Ip(address));
+    }
+
+    /* False if we cannot reach the network of the address */
+    pub fn connect_addr(&self, address : &NetAddrV2) -> bool
+    {
+        match ::connector::destination(address,&*self.config)
+        {
+            Some(dest) => self.connect_to(dest),
+            None       => return false
+        }
+
+        true
+    }
+
+    fn connect_to(&self, dest : Destination)
+    {
+        spawn_thread_run_peer(dest,self.config.clone(),self.logger.clone(),
+                              self.new_addr_channel(),self.new_chain_channel());
+    }
+
+    /* Accept inbound peers.  Returns the address we listen on, which tells the
+     * port when we ask for any.
+     */
+    pub fn listen(&self, address : SocketAddr) -> IoResult<SocketAddr>
+    {
+        let mut acceptor : TcpAcceptor = try!(TcpListener::bind(address).listen());
+        let bound : SocketAddr = try!(acceptor.socket_name());
+
+        spawn_thread_accept_peers(acceptor,self.config.clone(),self.logger.clone(),
+                                  self.new_addr_channel(),self.new_chain_channel(),
+                                  self.inbound.clone());
+
+        Ok(bound)
+    }
+
+    fn addr_mng_send_recv(&self, request : AddrManagerRequest) -> AddrManagerReply
+    {
+        self.addr_channel.sender.send(request);
+        self.addr_channel.receiver.recv()
+    }
+
+    fn chain_mng_send_recv(&self, request : ChainManagerRequest) -> ChainManagerReply
+    {
+        self.chain_channel.sender.send(request);
+        self.chain_channel.receiver.recv()
+    }
+
+    /* The address our peers advertise for us */
+    pub fn set_local_address(&self, address : NetAddrV2)
+    {
+        self.addr_channel.sender.send(AddrManagerRequest::AddrMngSetLocalAddress(address));
+    }
+
+    pub fn get_local_address(&self) -> Option<NetAddrV2>
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngGetLocalAddress)
+        {
+            AddrManagerReply::AddrMngLocalAddress(addr) => addr,
+            _                                           => unreachable!()
+        }
+    }
+
+    /* Connected peers with their ids */
+    pub fn get_peers(&self) -> Vec<(uint,PeerInfo)>
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngGetPeers)
+        {
+            AddrManagerReply::AddrMngPeers(peers) => peers,
+            _                                     => unreachable!()
+        }
+    }
+
+    /* Whether we were connected to it */
+    pub fn disconnect_peer(&self, address : SocketAddr) -> bool
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngDisconnectPeer(address))
+        {
+            AddrManagerReply::AddrMngResult(known) => known,
+            _                                      => unreachable!()
+        }
+    }
+
+    /* Connected peers with that address are disconnected as well */
+    pub fn ban(&self, ip : IpAddr, until : Timespec)
+    {
+        self.addr_channel.sender.send(AddrManagerRequest::AddrMngBan(ip,until));
+    }
+
+    /* Whether it was banned */
+    pub fn unban(&self, ip : IpAddr) -> bool
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngUnban(ip))
+        {
+            AddrManagerReply::AddrMngResult(banned) => banned,
+            _                                       => unreachable!()
+        }
+    }
+
+    pub fn is_banned(&self, ip : IpAddr) -> bool
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngIsBanned(ip))
+        {
+            AddrManagerReply::AddrMngResult(banned) => banned,
+            _                                       => unreachable!()
+        }
+    }
+
+    /* Number of addresses in each bucket of the address manager */
+    pub fn get_bucket_sizes(&self) -> Vec<uint>
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngGetBucketSizes)
+        {
+            AddrManagerReply::AddrMngBucketSizes(sizes) => sizes,
+            _                                           => unreachable!()
+        }
+    }
+
+    /* Addresses we know of each network, by the network ids of BIP155 */
+    pub fn get_network_sizes(&self) -> Vec<uint>
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngGetNetworkSizes)
+        {
+            AddrManagerReply::AddrMngNetworkSizes(sizes) => sizes,
+            _                                            => unreachable!()
+        }
+    }
+
+    pub fn get_addresses(&self) -> Vec<NetAddrV2>
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngGetManyAddresses)
+        {
+            AddrManagerReply::AddrMngAddresses(addrs) => addrs,
+            _                                         => unreachable!()
+        }
+    }
+
+    /* Hash and height of the tip of the active chain */
+    pub fn get_tip(&self) -> (Hash, u32)
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetTip)
+        {
+            ChainManagerReply::ChainMngTip(hash,height) => (hash, height),
+            _                                           => unreachable!()
+        }
+    }
+
+    pub fn get_chain_info(&self) -> ChainInfo
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetChainInfo)
+        {
+            ChainManagerReply::ChainMngChainInfo(info) => info,
+            _                                          => unreachable!()
+        }
+    }
+
+    /* Height of the block, if it is in the active chain, and of the tip */
+    pub fn get_active_height(&self, hash : Hash) -> (Option<u32>, u32)
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetActiveHeight(hash))
+        {
+            ChainManagerReply::ChainMngActiveHeight(height,tip) => (height, tip),
+            _                                                   => unreachable!()
+        }
+    }
+
+    /* Up to count headers of the active chain, from the given block on, with
+     * the height of the first one and of the tip.
+     */
+    pub fn get_headers(&self, hash : Hash, count : uint)
+                       -> (Vec<BlockHeader>, Option<u32>, u32)
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetHeaders(hash,count))
+        {
+            ChainManagerReply::ChainMngHeaders(headers,start,tip) => (headers, start, tip),
+            _                                                     => unreachable!()
+        }
+    }
+
+    /* The unspent outputs, with the height and the tip of the chain */
+    pub fn get_coins(&self,
+                     outpoints : Vec<OutPoint>,
+                     mempool   : bool) -> (u32, Hash, Vec<Option<Coin>>)
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetCoins(outpoints,mempool))
+        {
+            ChainManagerReply::ChainMngCoins(height,tip,coins) => (height, tip, coins),
+            _                                                  => unreachable!()
+        }
+    }
+
+    /* Hashes of the mempool txs, best fee rate first */
+    pub fn get_mempool(&self) -> Vec<Hash>
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetMempool(0))
+        {
+            ChainManagerReply::ChainMngMempool(hashes) => hashes,
+            _                                          => unreachable!()
+        }
+    }
+
+    /* Mine blocks paying to the script.  Returns their hashes. */
+    pub fn generate(&self, count : uint, script : Script) -> Vec<Hash>
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGenerate(count,script))
+        {
+            ChainManagerReply::ChainMngGenerated(hashes) => hashes,
+            _                                            => unreachable!()
+        }
+    }
+
+    pub fn get_block(&self, hash : Hash) -> Option<Block>
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetBlock(hash))
+        {
+            ChainManagerReply::ChainMngBlock(block) => block,
+            _                                       => unreachable!()
+        }
+    }
+
+    /* Submit a tx of our own, as if the given address relayed it */
+    pub fn add_tx(&self, address : SocketAddr, tx : Transaction) -> ChainManagerReply
+    {
+        self.chain_mng_send_recv(ChainManagerRequest::ChainMngAddTx(address,tx))
+    }
+
+    /* The tx, if it is in the mempool */
+    pub fn get_mempool_tx(&self, hash : Hash) -> Option<Transaction>
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetTx(hash))
+        {
+            ChainManagerReply::ChainMngTx(tx) => tx,
+            _                                 => unreachable!()
+        }
+    }
+}
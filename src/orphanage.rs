@@ -0,0 +1,247 @@
+extern crate time;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use std::io::net::ip::SocketAddr;
+
+use self::time::Timespec;
+use std::time::duration::Duration;
+
+use datatype::block::Block;
+use datatype::transaction::Transaction;
+use datatype::hash::Hash;
+
+use crypto::rand_interval;
+
+const MAX_ORPHANS : uint = 100;
+const MAX_ORPHANS_PER_PEER : uint = 25;
+
+/* Bigger orphans are not worth the memory, since we cannot validate them */
+const MAX_ORPHAN_TX_SIZE : uint = 100_000;
+
+const ORPHAN_EXPIRY_M : i64 = 20;
+
+struct Orphan
+{
+    tx      : Transaction,
+    peer    : SocketAddr,  /* Who sent it to us */
+    parents : Vec<Hash>,   /* Parents we are missing */
+    time    : Timespec
+}
+
+/* Transactions whose parents we do not know (yet).
+ */
+pub struct Orphanage
+{
+    orphans    : HashMap<Hash,Orphan>,
+    by_parent  : HashMap<Hash,HashSet<Hash>>,
+    per_peer   : HashMap<SocketAddr,uint>
+}
+
+#[allow(dead_code)]
+impl Orphanage
+{
+    pub fn new() -> Orphanage
+    {
+        Orphanage
+        {
+            orphans:   HashMap::new(),
+            by_parent: HashMap::new(),
+            per_peer:  HashMap::new()
+        }
+    }
+
+    pub fn len(&self) -> uint
+    {
+        self.orphans.len()
+    }
+
+    pub fn contains(&self, hash : &Hash) -> bool
+    {
+        self.orphans.contains_key(hash)
+    }
+
+    fn peer_count(&self, peer : &SocketAddr) -> uint
+    {
+        *self.per_peer.get(peer).unwrap_or(&0)
+    }
+
+    /* Returns false if the orphan was not added */
+    pub fn add(&mut self, tx : Transaction, peer : SocketAddr, parents : Vec<Hash>) -> bool
+    {
+        let hash : Hash = tx.get_hash();
+        let count : uint = self.peer_count(&peer);
+
+        if self.contains(&hash)
+        {
+            return true;
+        }
+
+        if tx.get_size() > MAX_ORPHAN_TX_SIZE || count >= MAX_ORPHANS_PER_PEER
+        {
+            return false;
+        }
+
+        for parent in parents.iter()
+        {
+            if !self.by_parent.contains_key(parent)
+            {
+                self.by_parent.insert(parent.clone(),HashSet::new());
+            }
+
+            self.by_parent.get_mut(parent).unwrap().insert(hash.clone());
+        }
+
+        self.per_peer.insert(peer,count+1);
+
+        self.orphans.insert(hash.clone(),Orphan
+        {
+            tx:      tx,
+            peer:    peer,
+            parents: parents,
+            time:    time::now_utc().to_timespec()
+        });
+
+        ::logger::log_orphan_added(&hash,&peer,self.len());
+
+        self.limit();
+
+        true
+    }
+
+    /* Remove an orphan returning the transaction and who sent it */
+    pub fn take(&mut self, hash : &Hash) -> Option<(Transaction, SocketAddr)>
+    {
+        let orphan : Orphan = match self.orphans.remove(hash)
+        {
+            Some(orphan) => orphan,
+            None         => return None
+        };
+        let count : uint = self.peer_count(&orphan.peer);
+
+        for parent in orphan.parents.iter()
+        {
+            let empty : bool;
+
+            {
+                let children = self.by_parent.get_mut(parent).unwrap();
+
+                children.remove(hash);
+                empty = children.is_empty();
+            }
+
+            if empty
+            {
+                self.by_parent.remove(parent);
+            }
+        }
+
+        if count > 1
+        {
+            self.per_peer.insert(orphan.peer,count-1);
+        }
+        else
+        {
+            self.per_peer.remove(&orphan.peer);
+        }
+
+        Some((orphan.tx, orphan.peer))
+    }
+
+    /* Orphans that are waiting for the given parent */
+    pub fn get_children(&self, parent : &Hash) -> Vec<Hash>
+    {
+        match self.by_parent.get(parent)
+        {
+            Some(children) => children.iter().map(|h| h.clone()).collect(),
+            None           => Vec::new()
+        }
+    }
+
+    /* Randomly evict orphans until we are within limits.  Evicting randomly
+     * means an attacker cannot choose which orphans get dropped.
+     */
+    fn limit(&mut self)
+    {
+        while self.orphans.len() > MAX_ORPHANS
+        {
+            let hashes : Vec<Hash> = self.orphans.keys().map(|h| h.clone()).collect();
+            let victim : &Hash = &hashes[rand_interval(0,hashes.len()-1)];
+
+            self.take(victim);
+
+            ::logger::log_orphan_evicted(victim);
+        }
+    }
+
+    pub fn expire(&mut self)
+    {
+        self.expire_at(time::now_utc().to_timespec());
+    }
+
+    /* Evict the orphans that are too old at the given time */
+    pub fn expire_at(&mut self, now : Timespec)
+    {
+        let limit : Timespec = now-Duration::minutes(ORPHAN_EXPIRY_M);
+        let expired : Vec<Hash>;
+
+        expired = self.orphans.iter()
+            .filter(|&(_, o)| o.time < limit)
+            .map(|(h, _)| h.clone())
+            .collect();
+
+        for hash in expired.iter()
+        {
+            self.take(hash);
+
+            ::logger::log_orphan_evicted(hash);
+        }
+    }
+
+    /* The peer disconnected, the orphans it sent us go with it */
+    pub fn erase_for_peer(&mut self, peer : &SocketAddr)
+    {
+        let erased : Vec<Hash>;
+
+        erased = self.orphans.iter()
+            .filter(|&(_, o)| o.peer == *peer)
+            .map(|(h, _)| h.clone())
+            .collect();
+
+        for hash in erased.iter()
+        {
+            self.take(hash);
+        }
+    }
+
+    /* Drop orphans that were included in a block or that spend the same
+     * outputs as a transaction in the block, since they can never be valid.
+     */
+    pub fn remove_for_block(&mut self, block : &Block)
+    {
+        let mut spent : HashSet<&::datatype::transaction::OutPoint> = HashSet::new();
+        let mut to_remove : Vec<Hash> = Vec::new();
+
+        for tx in block.get_txs().iter()
+        {
+            for in_tx in tx.get_in_txs().iter()
+            {
+                spent.insert(in_tx.get_prev_out());
+            }
+        }
+
+        for (hash, orphan) in self.orphans.iter()
+        {
+            if orphan.tx.get_in_txs().iter().any(|i| spent.contains(&i.get_prev_out()))
+            {
+                to_remove.push(hash.clone());
+            }
+        }
+
+        for hash in to_remove.iter()
+        {
+            self.take(hash);
+        }
+    }
+}
@@ -3,6 +3,10 @@ extern crate time;
 use std::io::net::ip::SocketAddr;
 use std::io::TcpStream;
 
+use std::rand::Rng;
+
+use std::sync::Arc;
+
 use self::time::Timespec;
 use std::time::duration::Duration;
 
@@ -16,11 +20,53 @@ use message::addr::Addr;
 use message::inv::Inv;
 use message::getdata::GetData;
 use message::reject::Reject;
+use message::reject::RejectType;
 use message::tx::Tx;
 use message::getaddr::GetAddr;
+use message::block::Block;
+use message::notfound::NotFound;
+use message::mempool::MemPool;
+use message::filterload::FilterLoad;
+use message::filteradd::FilterAdd;
+use message::filterclear::FilterClear;
+use message::merkleblock::MerkleBlock;
+use message::getcfilters::GetCFilters;
+use message::cfilter::CFilter;
+use message::getcfheaders::GetCFHeaders;
+use message::cfheaders::CFHeaders;
+use message::getcfcheckpt::GetCFCheckpt;
+use message::cfcheckpt::CFCheckpt;
+use message::sendcmpct::SendCmpct;
+use message::cmpctblock::CmpctBlock;
+use message::getblocktxn::GetBlockTxn;
+use message::blocktxn::BlockTxn;
+use message::sendheaders::SendHeaders;
+use message::getheaders::GetHeaders;
+use message::headers::Headers;
+use message::headers::MAX_HEADERS_RESULTS;
+use message::feefilter::FeeFilter;
+use message::wtxidrelay::WtxidRelay;
+use message::sendaddrv2::SendAddrV2;
+use message::addrv2::AddrV2;
 
 use datatype::invvect::InvVect;
+use datatype::invvect::InvEntry;
+use datatype::invvect::InvEntryType;
+use datatype::transaction::Transaction;
+use datatype::hash::Hash;
+use datatype::block::BlockHeader;
 use datatype::netaddr::NetAddr;
+use datatype::netaddr::NetAddrV2;
+use datatype::bloom::BloomFilter;
+use datatype::merkle::PartialMerkleTree;
+use datatype::blockfilter::BlockFilter;
+use datatype::compact::HeaderAndShortIds;
+use datatype::compact::BlockTxRequest;
+use datatype::compact::BlockTransactions;
+use datatype::compact::PartialBlock;
+use datatype::compact::CompactError;
+
+use config::Config;
 
 use msgbuffer::MsgBuffer;
 
@@ -28,6 +74,19 @@ use addrmng::AddrManagerChannel;
 use addrmng::AddrManagerRequest;
 use addrmng::AddrManagerReply;
 
+use chainmng::ChainManagerChannel;
+use chainmng::ChainManagerRequest;
+use chainmng::ChainManagerReply;
+
+use relay::KnownInventory;
+
+use connector::Connector;
+use connector::Destination;
+
+use v2transport::V2Handshake;
+use v2transport::V2Session;
+use v2transport::V2Cipher;
+
 macro_rules! some_ref_or(
     ($e:expr, $err:expr) => (match $e { Some(ref mut e) => e, None => return $err }))
 
@@ -44,13 +103,24 @@ pub enum PeerError
     ReadMsgInvalidChecksum,
     ReadMsgUnknownCommand,
     ReadMsgWrongNetwork,
+    ReadMsgNotAuthentic,
+    ReadMsgMalformed,
     WriteIOError,
     WriteTimeout,
     ConnectError,
     NotConnected,
     DoubleHandshake,
     UnsupportedProtoVersion,
-    PingTimeout
+    ServiceNotOffered,
+    InvalidBloomFilter,
+    InvalidFilterRequest,
+    InvalidCompactBlock,
+    UnexpectedWtxidRelay,
+    UnexpectedSendAddrV2,
+    V2HandshakeTimeout,
+    V2HandshakeFailed,
+    PingTimeout,
+    Disconnected     /* We were asked to disconnect, i.e. the peer was banned */
 }
 
 impl PeerError
@@ -67,67 +137,338 @@ impl PeerError
     }
 }
 
-const PERIODIC_PERIOD_S : uint = 5;
+const PERIODIC_PERIOD_S : uint = 1;
 
 const PERIOD_PING_S : uint = 2*60;
 const PERIOD_TIMEOUT_CHECK_S : uint = 10;
 const PERIOD_ANNOUNCE_ADDRS_S : uint = 15*60;
 const PERIOD_REQUEST_ADDRS_S : uint = 30*60;
+const PERIOD_RELAY_S : uint = 1;
+const PERIOD_ADVERTISE_LOCAL_S : uint = 24*60*60;
+const PERIOD_PEER_INFO_S : uint = 5;
 
-const TIMEOUT_S : uint = 10*60;
+/* What the address manager knows about a connected peer */
+#[deriving(Clone)]
+pub struct PeerInfo
+{
+    pub addr             : SocketAddr,
+    pub inbound          : bool,
+    pub connected        : Timespec,
+    /* From the version message, if we got it */
+    pub user_agent       : Option<String>,
+    pub protocol_version : Option<u32>,
+    pub services         : Option<::config::Services>,
+    pub start_height     : Option<u32>,
+    pub v2_transport     : bool,
+    /* Of the last ping */
+    pub lag              : Option<Duration>,
+    pub bytes_sent       : u64,
+    pub bytes_recv       : u64,
+    /* Whether the txs and blocks accepted from now on get announced to it */
+    pub relaying         : bool
+}
 
 pub struct Peer
 {
-    addr            : SocketAddr,
-    socket          : Option<TcpStream>,
-    version         : Option<Version>,
+    addr             : SocketAddr,
+    /* Where we dial the peer */
+    dest             : Destination,
+    config           : Arc<Config>,
+    socket           : Option<TcpStream>,
+    /* Whether the peer connected to us */
+    inbound          : bool,
+    version          : Option<Version>,
     /* last time we sent (and we are waiting for the pong) */
-    last_ping       : Option<Timespec>,
+    last_ping        : Option<Timespec>,
     /* last time we received an addr msg */
-    last_addr       : Option<Timespec>,
-    addrmng_channel : AddrManagerChannel
+    last_addr        : Option<Timespec>,
+    addrmng_channel  : AddrManagerChannel,
+    chainmng_channel : ChainManagerChannel,
+    /* Hashes the peer already knows about, we do not announce those */
+    known_inventory  : KnownInventory,
+    /* Txs waiting to be announced on the next trickle */
+    inv_queue        : Vec<Hash>,
+    next_trickle     : Timespec,
+    /* Sequence number of the next tx from the chain manager relay log */
+    relay_seq        : Option<u64>,
+    /* Whether the peer wants txs announced (BIP37) */
+    relay_txs        : bool,
+    bloom_filter     : Option<BloomFilter>,
+    /* Sequence number of the next block from the chain manager relay log */
+    block_seq        : Option<u64>,
+    /* Compact blocks version the peer speaks, if any (BIP152) */
+    cmpct_version    : Option<u64>,
+    /* Whether the peer wants new blocks sent as cmpctblock right away */
+    cmpct_announce   : bool,
+    /* Whether we asked the peer to send us new blocks right away */
+    cmpct_hb         : bool,
+    /* Block we are waiting the missing txs of */
+    partial_block    : Option<PartialBlock>,
+    /* Whether the peer wants new blocks announced with headers (BIP130) */
+    prefers_headers  : bool,
+    /* Last header the peer sent, the next ones follow it even if we do not
+     * have its block yet.
+     */
+    last_header      : Option<Hash>,
+    /* Minimum fee rate of the txs the peer wants announced (BIP133) */
+    fee_filter       : u64,
+    /* Last fee filter we sent and when we send the next one */
+    sent_fee_filter  : u64,
+    next_fee_filter  : Timespec,
+    /* Whether the handshake is over */
+    verack_received  : bool,
+    /* Ciphers of the v2 transport (BIP324), if the peer speaks it */
+    v2_send          : Option<V2Cipher>,
+    v2_recv          : Option<V2Cipher>,
+    /* What an inbound v1 peer sent while we looked for a v2 key */
+    v1_buffer        : Option<MsgBuffer>,
+    /* Whether the peer wants addresses in addrv2 messages (BIP155) */
+    addrv2           : bool,
+    connected        : Timespec,
+    lag              : Option<Duration>,
+    bytes_sent       : u64,
+    bytes_recv       : u64,
+    /* Size on the wire of the message being handled, for the logs */
+    recv_msg_size    : uint
 }
 
-const TIMEOUT_CONNECT_MS : uint = 10000;
-const TIMEOUT_WRITE_MS : uint = 5*60*1000;
-
 impl Peer
 {
-    pub fn new(addr            : SocketAddr,
-               addrmng_channel : AddrManagerChannel) -> Peer
+    pub fn new(addr             : SocketAddr,
+               config           : Arc<Config>,
+               addrmng_channel  : AddrManagerChannel,
+               chainmng_channel : ChainManagerChannel) -> Peer
     {
         Peer
         {
-            addr:            addr,
-            socket:          None,
-            version:         None,
-            last_ping:       None,
-            last_addr:       None,
-            addrmng_channel: addrmng_channel
+            addr:             addr,
+            dest:             Destination::Ip(addr),
+            config:           config,
+            socket:           None,
+            inbound:          false,
+            version:          None,
+            last_ping:        None,
+            last_addr:        None,
+            addrmng_channel:  addrmng_channel,
+            chainmng_channel: chainmng_channel,
+            known_inventory:  KnownInventory::new(::relay::MAX_KNOWN_INVENTORY),
+            inv_queue:        Vec::new(),
+            next_trickle:     time::now_utc().to_timespec(),
+            relay_seq:        None,
+            relay_txs:        true,
+            bloom_filter:     None,
+            block_seq:        None,
+            cmpct_version:    None,
+            cmpct_announce:   false,
+            cmpct_hb:         false,
+            partial_block:    None,
+            prefers_headers:  false,
+            last_header:      None,
+            fee_filter:       0,
+            sent_fee_filter:  0,
+            next_fee_filter:  time::now_utc().to_timespec(),
+            verack_received:  false,
+            v2_send:          None,
+            v2_recv:          None,
+            v1_buffer:        None,
+            addrv2:           false,
+            connected:        time::now_utc().to_timespec(),
+            lag:              None,
+            bytes_sent:       0,
+            bytes_recv:       0,
+            recv_msg_size:    0
         }
     }
 
+    /* Peers we dial by hostname through the proxy are known by the local
+     * address of the connection, as the inbound peers of the onion service
+     * are known by the address tor connects from.  Until connected, by the
+     * address of the proxy.
+     */
+    pub fn new_outbound(dest             : Destination,
+                        config           : Arc<Config>,
+                        addrmng_channel  : AddrManagerChannel,
+                        chainmng_channel : ChainManagerChannel) -> Peer
+    {
+        let addr : SocketAddr = match dest
+        {
+            Destination::Ip(addr)   => addr,
+            Destination::Host(_, _) => config.proxy.unwrap()
+        };
+        let mut peer : Peer = Peer::new(addr,config,addrmng_channel,chainmng_channel);
+
+        peer.dest = dest;
+
+        peer
+    }
+
+    pub fn new_inbound(socket           : TcpStream,
+                       addr             : SocketAddr,
+                       config           : Arc<Config>,
+                       addrmng_channel  : AddrManagerChannel,
+                       chainmng_channel : ChainManagerChannel) -> Peer
+    {
+        let mut peer : Peer = Peer::new(addr,config,addrmng_channel,chainmng_channel);
+
+        peer.socket = Some(socket);
+        peer.inbound = true;
+
+        peer
+    }
+
+    /* Try the v2 transport first.  Peers that do not speak it hang up when
+     * they get our key instead of a version message, so on failure we
+     * connect again and stick to v1.
+     */
     pub fn connect(&mut self) -> Result<(),PeerError>
     {
-        let timeout : Duration = Duration::milliseconds(TIMEOUT_CONNECT_MS as i64);
-        let maybesocket = TcpStream::connect_timeout(self.addr,timeout);
+        try!(self.connect_tcp());
+
+        if !self.config.peer_v2_transport
+        {
+            return Ok(());
+        }
+
+        match self.v2_handshake()
+        {
+            Ok(())   => Ok(()),
+            Err(err) =>
+            {
+                ::logger::log_v2_fallback(&self.addr,&err);
+
+                self.v2_send = None;
+                self.v2_recv = None;
+
+                self.connect_tcp()
+            }
+        }
+    }
+
+    /* The peer of an inbound connection speaks first, with a v2 key or a v1
+     * version message.
+     */
+    pub fn accept(&mut self) -> Result<(),PeerError>
+    {
+        assert!(self.inbound);
+
+        if !self.config.peer_v2_transport
+        {
+            return Ok(());
+        }
+
+        self.v2_handshake()
+    }
+
+    fn connect_tcp(&mut self) -> Result<(),PeerError>
+    {
+        let timeout : Duration = Duration::milliseconds(self.config.connect_timeout_ms as i64);
+        let connector : Connector = ::connector::connector(&*self.config);
+        let mut socket : TcpStream;
+
+        socket = match connector.connect(&self.dest,timeout)
+        {
+            Ok(socket) => socket,
+            Err(_)     => return Err(PeerError::ConnectError)
+        };
+
+        match self.dest
+        {
+            Destination::Ip(_)               => (),
+            Destination::Host(ref host,port) =>
+            {
+                match socket.socket_name()
+                {
+                    Ok(addr) => self.addr = addr,
+                    Err(_)   => return Err(PeerError::ConnectError)
+                }
+
+                ::logger::log_proxy_connected(&self.addr,host.as_slice(),port);
+            }
+        }
+
+        self.socket = Some(socket);
+
+        Ok(())
+    }
 
-        if maybesocket.is_err()
+    /* BIP324: we send our key and garbage, then once we have the key of the
+     * peer, our garbage terminator and version packet.  The garbage of the
+     * peer ends with its terminator, after that everything is in packets.
+     *
+     * As the responder we first wait to see the peer is not a v1 one.
+     */
+    fn v2_handshake(&mut self) -> Result<(),PeerError>
+    {
+        let timeout : Duration = Duration::milliseconds(self.config.v2_handshake_timeout_ms
+                                                        as i64);
+        let deadline : Timespec = time::now_utc().to_timespec()+timeout;
+        let handshake : V2Handshake = V2Handshake::new(!self.inbound);
+        let mut buffer : MsgBuffer = MsgBuffer::new(&*self.config);
+        let mut session : V2Session;
+        let theirs : Vec<u8>;
+        let garbage : Vec<u8>;
+        let version_packet : Vec<u8>;
+
+        if self.inbound
+            && try!(buffer.read_v1_prefix(some_ref_or!(self.socket,Err(PeerError::NotConnected)),
+                                          deadline))
         {
-            return Err(PeerError::ConnectError);
+            self.v1_buffer = Some(buffer);
+
+            return Ok(());
         }
 
-        self.socket = Some(maybesocket.unwrap());
+        try!(self.send(&handshake.get_key_and_garbage()));
+
+        theirs = try!(buffer.read_v2_key(some_ref_or!(self.socket,Err(PeerError::NotConnected)),
+                                         deadline));
+
+        session = match handshake.complete(theirs.as_slice(),self.config.get_params().magic)
+        {
+            Some(session) => session,
+            None          => return Err(PeerError::V2HandshakeFailed)
+        };
+
+        version_packet = session.send.encrypt_packet(&[],false);
+
+        try!(self.send(&(session.send_terminator.clone()+version_packet)));
+
+        garbage = try!(buffer.read_v2_garbage(session.recv_terminator.as_slice(),
+                                              some_ref_or!(self.socket,
+                                                           Err(PeerError::NotConnected)),
+                                              deadline));
+
+        session.recv.set_garbage(garbage);
+
+        ::logger::log_v2_session(&self.addr,&session.session_id);
+
+        self.v2_send = Some(session.send);
+        self.v2_recv = Some(session.recv);
 
         Ok(())
     }
 
-    fn send(&mut self, msg : &Vec<u8>) -> Result<(),PeerError>
+    /* Messages are serialized for v1, they are put in packets here for v2.
+     * Returns the size on the wire.
+     */
+    fn send(&mut self, msg : &Vec<u8>) -> Result<uint,PeerError>
+    {
+        let packet : Option<Vec<u8>> = self.v2_send.as_mut().map(|c| c.encrypt_message(msg));
+
+        match packet
+        {
+            Some(packet) => self.write(&packet).map(|_| packet.len()),
+            None         => self.write(msg).map(|_| msg.len())
+        }
+    }
+
+    fn write(&mut self, msg : &Vec<u8>) -> Result<(),PeerError>
     {
+        let timeout : u64 = self.config.write_timeout_ms as u64;
         let socket : &mut TcpStream = some_ref_or!(self.socket,Err(PeerError::NotConnected));
         let result;
 
-        socket.set_write_timeout(Some(TIMEOUT_WRITE_MS as u64));
+        socket.set_write_timeout(Some(timeout));
 
         result = socket.write(msg.as_slice());
 
@@ -140,16 +481,20 @@ impl Peer
             }
         }
 
+        self.bytes_sent += msg.len() as u64;
+
         Ok(())
     }
 
     pub fn send_version(&mut self) -> Result<(),PeerError>
     {
-        let version = Version::new(::config::name_version_bip0014(),0);
+        let version = Version::new(::config::name_version_bip0014(),
+                                   self.config.get_services(),0);
 
-        try!(self.send(&version.serialize()));
+        let data : Vec<u8> = version.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_sent_msg(&self.addr,&Message::MsgVersion(version));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgVersion(version),size);
 
         Ok(())
     }
@@ -158,9 +503,10 @@ impl Peer
     {
         let verack = VerAck::new();
 
-        try!(self.send(&verack.serialize()));
+        let data : Vec<u8> = verack.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_sent_msg(&self.addr,&Message::MsgVerAck(verack));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgVerAck(verack),size);
 
         Ok(())
     }
@@ -174,11 +520,12 @@ impl Peer
 
         ping = Ping::new(((now.sec as u64)<<10) | ((now.nsec as u64)/1_000_000));
 
-        try!(self.send(&ping.serialize()));
+        let data : Vec<u8> = ping.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
         self.last_ping = Some(now);
 
-        ::logger::log_sent_msg(&self.addr,&Message::MsgPing(ping));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgPing(ping),size);
 
         Ok(())
     }
@@ -187,303 +534,1791 @@ impl Peer
     {
         let pong = Pong::new(nounce);
 
-        try!(self.send(&pong.serialize()));
+        let data : Vec<u8> = pong.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_sent_msg(&self.addr,&Message::MsgPong(pong));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgPong(pong),size);
 
         Ok(())
     }
 
-    fn send_getdata(&mut self, inv : &InvVect) -> Result<(),PeerError>
+    fn send_inv(&mut self, invvect : &InvVect) -> Result<(),PeerError>
     {
-        let getdata = GetData::from_inv(inv);
+        let mut inv = Inv::new();
 
-        try!(self.send(&getdata.serialize()));
+        for entry in invvect.iter()
+        {
+            inv.add(entry.clone());
+        }
 
-        ::logger::log_sent_msg(&self.addr,&Message::MsgGetData(getdata));
+        let data : Vec<u8> = inv.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgInv(inv),size);
 
         Ok(())
     }
 
-    fn send_addr(&mut self, addrs : &Vec<NetAddr>) -> Result<(),PeerError>
+    fn send_getdata(&mut self, inv : &InvVect) -> Result<(),PeerError>
     {
-        let addr = Addr::from_addrs(addrs);
+        let getdata = GetData::from_inv(inv);
 
-        try!(self.send(&addr.serialize()));
+        let data : Vec<u8> = getdata.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_sent_msg(&self.addr,&Message::MsgAddr(addr));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgGetData(getdata),size);
 
         Ok(())
     }
 
-    fn send_getaddr(&mut self) -> Result<(),PeerError>
+    fn send_reject(&mut self,
+                   msg    : &str,
+                   typ    : RejectType,
+                   reason : &str,
+                   hash   : Option<Hash>) -> Result<(),PeerError>
     {
-        let getaddr = GetAddr::new();
+        let reject = Reject::new(msg.to_string(),typ,reason.to_string(),hash);
 
-        try!(self.send(&getaddr.serialize()));
+        let data : Vec<u8> = reject.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_sent_msg(&self.addr,&Message::MsgGetAddr(getaddr));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgReject(reject),size);
 
         Ok(())
     }
 
-    fn addr_mng_send(&self, request : AddrManagerRequest)
+    fn send_notfound(&mut self, inv : &InvVect) -> Result<(),PeerError>
     {
-        self.addrmng_channel.sender.send(request);
-    }
+        let notfound = NotFound::from_inv(inv);
 
-    fn addr_mng_send_recv(&self, request : AddrManagerRequest) -> AddrManagerReply
-    {
-        self.addrmng_channel.sender.send(request);
-        self.addrmng_channel.receiver.recv()
-    }
+        let data : Vec<u8> = notfound.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-    /* TODO we should call this periodically */
-    fn addr_mng_add_self(&self)
-    {
-        let mut singleton_addrs : Vec<NetAddr> = Vec::with_capacity(1);
-        let mut addr : NetAddr;
-        let now : Timespec = time::now_utc().to_timespec();
+        ::logger::log_sent_msg(&self.addr,&Message::MsgNotFound(notfound),size);
 
-        assert!(self.version.is_some());
+        Ok(())
+    }
 
-        addr = self.version.as_ref().unwrap().get_addr_send().clone();
+    fn send_block(&mut self, block : ::datatype::block::Block) -> Result<(),PeerError>
+    {
+        let block = Block::new(block);
 
-        addr.time = Some(now);
+        let data : Vec<u8> = block.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        singleton_addrs.push(addr);
+        ::logger::log_sent_msg(&self.addr,&Message::MsgBlock(block),size);
 
-        if addr.is_valid_addr()
-        {
-            self.addr_mng_send(AddrManagerRequest::AddrMngAddAddresses(self.addr.ip,singleton_addrs));
-        }
+        Ok(())
     }
 
-    fn handle_version(&mut self, version : Version) -> Result<(),PeerError>
+    /* Send a merkleblock with the txs matching the peer's filter, followed by
+     * the matched txs themselves.  Without a filter there is nothing to send.
+     */
+    fn send_filtered_block(&mut self, block : &::datatype::block::Block) -> Result<(),PeerError>
     {
-        /* Do not allow a peer send a version msg twice */
-        if self.version.is_some()
-        {
-            return Err(PeerError::DoubleHandshake);
-        }
+        let mut txids : Vec<Hash> = Vec::with_capacity(block.get_txs().len());
+        let mut matches : Vec<bool> = Vec::with_capacity(block.get_txs().len());
+        let merkleblock : MerkleBlock;
 
-        if version.get_protocol_version() < ::config::PROTOCOL_VERSION_MIN
         {
-            return Err(PeerError::UnsupportedProtoVersion);
+            let filter : &mut BloomFilter = match self.bloom_filter
+            {
+                Some(ref mut filter) => filter,
+                None                 => return Ok(())
+            };
+
+            for tx in block.get_txs().iter()
+            {
+                txids.push(tx.get_hash());
+                matches.push(filter.is_relevant_and_update(tx));
+            }
         }
 
-        self.version = Some(version.clone());
+        merkleblock = MerkleBlock::new(block.get_header().clone(),
+                                       PartialMerkleTree::from_txids(txids.as_slice(),
+                                                                     matches.as_slice()));
 
-        try!(self.send_verack());
+        let data : Vec<u8> = merkleblock.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        self.addr_mng_add_self();
+        ::logger::log_sent_msg(&self.addr,&Message::MsgMerkleBlock(merkleblock),size);
 
-        ::logger::log_received_msg(&self.addr,&Message::MsgVersion(version));
+        for (tx, matched) in block.get_txs().iter().zip(matches.iter())
+        {
+            if *matched
+            {
+                try!(self.send_tx(tx.clone()));
+            }
+        }
 
         Ok(())
     }
 
-    fn handle_verack(&mut self, verack : VerAck) -> Result<(),PeerError>
+    fn send_cfilter(&mut self, filter : BlockFilter) -> Result<(),PeerError>
     {
-        ::logger::log_received_msg(&self.addr,&Message::MsgVerAck(verack));
+        let cfilter = CFilter::new(filter);
+
+        let data : Vec<u8> = cfilter.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgCFilter(cfilter),size);
 
         Ok(())
     }
 
-    fn handle_ping(&mut self, ping : Ping) -> Result<(),PeerError>
+    fn send_cfheaders(&mut self, cfheaders : CFHeaders) -> Result<(),PeerError>
     {
-        try!(self.send_pong(ping.get_nounce()));
+        let data : Vec<u8> = cfheaders.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_received_msg(&self.addr,&Message::MsgPing(ping));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgCFHeaders(cfheaders),size);
 
         Ok(())
     }
 
-    fn handle_pong(&mut self, pong : Pong) -> Result<(),PeerError>
+    fn send_cfcheckpt(&mut self, cfcheckpt : CFCheckpt) -> Result<(),PeerError>
     {
-        let now : Timespec = time::now_utc().to_timespec();
-        let nounce : u64 = pong.get_nounce();
-        let then : Timespec;
-        let lag : Duration;
+        let data : Vec<u8> = cfcheckpt.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        self.last_ping = None;
+        ::logger::log_sent_msg(&self.addr,&Message::MsgCFCheckpt(cfcheckpt),size);
 
-        then = Timespec::new((nounce>>10) as i64,
-                             ((nounce&(0x400-1))*1_000_000) as i32);
+        Ok(())
+    }
 
-        lag = now-then;
+    fn send_sendcmpct(&mut self, announce : bool) -> Result<(),PeerError>
+    {
+        let sendcmpct = SendCmpct::new(announce,::datatype::compact::CMPCT_VERSION);
 
-        ::logger::log_received_msg(&self.addr,&Message::MsgPong(pong));
+        let data : Vec<u8> = sendcmpct.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_lag(&self.addr,&lag);
+        ::logger::log_sent_msg(&self.addr,&Message::MsgSendCmpct(sendcmpct),size);
 
         Ok(())
     }
 
-    fn handle_addr(&mut self, addr : Addr) -> Result<(),PeerError>
+    fn send_cmpctblock(&mut self, block : &::datatype::block::Block) -> Result<(),PeerError>
     {
-        let now : Timespec = time::now_utc().to_timespec();
+        let nonce : u64 = ::crypto::rng().gen::<u64>();
+        let cmpctblock = CmpctBlock::new(HeaderAndShortIds::from_block(block,nonce));
 
-        self.addr_mng_send(AddrManagerRequest::AddrMngAddAddresses(self.addr.ip,
-                                                                   addr.get_addresses().clone()));
-
-        self.last_addr = Some(now);
+        let data : Vec<u8> = cmpctblock.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_received_msg(&self.addr,&Message::MsgAddr(addr));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgCmpctBlock(cmpctblock),size);
 
         Ok(())
     }
 
-    fn handle_inv(&mut self, inv : Inv) -> Result<(),PeerError>
+    fn send_getblocktxn(&mut self, request : BlockTxRequest) -> Result<(),PeerError>
     {
-        try!(self.send_getdata(inv.get_invvect()));
+        let getblocktxn = GetBlockTxn::new(request);
+
+        let data : Vec<u8> = getblocktxn.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        ::logger::log_received_msg(&self.addr,&Message::MsgInv(inv));
+        ::logger::log_sent_msg(&self.addr,&Message::MsgGetBlockTxn(getblocktxn),size);
 
         Ok(())
     }
 
-    fn handle_getdata(&mut self, getdata : GetData) -> Result<(),PeerError>
+    fn send_blocktxn(&mut self, txs : BlockTransactions) -> Result<(),PeerError>
     {
-        ::logger::log_received_msg(&self.addr,&Message::MsgGetData(getdata));
+        let blocktxn = BlockTxn::new(txs);
+
+        let data : Vec<u8> = blocktxn.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgBlockTxn(blocktxn),size);
 
         Ok(())
     }
 
-    fn handle_reject(&mut self, reject : Reject) -> Result<(),PeerError>
+    fn send_sendheaders(&mut self) -> Result<(),PeerError>
     {
-        ::logger::log_received_msg(&self.addr,&Message::MsgReject(reject));
+        let sendheaders = SendHeaders::new();
+
+        let data : Vec<u8> = sendheaders.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgSendHeaders(sendheaders),size);
 
         Ok(())
     }
 
-    fn handle_tx(&mut self, tx : Tx) -> Result<(),PeerError>
+    /* Ask for the headers after the locator, as many as fit in a headers
+     * message.
+     */
+    fn send_getheaders(&mut self, locator : Vec<Hash>) -> Result<(),PeerError>
     {
-        ::logger::log_received_msg(&self.addr,&Message::MsgTx(tx));
+        let getheaders = GetHeaders::new(::config::PROTOCOL_VERSION,locator,Hash::zero());
+
+        let data : Vec<u8> = getheaders.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgGetHeaders(getheaders),size);
 
         Ok(())
     }
 
-    fn handle_getaddr(&mut self, getaddr : GetAddr) -> Result<(),PeerError>
+    fn send_headers(&mut self, headers : Vec<BlockHeader>) -> Result<(),PeerError>
     {
-        try!(self.announce_addresses(true));
+        let headers = Headers::new(headers);
 
-        ::logger::log_received_msg(&self.addr,&Message::MsgGetAddr(getaddr));
+        let data : Vec<u8> = headers.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgHeaders(headers),size);
 
         Ok(())
     }
 
-    fn announce_addresses(&mut self, many : bool) -> Result<(),PeerError>
+    fn send_sendaddrv2(&mut self) -> Result<(),PeerError>
     {
-        let request : AddrManagerRequest;
-        let reply   : AddrManagerReply;
-
-        request = if many { AddrManagerRequest::AddrMngGetManyAddresses }
-                  else { AddrManagerRequest::AddrMngGetSomeAddresses };
+        let sendaddrv2 = SendAddrV2::new();
 
-        reply = self.addr_mng_send_recv(request);
+        let data : Vec<u8> = sendaddrv2.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        match reply
-        {
-            AddrManagerReply::AddrMngAddresses(addrs) =>
-            {
-                assert!(addrs.len() <= ::message::addr::MSG_ADDR_MAX);
+        ::logger::log_sent_msg(&self.addr,&Message::MsgSendAddrV2(sendaddrv2),size);
 
-                self.send_addr(&addrs)
-            },
-        }
+        Ok(())
     }
 
-    fn periodic_sendping(&mut self) -> Result<(),PeerError>
+    fn send_addrv2(&mut self, addrs : &Vec<NetAddrV2>) -> Result<(),PeerError>
     {
-        if self.last_ping.is_none()
-        {
-            try!(self.send_ping());
-        }
+        let addrv2 = AddrV2::from_addrs(addrs);
+
+        let data : Vec<u8> = addrv2.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgAddrV2(addrv2),size);
 
         Ok(())
     }
 
-    fn periodic_timeout_check(&mut self) -> Result<(),PeerError>
+    fn send_feefilter(&mut self, fee_rate : u64) -> Result<(),PeerError>
     {
-        if self.last_ping.is_some()
-        {
-            let now : Timespec = time::now_utc().to_timespec();
-            let last : Timespec = self.last_ping.unwrap();
+        let feefilter = FeeFilter::new(fee_rate);
 
-            if now > last+Duration::seconds(TIMEOUT_S as i64)
-            {
-                return Err(PeerError::PingTimeout);
-            }
-        }
+        let data : Vec<u8> = feefilter.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgFeeFilter(feefilter),size);
 
         Ok(())
     }
 
-    fn periodic_announce_addrs(&mut self) -> Result<(),PeerError>
+    fn send_tx(&mut self, transaction : Transaction) -> Result<(),PeerError>
     {
-        self.announce_addresses(false)
+        let tx = Tx::new(transaction);
+
+        let data : Vec<u8> = tx.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
+
+        ::logger::log_sent_msg(&self.addr,&Message::MsgTx(tx),size);
+
+        Ok(())
     }
 
-    fn periodic_request_addrs(&mut self) -> Result<(),PeerError>
+    fn send_addr(&mut self, addrs : &Vec<NetAddr>) -> Result<(),PeerError>
     {
-        let now = time::now_utc().to_timespec();
-        let then;
-
-        if self.last_addr.is_none()
-        {
-            return self.send_getaddr();
-        }
+        let addr = Addr::from_addrs(addrs);
 
-        then = self.last_addr.unwrap();
+        let data : Vec<u8> = addr.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-        if now > then+Duration::seconds(PERIOD_REQUEST_ADDRS_S as i64)
-        {
-            return self.send_getaddr();
-        }
+        ::logger::log_sent_msg(&self.addr,&Message::MsgAddr(addr),size);
 
         Ok(())
     }
 
-    /* Warning: This ignores non fatal errors, i.e. it returns Ok with non-fatal
-     *          errors
-     */
-    fn periodic(&mut self, periodics : &mut Vec<Periodic>) -> Result<(),PeerError>
+    fn send_getaddr(&mut self) -> Result<(),PeerError>
     {
-        for p in periodics.iter_mut()
-        {
-            if p.is_time()
-            {
-                let result;
-
-                result = match p.token()
-                {
-                    PeriodicToken::PeriodicPing         =>
-                        self.periodic_sendping(),
-                    PeriodicToken::PeriodicTimeoutCheck =>
-                        self.periodic_timeout_check(),
-                    PeriodicToken::PeriodicAnnounceAddresses =>
-                        self.periodic_announce_addrs(),
-                    PeriodicToken::PeriodicRequestAddresses  =>
-                        self.periodic_request_addrs()
-                };
+        let getaddr = GetAddr::new();
 
-                match result
-                {
-                    Err(err) => if err.is_fatal() { return Err(err) },
-                    _        => ()
-                }
+        let data : Vec<u8> = getaddr.serialize(self.config.get_params().magic);
+        let size : uint = try!(self.send(&data));
 
-                p.done();
-            }
-        }
+        ::logger::log_sent_msg(&self.addr,&Message::MsgGetAddr(getaddr),size);
 
         Ok(())
     }
 
-    fn init_periodics() -> Vec<Periodic>
+    fn addr_mng_send(&self, request : AddrManagerRequest)
     {
-        let mut periodics : Vec<Periodic> = Vec::new();
+        self.addrmng_channel.sender.send(request);
+    }
+
+    fn addr_mng_send_recv(&self, request : AddrManagerRequest) -> AddrManagerReply
+    {
+        self.addrmng_channel.sender.send(request);
+        self.addrmng_channel.receiver.recv()
+    }
+
+    fn chain_mng_send(&self, request : ChainManagerRequest)
+    {
+        self.chainmng_channel.sender.send(request);
+    }
+
+    fn chain_mng_send_recv(&self, request : ChainManagerRequest) -> ChainManagerReply
+    {
+        self.chainmng_channel.sender.send(request);
+        self.chainmng_channel.receiver.recv()
+    }
+
+    /* TODO we should call this periodically */
+    fn addr_mng_add_self(&self)
+    {
+        let mut singleton_addrs : Vec<NetAddrV2> = Vec::with_capacity(1);
+        let mut addr : NetAddr;
+        let now : Timespec = time::now_utc().to_timespec();
+
+        assert!(self.version.is_some());
+
+        addr = self.version.as_ref().unwrap().get_addr_send().clone();
+
+        addr.time = Some(now);
+
+        if addr.is_valid_addr()
+        {
+            singleton_addrs.push(NetAddrV2::from_netaddr(&addr).unwrap());
+
+            self.addr_mng_send(AddrManagerRequest::AddrMngAddAddresses(self.addr.ip,singleton_addrs));
+        }
+    }
+
+    fn handle_version(&mut self, version : Version) -> Result<(),PeerError>
+    {
+        /* Do not allow a peer send a version msg twice */
+        if self.version.is_some()
+        {
+            try!(self.send_reject("version",RejectType::RejectDuplicate,
+                                  "Duplicate version message",None));
+
+            return Err(PeerError::DoubleHandshake);
+        }
+
+        if version.get_protocol_version() < ::config::PROTOCOL_VERSION_MIN
+        {
+            let reason : String = format!("Version must be {} or greater",
+                                          ::config::PROTOCOL_VERSION_MIN);
+
+            try!(self.send_reject("version",RejectType::RejectObsolete,
+                                  reason.as_slice(),None));
+
+            return Err(PeerError::UnsupportedProtoVersion);
+        }
+
+        self.version = Some(version.clone());
+        self.relay_txs = version.get_relay();
+
+        if self.inbound
+        {
+            try!(self.send_version());
+        }
+
+        /* sendaddrv2 has to come before our verack.  We do not send
+         * wtxidrelay, see handle_wtxidrelay.
+         */
+        if version.get_protocol_version() >= ::config::WTXID_RELAY_VERSION
+        {
+            try!(self.send_sendaddrv2());
+        }
+
+        try!(self.send_verack());
+
+        self.addr_mng_add_self();
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgVersion(version),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_verack(&mut self, verack : VerAck) -> Result<(),PeerError>
+    {
+        let locator : Vec<Hash>;
+
+        self.verack_received = true;
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgVerAck(verack),self.recv_msg_size);
+
+        if self.version.as_ref().map_or(false,|v|
+               v.get_protocol_version() >= ::config::SENDHEADERS_VERSION)
+        {
+            try!(self.send_sendheaders());
+        }
+
+        /* Tell the peer we speak compact blocks, in low bandwidth mode until
+         * it proves to be fast at giving us blocks.
+         */
+        if self.version.as_ref().map_or(false,|v|
+               v.get_protocol_version() >= ::config::SHORT_IDS_BLOCKS_VERSION)
+        {
+            try!(self.send_sendcmpct(false));
+        }
+
+        /* Sync the chain: the peer sends the headers of the blocks we miss,
+         * then we ask for the blocks.
+         */
+        locator = self.get_locator();
+
+        try!(self.send_getheaders(locator));
+
+        self.advertise_local_address()
+    }
+
+    fn get_locator(&self) -> Vec<Hash>
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetLocator)
+        {
+            ChainManagerReply::ChainMngLocator(locator) => locator,
+            _                                           => unreachable!()
+        }
+    }
+
+    fn handle_ping(&mut self, ping : Ping) -> Result<(),PeerError>
+    {
+        try!(self.send_pong(ping.get_nounce()));
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgPing(ping),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_pong(&mut self, pong : Pong) -> Result<(),PeerError>
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let nounce : u64 = pong.get_nounce();
+        let then : Timespec;
+        let lag : Duration;
+
+        self.last_ping = None;
+
+        then = Timespec::new((nounce>>10) as i64,
+                             ((nounce&(0x400-1))*1_000_000) as i32);
+
+        lag = now-then;
+
+        self.lag = Some(lag);
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgPong(pong),self.recv_msg_size);
+
+        ::logger::log_lag(&self.addr,&lag);
+
+        Ok(())
+    }
+
+    fn handle_addr(&mut self, addr : Addr) -> Result<(),PeerError>
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let addrs : Vec<NetAddrV2>;
+
+        addrs = addr.get_addresses().iter().filter_map(|a| NetAddrV2::from_netaddr(a)).collect();
+
+        self.addr_mng_send(AddrManagerRequest::AddrMngAddAddresses(self.addr.ip,addrs));
+
+        self.last_addr = Some(now);
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgAddr(addr),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    /* The address manager drops the addresses of the networks we do not know */
+    fn handle_addrv2(&mut self, addrv2 : AddrV2) -> Result<(),PeerError>
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let addrs : Vec<NetAddrV2>;
+
+        addrs = addrv2.get_addresses().iter()
+                      .filter(|addr| addr.has_valid_size())
+                      .map(|addr| addr.clone())
+                      .collect();
+
+        self.addr_mng_send(AddrManagerRequest::AddrMngAddAddresses(self.addr.ip,addrs));
+
+        self.last_addr = Some(now);
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgAddrV2(addrv2),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_inv(&mut self, inv : Inv) -> Result<(),PeerError>
+    {
+        let mut getdata : InvVect = InvVect::new();
+        let mut txs : Vec<Hash> = Vec::new();
+        let mut blocks : Vec<Hash> = Vec::new();
+
+        for entry in inv.get_invvect().iter()
+        {
+            match entry.typ
+            {
+                InvEntryType::MsgTx =>
+                {
+                    self.known_inventory.insert(entry.hash.clone());
+                    txs.push(entry.hash.clone());
+                },
+                /* Never negotiated, see handle_wtxidrelay */
+                InvEntryType::MsgWitnessTx => (),
+                InvEntryType::MsgBlock =>
+                {
+                    self.known_inventory.insert(entry.hash.clone());
+                    blocks.push(entry.hash.clone());
+                },
+                InvEntryType::Error => (),
+                _                   => getdata.add(entry.clone())
+            }
+        }
+
+        if !blocks.is_empty()
+        {
+            let typ : InvEntryType = if self.cmpct_version.is_some() { InvEntryType::MsgCmpctBlock }
+                                     else                            { InvEntryType::MsgBlock };
+
+            for hash in self.blocks_to_request(blocks).into_iter()
+            {
+                getdata.add(InvEntry { typ: typ, hash: hash });
+            }
+        }
+
+        /* Only request the txs nobody else is sending us already */
+        if !txs.is_empty()
+        {
+            let request = ChainManagerRequest::ChainMngTxAnnounced(self.addr,txs);
+
+            match self.chain_mng_send_recv(request)
+            {
+                ChainManagerReply::ChainMngTxToRequest(hashes) =>
+                {
+                    for hash in hashes.into_iter()
+                    {
+                        getdata.add(InvEntry { typ: InvEntryType::MsgTx, hash: hash });
+                    }
+                },
+                _ => unreachable!()
+            }
+        }
+
+        if getdata.len() > 0
+        {
+            try!(self.send_getdata(&getdata));
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgInv(inv),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_getdata(&mut self, getdata : GetData) -> Result<(),PeerError>
+    {
+        let mut notfound : InvVect = InvVect::new();
+
+        for entry in getdata.get_invvect().iter()
+        {
+            let found : bool;
+
+            found = match entry.typ
+            {
+                InvEntryType::MsgTx | InvEntryType::MsgWitnessTx =>
+                {
+                    let request = ChainManagerRequest::ChainMngGetTx(entry.hash.clone());
+
+                    match self.chain_mng_send_recv(request)
+                    {
+                        ChainManagerReply::ChainMngTx(Some(tx)) =>
+                        {
+                            try!(self.send_tx(tx));
+                            true
+                        },
+                        ChainManagerReply::ChainMngTx(None)     => false,
+                        _                                       => unreachable!()
+                    }
+                },
+                InvEntryType::MsgBlock =>
+                {
+                    let request = ChainManagerRequest::ChainMngGetBlock(entry.hash.clone());
+
+                    match self.chain_mng_send_recv(request)
+                    {
+                        ChainManagerReply::ChainMngBlock(Some(block)) =>
+                        {
+                            try!(self.send_block(block));
+                            true
+                        },
+                        ChainManagerReply::ChainMngBlock(None)        => false,
+                        _                                             => unreachable!()
+                    }
+                },
+                InvEntryType::MsgFilteredBlock =>
+                {
+                    let request = ChainManagerRequest::ChainMngGetBlock(entry.hash.clone());
+
+                    match self.chain_mng_send_recv(request)
+                    {
+                        ChainManagerReply::ChainMngBlock(Some(block)) =>
+                        {
+                            try!(self.send_filtered_block(&block));
+                            true
+                        },
+                        ChainManagerReply::ChainMngBlock(None)        => false,
+                        _                                             => unreachable!()
+                    }
+                },
+                InvEntryType::MsgCmpctBlock =>
+                {
+                    let request = ChainManagerRequest::ChainMngGetBlock(entry.hash.clone());
+
+                    match self.chain_mng_send_recv(request)
+                    {
+                        ChainManagerReply::ChainMngBlock(Some(block)) =>
+                        {
+                            try!(self.send_cmpctblock(&block));
+                            true
+                        },
+                        ChainManagerReply::ChainMngBlock(None)        => false,
+                        _                                             => unreachable!()
+                    }
+                },
+                InvEntryType::Error => false
+            };
+
+            if !found
+            {
+                notfound.add(entry.clone());
+            }
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgGetData(getdata),self.recv_msg_size);
+
+        if notfound.len() > 0
+        {
+            try!(self.send_notfound(&notfound));
+        }
+
+        Ok(())
+    }
+
+    /* BIP35: Announce all the txs in our mempool, best fee rate first */
+    fn handle_mempool(&mut self, mempool : MemPool) -> Result<(),PeerError>
+    {
+        let mut inv : InvVect = InvVect::new();
+        let request : ChainManagerRequest;
+        let hashes : Vec<Hash>;
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgMemPool(mempool),self.recv_msg_size);
+
+        try!(self.check_bloom_service());
+
+        request = ChainManagerRequest::ChainMngGetMempool(self.fee_filter);
+
+        hashes = match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngMempool(hashes) => hashes,
+            _                                          => unreachable!()
+        };
+
+        for hash in hashes.into_iter()
+        {
+            if self.known_inventory.contains(&hash) || !self.is_relevant(&hash)
+            {
+                continue;
+            }
+
+            self.known_inventory.insert(hash.clone());
+
+            inv.add(InvEntry { typ: InvEntryType::MsgTx, hash: hash });
+
+            if inv.len() == ::message::inv::MSG_INV_MAX
+            {
+                try!(self.send_inv(&inv));
+
+                inv = InvVect::new();
+            }
+        }
+
+        if inv.len() > 0
+        {
+            try!(self.send_inv(&inv));
+        }
+
+        Ok(())
+    }
+
+    fn check_bloom_service(&self) -> Result<(),PeerError>
+    {
+        if !self.config.peer_bloom_filters
+        {
+            return Err(PeerError::ServiceNotOffered);
+        }
+
+        Ok(())
+    }
+
+    fn handle_filterload(&mut self, filterload : FilterLoad) -> Result<(),PeerError>
+    {
+        let filter : BloomFilter = filterload.get_filter().clone();
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgFilterLoad(filterload),
+                                   self.recv_msg_size);
+
+        try!(self.check_bloom_service());
+
+        if !filter.is_within_limits()
+        {
+            return Err(PeerError::InvalidBloomFilter);
+        }
+
+        self.bloom_filter = Some(filter);
+        self.relay_txs = true;
+
+        Ok(())
+    }
+
+    fn handle_filteradd(&mut self, filteradd : FilterAdd) -> Result<(),PeerError>
+    {
+        try!(self.check_bloom_service());
+
+        if filteradd.get_data().len() > ::datatype::bloom::MAX_FILTERADD_SIZE
+        {
+            return Err(PeerError::InvalidBloomFilter);
+        }
+
+        match self.bloom_filter
+        {
+            Some(ref mut filter) => filter.insert(filteradd.get_data().as_slice()),
+            None                 => return Err(PeerError::InvalidBloomFilter)
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgFilterAdd(filteradd),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_filterclear(&mut self, filterclear : FilterClear) -> Result<(),PeerError>
+    {
+        try!(self.check_bloom_service());
+
+        self.bloom_filter = None;
+        self.relay_txs = true;
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgFilterClear(filterclear),
+                                   self.recv_msg_size);
+
+        Ok(())
+    }
+
+    /* We do not request filtered blocks */
+    fn handle_merkleblock(&mut self, merkleblock : MerkleBlock) -> Result<(),PeerError>
+    {
+        ::logger::log_received_msg(&self.addr,&Message::MsgMerkleBlock(merkleblock),
+                                   self.recv_msg_size);
+
+        Ok(())
+    }
+
+    /* We only serve basic filters, and only if we advertise them */
+    fn check_filter_request(&self, filter_type : u8) -> Result<(),PeerError>
+    {
+        if !self.config.peer_block_filters
+        {
+            return Err(PeerError::ServiceNotOffered);
+        }
+
+        if filter_type != ::datatype::blockfilter::FILTER_TYPE_BASIC
+        {
+            return Err(PeerError::InvalidFilterRequest);
+        }
+
+        Ok(())
+    }
+
+    fn handle_getcfilters(&mut self, getcfilters : GetCFilters) -> Result<(),PeerError>
+    {
+        let request : ChainManagerRequest;
+        let filters : Vec<BlockFilter>;
+
+        try!(self.check_filter_request(getcfilters.get_filter_type()));
+
+        request = ChainManagerRequest::ChainMngGetCFilters(getcfilters.get_start_height(),
+                                                           getcfilters.get_stop_hash().clone());
+
+        filters = match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngCFilters(Some(filters)) => filters,
+            ChainManagerReply::ChainMngCFilters(None)          =>
+                return Err(PeerError::InvalidFilterRequest),
+            _                                                  => unreachable!()
+        };
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgGetCFilters(getcfilters),
+                                   self.recv_msg_size);
+
+        for filter in filters.into_iter()
+        {
+            try!(self.send_cfilter(filter));
+        }
+
+        Ok(())
+    }
+
+    fn handle_getcfheaders(&mut self, getcfheaders : GetCFHeaders) -> Result<(),PeerError>
+    {
+        let request : ChainManagerRequest;
+        let cfheaders : CFHeaders;
+
+        try!(self.check_filter_request(getcfheaders.get_filter_type()));
+
+        request = ChainManagerRequest::ChainMngGetCFHeaders(getcfheaders.get_start_height(),
+                                                            getcfheaders.get_stop_hash().clone());
+
+        cfheaders = match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngCFHeaders(Some((prev, hashes))) =>
+                CFHeaders::new(getcfheaders.get_filter_type(),
+                               getcfheaders.get_stop_hash().clone(),prev,hashes),
+            ChainManagerReply::ChainMngCFHeaders(None)                 =>
+                return Err(PeerError::InvalidFilterRequest),
+            _                                                          => unreachable!()
+        };
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgGetCFHeaders(getcfheaders),
+                                   self.recv_msg_size);
+
+        self.send_cfheaders(cfheaders)
+    }
+
+    fn handle_getcfcheckpt(&mut self, getcfcheckpt : GetCFCheckpt) -> Result<(),PeerError>
+    {
+        let request : ChainManagerRequest;
+        let cfcheckpt : CFCheckpt;
+
+        try!(self.check_filter_request(getcfcheckpt.get_filter_type()));
+
+        request = ChainManagerRequest::ChainMngGetCFCheckpt(getcfcheckpt.get_stop_hash().clone());
+
+        cfcheckpt = match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngCFCheckpt(Some(headers)) =>
+                CFCheckpt::new(getcfcheckpt.get_filter_type(),
+                               getcfcheckpt.get_stop_hash().clone(),headers),
+            ChainManagerReply::ChainMngCFCheckpt(None)          =>
+                return Err(PeerError::InvalidFilterRequest),
+            _                                                   => unreachable!()
+        };
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgGetCFCheckpt(getcfcheckpt),
+                                   self.recv_msg_size);
+
+        self.send_cfcheckpt(cfcheckpt)
+    }
+
+    /* We do not request filters */
+    fn handle_cfilter(&mut self, cfilter : CFilter) -> Result<(),PeerError>
+    {
+        ::logger::log_received_msg(&self.addr,&Message::MsgCFilter(cfilter),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_cfheaders(&mut self, cfheaders : CFHeaders) -> Result<(),PeerError>
+    {
+        ::logger::log_received_msg(&self.addr,&Message::MsgCFHeaders(cfheaders),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_cfcheckpt(&mut self, cfcheckpt : CFCheckpt) -> Result<(),PeerError>
+    {
+        ::logger::log_received_msg(&self.addr,&Message::MsgCFCheckpt(cfcheckpt),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_notfound(&mut self, notfound : NotFound) -> Result<(),PeerError>
+    {
+        let mut txs : Vec<Hash> = Vec::new();
+        let mut blocks : Vec<Hash> = Vec::new();
+
+        for entry in notfound.get_invvect().iter()
+        {
+            match entry.typ
+            {
+                InvEntryType::MsgTx | InvEntryType::MsgWitnessTx => txs.push(entry.hash.clone()),
+                InvEntryType::MsgBlock | InvEntryType::MsgCmpctBlock
+                    | InvEntryType::MsgFilteredBlock             => blocks.push(entry.hash.clone()),
+                InvEntryType::Error                              => ()
+            }
+        }
+
+        if !txs.is_empty()
+        {
+            self.chain_mng_send(ChainManagerRequest::ChainMngTxNotFound(self.addr,txs));
+        }
+
+        if !blocks.is_empty()
+        {
+            self.chain_mng_send(ChainManagerRequest::ChainMngBlockNotFound(self.addr,blocks));
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgNotFound(notfound),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_reject(&mut self, reject : Reject) -> Result<(),PeerError>
+    {
+        ::logger::log_received_msg(&self.addr,&Message::MsgReject(reject),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_tx(&mut self, tx : Tx) -> Result<(),PeerError>
+    {
+        let request : ChainManagerRequest;
+        let reply : ChainManagerReply;
+        let hash : Hash = tx.get_transaction().get_hash();
+
+        self.known_inventory.insert(hash.clone());
+
+        request = ChainManagerRequest::ChainMngAddTx(self.addr,
+                                                     tx.get_transaction().clone());
+
+        reply = self.chain_mng_send_recv(request);
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgTx(tx),self.recv_msg_size);
+
+        match reply
+        {
+            ChainManagerReply::ChainMngTxAccepted        => (),
+            ChainManagerReply::ChainMngTxRejected(err)   =>
+                try!(self.send_reject("tx",err.get_reject_type(),err.get_reason(),Some(hash))),
+            ChainManagerReply::ChainMngTxOrphan(parents) =>
+            {
+                /* Ask the peer that sent us the orphan for its parents */
+                let mut inv : InvVect = InvVect::new();
+
+                for parent in parents.into_iter()
+                {
+                    inv.add(InvEntry { typ: InvEntryType::MsgTx, hash: parent });
+                }
+
+                if inv.len() > 0
+                {
+                    try!(self.send_getdata(&inv));
+                }
+            },
+            _                                            => unreachable!()
+        }
+
+        Ok(())
+    }
+
+    fn handle_block(&mut self, block : Block) -> Result<(),PeerError>
+    {
+        let b : ::datatype::block::Block = block.get_block().clone();
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgBlock(block),self.recv_msg_size);
+
+        self.add_block(b)
+    }
+
+    fn add_block(&mut self, block : ::datatype::block::Block) -> Result<(),PeerError>
+    {
+        let request : ChainManagerRequest;
+        let reply : ChainManagerReply;
+        let hash : Hash;
+
+        hash = block.get_hash();
+
+        self.known_inventory.insert(hash.clone());
+
+        request = ChainManagerRequest::ChainMngAddBlock(self.addr,block);
+
+        reply = self.chain_mng_send_recv(request);
+
+        match reply
+        {
+            ChainManagerReply::ChainMngBlockAccepted      => (),
+            ChainManagerReply::ChainMngBlockRejected(err) =>
+                try!(self.send_reject("block",err.get_reject_type(),err.get_reason(),Some(hash))),
+            _                                             => unreachable!()
+        }
+
+        Ok(())
+    }
+
+    fn handle_sendcmpct(&mut self, sendcmpct : SendCmpct) -> Result<(),PeerError>
+    {
+        /* Ignore the versions we do not speak, 2 included (see CMPCT_VERSION) */
+        if sendcmpct.get_version() == ::datatype::compact::CMPCT_VERSION
+        {
+            if self.cmpct_version.is_none()
+            {
+                self.chain_mng_send(ChainManagerRequest::ChainMngCompactPeer(self.addr));
+            }
+
+            self.cmpct_version = Some(sendcmpct.get_version());
+            self.cmpct_announce = sendcmpct.get_announce();
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgSendCmpct(sendcmpct),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    /* Ask for the whole block when we cannot reconstruct it */
+    fn request_block(&mut self, hash : Hash) -> Result<(),PeerError>
+    {
+        let mut inv : InvVect = InvVect::new();
+
+        inv.add(InvEntry { typ: InvEntryType::MsgBlock, hash: hash });
+
+        self.send_getdata(&inv)
+    }
+
+    fn handle_cmpctblock(&mut self, cmpctblock : CmpctBlock) -> Result<(),PeerError>
+    {
+        let hash : Hash = cmpctblock.get_block().get_header().get_hash();
+        let request : ChainManagerRequest;
+        let partial : PartialBlock;
+        let missing : Vec<uint>;
+
+        self.known_inventory.insert(hash.clone());
+
+        request = ChainManagerRequest::ChainMngReconstruct(cmpctblock.get_block().clone());
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgCmpctBlock(cmpctblock),
+                                   self.recv_msg_size);
+
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetBlock(hash.clone()))
+        {
+            ChainManagerReply::ChainMngBlock(Some(_)) => return Ok(()),
+            ChainManagerReply::ChainMngBlock(None)    => (),
+            _                                         => unreachable!()
+        }
+
+        /* Two txs of the block can have the same short id, in which case we
+         * fall back to the full block (BIP152).  Bad indexes and counts are
+         * the fault of the peer.
+         */
+        partial = match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngPartialBlock(Ok(partial)) => partial,
+            ChainManagerReply::ChainMngPartialBlock(Err(CompactError::CompactDuplicateShortId)) =>
+                return self.request_block(hash),
+            ChainManagerReply::ChainMngPartialBlock(Err(_))      =>
+                return Err(PeerError::InvalidCompactBlock),
+            _                                                    => unreachable!()
+        };
+
+        missing = partial.get_missing();
+
+        if !missing.is_empty()
+        {
+            self.partial_block = Some(partial);
+
+            return self.send_getblocktxn(BlockTxRequest::new(hash,missing));
+        }
+
+        match partial.fill(&[])
+        {
+            Ok(block) => self.add_block(block),
+            Err(_)    => self.request_block(hash)   /* Short id collision */
+        }
+    }
+
+    fn handle_getblocktxn(&mut self, getblocktxn : GetBlockTxn) -> Result<(),PeerError>
+    {
+        let request : ChainManagerRequest;
+        let block : ::datatype::block::Block;
+        let mut txs : Vec<Transaction> = Vec::new();
+
+        request = ChainManagerRequest::ChainMngGetBlock(
+            getblocktxn.get_request().get_block_hash().clone());
+
+        block = match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngBlock(Some(block)) => block,
+            ChainManagerReply::ChainMngBlock(None)        => return Ok(()),
+            _                                             => unreachable!()
+        };
+
+        for index in getblocktxn.get_request().get_indexes().iter()
+        {
+            match block.get_txs().get(*index)
+            {
+                Some(tx) => txs.push(tx.clone()),
+                None     => return Err(PeerError::InvalidCompactBlock)
+            }
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgGetBlockTxn(getblocktxn),
+                                   self.recv_msg_size);
+
+        self.send_blocktxn(BlockTransactions::new(block.get_hash(),txs))
+    }
+
+    fn handle_blocktxn(&mut self, blocktxn : BlockTxn) -> Result<(),PeerError>
+    {
+        let hash : Hash = blocktxn.get_txs().get_block_hash().clone();
+        let result;
+
+        /* Ignore txs for blocks we did not ask for */
+        match self.partial_block
+        {
+            Some(ref partial) if partial.get_hash() == hash => (),
+            _                                               =>
+            {
+                ::logger::log_received_msg(&self.addr,&Message::MsgBlockTxn(blocktxn),
+                                           self.recv_msg_size);
+
+                return Ok(());
+            }
+        }
+
+        result = self.partial_block.take().unwrap().fill(blocktxn.get_txs().get_txs().as_slice());
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgBlockTxn(blocktxn),self.recv_msg_size);
+
+        match result
+        {
+            Ok(block) => self.add_block(block),
+            Err(_)    => self.request_block(hash)
+        }
+    }
+
+    /* Send new blocks as cmpctblock to the peers that asked for it, announce
+     * them with headers to the ones that prefer it and with inv to the rest.
+     */
+    fn announce_block(&mut self, hash : Hash) -> Result<(),PeerError>
+    {
+        let mut inv : InvVect = InvVect::new();
+        let block : ::datatype::block::Block;
+
+        if self.known_inventory.contains(&hash)
+        {
+            return Ok(());
+        }
+
+        self.known_inventory.insert(hash.clone());
+
+        block = match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetBlock(hash.clone()))
+        {
+            ChainManagerReply::ChainMngBlock(Some(block)) => block,
+            ChainManagerReply::ChainMngBlock(None)        => return Ok(()),
+            _                                             => unreachable!()
+        };
+
+        if self.cmpct_version.is_some() && self.cmpct_announce
+        {
+            return self.send_cmpctblock(&block);
+        }
+
+        /* A header only connects if the peer knows its parent, otherwise the
+         * inv makes it ask for what it is missing.
+         */
+        if self.prefers_headers &&
+           self.known_inventory.contains(block.get_header().get_prev_block())
+        {
+            return self.send_headers(vec![block.get_header().clone()]);
+        }
+
+        inv.add(InvEntry { typ: InvEntryType::MsgBlock, hash: hash });
+
+        self.send_inv(&inv)
+    }
+
+    fn handle_sendheaders(&mut self, sendheaders : SendHeaders) -> Result<(),PeerError>
+    {
+        self.prefers_headers = true;
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgSendHeaders(sendheaders),
+                                   self.recv_msg_size);
+
+        Ok(())
+    }
+
+    /* BIP339: Only meaningful during the handshake.  We cannot read the
+     * witness of txs, so we cannot compute their wtxid, and since we do not
+     * send wtxidrelay ourselves txs keep being relayed by txid.
+     */
+    fn handle_wtxidrelay(&mut self, wtxidrelay : WtxidRelay) -> Result<(),PeerError>
+    {
+        ::logger::log_received_msg(&self.addr,&Message::MsgWtxidRelay(wtxidrelay),
+                                   self.recv_msg_size);
+
+        if self.version.is_none() || self.verack_received
+        {
+            return Err(PeerError::UnexpectedWtxidRelay);
+        }
+
+        Ok(())
+    }
+
+    fn handle_sendaddrv2(&mut self, sendaddrv2 : SendAddrV2) -> Result<(),PeerError>
+    {
+        ::logger::log_received_msg(&self.addr,&Message::MsgSendAddrV2(sendaddrv2),
+                                   self.recv_msg_size);
+
+        if self.version.is_none() || self.verack_received
+        {
+            return Err(PeerError::UnexpectedSendAddrV2);
+        }
+
+        self.addrv2 = true;
+
+        Ok(())
+    }
+
+    fn handle_feefilter(&mut self, feefilter : FeeFilter) -> Result<(),PeerError>
+    {
+        /* Ignore nonsense values instead of disconnecting */
+        if feefilter.get_fee_rate() <= ::consensus::MAX_MONEY
+        {
+            self.fee_filter = feefilter.get_fee_rate();
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgFeeFilter(feefilter),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn handle_getheaders(&mut self, getheaders : GetHeaders) -> Result<(),PeerError>
+    {
+        let request : ChainManagerRequest;
+        let headers : Vec<BlockHeader>;
+
+        request = ChainManagerRequest::ChainMngFindHeaders(getheaders.get_locator().clone(),
+                                                           getheaders.get_hash_stop().clone());
+
+        headers = match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngHeaders(headers, _, _) => headers,
+            _                                                 => unreachable!()
+        };
+
+        for header in headers.iter()
+        {
+            self.known_inventory.insert(header.get_hash());
+        }
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgGetHeaders(getheaders),
+                                   self.recv_msg_size);
+
+        self.send_headers(headers)
+    }
+
+    /* We do not validate the headers before we have the blocks: we request
+     * the blocks we do not have, in order, as full blocks when there are
+     * many (a sync) and as compact ones when there is one (an announcement).
+     */
+    fn handle_headers(&mut self, headers : Headers) -> Result<(),PeerError>
+    {
+        let mut getdata : InvVect = InvVect::new();
+        let mut hashes : Vec<Hash> = Vec::new();
+        let count : uint = headers.get_headers().len();
+        let typ : InvEntryType;
+        let connects : bool = match headers.get_headers().first()
+        {
+            Some(first) => self.connects(first.get_prev_block()),
+            None        => true
+        };
+
+        /* Headers that do not follow anything we know: we missed blocks, so
+         * we ask for the headers in between.
+         */
+        if !connects
+        {
+            let locator : Vec<Hash> = self.get_locator();
+
+            ::logger::log_received_msg(&self.addr,&Message::MsgHeaders(headers),
+                                       self.recv_msg_size);
+
+            return self.send_getheaders(locator);
+        }
+
+        for header in headers.get_headers().iter()
+        {
+            let hash : Hash = header.get_hash();
+
+            self.known_inventory.insert(hash.clone());
+            hashes.push(hash);
+        }
+
+        typ = if self.cmpct_version.is_some() && count == 1 { InvEntryType::MsgCmpctBlock }
+              else                                          { InvEntryType::MsgBlock };
+
+        for hash in self.blocks_to_request(hashes).into_iter()
+        {
+            getdata.add(InvEntry { typ: typ, hash: hash });
+        }
+
+        self.last_header = headers.get_headers().last().map(|header| header.get_hash());
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgHeaders(headers),self.recv_msg_size);
+
+        if getdata.len() > 0
+        {
+            try!(self.send_getdata(&getdata));
+        }
+
+        /* A full message, there may be more */
+        if count == MAX_HEADERS_RESULTS
+        {
+            let locator : Vec<Hash> = vec![self.last_header.clone().unwrap()];
+
+            try!(self.send_getheaders(locator));
+        }
+
+        Ok(())
+    }
+
+    /* The announced blocks we do not have and that no other peer is sending
+     * us already, in order.
+     */
+    fn blocks_to_request(&mut self, hashes : Vec<Hash>) -> Vec<Hash>
+    {
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngBlocksAnnounced(self.addr,
+                                                                                    hashes))
+        {
+            ChainManagerReply::ChainMngBlocksToRequest(hashes) => hashes,
+            _                                                  => unreachable!()
+        }
+    }
+
+    /* Whether a header with this parent follows what we know */
+    fn connects(&self, prev : &Hash) -> bool
+    {
+        if self.last_header.as_ref() == Some(prev)
+        {
+            return true;
+        }
+
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetBlock(prev.clone()))
+        {
+            ChainManagerReply::ChainMngBlock(block) => block.is_some(),
+            _                                       => unreachable!()
+        }
+    }
+
+    fn handle_getaddr(&mut self, getaddr : GetAddr) -> Result<(),PeerError>
+    {
+        try!(self.announce_addresses(true));
+
+        ::logger::log_received_msg(&self.addr,&Message::MsgGetAddr(getaddr),self.recv_msg_size);
+
+        Ok(())
+    }
+
+    fn announce_addresses(&mut self, many : bool) -> Result<(),PeerError>
+    {
+        let request : AddrManagerRequest;
+        let reply   : AddrManagerReply;
+
+        request = if many { AddrManagerRequest::AddrMngGetManyAddresses }
+                  else { AddrManagerRequest::AddrMngGetSomeAddresses };
+
+        reply = self.addr_mng_send_recv(request);
+
+        match reply
+        {
+            AddrManagerReply::AddrMngAddresses(ref addrs) if self.addrv2 =>
+            {
+                assert!(addrs.len() <= ::message::addr::MSG_ADDR_MAX);
+
+                self.send_addrv2(addrs)
+            },
+            /* Only IPv4 and IPv6 addresses fit in addr messages */
+            AddrManagerReply::AddrMngAddresses(ref addrs) =>
+            {
+                assert!(addrs.len() <= ::message::addr::MSG_ADDR_MAX);
+
+                self.send_addr(&addrs.iter().filter_map(|a| a.to_netaddr()).collect())
+            },
+            _ => unreachable!()
+        }
+    }
+
+    /* Our onion address can only go in addrv2 messages */
+    fn advertise_local_address(&mut self) -> Result<(),PeerError>
+    {
+        let reply : AddrManagerReply;
+
+        if !self.addrv2
+        {
+            return Ok(());
+        }
+
+        reply = self.addr_mng_send_recv(AddrManagerRequest::AddrMngGetLocalAddress);
+
+        match reply
+        {
+            AddrManagerReply::AddrMngLocalAddress(Some(mut addr)) =>
+            {
+                addr.time = Some(time::now_utc().to_timespec());
+
+                self.send_addrv2(&vec![addr])
+            },
+            AddrManagerReply::AddrMngLocalAddress(None) => Ok(()),
+            _ => unreachable!()
+        }
+    }
+
+    fn periodic_sendping(&mut self) -> Result<(),PeerError>
+    {
+        if self.last_ping.is_none()
+        {
+            try!(self.send_ping());
+        }
+
+        Ok(())
+    }
+
+    fn periodic_timeout_check(&mut self) -> Result<(),PeerError>
+    {
+        if self.last_ping.is_some()
+        {
+            let now : Timespec = time::now_utc().to_timespec();
+            let last : Timespec = self.last_ping.unwrap();
+
+            if now > last+Duration::seconds(self.config.peer_timeout_s as i64)
+            {
+                return Err(PeerError::PingTimeout);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn periodic_announce_addrs(&mut self) -> Result<(),PeerError>
+    {
+        self.announce_addresses(false)
+    }
+
+    fn periodic_advertise_local(&mut self) -> Result<(),PeerError>
+    {
+        self.advertise_local_address()
+    }
+
+    pub fn get_addr(&self) -> SocketAddr
+    {
+        self.addr
+    }
+
+    pub fn get_info(&self) -> PeerInfo
+    {
+        PeerInfo
+        {
+            addr:             self.addr,
+            inbound:          self.inbound,
+            connected:        self.connected,
+            user_agent:       self.version.as_ref().map(|v| v.get_version().clone()),
+            protocol_version: self.version.as_ref().map(|v| v.get_protocol_version()),
+            services:         self.version.as_ref().map(|v| v.get_services()),
+            start_height:     self.version.as_ref().map(|v| v.get_best_height()),
+            v2_transport:     self.v2_send.is_some(),
+            lag:              self.lag,
+            bytes_sent:       self.bytes_sent,
+            bytes_recv:       self.bytes_recv,
+            relaying:         self.relay_seq.is_some() && self.block_seq.is_some()
+        }
+    }
+
+    /* Keeps the address manager up to date about us, and tells us whether we
+     * should go away.
+     */
+    fn periodic_peer_info(&mut self) -> Result<(),PeerError>
+    {
+        match self.addr_mng_send_recv(AddrManagerRequest::AddrMngUpdatePeer(self.get_info()))
+        {
+            AddrManagerReply::AddrMngKeepPeer(true)  => Ok(()),
+            AddrManagerReply::AddrMngKeepPeer(false) => Err(PeerError::Disconnected),
+            _                                        => unreachable!()
+        }
+    }
+
+    /* The peer is gone, the managers forget it */
+    pub fn remove(&self)
+    {
+        self.addr_mng_send(AddrManagerRequest::AddrMngRemovePeer(self.addr));
+        self.chain_mng_send(ChainManagerRequest::ChainMngRemovePeer(self.addr));
+    }
+
+    fn periodic_request_addrs(&mut self) -> Result<(),PeerError>
+    {
+        let now = time::now_utc().to_timespec();
+        let then;
+
+        if self.last_addr.is_none()
+        {
+            return self.send_getaddr();
+        }
+
+        then = self.last_addr.unwrap();
+
+        if now > then+Duration::seconds(PERIOD_REQUEST_ADDRS_S as i64)
+        {
+            return self.send_getaddr();
+        }
+
+        Ok(())
+    }
+
+    /* Whether the tx in our mempool matches the peer's bloom filter (if it
+     * loaded one).  Matching may update the filter.
+     */
+    fn is_relevant(&mut self, hash : &Hash) -> bool
+    {
+        let tx : Transaction;
+
+        if self.bloom_filter.is_none()
+        {
+            return true;
+        }
+
+        tx = match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetTx(hash.clone()))
+        {
+            ChainManagerReply::ChainMngTx(Some(tx)) => tx,
+            ChainManagerReply::ChainMngTx(None)     => return false,
+            _                                       => unreachable!()
+        };
+
+        self.bloom_filter.as_mut().unwrap().is_relevant_and_update(&tx)
+    }
+
+    /* Tell the peer our minimum fee rate every now and then, and sooner when
+     * it changed a lot, so it does not announce txs we would not accept.
+     */
+    fn periodic_feefilter(&mut self, now : Timespec) -> Result<(),PeerError>
+    {
+        let min_fee_rate : u64;
+        let max_delay : Timespec;
+
+        if self.version.as_ref().map_or(true,|v|
+               v.get_protocol_version() < ::config::FEEFILTER_VERSION)
+        {
+            return Ok(());
+        }
+
+        min_fee_rate = match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetMinFeeRate)
+        {
+            ChainManagerReply::ChainMngMinFeeRate(rate) => rate,
+            _                                           => unreachable!()
+        };
+
+        max_delay = now+Duration::milliseconds(::relay::FEEFILTER_MAX_CHANGE_DELAY_MS);
+
+        if now > self.next_fee_filter
+        {
+            let fee_filter : u64 = ::relay::round_fee_filter(min_fee_rate);
+
+            if fee_filter != self.sent_fee_filter
+            {
+                try!(self.send_feefilter(fee_filter));
+
+                self.sent_fee_filter = fee_filter;
+            }
+
+            self.next_fee_filter = ::relay::poisson_next(now,
+                                                         ::relay::FEEFILTER_BROADCAST_INTERVAL_MS);
+        }
+        else if self.next_fee_filter > max_delay &&
+                (min_fee_rate < 3*self.sent_fee_filter/4 || min_fee_rate > 4*self.sent_fee_filter/3)
+        {
+            let delay_ms : uint;
+
+            delay_ms = ::crypto::rand_interval(0,::relay::FEEFILTER_MAX_CHANGE_DELAY_MS as uint);
+
+            self.next_fee_filter = now+Duration::milliseconds(delay_ms as i64);
+        }
+
+        Ok(())
+    }
+
+    /* Queue the txs newly accepted to the mempool, request again the txs
+     * other peers did not deliver, and announce the queue when it is time.
+     */
+    fn periodic_relay(&mut self) -> Result<(),PeerError>
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let mut getdata : InvVect = InvVect::new();
+        let mut inv : InvVect = InvVect::new();
+
+        /* Nothing to relay before the handshake */
+        if self.version.is_none()
+        {
+            return Ok(());
+        }
+
+        let request = ChainManagerRequest::ChainMngGetRelay(self.relay_seq,self.fee_filter);
+
+        match self.chain_mng_send_recv(request)
+        {
+            ChainManagerReply::ChainMngRelay(hashes,next) =>
+            {
+                for hash in hashes.into_iter()
+                {
+                    if self.relay_txs && !self.known_inventory.contains(&hash)
+                    {
+                        self.inv_queue.push(hash);
+                    }
+                }
+
+                self.relay_seq = Some(next);
+            },
+            _ => unreachable!()
+        }
+
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetBlockRelay(self.block_seq))
+        {
+            ChainManagerReply::ChainMngBlockRelay(hashes,next) =>
+            {
+                for hash in hashes.into_iter()
+                {
+                    try!(self.announce_block(hash));
+                }
+
+                self.block_seq = Some(next);
+            },
+            _ => unreachable!()
+        }
+
+        try!(self.periodic_feefilter(now));
+
+        /* The chain manager picks which peers are in high bandwidth mode */
+        if self.cmpct_version.is_some()
+        {
+            let request = ChainManagerRequest::ChainMngIsHighBandwidth(self.addr);
+
+            match self.chain_mng_send_recv(request)
+            {
+                ChainManagerReply::ChainMngHighBandwidth(hb) =>
+                    if hb != self.cmpct_hb
+                    {
+                        try!(self.send_sendcmpct(hb));
+
+                        self.cmpct_hb = hb;
+                    },
+                _ => unreachable!()
+            }
+        }
+
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetRerequests(self.addr))
+        {
+            ChainManagerReply::ChainMngTxToRequest(hashes) =>
+            {
+                for hash in hashes.into_iter()
+                {
+                    getdata.add(InvEntry { typ: InvEntryType::MsgTx, hash: hash });
+                }
+            },
+            _ => unreachable!()
+        }
+
+        /* Blocks the peer announced that another peer did not send us */
+        match self.chain_mng_send_recv(ChainManagerRequest::ChainMngGetBlockRerequests(self.addr))
+        {
+            ChainManagerReply::ChainMngBlocksToRequest(hashes) =>
+            {
+                for hash in hashes.into_iter()
+                {
+                    getdata.add(InvEntry { typ: InvEntryType::MsgBlock, hash: hash });
+                }
+            },
+            _ => unreachable!()
+        }
+
+        if getdata.len() > 0
+        {
+            try!(self.send_getdata(&getdata));
+        }
+
+        if now < self.next_trickle || self.inv_queue.is_empty()
+        {
+            return Ok(());
+        }
+
+        self.next_trickle = ::relay::poisson_next(now,::relay::INVENTORY_BROADCAST_INTERVAL_MS);
+
+        /* Announce in random order, so the order does not reveal which txs
+         * arrived first.
+         */
+        ::crypto::rng().shuffle(self.inv_queue.as_mut_slice());
+
+        while inv.len() < ::relay::INVENTORY_BROADCAST_MAX
+        {
+            let hash : Hash = match self.inv_queue.pop()
+            {
+                Some(hash) => hash,
+                None       => break
+            };
+
+            if self.known_inventory.contains(&hash) || !self.is_relevant(&hash)
+            {
+                continue;
+            }
+
+            self.known_inventory.insert(hash.clone());
+
+            inv.add(InvEntry { typ: InvEntryType::MsgTx, hash: hash });
+        }
+
+        if inv.len() > 0
+        {
+            try!(self.send_inv(&inv));
+        }
+
+        Ok(())
+    }
+
+    /* Warning: This ignores non fatal errors, i.e. it returns Ok with non-fatal
+     *          errors
+     */
+    fn periodic(&mut self, periodics : &mut Vec<Periodic>) -> Result<(),PeerError>
+    {
+        for p in periodics.iter_mut()
+        {
+            if p.is_time()
+            {
+                let result;
+
+                result = match p.token()
+                {
+                    PeriodicToken::PeriodicPing         =>
+                        self.periodic_sendping(),
+                    PeriodicToken::PeriodicTimeoutCheck =>
+                        self.periodic_timeout_check(),
+                    PeriodicToken::PeriodicAnnounceAddresses =>
+                        self.periodic_announce_addrs(),
+                    PeriodicToken::PeriodicRequestAddresses  =>
+                        self.periodic_request_addrs(),
+                    PeriodicToken::PeriodicRelay             =>
+                        self.periodic_relay(),
+                    PeriodicToken::PeriodicAdvertiseLocal    =>
+                        self.periodic_advertise_local(),
+                    PeriodicToken::PeriodicPeerInfo          =>
+                        self.periodic_peer_info()
+                };
+
+                match result
+                {
+                    Err(err) => if err.is_fatal() { return Err(err) },
+                    _        => ()
+                }
+
+                p.done();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn init_periodics() -> Vec<Periodic>
+    {
+        let mut periodics : Vec<Periodic> = Vec::new();
 
         periodics.push(Periodic::new(Duration::seconds(PERIOD_PING_S as i64),
                                      PeriodicToken::PeriodicPing));
@@ -493,18 +2328,36 @@ impl Peer
                                      PeriodicToken::PeriodicAnnounceAddresses));
         periodics.push(Periodic::new(Duration::seconds(PERIOD_REQUEST_ADDRS_S as i64),
                                      PeriodicToken::PeriodicRequestAddresses));
+        periodics.push(Periodic::new(Duration::seconds(PERIOD_RELAY_S as i64),
+                                     PeriodicToken::PeriodicRelay));
+        periodics.push(Periodic::new(Duration::seconds(PERIOD_ADVERTISE_LOCAL_S as i64),
+                                     PeriodicToken::PeriodicAdvertiseLocal));
+        periodics.push(Periodic::new(Duration::seconds(PERIOD_PEER_INFO_S as i64),
+                                     PeriodicToken::PeriodicPeerInfo));
 
         periodics
     }
 
     pub fn read_loop(&mut self) -> Result<(),PeerError>
     {
-        let mut buffer : MsgBuffer = MsgBuffer::new();
+        let mut buffer : MsgBuffer;
         let mut last_periodic : Timespec = time::now_utc().to_timespec();
         let mut periodics : Vec<Periodic>;
 
+        buffer = match self.v1_buffer.take()
+        {
+            Some(buffer) => buffer,
+            None         => MsgBuffer::new(&*self.config)
+        };
+
         periodics = Peer::init_periodics();
 
+        match self.v2_recv.take()
+        {
+            Some(cipher) => buffer.start_v2(cipher),
+            None         => ()
+        }
+
         loop
         {
             let maybemsg : Result<Message,PeerError>;
@@ -513,6 +2366,8 @@ impl Peer
             if time::now_utc().to_timespec()
                 > last_periodic+Duration::seconds(PERIODIC_PERIOD_S as i64)
             {
+                self.bytes_recv = buffer.get_bytes_read();
+
                 match self.periodic(&mut periodics)
                 {
                     Err(err) => if err.is_fatal() { return Err(err) },
@@ -536,18 +2391,44 @@ impl Peer
                 continue;
             }
 
+            self.recv_msg_size = buffer.get_msg_size();
+
             result = match maybemsg.unwrap()
             {
-                Message::MsgVersion(version) => self.handle_version(version),
-                Message::MsgVerAck(verack)   => self.handle_verack(verack),
-                Message::MsgPing(ping)       => self.handle_ping(ping),
-                Message::MsgPong(pong)       => self.handle_pong(pong),
-                Message::MsgAddr(addrs)      => self.handle_addr(addrs),
-                Message::MsgInv(inv)         => self.handle_inv(inv),
-                Message::MsgGetData(getdata) => self.handle_getdata(getdata),
-                Message::MsgReject(reject)   => self.handle_reject(reject),
-                Message::MsgTx(tx)           => self.handle_tx(tx),
-                Message::MsgGetAddr(getaddr) => self.handle_getaddr(getaddr),
+                Message::MsgVersion(version)           => self.handle_version(version),
+                Message::MsgVerAck(verack)             => self.handle_verack(verack),
+                Message::MsgPing(ping)                 => self.handle_ping(ping),
+                Message::MsgPong(pong)                 => self.handle_pong(pong),
+                Message::MsgAddr(addrs)                => self.handle_addr(addrs),
+                Message::MsgInv(inv)                   => self.handle_inv(inv),
+                Message::MsgGetData(getdata)           => self.handle_getdata(getdata),
+                Message::MsgReject(reject)             => self.handle_reject(reject),
+                Message::MsgTx(tx)                     => self.handle_tx(tx),
+                Message::MsgGetAddr(getaddr)           => self.handle_getaddr(getaddr),
+                Message::MsgBlock(block)               => self.handle_block(block),
+                Message::MsgNotFound(notfound)         => self.handle_notfound(notfound),
+                Message::MsgMemPool(mempool)           => self.handle_mempool(mempool),
+                Message::MsgFilterLoad(filterload)     => self.handle_filterload(filterload),
+                Message::MsgFilterAdd(filteradd)       => self.handle_filteradd(filteradd),
+                Message::MsgFilterClear(filterclear)   => self.handle_filterclear(filterclear),
+                Message::MsgMerkleBlock(merkleblock)   => self.handle_merkleblock(merkleblock),
+                Message::MsgGetCFilters(getcfilters)   => self.handle_getcfilters(getcfilters),
+                Message::MsgCFilter(cfilter)           => self.handle_cfilter(cfilter),
+                Message::MsgGetCFHeaders(getcfheaders) => self.handle_getcfheaders(getcfheaders),
+                Message::MsgCFHeaders(cfheaders)       => self.handle_cfheaders(cfheaders),
+                Message::MsgGetCFCheckpt(getcfcheckpt) => self.handle_getcfcheckpt(getcfcheckpt),
+                Message::MsgCFCheckpt(cfcheckpt)       => self.handle_cfcheckpt(cfcheckpt),
+                Message::MsgSendCmpct(sendcmpct)       => self.handle_sendcmpct(sendcmpct),
+                Message::MsgCmpctBlock(cmpctblock)     => self.handle_cmpctblock(cmpctblock),
+                Message::MsgGetBlockTxn(getblocktxn)   => self.handle_getblocktxn(getblocktxn),
+                Message::MsgBlockTxn(blocktxn)         => self.handle_blocktxn(blocktxn),
+                Message::MsgSendHeaders(sendheaders)   => self.handle_sendheaders(sendheaders),
+                Message::MsgGetHeaders(getheaders)     => self.handle_getheaders(getheaders),
+                Message::MsgHeaders(headers)           => self.handle_headers(headers),
+                Message::MsgFeeFilter(feefilter)       => self.handle_feefilter(feefilter),
+                Message::MsgWtxidRelay(wtxidrelay)     => self.handle_wtxidrelay(wtxidrelay),
+                Message::MsgSendAddrV2(sendaddrv2)     => self.handle_sendaddrv2(sendaddrv2),
+                Message::MsgAddrV2(addrv2)             => self.handle_addrv2(addrv2),
             };
 
             match result
@@ -564,7 +2445,10 @@ enum PeriodicToken
     PeriodicPing,
     PeriodicTimeoutCheck,
     PeriodicAnnounceAddresses,
-    PeriodicRequestAddresses
+    PeriodicRequestAddresses,
+    PeriodicRelay,
+    PeriodicAdvertiseLocal,
+    PeriodicPeerInfo
 }
 
 /* TODO: Remove token and instead store a closure with the call to run.
@@ -620,22 +2504,37 @@ impl Periodic
  * pong             F  |   F
  * addr             F  |   F
  * getaddr          F  |   F
- * inv              P  |
- * getdata             |   P
- * reject           P  |
- * tx               P  |
- * block               |
- * notfound            |
+ * inv              F  |   P
+ * getdata          F  |   P
+ * reject           P  |   F
+ * tx               F  |   P
+ * block            P  |   P
+ * notfound         F  |   F
+ * mempool          F  |
+ * filterload       F  |
+ * filteradd        F  |
+ * filterclear      F  |
+ * merkleblock      P  |   F
+ * getcfilters      F  |
+ * cfilter          P  |   F
+ * getcfheaders     F  |
+ * cfheaders        P  |   F
+ * getcfcheckpt     F  |
+ * cfcheckpt        P  |   F
+ * sendcmpct        F  |   F
+ * cmpctblock       F  |   F
+ * getblocktxn      F  |   F
+ * blocktxn         F  |   F
  * getblocks           |
- * getheaders          |
- * headers             |
+ * sendheaders      F  |   F
+ * getheaders       F  |   F
+ * headers          P  |   P
+ * feefilter        F  |   F
+ * wtxidrelay       P  |
+ * sendaddrv2       F  |   F
+ * addrv2           P  |   P
  *
  *
  * Later:
- *     filterload
- *     filteradd
- *     filterclear
- *     merkleblock
- *     mempool
  *     alert
  */
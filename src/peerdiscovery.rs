@@ -21,7 +21,9 @@ use self::http::client::RequestWriter;
 use self::http::method::Get;
 use self::http::client::ResponseReader;
 
-use std::io::net::addrinfo::get_host_addresses;
+use connector::Connector;
+
+use config::Config;
 
 macro_rules! try_err_nil(
     ($e:expr) => (match $e { Ok(e) => e, Err(_) => return Err(()) }))
@@ -35,8 +37,13 @@ macro_rules! try_emp_vec(
 macro_rules! unwrap_emp_vec(
     ($e:expr) => (match $e { Some(e) => e, None => return Vec::new() }))
 
-fn discover_hardcoded() -> Vec<SocketAddr>
+fn discover_hardcoded(config : &Config) -> Vec<SocketAddr>
 {
+    if config.network != ::config::Network::MainNet
+    {
+        return Vec::new();
+    }
+
     vec![ SocketAddr { ip: Ipv4Addr(93,93,135,12), port: 8333 }, /* UK */
           SocketAddr { ip: Ipv4Addr(70,69,238,84), port: 8333 }, /* Canada */
           SocketAddr { ip: Ipv4Addr(54,232,98,22), port: 8333 }, /* Brazil */
@@ -105,13 +112,21 @@ fn get_last_snapshot_getaddr_bitnodes_io() -> Result<String,()>
     Ok(unwrap_err_nil!(lastest_snapshots_url.as_string()).to_string())
 }
 
-fn discover_getaddr_bitnodes_io() -> Vec<SocketAddr>
+fn discover_getaddr_bitnodes_io(config : &Config) -> Vec<SocketAddr>
 {
     let snapshot_url : String;
     let root : json::Json;
     let nodes : &json::Json;
     let mut peers = Vec::new();
 
+    /* rust-http can't go through the proxy, and fetching this directly would
+     * reveal us.  Also, bitnodes only crawls the main network.
+     */
+    if config.proxy.is_some() || config.network != ::config::Network::MainNet
+    {
+        return peers;
+    }
+
     snapshot_url = try_emp_vec!(get_last_snapshot_getaddr_bitnodes_io());
 
     root = try_emp_vec!(json_from_url(snapshot_url));
@@ -146,20 +161,19 @@ fn discover_getaddr_bitnodes_io() -> Vec<SocketAddr>
 }
 
 /* TODO: it might make sense to have a timeout in the http GET.
+ *
+ * The lookups go through the proxy when there is one, but Tor only returns one
+ * address per lookup.
  */
-fn discover_dns_lookup_seeds() -> Vec<SocketAddr>
+fn discover_dns_lookup_seeds(config : &Config) -> Vec<SocketAddr>
 {
-    let hostnames = [ "seed.bitcoin.sipa.be",
-                       "dnsseed.bluematt.me",
-                       "dnsseed.bitcoin.dashjr.org",
-                       "seed.bitcoinstats.com",
-                       "seed.bitnodes.io",
-                       "bitseed.xf2.org" ];
+    let hostnames : &[&str] = config.get_params().dns_seeds;
     let mut peers : Vec<SocketAddr> = Vec::new();
+    let connector : Connector = ::connector::connector(config);
 
     for hostname in hostnames.iter()
     {
-        let result = get_host_addresses(*hostname);
+        let result = connector.resolve(*hostname);
 
         if result.is_err()
         {
@@ -173,7 +187,7 @@ fn discover_dns_lookup_seeds() -> Vec<SocketAddr>
                 Ipv4Addr(..) =>
                 {
                     let sock_addr = SocketAddr { ip: *addr,
-                                                 port: ::config::DEFAULT_PORT };
+                                                 port: config.get_params().default_port };
 
                     peers.push(sock_addr);
                 },
@@ -185,22 +199,26 @@ fn discover_dns_lookup_seeds() -> Vec<SocketAddr>
     peers
 }
 
-pub fn discover_peers(count : uint) -> Vec<SocketAddr>
+pub fn discover_peers(config : &Config) -> Vec<SocketAddr>
 {
     let discovery_methods = [ discover_hardcoded,
                               discover_getaddr_bitnodes_io,
                               discover_dns_lookup_seeds ];
     let mut peers_by_method : Vec<Vec<SocketAddr>>;
-    let mut peers : Vec<SocketAddr> = Vec::with_capacity(count);
+    let mut peers : Vec<SocketAddr>;
+    let count : uint;
 
-    peers_by_method = discovery_methods.iter().map(|m| (*m)()).collect();
+    peers_by_method = discovery_methods.iter().map(|m| (*m)(config)).collect();
 
     for pm in peers_by_method.iter_mut()
     {
         ::crypto::rng().shuffle(pm.as_mut_slice());
     }
 
-    assert!(peers_by_method.iter().map(|v| v.len()).sum() >= count);
+    /* Through a proxy we have fewer discovery methods available */
+    count = ::std::cmp::min(config.initial_discovery_peers,
+                            peers_by_method.iter().map(|v| v.len()).sum());
+    peers = Vec::with_capacity(count);
 
     /* We take peers randomly from various peer discovery methods.  We make sure
      * that peers are uniformly distributed from each discovery method, since
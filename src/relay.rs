@@ -0,0 +1,390 @@
+extern crate time;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::RingBuf;
+
+use std::io::net::ip::SocketAddr;
+
+use std::num::Float;
+use std::rand::Rng;
+
+use self::time::Timespec;
+use std::time::duration::Duration;
+
+use datatype::hash::Hash;
+
+/* How many hashes we remember a peer knows about */
+pub const MAX_KNOWN_INVENTORY : uint = 50000;
+
+/* Average time between two inv announcements to a peer */
+pub const INVENTORY_BROADCAST_INTERVAL_MS : i64 = 2000;
+/* Maximum number of hashes announced on each trickle */
+pub const INVENTORY_BROADCAST_MAX : uint = 35;
+
+/* How long we wait for a peer to reply to a getdata before asking another
+ * peer that announced the same tx.
+ */
+const TX_REQUEST_TIMEOUT_S : i64 = 60;
+/* After this long we give up on a tx entirely */
+const TX_REQUEST_EXPIRY_M : i64 = 10;
+/* Announcements of a peer we keep track of, the others are ignored */
+const MAX_PEER_TX_ANNOUNCEMENTS : uint = 5000;
+
+const MAX_RELAY_LOG : uint = 10000;
+
+/* Average time between two feefilter messages to a peer */
+pub const FEEFILTER_BROADCAST_INTERVAL_MS : i64 = 10*60*1000;
+/* When our minimum fee rate changes a lot, we send it within this time */
+pub const FEEFILTER_MAX_CHANGE_DELAY_MS : i64 = 5*60*1000;
+
+/* Fee filters are rounded to one of these buckets, spaced geometrically */
+const FEEFILTER_BUCKET_MAX : u64 = 10_000_000;
+const FEEFILTER_BUCKET_SPACING : f64 = 1.1;
+
+/* Return the time of the next event of a Poisson process with the given
+ * average interval.  Randomizing when we announce makes it harder to find out
+ * where a transaction originated by timing its announcements.
+ */
+pub fn poisson_next(now : Timespec, avg_ms : i64) -> Timespec
+{
+    let u : f64 = ::crypto::rng().gen::<f64>();
+    let delay_ms : f64 = -(1.0-u).ln()*(avg_ms as f64);
+
+    now+Duration::milliseconds(delay_ms as i64)
+}
+
+/* Round the fee rate down to a bucket, and then randomly to lower buckets,
+ * so the feefilter we send does not reveal our exact mempool state and
+ * cannot be used to fingerprint us.
+ */
+pub fn round_fee_filter(rate : u64) -> u64
+{
+    let mut buckets : Vec<u64> = vec![0];
+    let mut bucket : f64 = (::mempool::MIN_RELAY_FEE_RATE/2) as f64;
+    let mut i : uint;
+
+    while bucket <= FEEFILTER_BUCKET_MAX as f64
+    {
+        buckets.push(bucket as u64);
+        bucket *= FEEFILTER_BUCKET_SPACING;
+    }
+
+    i = buckets.iter().rposition(|b| *b <= rate).unwrap();
+
+    /* Two times out of three, round down one more bucket */
+    while i > 0 && ::crypto::rand_interval(0,2) != 0
+    {
+        i -= 1;
+    }
+
+    buckets[i]
+}
+
+/* Bounded set of hashes a peer knows about (because it announced them to us
+ * or we announced them to it).  The oldest are forgotten first.
+ */
+pub struct KnownInventory
+{
+    set   : HashSet<Hash>,
+    order : RingBuf<Hash>,
+    cap   : uint
+}
+
+impl KnownInventory
+{
+    pub fn new(cap : uint) -> KnownInventory
+    {
+        KnownInventory
+        {
+            set:   HashSet::new(),
+            order: RingBuf::new(),
+            cap:   cap
+        }
+    }
+
+    pub fn contains(&self, hash : &Hash) -> bool
+    {
+        self.set.contains(hash)
+    }
+
+    pub fn insert(&mut self, hash : Hash)
+    {
+        if self.set.contains(&hash)
+        {
+            return;
+        }
+
+        if self.order.len() >= self.cap
+        {
+            let oldest : Hash = self.order.pop_front().unwrap();
+
+            self.set.remove(&oldest);
+        }
+
+        self.set.insert(hash.clone());
+        self.order.push_back(hash);
+    }
+}
+
+struct TxRequest
+{
+    peer       : Option<SocketAddr>,  /* Who we asked, if anyone */
+    time       : Timespec,            /* When we asked */
+    first_seen : Timespec,
+    announcers : Vec<SocketAddr>
+}
+
+/* Keeps track of the txs we requested from all the peers, so we only ask one
+ * peer at a time for each tx, and ask another one if it does not deliver.
+ */
+pub struct TxRequestTracker
+{
+    requests      : HashMap<Hash,TxRequest>,
+    announcements : HashMap<SocketAddr,uint>  /* Requests each peer is an announcer of */
+}
+
+/* One announcer less for the peer */
+fn forget_announcement(announcements : &mut HashMap<SocketAddr,uint>, peer : &SocketAddr)
+{
+    let count : uint = *announcements.get(peer).unwrap();
+
+    if count == 1
+    {
+        announcements.remove(peer);
+    }
+    else
+    {
+        announcements.insert(*peer,count-1);
+    }
+}
+
+#[allow(dead_code)]
+impl TxRequestTracker
+{
+    pub fn new() -> TxRequestTracker
+    {
+        TxRequestTracker
+        {
+            requests:      HashMap::new(),
+            announcements: HashMap::new()
+        }
+    }
+
+    pub fn len(&self) -> uint
+    {
+        self.requests.len()
+    }
+
+    /* A peer announced a tx we do not have.  Returns true if the peer should
+     * request it now.  Once a peer has too many announcements in flight, the
+     * new ones are ignored.
+     */
+    pub fn announced(&mut self, peer : SocketAddr, hash : Hash) -> bool
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let timeout : Duration = Duration::seconds(TX_REQUEST_TIMEOUT_S);
+        let count : uint = *self.announcements.get(&peer).unwrap_or(&0);
+
+        if count >= MAX_PEER_TX_ANNOUNCEMENTS
+           && !self.requests.get(&hash).map_or(false,|r| r.announcers.contains(&peer))
+        {
+            return false;
+        }
+
+        if !self.requests.contains_key(&hash)
+        {
+            self.requests.insert(hash.clone(),TxRequest
+            {
+                peer:       None,
+                time:       now,
+                first_seen: now,
+                announcers: Vec::new()
+            });
+        }
+
+        let request = self.requests.get_mut(&hash).unwrap();
+
+        if !request.announcers.contains(&peer)
+        {
+            request.announcers.push(peer);
+            self.announcements.insert(peer,count+1);
+        }
+
+        if request.peer.is_none() || now > request.time+timeout
+        {
+            request.peer = Some(peer);
+            request.time = now;
+
+            return true;
+        }
+
+        false
+    }
+
+    /* Timed out requests that the given peer announced and that we did not
+     * request from it yet.  They are reassigned to the peer.
+     */
+    pub fn get_rerequests(&mut self, peer : SocketAddr) -> Vec<Hash>
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let timeout : Duration = Duration::seconds(TX_REQUEST_TIMEOUT_S);
+        let mut hashes : Vec<Hash> = Vec::new();
+
+        for (hash, request) in self.requests.iter_mut()
+        {
+            let expired : bool = request.peer.is_none() ||
+                (request.peer != Some(peer) && now > request.time+timeout);
+
+            if expired && request.announcers.contains(&peer)
+            {
+                request.peer = Some(peer);
+                request.time = now;
+
+                hashes.push(hash.clone());
+            }
+        }
+
+        hashes
+    }
+
+    /* The peer replied it does not have the tx.  Forget it announced it and
+     * let the next peer that did take the request.
+     */
+    pub fn not_found(&mut self, peer : SocketAddr, hash : &Hash)
+    {
+        match self.requests.get_mut(hash)
+        {
+            Some(request) =>
+            {
+                if request.announcers.contains(&peer)
+                {
+                    request.announcers.retain(|a| *a != peer);
+                    forget_announcement(&mut self.announcements,&peer);
+                }
+
+                if request.peer == Some(peer)
+                {
+                    request.peer = None;
+                }
+            },
+            None => ()
+        }
+    }
+
+    /* The peer disconnected.  What we requested from it goes to the next peer
+     * that announced it, and the txs nobody else announced are forgotten.
+     */
+    pub fn remove_peer(&mut self, peer : SocketAddr)
+    {
+        let mut orphaned : Vec<Hash> = Vec::new();
+
+        for (hash, request) in self.requests.iter_mut()
+        {
+            request.announcers.retain(|a| *a != peer);
+
+            if request.peer == Some(peer)
+            {
+                request.peer = None;
+            }
+
+            if request.announcers.is_empty()
+            {
+                orphaned.push(hash.clone());
+            }
+        }
+
+        for hash in orphaned.iter()
+        {
+            self.requests.remove(hash);
+        }
+
+        self.announcements.remove(&peer);
+    }
+
+    /* We got the tx (valid or not), so we do not need to ask anyone else */
+    pub fn received(&mut self, hash : &Hash)
+    {
+        self.remove(hash);
+    }
+
+    fn remove(&mut self, hash : &Hash)
+    {
+        match self.requests.remove(hash)
+        {
+            Some(request) =>
+                for peer in request.announcers.iter()
+                {
+                    forget_announcement(&mut self.announcements,peer);
+                },
+            None => ()
+        }
+    }
+
+    pub fn expire(&mut self)
+    {
+        let now : Timespec = time::now_utc().to_timespec();
+        let limit : Timespec = now-Duration::minutes(TX_REQUEST_EXPIRY_M);
+        let expired : Vec<Hash>;
+
+        expired = self.requests.iter()
+            .filter(|&(_, r)| r.first_seen < limit)
+            .map(|(h, _)| h.clone())
+            .collect();
+
+        for hash in expired.iter()
+        {
+            self.remove(hash);
+        }
+    }
+}
+
+/* Txs accepted to the mempool, in order.  Each peer remembers the sequence
+ * number of the last one it saw and periodically asks for the newer ones to
+ * announce them.
+ */
+pub struct RelayLog
+{
+    hashes : RingBuf<Hash>,
+    first  : u64  /* Sequence number of hashes[0] */
+}
+
+impl RelayLog
+{
+    pub fn new() -> RelayLog
+    {
+        RelayLog
+        {
+            hashes: RingBuf::new(),
+            first:  0
+        }
+    }
+
+    pub fn push(&mut self, hash : Hash)
+    {
+        if self.hashes.len() >= MAX_RELAY_LOG
+        {
+            self.hashes.pop_front();
+            self.first += 1;
+        }
+
+        self.hashes.push_back(hash);
+    }
+
+    /* Returns the hashes after seq and the next sequence number.  Without a
+     * sequence number we just return where the log is at.
+     */
+    pub fn get_since(&self, seq : Option<u64>) -> (Vec<Hash>, u64)
+    {
+        let next : u64 = self.first+(self.hashes.len() as u64);
+        let from : u64;
+
+        from = match seq
+        {
+            Some(seq) => ::std::cmp::max(seq,self.first),
+            None      => return (Vec::new(), next)
+        };
+
+        (self.hashes.iter().skip((from-self.first) as uint).map(|h| h.clone()).collect(), next)
+    }
+}
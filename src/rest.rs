@@ -0,0 +1,518 @@
+extern crate serialize;
+
+use self::serialize::json::Json;
+use self::serialize::json::ToJson;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::hash::Hash;
+use datatype::script::Script;
+use datatype::transaction::Transaction;
+use datatype::transaction::TxLock;
+use datatype::transaction::OutPoint;
+
+use marshalling::Marshalling;
+
+use utxo::Coin;
+
+use chainmng::ChainInfo;
+
+use node::Node;
+
+use rpc::HttpRequest;
+use rpc::HttpResponse;
+
+/* Read only REST interface, with the paths and formats of the reference
+ * implementation.  It is served by the RPC server, without authentication,
+ * when the rest option is set.
+ *
+ * Everything but the mempool information comes in the binary format of the
+ * protocol, in that format in hexadecimal, or in JSON.
+ */
+
+pub const PREFIX : &'static str = "/rest/";
+
+const MAX_HEADERS_COUNT : uint = 2000;
+const MAX_GETUTXOS_OUTPOINTS : uint = 15;
+
+const SATOSHIS_PER_COIN : f64 = 100000000.0;
+
+enum RestFormat
+{
+    RestFormatBinary,
+    RestFormatHex,
+    RestFormatJson
+}
+
+/* The path without the extension, and the format the extension names */
+fn parse_format(path : &str) -> (&str, Option<RestFormat>)
+{
+    let dot : uint = match path.rfind('.')
+    {
+        Some(dot) => dot,
+        None      => return (path, None)
+    };
+
+    (path.slice_to(dot), match path.slice_from(dot+1)
+                         {
+                             "bin"  => Some(RestFormat::RestFormatBinary),
+                             "hex"  => Some(RestFormat::RestFormatHex),
+                             "json" => Some(RestFormat::RestFormatJson),
+                             _      => None
+                         })
+}
+
+fn format_not_found() -> HttpResponse
+{
+    HttpResponse::text(404,"output format not found (available: bin, hex, json)")
+}
+
+fn bad_request(message : String) -> HttpResponse
+{
+    HttpResponse::text(400,message.as_slice())
+}
+
+/* The same data in the format asked for */
+fn respond(format : RestFormat, data : || -> Vec<u8>, json : || -> Json) -> HttpResponse
+{
+    match format
+    {
+        RestFormat::RestFormatBinary =>
+            HttpResponse::new(200,"application/octet-stream",data()),
+        RestFormat::RestFormatHex    =>
+            HttpResponse::text(200,::crypto::to_hexstr(data().as_slice()).as_slice()),
+        RestFormat::RestFormatJson   =>
+            HttpResponse::json(200,&json())
+    }
+}
+
+fn value_json(satoshis : u64) -> Json
+{
+    ((satoshis as f64)/SATOSHIS_PER_COIN).to_json()
+}
+
+fn script_json(script : &Script) -> Json
+{
+    ::rpc::object(vec![("hex", ::crypto::to_hexstr(script.get_bytes().as_slice()).to_json())])
+}
+
+fn lock_time(lock : TxLock) -> u32
+{
+    match lock
+    {
+        TxLock::LockLocked       => 0,
+        TxLock::LockUnlocked     => 0xffffffff,
+        TxLock::LockBlock(block) => block,
+        TxLock::LockTime(tm)     => tm.sec as u32
+    }
+}
+
+fn tx_json(tx : &Transaction) -> Json
+{
+    let mut vin : Vec<Json> = Vec::new();
+    let mut vout : Vec<Json> = Vec::new();
+    let mut marshalling : Marshalling = Marshalling::new();
+
+    for in_tx in tx.get_in_txs().iter()
+    {
+        let sequence : Json = in_tx.get_sequence().to_json();
+
+        if tx.is_coinbase()
+        {
+            vin.push(::rpc::object(vec![
+                ("coinbase", ::crypto::to_hexstr(in_tx.get_script().get_bytes().as_slice())
+                             .to_json()),
+                ("sequence", sequence)]));
+        }
+        else
+        {
+            vin.push(::rpc::object(vec![
+                ("txid",      format!("{}",in_tx.get_prev_out().get_hash()).to_json()),
+                ("vout",      in_tx.get_prev_out().get_index().to_json()),
+                ("scriptSig", script_json(in_tx.get_script())),
+                ("sequence",  sequence)]));
+        }
+    }
+
+    for (n, out_tx) in tx.get_out_txs().iter().enumerate()
+    {
+        vout.push(::rpc::object(vec![("value",        value_json(out_tx.get_value()
+                                                                       .get_satoshis())),
+                                     ("n",            n.to_json()),
+                                     ("scriptPubKey", script_json(out_tx.get_script()))]));
+    }
+
+    marshalling.write_transaction(tx);
+
+    ::rpc::object(vec![("txid",     format!("{}",tx.get_hash()).to_json()),
+                       ("hash",     format!("{}",tx.get_witness_hash()).to_json()),
+                       ("version",  tx.get_version().to_json()),
+                       ("size",     tx.get_size().to_json()),
+                       ("locktime", lock_time(tx.get_lock()).to_json()),
+                       ("vin",      vin.to_json()),
+                       ("vout",     vout.to_json()),
+                       ("hex",      ::crypto::to_hexstr(marshalling.get().as_slice()).to_json())])
+}
+
+/* Height is None for blocks out of the active chain */
+fn header_fields(header : &BlockHeader,
+                 height : Option<u32>,
+                 tip    : u32) -> Vec<(&'static str, Json)>
+{
+    let mut fields : Vec<(&'static str, Json)>;
+
+    fields = vec![("hash",              format!("{}",header.get_hash()).to_json()),
+                  ("confirmations",     height.map(|h| (tip-h+1) as i64).unwrap_or(-1)
+                                              .to_json()),
+                  ("version",           header.get_version().to_json()),
+                  ("merkleroot",        format!("{}",header.get_merkle_root()).to_json()),
+                  ("time",              header.get_time().sec.to_json()),
+                  ("nonce",             header.get_nounce().to_json()),
+                  ("bits",              format!("{:08x}",header.get_bits()).to_json()),
+                  ("difficulty",        ::rpc::get_difficulty(header.get_bits()).to_json()),
+                  ("previousblockhash", format!("{}",header.get_prev_block()).to_json())];
+
+    match height
+    {
+        Some(height) => fields.push(("height",height.to_json())),
+        None         => ()
+    }
+
+    fields
+}
+
+/* With the txs in full, or only their ids */
+fn block_json(block : &Block, height : Option<u32>, tip : u32, details : bool) -> Json
+{
+    let mut fields : Vec<(&'static str, Json)> = header_fields(block.get_header(),height,tip);
+    let txs : Vec<Json>;
+
+    txs = if details { block.get_txs().iter().map(|tx| tx_json(tx)).collect() }
+          else { block.get_txs().iter().map(|tx| format!("{}",tx.get_hash()).to_json())
+                                       .collect() };
+
+    fields.push(("size",block.get_size().to_json()));
+    fields.push(("nTx",block.get_txs().len().to_json()));
+    fields.push(("tx",txs.to_json()));
+
+    ::rpc::object(fields)
+}
+
+fn parse_hash(str : &str) -> Result<Hash,HttpResponse>
+{
+    match Hash::from_hexstr(str)
+    {
+        Some(hash) => Ok(hash),
+        None       => Err(bad_request(format!("Invalid hash: {}",str)))
+    }
+}
+
+/* /rest/block/<hash>.<format> and /rest/block/notxdetails/<hash>.<format> */
+fn rest_block(node : &Node, path : &str, details : bool) -> HttpResponse
+{
+    let (hashstr, format) = parse_format(path);
+    let format : RestFormat = match format
+    {
+        Some(format) => format,
+        None         => return format_not_found()
+    };
+    let hash : Hash = match parse_hash(hashstr)
+    {
+        Ok(hash)  => hash,
+        Err(resp) => return resp
+    };
+    let block : Block = match node.get_block(hash.clone())
+    {
+        Some(block) => block,
+        None        => return HttpResponse::text(404,format!("{} not found",hashstr).as_slice())
+    };
+    let (height, tip) = node.get_active_height(hash);
+
+    respond(format,
+            || { let mut m : Marshalling = Marshalling::new(); m.write_block(&block); m.get() },
+            || block_json(&block,height,tip,details))
+}
+
+/* /rest/tx/<txid>.<format>, we have no transaction index, so only the
+ * mempool.
+ */
+fn rest_tx(node : &Node, path : &str) -> HttpResponse
+{
+    let (hashstr, format) = parse_format(path);
+    let format : RestFormat = match format
+    {
+        Some(format) => format,
+        None         => return format_not_found()
+    };
+    let hash : Hash = match parse_hash(hashstr)
+    {
+        Ok(hash)  => hash,
+        Err(resp) => return resp
+    };
+    let tx : Transaction = match node.get_mempool_tx(hash)
+    {
+        Some(tx) => tx,
+        None     => return HttpResponse::text(404,format!("{} not found",hashstr).as_slice())
+    };
+
+    respond(format,
+            || { let mut m : Marshalling = Marshalling::new(); m.write_transaction(&tx); m.get() },
+            || tx_json(&tx))
+}
+
+/* /rest/headers/<count>/<hash>.<format>, the headers of the active chain
+ * from that block on.
+ */
+fn rest_headers(node : &Node, path : &str) -> HttpResponse
+{
+    let (params, format) = parse_format(path);
+    let format : RestFormat = match format
+    {
+        Some(format) => format,
+        None         => return format_not_found()
+    };
+    let parts : Vec<&str> = params.split('/').collect();
+    let count : uint;
+    let hash : Hash;
+
+    if parts.len() != 2
+    {
+        return bad_request("No header count specified. Use /rest/headers/<count>/<hash>.<ext>."
+                           .to_string());
+    }
+
+    count = match from_str::<uint>(parts[0])
+    {
+        Some(count) if count >= 1 && count <= MAX_HEADERS_COUNT => count,
+        _ => return bad_request(format!("Header count is invalid or out of acceptable range \
+                                         (1-{}): {}",MAX_HEADERS_COUNT,parts[0]))
+    };
+
+    hash = match parse_hash(parts[1])
+    {
+        Ok(hash)  => hash,
+        Err(resp) => return resp
+    };
+
+    let (headers, start, tip) = node.get_headers(hash,count);
+
+    respond(format,
+            ||
+            {
+                let mut m : Marshalling = Marshalling::new();
+
+                for header in headers.iter()
+                {
+                    m.write_block_header(header);
+                }
+
+                m.get()
+            },
+            || headers.iter().enumerate()
+                      .map(|(i, header)| ::rpc::object(header_fields(header,
+                                                                     start.map(|s| s+i as u32),
+                                                                     tip)))
+                      .collect::<Vec<Json>>().to_json())
+}
+
+fn parse_outpoint(str : &str) -> Option<OutPoint>
+{
+    let dash : uint = match str.find('-')
+    {
+        Some(dash) => dash,
+        None       => return None
+    };
+
+    match (Hash::from_hexstr(str.slice_to(dash)),from_str::<u32>(str.slice_from(dash+1)))
+    {
+        (Some(hash),Some(index)) => Some(OutPoint::new(hash,index)),
+        _                        => None
+    }
+}
+
+/* /rest/getutxos[/checkmempool]/<txid>-<n>/<txid>-<n>/....<format>, which of
+ * the outputs are unspent, and those outputs.
+ */
+fn rest_getutxos(node : &Node, path : &str) -> HttpResponse
+{
+    let (params, format) = parse_format(path);
+    let format : RestFormat = match format
+    {
+        Some(format) => format,
+        None         => return format_not_found()
+    };
+    let mut parts : Vec<&str> = params.split('/').filter(|p| !p.is_empty()).collect();
+    let mut mempool : bool = false;
+    let mut outpoints : Vec<OutPoint> = Vec::new();
+    let mut bitmap : Vec<u8>;
+    let mut bits : String = String::new();
+    let found : Vec<Coin>;
+
+    if parts.len() > 0 && parts[0] == "checkmempool"
+    {
+        mempool = true;
+        parts.remove(0);
+    }
+
+    if parts.is_empty()
+    {
+        return bad_request("Error: empty request".to_string());
+    }
+
+    if parts.len() > MAX_GETUTXOS_OUTPOINTS
+    {
+        return bad_request(format!("Error: max outpoints exceeded (max: {}, tried: {})",
+                                   MAX_GETUTXOS_OUTPOINTS,parts.len()));
+    }
+
+    for part in parts.iter()
+    {
+        match parse_outpoint(*part)
+        {
+            Some(outpoint) => outpoints.push(outpoint),
+            None           => return bad_request("Parse error".to_string())
+        }
+    }
+
+    let (height, tip, coins) = node.get_coins(outpoints,mempool);
+
+    bitmap = Vec::from_elem((coins.len()+7)/8,0u8);
+
+    for (i, coin) in coins.iter().enumerate()
+    {
+        if coin.is_some()
+        {
+            *bitmap.get_mut(i/8) |= 1 << (i%8);
+        }
+
+        bits.push(if coin.is_some() { '1' } else { '0' });
+    }
+
+    found = coins.into_iter().filter_map(|coin| coin).collect();
+
+    respond(format,
+            ||
+            {
+                let mut m : Marshalling = Marshalling::new();
+
+                m.write_uint32(height);
+                m.write_hash(&tip);
+                m.write_varbytes(bitmap.as_slice());
+                m.write_varint(found.len() as u64);
+
+                /* The version of the tx is not kept anymore, it is always 0 */
+                for coin in found.iter()
+                {
+                    m.write_uint32(0);
+                    m.write_uint32(coin.get_height());
+                    m.write_value(coin.get_output().get_value());
+                    m.write_script(coin.get_output().get_script());
+                }
+
+                m.get()
+            },
+            ||
+            {
+                let utxos : Vec<Json> = found.iter().map(|coin| ::rpc::object(vec![
+                    ("height",       coin.get_height().to_json()),
+                    ("value",        value_json(coin.get_output().get_value().get_satoshis())),
+                    ("scriptPubKey", script_json(coin.get_output().get_script()))])).collect();
+
+                ::rpc::object(vec![("chainHeight",  height.to_json()),
+                                   ("chaintipHash", format!("{}",tip).to_json()),
+                                   ("bitmap",       bits.to_json()),
+                                   ("utxos",        utxos.to_json())])
+            })
+}
+
+/* /rest/mempool/info.json, only in JSON */
+fn rest_mempool_info(node : &Node, path : &str) -> HttpResponse
+{
+    let info : ChainInfo;
+
+    match parse_format(path)
+    {
+        ("", Some(RestFormat::RestFormatJson)) => (),
+        _ => return HttpResponse::text(404,"output format not found (available: json)")
+    }
+
+    info = node.get_chain_info();
+
+    HttpResponse::json(200,&::rpc::object(vec![
+        ("loaded",        true.to_json()),
+        ("size",          info.mempool_txs.to_json()),
+        ("bytes",         info.mempool_size.to_json()),
+        ("usage",         info.mempool_size.to_json()),
+        ("maxmempool",    ::mempool::MAX_MEMPOOL_SIZE.to_json()),
+        /* Fee rates are in coins per 1000 bytes */
+        ("mempoolminfee", value_json(info.min_fee_rate)),
+        ("minrelaytxfee", value_json(::mempool::MIN_RELAY_FEE_RATE))]))
+}
+
+/* What follows the name of the endpoint, if the path starts with the whole
+ * name: getutxosX is not getutxos.
+ */
+fn after_endpoint<'a>(path : &'a str, endpoint : &str) -> Option<&'a str>
+{
+    let rest : &str;
+
+    if !path.starts_with(endpoint)
+    {
+        return None;
+    }
+
+    rest = path.slice_from(endpoint.len());
+
+    if rest.is_empty() || rest.starts_with("/") || rest.starts_with(".")
+    {
+        Some(rest)
+    }
+    else
+    {
+        None
+    }
+}
+
+pub fn handle(node : &Node, request : &HttpRequest) -> HttpResponse
+{
+    let mut path : &str = request.path.as_slice().slice_from(PREFIX.len());
+
+    if request.method.as_slice() != "GET"
+    {
+        return HttpResponse::text(405,"REST interface handles only GET requests");
+    }
+
+    /* We take no query parameters */
+    match path.find('?')
+    {
+        Some(query) => path = path.slice_to(query),
+        None        => ()
+    }
+
+    /* The endpoints taking parameters end with their slash */
+    if path.starts_with("block/notxdetails/")
+    {
+        rest_block(node,path.slice_from("block/notxdetails/".len()),false)
+    }
+    else if path.starts_with("block/")
+    {
+        rest_block(node,path.slice_from("block/".len()),true)
+    }
+    else if path.starts_with("tx/")
+    {
+        rest_tx(node,path.slice_from("tx/".len()))
+    }
+    else if path.starts_with("headers/")
+    {
+        rest_headers(node,path.slice_from("headers/".len()))
+    }
+    else
+    {
+        match (after_endpoint(path,"getutxos"), after_endpoint(path,"mempool/info"))
+        {
+            (Some(rest), _) => rest_getutxos(node,rest),
+            (_, Some(rest)) => rest_mempool_info(node,rest),
+            _               => HttpResponse::text(404,"Not found")
+        }
+    }
+}
@@ -0,0 +1,1280 @@
+extern crate time;
+extern crate serialize;
+
+use std::io::BufferedStream;
+use std::io::TcpStream;
+use std::io::TcpListener;
+use std::io::TcpAcceptor;
+use std::io::Listener;
+use std::io::Acceptor;
+use std::io::File;
+use std::io::IoResult;
+use std::io::net::ip::SocketAddr;
+use std::io::net::ip::IpAddr;
+use std::io::timer::sleep;
+use std::time::duration::Duration;
+
+use std::collections::TreeMap;
+use std::comm::Sender;
+use std::rand::Rng;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUint;
+use std::sync::atomic::SeqCst;
+
+use self::time::Timespec;
+
+use self::serialize::json;
+use self::serialize::json::Json;
+use self::serialize::json::ToJson;
+use self::serialize::base64::FromBase64;
+
+use datatype::hash::Hash;
+use datatype::transaction::Transaction;
+use datatype::netaddr::NetAddr;
+use datatype::netaddr::NetAddrV2;
+use datatype::netaddr::NetworkId;
+
+use marshalling::Unmarshalling;
+
+use chainmng::ChainManagerReply;
+use chainmng::ChainInfo;
+
+use mempool::MempoolError;
+
+use logger::LogLevel;
+use logger::NodeLogger;
+
+use node::Node;
+
+/* A JSON-RPC server over HTTP, with the method names, parameters and results
+ * of the reference implementation, so the tools written for it work with us.
+ * Only the methods to inspect and control the node are there, we have no
+ * wallet.
+ *
+ * Clients authenticate with HTTP basic authentication, with the configured
+ * user and password or, if there is no password, with the user "__cookie__"
+ * and the password in the cookie file.  Only we can read it, and each network
+ * has its own in a directory of the data directory named after it.
+ */
+
+const COOKIE_USER : &'static str = "__cookie__";
+const COOKIE_SIZE : uint = 32;
+
+const MAX_HEADERS : uint = 100;
+const MAX_LINE_SIZE : uint = 8*1024;
+const MAX_BODY_SIZE : uint = 4*1024*1024;
+
+/* Each client has a thread, the others are told to come back later */
+const MAX_CLIENTS : uint = 16;
+
+const READ_TIMEOUT_MS : u64 = 30*1000;
+
+/* Makes guessing the password slow */
+const AUTH_FAILURE_DELAY_MS : i64 = 250;
+
+const DEFAULT_BAN_TIME_S : i64 = 24*60*60;
+
+/* Error codes of the reference implementation */
+const RPC_INVALID_REQUEST : i64 = -32600;
+const RPC_METHOD_NOT_FOUND : i64 = -32601;
+const RPC_INVALID_PARAMS : i64 = -32602;
+const RPC_PARSE_ERROR : i64 = -32700;
+const RPC_INVALID_ADDRESS_OR_KEY : i64 = -5;
+const RPC_INVALID_PARAMETER : i64 = -8;
+const RPC_DESERIALIZATION_ERROR : i64 = -22;
+const RPC_CLIENT_NODE_ALREADY_ADDED : i64 = -23;
+const RPC_CLIENT_NODE_NOT_ADDED : i64 = -24;
+const RPC_VERIFY_ERROR : i64 = -25;
+const RPC_VERIFY_REJECTED : i64 = -26;
+const RPC_CLIENT_NODE_NOT_CONNECTED : i64 = -29;
+const RPC_CLIENT_INVALID_IP_OR_SUBNET : i64 = -30;
+
+/* The parameters of every method in order, to also take them by name */
+static METHOD_PARAMS : &'static [(&'static str, &'static [&'static str])] = &[
+    ("getpeerinfo",        &[]),
+    ("getnetworkinfo",     &[]),
+    ("getaddrmaninfo",     &[]),
+    ("getblockchaininfo",  &[]),
+    ("getrawmempool",      &["verbose"]),
+    ("getrawtransaction",  &["txid", "verbose"]),
+    ("sendrawtransaction", &["hexstring"]),
+    ("addnode",            &["node", "command"]),
+    ("disconnectnode",     &["address", "nodeid"]),
+    ("setban",             &["subnet", "command", "bantime", "absolute"]),
+    ("logging",            &["include", "exclude", "level", "peers"]),
+    ("stop",               &[]) ];
+
+#[deriving(Show)]
+pub struct RpcError
+{
+    pub code    : i64,
+    pub message : String
+}
+
+impl RpcError
+{
+    pub fn new(code : i64, message : &str) -> RpcError
+    {
+        RpcError { code: code, message: message.to_string() }
+    }
+
+    fn to_json(&self) -> Json
+    {
+        object(vec![("code",    self.code.to_json()),
+                    ("message", self.message.to_json())])
+    }
+
+    /* Like the reference implementation, only the errors about the request
+     * itself are not 500.
+     */
+    fn get_http_status(&self) -> u16
+    {
+        match self.code
+        {
+            RPC_INVALID_REQUEST  => 400,
+            RPC_PARSE_ERROR      => 400,
+            RPC_METHOD_NOT_FOUND => 404,
+            _                    => 500
+        }
+    }
+}
+
+pub struct HttpRequest
+{
+    pub method  : String,
+    pub path    : String,
+    pub headers : Vec<(String,String)>,
+    pub body    : Vec<u8>
+}
+
+impl HttpRequest
+{
+    /* Header names are case insensitive, we keep them in lowercase */
+    pub fn get_header(&self, name : &str) -> Option<&str>
+    {
+        self.headers.iter().find(|&&(ref n, _)| n.as_slice() == name)
+                           .map(|&(_, ref value)| value.as_slice())
+    }
+
+    fn keep_alive(&self) -> bool
+    {
+        match self.get_header("connection")
+        {
+            Some(value) => value != "close",
+            None        => true
+        }
+    }
+}
+
+pub struct HttpResponse
+{
+    pub status       : u16,
+    pub content_type : &'static str,
+    pub body         : Vec<u8>
+}
+
+impl HttpResponse
+{
+    pub fn new(status : u16, content_type : &'static str, body : Vec<u8>) -> HttpResponse
+    {
+        HttpResponse { status: status, content_type: content_type, body: body }
+    }
+
+    pub fn json(status : u16, json : &Json) -> HttpResponse
+    {
+        HttpResponse::new(status,"application/json",format!("{}\n",json).into_bytes())
+    }
+
+    pub fn text(status : u16, text : &str) -> HttpResponse
+    {
+        HttpResponse::new(status,"text/plain",format!("{}\n",text).into_bytes())
+    }
+}
+
+fn http_reason(status : u16) -> &'static str
+{
+    match status
+    {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Request Entity Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _   => "Unknown"
+    }
+}
+
+pub fn object(fields : Vec<(&str, Json)>) -> Json
+{
+    let mut object : TreeMap<String,Json> = TreeMap::new();
+
+    for (name, value) in fields.into_iter()
+    {
+        object.insert(name.to_string(),value);
+    }
+
+    object.to_json()
+}
+
+/* The password clients must give, with its user */
+fn get_credentials(config : &::config::Config) -> IoResult<(String,String)>
+{
+    let mut cookie : [u8, ..COOKIE_SIZE] = [0u8, ..COOKIE_SIZE];
+    let password : String;
+    let path : Path;
+    let mut file : File;
+
+    match config.rpc_password
+    {
+        Some(ref password) =>
+            return Ok((config.rpc_user.clone().unwrap_or(String::new()),password.clone())),
+        None               => ()
+    }
+
+    ::crypto::rng().fill_bytes(&mut cookie);
+    password = ::crypto::to_hexstr(&cookie);
+
+    path = cookie_path(config);
+
+    if !path.dir_path().exists()
+    {
+        try!(::std::io::fs::mkdir_recursive(&path.dir_path(),::std::io::USER_RWX));
+    }
+
+    /* The permissions are set before there is anything to read */
+    file = try!(File::create(&path));
+
+    try!(::std::io::fs::chmod(&path,::std::io::USER_READ|::std::io::USER_WRITE));
+
+    try!(file.write_str(format!("{}:{}",COOKIE_USER,password).as_slice()));
+
+    Ok((COOKIE_USER.to_string(),password))
+}
+
+fn cookie_path(config : &::config::Config) -> Path
+{
+    ::common::cookie_path(&config.datadir,config.get_params().name)
+}
+
+/* The cookie is only valid while we run */
+#[allow(unused_must_use)]
+pub fn remove_cookie(config : &::config::Config)
+{
+    if config.rpc_password.is_none()
+    {
+        ::std::io::fs::unlink(&cookie_path(config));
+    }
+}
+
+fn is_authorized(request : &HttpRequest, user : &str, password : &str) -> bool
+{
+    let credentials : Vec<u8>;
+
+    credentials = match request.get_header("authorization")
+    {
+        Some(value) if value.starts_with("Basic ") =>
+            match value.slice_from(6).trim().from_base64()
+            {
+                Ok(credentials) => credentials,
+                Err(_)          => return false
+            },
+        _ => return false
+    };
+
+    /* The hashes have the same length whatever the password is, so the time
+     * tells nothing about it.
+     */
+    ::crypto::constant_time_eq(&::crypto::sha256(credentials.as_slice()),
+                               &::crypto::sha256(format!("{}:{}",user,password).as_bytes()))
+}
+
+/* None if the connection was closed or the line is too long */
+fn read_line(stream : &mut BufferedStream<TcpStream>) -> Option<String>
+{
+    let mut line : Vec<u8> = Vec::new();
+
+    loop
+    {
+        match stream.read_byte()
+        {
+            Ok(b'\n')                             => break,
+            Ok(byte) if line.len() < MAX_LINE_SIZE => line.push(byte),
+            _                                      => return None
+        }
+    }
+
+    match String::from_utf8(line)
+    {
+        Ok(line) => Some(line.as_slice().trim_right_chars('\r').to_string()),
+        Err(_)   => None
+    }
+}
+
+/* None if the connection was closed or the request is not valid HTTP */
+fn read_request(stream : &mut BufferedStream<TcpStream>) -> Option<HttpRequest>
+{
+    let line : String = match read_line(stream)
+    {
+        Some(line) => line,
+        None       => return None
+    };
+    let parts : Vec<&str> = line.as_slice().split(' ').collect();
+    let mut headers : Vec<(String,String)> = Vec::new();
+    let length : uint;
+    let body : Vec<u8>;
+
+    if parts.len() != 3 || !parts[2].starts_with("HTTP/1.")
+    {
+        return None;
+    }
+
+    loop
+    {
+        let header : String = match read_line(stream)
+        {
+            Some(header) => header,
+            None         => return None
+        };
+        let colon : uint;
+
+        if header.is_empty()
+        {
+            break;
+        }
+
+        colon = match header.as_slice().find(':')
+        {
+            Some(colon) if headers.len() < MAX_HEADERS => colon,
+            _                                          => return None
+        };
+
+        headers.push((header.as_slice().slice_to(colon).chars().map(|c| c.to_lowercase())
+                                                        .collect(),
+                      header.as_slice().slice_from(colon+1).trim().to_string()));
+    }
+
+    length = match headers.iter().find(|&&(ref n, _)| n.as_slice() == "content-length")
+    {
+        Some(&(_, ref value)) => match from_str::<uint>(value.as_slice())
+        {
+            Some(length) if length <= MAX_BODY_SIZE => length,
+            _                                       => return None
+        },
+        None                  => 0
+    };
+
+    body = match stream.read_exact(length)
+    {
+        Ok(body) => body,
+        Err(_)   => return None
+    };
+
+    Some(HttpRequest
+         {
+             method:  parts[0].to_string(),
+             path:    parts[1].to_string(),
+             headers: headers,
+             body:    body
+         })
+}
+
+fn write_response(stream    : &mut BufferedStream<TcpStream>,
+                  response  : &HttpResponse,
+                  keepalive : bool) -> IoResult<()>
+{
+    try!(stream.write_str(format!("HTTP/1.1 {} {}\r\n",response.status,
+                                  http_reason(response.status)).as_slice()));
+    try!(stream.write_str(format!("Content-Type: {}\r\n",response.content_type).as_slice()));
+    try!(stream.write_str(format!("Content-Length: {}\r\n",response.body.len()).as_slice()));
+
+    if response.status == 401
+    {
+        try!(stream.write_str("WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n"));
+    }
+
+    if !keepalive
+    {
+        try!(stream.write_str("Connection: close\r\n"));
+    }
+
+    try!(stream.write_str("\r\n"));
+    try!(stream.write(response.body.as_slice()));
+
+    stream.flush()
+}
+
+/* Serves the requests of one client connection */
+struct Handler
+{
+    node     : Node,
+    client   : SocketAddr,
+    /* Set by the stop method, we stop once we replied */
+    stopping : bool
+}
+
+fn named_params(method : &str, params : &TreeMap<String,Json>) -> Result<Vec<Json>,RpcError>
+{
+    let names : &[&str];
+
+    names = match METHOD_PARAMS.iter().find(|&&(m, _)| m == method)
+    {
+        Some(&(_, names)) => names,
+        None              => return Err(RpcError::new(RPC_METHOD_NOT_FOUND,"Method not found"))
+    };
+
+    for name in params.keys()
+    {
+        if !names.iter().any(|n| *n == name.as_slice())
+        {
+            return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                     format!("Unknown named parameter {}",name).as_slice()));
+        }
+    }
+
+    Ok(names.iter().map(|name| params.get(&name.to_string()).map(|p| p.clone())
+                                     .unwrap_or(().to_json()))
+                   .collect())
+}
+
+fn param<'a>(params : &'a [Json], i : uint) -> Option<&'a Json>
+{
+    match params.get(i)
+    {
+        Some(value) if !value.is_null() => Some(value),
+        _                               => None
+    }
+}
+
+fn param_str<'a>(params : &'a [Json], i : uint, name : &str) -> Result<&'a str,RpcError>
+{
+    match param(params,i).and_then(|p| p.as_string())
+    {
+        Some(value) => Ok(value),
+        None        => Err(RpcError::new(RPC_INVALID_PARAMS,
+                                         format!("{} must be a string",name).as_slice()))
+    }
+}
+
+fn param_bool(params : &[Json], i : uint, name : &str) -> Result<Option<bool>,RpcError>
+{
+    match param(params,i)
+    {
+        None        => Ok(None),
+        Some(value) => match (value.as_boolean(),value.as_i64())
+        {
+            /* The verbosity of some methods used to be a number */
+            (Some(value),_) => Ok(Some(value)),
+            (_,Some(value)) => Ok(Some(value != 0)),
+            _               => Err(RpcError::new(RPC_INVALID_PARAMS,
+                                                 format!("{} must be a boolean",name)
+                                                 .as_slice()))
+        }
+    }
+}
+
+fn param_i64(params : &[Json], i : uint, name : &str) -> Result<Option<i64>,RpcError>
+{
+    match param(params,i)
+    {
+        None        => Ok(None),
+        Some(value) => match value.as_i64()
+        {
+            Some(value) => Ok(Some(value)),
+            None        => Err(RpcError::new(RPC_INVALID_PARAMS,
+                                             format!("{} must be a number",name).as_slice()))
+        }
+    }
+}
+
+fn param_strs(params : &[Json], i : uint, name : &str) -> Result<Vec<String>,RpcError>
+{
+    match param(params,i)
+    {
+        None        => Ok(Vec::new()),
+        Some(value) => match value.as_list()
+        {
+            Some(list) if list.iter().all(|v| v.is_string()) =>
+                Ok(list.iter().map(|v| v.as_string().unwrap().to_string()).collect()),
+            _ =>
+                Err(RpcError::new(RPC_INVALID_PARAMS,
+                                  format!("{} must be an array of strings",name).as_slice()))
+        }
+    }
+}
+
+fn param_hash(params : &[Json], i : uint, name : &str) -> Result<Hash,RpcError>
+{
+    let str : &str = try!(param_str(params,i,name));
+
+    match Hash::from_hexstr(str)
+    {
+        Some(hash) => Ok(hash),
+        None       => Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                        format!("{} must be of length 64 (not {}, for '{}')",
+                                                name,str.len(),str).as_slice()))
+    }
+}
+
+/* An IP address or an onion service, with or without the port */
+fn parse_node_netaddr(str : &str, default_port : u16) -> Option<NetAddrV2>
+{
+    let services : ::config::Services = 0;
+    let host : &str;
+    let port : Option<u16>;
+
+    match parse_node_address(str,default_port)
+    {
+        Some(addr) => return NetAddrV2::from_netaddr(&NetAddr::new(None,services,Some(addr))),
+        None       => ()
+    }
+
+    match str.rfind(':')
+    {
+        Some(i) =>
+        {
+            host = str.slice_to(i);
+            port = from_str(str.slice_from(i+1));
+        },
+        None    =>
+        {
+            host = str;
+            port = Some(default_port);
+        }
+    }
+
+    match port
+    {
+        Some(port) if host.ends_with(".onion") => NetAddrV2::from_onion(host,port,services,None),
+        _                                      => None
+    }
+}
+
+/* An address with or without the port */
+fn parse_node_address(str : &str, default_port : u16) -> Option<SocketAddr>
+{
+    match from_str::<SocketAddr>(str)
+    {
+        Some(addr) => return Some(addr),
+        None       => ()
+    }
+
+    from_str::<IpAddr>(str).map(|ip| SocketAddr { ip: ip, port: default_port })
+}
+
+/* A single address, we do not ban subnets */
+fn parse_ban_address(str : &str) -> Option<IpAddr>
+{
+    let ip : &str = if str.ends_with("/32") || str.ends_with("/128")
+                    { str.slice_to(str.rfind('/').unwrap()) }
+                    else { str };
+
+    from_str::<IpAddr>(ip)
+}
+
+/* Like the reference implementation, relative to the minimum difficulty of
+ * the main network.
+ */
+pub fn get_difficulty(bits : u32) -> f64
+{
+    let mut shift : u32 = (bits >> 24) & 0xff;
+    let mut difficulty : f64 = (0x0000ffff as f64)/((bits & 0x00ffffff) as f64);
+
+    while shift < 29
+    {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+
+    while shift > 29
+    {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+
+    difficulty
+}
+
+fn version_number() -> u32
+{
+    10000*(::config::VERSION_MAJOR as u32)+100*(::config::VERSION_MINOR as u32)
+        +(::config::VERSION_FIXES as u32)
+}
+
+fn log_categories_json(enabled : u64) -> Json
+{
+    let mut categories : TreeMap<String,Json> = TreeMap::new();
+
+    for name in ::logger::category_names(::logger::LOG_CATEGORIES_ALL).iter()
+    {
+        let flag : u64 = ::logger::parse_categories(*name).unwrap();
+
+        categories.insert(name.to_string(),(enabled & flag != 0).to_json());
+    }
+
+    categories.to_json()
+}
+
+fn decode_transaction(data : Vec<u8>) -> Option<Transaction>
+{
+    /* Unmarshalling panics on invalid data, so we do it in its own task */
+    let result = ::std::task::try(proc() {
+        let mut unmarshalling : Unmarshalling = Unmarshalling::new(&data);
+        let tx : Transaction = unmarshalling.read_transaction();
+
+        (tx, unmarshalling.remaining())
+    });
+
+    match result
+    {
+        Ok((tx, 0)) => Some(tx),
+        _           => None
+    }
+}
+
+impl Handler
+{
+    fn getpeerinfo(&self) -> Result<Json,RpcError>
+    {
+        let mut peers : Vec<Json> = Vec::new();
+
+        for &(id, ref info) in self.node.get_peers().iter()
+        {
+            let mut fields : Vec<(&str,Json)>;
+
+            fields = vec![("id",                      id.to_json()),
+                          ("addr",                    info.addr.to_string().to_json()),
+                          ("inbound",                 info.inbound.to_json()),
+                          ("conntime",                info.connected.sec.to_json()),
+                          ("bytessent",               info.bytes_sent.to_json()),
+                          ("bytesrecv",               info.bytes_recv.to_json()),
+                          ("transport_protocol_type",
+                           (if info.v2_transport { "v2" } else { "v1" }).to_string().to_json())];
+
+            match info.user_agent
+            {
+                Some(ref user_agent) => fields.push(("subver",user_agent.to_json())),
+                None                 => ()
+            }
+
+            match info.protocol_version
+            {
+                Some(version) => fields.push(("version",version.to_json())),
+                None          => ()
+            }
+
+            match info.services
+            {
+                Some(services) => fields.push(("services",format!("{:016x}",services).to_json())),
+                None           => ()
+            }
+
+            match info.start_height
+            {
+                Some(height) => fields.push(("startingheight",height.to_json())),
+                None         => ()
+            }
+
+            match info.lag
+            {
+                Some(lag) =>
+                    fields.push(("pingtime",((lag.num_milliseconds() as f64)/1000.0).to_json())),
+                None      => ()
+            }
+
+            peers.push(object(fields));
+        }
+
+        Ok(peers.to_json())
+    }
+
+    fn getnetworkinfo(&self) -> Result<Json,RpcError>
+    {
+        let peers : Vec<(uint,::peer::PeerInfo)> = self.node.get_peers();
+        let inbound : uint = peers.iter().filter(|&&(_, ref info)| info.inbound).count();
+        let services : ::config::Services = self.node.get_config().get_services();
+        let mut local : Vec<Json> = Vec::new();
+
+        match self.node.get_local_address()
+        {
+            Some(addr) =>
+            {
+                let address : String = match addr.get_onion()
+                {
+                    Some(onion) => onion,
+                    None        => match addr.to_netaddr().and_then(|netaddr| netaddr.addr)
+                    {
+                        Some(socketaddr) => socketaddr.ip.to_string(),
+                        None             => format!("{}",addr)
+                    }
+                };
+
+                local.push(object(vec![("address", address.to_json()),
+                                       ("port",    addr.port.to_json())]));
+            },
+            None       => ()
+        }
+
+        Ok(object(vec![("version",         version_number().to_json()),
+                       ("subversion",      ::config::name_version_bip0014().to_json()),
+                       ("protocolversion", ::config::PROTOCOL_VERSION.to_json()),
+                       ("localservices",   format!("{:016x}",services).to_json()),
+                       ("localrelay",      true.to_json()),
+                       ("networkactive",   true.to_json()),
+                       ("connections",     peers.len().to_json()),
+                       ("connections_in",  inbound.to_json()),
+                       ("connections_out", (peers.len()-inbound).to_json()),
+                       ("localaddresses",  local.to_json()),
+                       ("warnings",        "".to_string().to_json())]))
+    }
+
+    /* We have no tried table */
+    fn getaddrmaninfo(&self) -> Result<Json,RpcError>
+    {
+        let buckets : Vec<uint> = self.node.get_bucket_sizes();
+        let sizes : Vec<uint> = self.node.get_network_sizes();
+        let count : uint = buckets.iter().fold(0,|sum, b| sum+*b);
+        let network = |new : uint| object(vec![("new",   new.to_json()),
+                                                ("tried", 0u.to_json()),
+                                                ("total", new.to_json())]);
+
+        Ok(object(vec![("ipv4",         network(sizes[NetworkId::NetIPv4 as uint])),
+                       ("ipv6",         network(sizes[NetworkId::NetIPv6 as uint])),
+                       ("onion",        network(sizes[NetworkId::NetTorV3 as uint])),
+                       ("i2p",          network(sizes[NetworkId::NetI2P as uint])),
+                       ("cjdns",        network(sizes[NetworkId::NetCJDNS as uint])),
+                       ("all_networks", network(count)),
+                       ("buckets",      buckets.to_json())]))
+    }
+
+    fn getblockchaininfo(&self) -> Result<Json,RpcError>
+    {
+        let info : ChainInfo = self.node.get_chain_info();
+        let chain : &str = self.node.get_config().get_params().name;
+
+        Ok(object(vec![("chain",         chain.to_string().to_json()),
+                       ("blocks",        info.height.to_json()),
+                       ("headers",       info.height.to_json()),
+                       ("bestblockhash", format!("{}",info.tip).to_json()),
+                       ("difficulty",    get_difficulty(info.bits).to_json()),
+                       ("time",          info.time.sec.to_json()),
+                       ("mediantime",    info.median_time.sec.to_json()),
+                       ("pruned",        false.to_json()),
+                       ("warnings",      "".to_string().to_json())]))
+    }
+
+    fn getrawmempool(&self, params : &[Json]) -> Result<Json,RpcError>
+    {
+        if try!(param_bool(params,0,"verbose")).unwrap_or(false)
+        {
+            return Err(RpcError::new(RPC_INVALID_PARAMETER,"verbose is not supported"));
+        }
+
+        Ok(self.node.get_mempool().iter().map(|hash| format!("{}",hash)).collect::<Vec<String>>()
+               .to_json())
+    }
+
+    /* We have no transaction index, only the mempool */
+    fn getrawtransaction(&self, params : &[Json]) -> Result<Json,RpcError>
+    {
+        let hash : Hash = try!(param_hash(params,0,"txid"));
+        let verbose : bool = try!(param_bool(params,1,"verbose")).unwrap_or(false);
+        let tx : Transaction;
+        let hex : String;
+
+        tx = match self.node.get_mempool_tx(hash)
+        {
+            Some(tx) => tx,
+            None     => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY,
+                                                 "No such mempool transaction"))
+        };
+
+        hex = ::crypto::to_hexstr(tx.serialize().as_slice());
+
+        if !verbose
+        {
+            return Ok(hex.to_json());
+        }
+
+        Ok(object(vec![("txid",    format!("{}",tx.get_hash()).to_json()),
+                       ("hash",    format!("{}",tx.get_witness_hash()).to_json()),
+                       ("size",    tx.get_size().to_json()),
+                       ("version", tx.get_version().to_json()),
+                       ("hex",     hex.to_json())]))
+    }
+
+    /* Goes through the mempool like the txs of our peers, so it is relayed */
+    fn sendrawtransaction(&self, params : &[Json]) -> Result<Json,RpcError>
+    {
+        let data : Vec<u8>;
+        let tx : Transaction;
+        let hash : Hash;
+
+        data = match ::crypto::from_hexstr(try!(param_str(params,0,"hexstring")))
+        {
+            Some(data) => data,
+            None       => return Err(RpcError::new(RPC_DESERIALIZATION_ERROR,"TX decode failed"))
+        };
+
+        tx = match decode_transaction(data)
+        {
+            Some(tx) => tx,
+            None     => return Err(RpcError::new(RPC_DESERIALIZATION_ERROR,"TX decode failed"))
+        };
+
+        hash = tx.get_hash();
+
+        match self.node.add_tx(self.client,tx)
+        {
+            ChainManagerReply::ChainMngTxAccepted =>
+                Ok(format!("{}",hash).to_json()),
+            ChainManagerReply::ChainMngTxRejected(MempoolError::MempoolDuplicate) =>
+                Ok(format!("{}",hash).to_json()),
+            ChainManagerReply::ChainMngTxRejected(err) =>
+                Err(RpcError::new(RPC_VERIFY_REJECTED,err.get_reason())),
+            ChainManagerReply::ChainMngTxOrphan(_) =>
+                Err(RpcError::new(RPC_VERIFY_ERROR,"bad-txns-inputs-missingorspent")),
+            _ =>
+                unreachable!()
+        }
+    }
+
+    /* We do not keep a list of nodes to reconnect to, so "add" is "onetry" */
+    fn addnode(&self, params : &[Json]) -> Result<Json,RpcError>
+    {
+        let default_port : u16 = self.node.get_config().get_params().default_port;
+        let address : NetAddrV2;
+
+        address = match parse_node_netaddr(try!(param_str(params,0,"node")),default_port)
+        {
+            Some(address) => address,
+            None          => return Err(RpcError::new(RPC_INVALID_PARAMETER,"Invalid node address"))
+        };
+
+        match try!(param_str(params,1,"command"))
+        {
+            "add" | "onetry" =>
+            {
+                if !self.node.connect_addr(&address)
+                {
+                    return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                             "Onion nodes can only be reached through a proxy"));
+                }
+            },
+            "remove"         =>
+                return Err(RpcError::new(RPC_CLIENT_NODE_NOT_ADDED,
+                                         "Error: Node could not be removed. It has not been \
+                                          added previously.")),
+            _                =>
+                return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                         "command must be one of add, remove and onetry"))
+        }
+
+        Ok(().to_json())
+    }
+
+    fn disconnectnode(&self, params : &[Json]) -> Result<Json,RpcError>
+    {
+        let default_port : u16 = self.node.get_config().get_params().default_port;
+        let address : Option<SocketAddr>;
+
+        address = match (param(params,0),try!(param_i64(params,1,"nodeid")))
+        {
+            (Some(_),None) => parse_node_address(try!(param_str(params,0,"address")),default_port),
+            (None,Some(id)) =>
+                self.node.get_peers().iter().find(|&&(i, _)| i as i64 == id)
+                                            .map(|&(_, ref info)| info.addr),
+            _              =>
+                return Err(RpcError::new(RPC_INVALID_PARAMS,
+                                         "Only one of address and nodeid should be provided."))
+        };
+
+        match address
+        {
+            Some(address) if self.node.disconnect_peer(address) => Ok(().to_json()),
+            _ => Err(RpcError::new(RPC_CLIENT_NODE_NOT_CONNECTED,
+                                   "Node not found in connected nodes"))
+        }
+    }
+
+    fn setban(&self, params : &[Json]) -> Result<Json,RpcError>
+    {
+        let ip : IpAddr;
+        let bantime : i64;
+        let absolute : bool;
+        let until : Timespec;
+
+        ip = match parse_ban_address(try!(param_str(params,0,"subnet")))
+        {
+            Some(ip) => ip,
+            None     => return Err(RpcError::new(RPC_CLIENT_INVALID_IP_OR_SUBNET,
+                                                 "Error: Invalid IP/Subnet"))
+        };
+
+        match try!(param_str(params,1,"command"))
+        {
+            "add"    => (),
+            "remove" =>
+            {
+                if !self.node.unban(ip)
+                {
+                    return Err(RpcError::new(RPC_CLIENT_INVALID_IP_OR_SUBNET,
+                                             "Error: Unban failed. Requested address/subnet \
+                                              was not previously manually banned."));
+                }
+
+                return Ok(().to_json());
+            },
+            _        =>
+                return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                         "command must be one of add and remove"))
+        }
+
+        if self.node.is_banned(ip)
+        {
+            return Err(RpcError::new(RPC_CLIENT_NODE_ALREADY_ADDED,
+                                     "Error: IP/Subnet already banned"));
+        }
+
+        bantime = match try!(param_i64(params,2,"bantime"))
+        {
+            Some(bantime) if bantime > 0 => bantime,
+            _                            => DEFAULT_BAN_TIME_S
+        };
+        absolute = try!(param_bool(params,3,"absolute")).unwrap_or(false);
+
+        until = if absolute { Timespec::new(bantime,0) }
+                else { time::now_utc().to_timespec()+Duration::seconds(bantime) };
+
+        self.node.ban(ip,until);
+
+        Ok(().to_json())
+    }
+
+    /* Categories to enable and to disable, like the reference implementation.
+     * We also take the level and the peers to log.  Nothing changes unless
+     * all of them are valid.
+     */
+    fn logging(&self, params : &[Json]) -> Result<Json,RpcError>
+    {
+        let logger : &NodeLogger = self.node.get_logger();
+        let mut categories : u64 = logger.get_categories();
+        let mut level : LogLevel = logger.get_level();
+        let mut peers : Vec<IpAddr> = logger.get_peers();
+
+        for name in try!(param_strs(params,0,"include")).iter()
+        {
+            match ::logger::parse_categories(name.as_slice())
+            {
+                Some(flags) => categories |= flags,
+                None        => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                                        format!("unknown logging category {}",
+                                                                name).as_slice()))
+            }
+        }
+
+        for name in try!(param_strs(params,1,"exclude")).iter()
+        {
+            match ::logger::parse_categories(name.as_slice())
+            {
+                Some(flags) => categories &= !flags,
+                None        => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                                        format!("unknown logging category {}",
+                                                                name).as_slice()))
+            }
+        }
+
+        match param(params,2)
+        {
+            Some(_) => match LogLevel::from_name(try!(param_str(params,2,"level")))
+            {
+                Some(name) => level = name,
+                None       => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                                       "unknown logging level"))
+            },
+            None    => ()
+        }
+
+        match param(params,3)
+        {
+            Some(_) =>
+            {
+                peers = Vec::new();
+
+                for peer in try!(param_strs(params,3,"peers")).iter()
+                {
+                    match from_str::<IpAddr>(peer.as_slice())
+                    {
+                        Some(ip) => peers.push(ip),
+                        None     => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                                             "peers must be IP addresses"))
+                    }
+                }
+            },
+            None    => ()
+        }
+
+        logger.set_level(level);
+        logger.set_peers(peers);
+        logger.set_categories(categories);
+
+        Ok(log_categories_json(categories))
+    }
+
+    fn stop(&mut self) -> Result<Json,RpcError>
+    {
+        self.stopping = true;
+
+        Ok(format!("{} stopping",::config::NAME).to_json())
+    }
+
+    fn call(&mut self, method : &str, params : &[Json]) -> Result<Json,RpcError>
+    {
+        match method
+        {
+            "getpeerinfo"        => self.getpeerinfo(),
+            "getnetworkinfo"     => self.getnetworkinfo(),
+            "getaddrmaninfo"     => self.getaddrmaninfo(),
+            "getblockchaininfo"  => self.getblockchaininfo(),
+            "getrawmempool"      => self.getrawmempool(params),
+            "getrawtransaction"  => self.getrawtransaction(params),
+            "sendrawtransaction" => self.sendrawtransaction(params),
+            "addnode"            => self.addnode(params),
+            "disconnectnode"     => self.disconnectnode(params),
+            "setban"             => self.setban(params),
+            "logging"            => self.logging(params),
+            "stop"               => self.stop(),
+            _                    => Err(RpcError::new(RPC_METHOD_NOT_FOUND,"Method not found"))
+        }
+    }
+
+    /* The reply object and the error, if any */
+    fn handle_call(&mut self, request : &Json) -> (Json, Option<RpcError>)
+    {
+        let id : Json = request.find("id").map(|id| id.clone()).unwrap_or(().to_json());
+        let result : Result<Json,RpcError>;
+        let empty : Vec<Json> = Vec::new();
+
+        result = match (request.find("method").and_then(|m| m.as_string()),
+                        request.find("params"))
+        {
+            (None,_)                        =>
+                Err(RpcError::new(RPC_INVALID_REQUEST,"Method must be a string")),
+            (Some(method),None)             =>
+                self.call(method,empty.as_slice()),
+            (Some(method),Some(params))     => match (params.as_list(),params.as_object())
+            {
+                (Some(params),_)         => self.call(method,params.as_slice()),
+                (_,Some(params))         => match named_params(method,params)
+                {
+                    Ok(params) => self.call(method,params.as_slice()),
+                    Err(err)   => Err(err)
+                },
+                _ if params.is_null()    => self.call(method,empty.as_slice()),
+                _                        => Err(RpcError::new(RPC_INVALID_REQUEST,
+                                                              "Params must be an array or \
+                                                               an object"))
+            }
+        };
+
+        match result
+        {
+            Ok(result) =>
+                (object(vec![("result", result),
+                             ("error",  ().to_json()),
+                             ("id",     id)]), None),
+            Err(err)   =>
+                (object(vec![("result", ().to_json()),
+                             ("error",  err.to_json()),
+                             ("id",     id)]), Some(err))
+        }
+    }
+
+    /* A request object or a batch of them in an array */
+    fn handle_jsonrpc(&mut self, body : &[u8]) -> HttpResponse
+    {
+        let request : Json;
+
+        request = match ::std::str::from_utf8(body).and_then(|body| json::from_str(body).ok())
+        {
+            Some(request) => request,
+            None          =>
+            {
+                let err : RpcError = RpcError::new(RPC_PARSE_ERROR,"Parse error");
+
+                return HttpResponse::json(err.get_http_status(),
+                                          &object(vec![("result", ().to_json()),
+                                                       ("error",  err.to_json()),
+                                                       ("id",     ().to_json())]));
+            }
+        };
+
+        match request.as_list()
+        {
+            Some(requests) =>
+            {
+                let mut replies : Vec<Json> = Vec::new();
+
+                for request in requests.iter()
+                {
+                    let (reply, _) = self.handle_call(request);
+
+                    replies.push(reply);
+                }
+
+                return HttpResponse::json(200,&replies.to_json());
+            },
+            None           => ()
+        }
+
+        match self.handle_call(&request)
+        {
+            (reply, None)      => HttpResponse::json(200,&reply),
+            (reply, Some(err)) => HttpResponse::json(err.get_http_status(),&reply)
+        }
+    }
+
+    fn handle(&mut self, request : &HttpRequest) -> HttpResponse
+    {
+        if request.path.as_slice() != "/"
+        {
+            return HttpResponse::text(404,"Not found");
+        }
+
+        if request.method.as_slice() != "POST"
+        {
+            return HttpResponse::text(405,"JSON-RPC server handles only POST requests");
+        }
+
+        self.handle_jsonrpc(request.body.as_slice())
+    }
+}
+
+fn serve(stream      : TcpStream,
+         client      : SocketAddr,
+         node        : Node,
+         credentials : &(String,String),
+         stop        : Sender<()>)
+{
+    let mut stream : BufferedStream<TcpStream> = BufferedStream::new(stream);
+    let &(ref user, ref password) = credentials;
+    let mut handler : Handler = Handler { node: node, client: client, stopping: false };
+
+    loop
+    {
+        let request : HttpRequest = match read_request(&mut stream)
+        {
+            Some(request) => request,
+            None          => return
+        };
+        let keepalive : bool = request.keep_alive();
+        let response : HttpResponse;
+
+        /* The REST interface needs no authentication */
+        if handler.node.get_config().rest && request.path.as_slice().starts_with(::rest::PREFIX)
+        {
+            response = ::rest::handle(&handler.node,&request);
+        }
+        else if is_authorized(&request,user.as_slice(),password.as_slice())
+        {
+            response = handler.handle(&request);
+        }
+        else
+        {
+            sleep(Duration::milliseconds(AUTH_FAILURE_DELAY_MS));
+            response = HttpResponse::text(401,"Unauthorized");
+        }
+
+        if write_response(&mut stream,&response,keepalive && !handler.stopping).is_err()
+        {
+            return;
+        }
+
+        if handler.stopping
+        {
+            stop.send(());
+            return;
+        }
+
+        if !keepalive
+        {
+            return;
+        }
+    }
+}
+
+#[allow(unused_must_use)]
+fn reject_client(stream : TcpStream)
+{
+    let mut stream : BufferedStream<TcpStream> = BufferedStream::new(stream);
+
+    write_response(&mut stream,&HttpResponse::text(503,"Too many clients"),false);
+}
+
+fn spawn_thread_accept_clients(acceptor    : TcpAcceptor,
+                               node        : Node,
+                               credentials : (String,String),
+                               stop        : Sender<()>)
+{
+    spawn(proc() {
+        let mut acceptor : TcpAcceptor = acceptor;
+        let clients : Arc<AtomicUint> = Arc::new(AtomicUint::new(0));
+
+        node.get_logger().install();
+
+        for maybestream in acceptor.incoming()
+        {
+            let mut stream : TcpStream = match maybestream
+            {
+                Ok(stream) => stream,
+                Err(_)     => continue
+            };
+            let client : SocketAddr = match stream.peer_name()
+            {
+                Ok(client) => client,
+                Err(_)     => continue
+            };
+            let node : Node = node.handle();
+            let credentials : (String,String) = credentials.clone();
+            let stop : Sender<()> = stop.clone();
+            let clients : Arc<AtomicUint> = clients.clone();
+
+            if clients.load(SeqCst) >= MAX_CLIENTS
+            {
+                reject_client(stream);
+                continue;
+            }
+
+            clients.fetch_add(1,SeqCst);
+
+            stream.set_read_timeout(Some(READ_TIMEOUT_MS));
+
+            spawn(proc() {
+                node.get_logger().install();
+
+                serve(stream,client,node,&credentials,stop);
+
+                clients.fetch_sub(1,SeqCst);
+            });
+        }
+    });
+}
+
+/* Listens on the configured address, and writes the cookie file if there is
+ * no password.  The stop method sends to stop.
+ */
+pub fn start(node : &Node, stop : Sender<()>) -> IoResult<SocketAddr>
+{
+    let config : &::config::Config = node.get_config();
+    let address : SocketAddr = SocketAddr { ip: config.rpc_bind, port: config.get_rpc_port() };
+    let mut acceptor : TcpAcceptor = try!(TcpListener::bind(address).listen());
+    let bound : SocketAddr = try!(acceptor.socket_name());
+    let credentials : (String,String) = try!(get_credentials(config));
+
+    spawn_thread_accept_clients(acceptor,node.handle(),credentials,stop);
+
+    Ok(bound)
+}
@@ -0,0 +1,965 @@
+use std::rand::Rng;
+
+/* Just enough of secp256k1 for the key exchange of the v2 transport (BIP324)
+ * and for checking the signatures of scripts: field and group arithmetic, x
+ * only ECDH, the ElligatorSwift encoding and ECDSA verification.
+ *
+ * What handles secrets runs in constant time, as far as the compiler keeps
+ * it so: the field operations reduce without branching on the values, and
+ * the multiplication of a point by a scalar does the same additions whatever
+ * the scalar is, with formulas complete for every pair of points, keeping
+ * each sum or not with a mask.  The rest (square roots, the ElligatorSwift
+ * mapping, the conversion of the result to affine coordinates and ECDSA
+ * verification) only branches on public data.
+ */
+
+pub const SECRET_SIZE : uint = 32;
+pub const ELLSWIFT_SIZE : uint = 64;
+
+/* p = 2^256 - 2^32 - 977 */
+const P : [u32, ..8] = [0xfffffc2f, 0xfffffffe, 0xffffffff, 0xffffffff,
+                        0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff];
+const P_MINUS_2 : [u32, ..8] = [0xfffffc2d, 0xfffffffe, 0xffffffff, 0xffffffff,
+                                0xffffffff, 0xffffffff, 0xffffffff, 0xffffffff];
+const P_PLUS_1_DIV_4 : [u32, ..8] = [0xbfffff0c, 0xffffffff, 0xffffffff, 0xffffffff,
+                                     0xffffffff, 0xffffffff, 0xffffffff, 0x3fffffff];
+
+/* Order of the group, big endian */
+const N : [u8, ..32] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+                        0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b,
+                        0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41];
+
+/* n, least significant limb first */
+const N_LIMBS : [u32, ..8] = [0xd0364141, 0xbfd25e8c, 0xaf48a03b, 0xbaaedce6,
+                              0xfffffffe, 0xffffffff, 0xffffffff, 0xffffffff];
+const N_MINUS_2 : [u32, ..8] = [0xd036413f, 0xbfd25e8c, 0xaf48a03b, 0xbaaedce6,
+                                0xfffffffe, 0xffffffff, 0xffffffff, 0xffffffff];
+
+const G_X : [u32, ..8] = [0x16f81798, 0x59f2815b, 0x2dce28d9, 0x029bfcdb,
+                          0xce870b07, 0x55a06295, 0xf9dcbbac, 0x79be667e];
+const G_Y : [u32, ..8] = [0xfb10d4b8, 0x9c47d08f, 0xa6855419, 0xfd17b448,
+                          0x0e1108a8, 0x5da4fbfc, 0x26a3c465, 0x483ada77];
+
+/* sqrt(-3), the root returned by FieldElem::sqrt */
+const SQRT_MINUS_3 : [u32, ..8] = [0x1cd5f852, 0x7d8d27ae, 0xda14ecd4, 0xc61f6d15,
+                                   0xa797962c, 0x233770c2, 0x3507f1df, 0x0a2d2ba9];
+
+fn geq(limbs : &[u32, ..8], modulus : &[u32, ..8]) -> bool
+{
+    for i in range(0u,8).rev()
+    {
+        if limbs[i] != modulus[i]
+        {
+            return limbs[i] > modulus[i];
+        }
+    }
+
+    true
+}
+
+/* Subtract the modulus, modulo 2^256 */
+fn sub_modulus(limbs : &mut [u32, ..8], modulus : &[u32, ..8])
+{
+    let mut borrow : u64 = 0;
+
+    for i in range(0u,8)
+    {
+        let v : u64 = (1u64 << 32)+(limbs[i] as u64)-(modulus[i] as u64)-borrow;
+
+        limbs[i] = v as u32;
+        borrow = 1-(v >> 32);
+    }
+}
+
+/* Subtract the modulus if the value, with carry as its bit 256, is not below
+ * it.  Without branches, for the arithmetic on secrets.
+ */
+fn reduce_once(limbs : &mut [u32, ..8], carry : u32, modulus : &[u32, ..8])
+{
+    let mut diff : [u32, ..8] = [0u32, ..8];
+    let mut borrow : u64 = 0;
+    let mask : u32;
+
+    for i in range(0u,8)
+    {
+        let v : u64 = (1u64 << 32)+(limbs[i] as u64)-(modulus[i] as u64)-borrow;
+
+        diff[i] = v as u32;
+        borrow = 1-(v >> 32);
+    }
+
+    /* All ones if the carry is set or if nothing was borrowed */
+    mask = 0u32-((carry | (1-borrow as u32)) & 1);
+
+    for i in range(0u,8)
+    {
+        limbs[i] = (diff[i] & mask) | (limbs[i] & !mask);
+    }
+}
+
+/* From 32 bytes big endian, not reduced */
+fn limbs_from_bytes(data : &[u8]) -> [u32, ..8]
+{
+    let mut limbs : [u32, ..8] = [0u32, ..8];
+
+    assert!(data.len() == 32);
+
+    for i in range(0u,32)
+    {
+        limbs[(31-i)/4] |= (data[i] as u32) << 8*((31-i)%4);
+    }
+
+    limbs
+}
+
+fn limbs_to_bytes(limbs : &[u32, ..8]) -> [u8, ..32]
+{
+    let mut data : [u8, ..32] = [0u8, ..32];
+
+    for i in range(0u,32)
+    {
+        data[i] = (limbs[(31-i)/4] >> 8*((31-i)%4)) as u8;
+    }
+
+    data
+}
+
+/* The full 512 bits product */
+fn mul_wide(a : &[u32, ..8], b : &[u32, ..8]) -> [u32, ..16]
+{
+    let mut product : [u32, ..16] = [0u32, ..16];
+
+    for i in range(0u,8)
+    {
+        let mut carry : u64 = 0;
+
+        for j in range(0u,8)
+        {
+            let v : u64 = (a[i] as u64)*(b[j] as u64)+(product[i+j] as u64)+carry;
+
+            product[i+j] = v as u32;
+            carry = v >> 32;
+        }
+
+        product[i+8] = carry as u32;
+    }
+
+    product
+}
+
+/* An element of the field of integers modulo p */
+#[deriving(Clone, PartialEq)]
+pub struct FieldElem
+{
+    limbs : [u32, ..8] /* Least significant limb first, always below p */
+}
+
+impl FieldElem
+{
+    pub fn from_u32(v : u32) -> FieldElem
+    {
+        let mut limbs : [u32, ..8] = [0u32, ..8];
+
+        limbs[0] = v;
+
+        FieldElem { limbs: limbs }
+    }
+
+    fn from_limbs(limbs : [u32, ..8]) -> FieldElem
+    {
+        FieldElem { limbs: limbs }
+    }
+
+    /* From 32 bytes big endian, reduced modulo p */
+    pub fn from_bytes(data : &[u8]) -> FieldElem
+    {
+        let mut r = FieldElem::from_limbs(limbs_from_bytes(data));
+
+        if geq(&r.limbs,&P)
+        {
+            sub_modulus(&mut r.limbs,&P);
+        }
+
+        r
+    }
+
+    pub fn to_bytes(&self) -> [u8, ..32]
+    {
+        limbs_to_bytes(&self.limbs)
+    }
+
+    pub fn is_zero(&self) -> bool
+    {
+        self.limbs.iter().all(|l| *l == 0)
+    }
+
+    pub fn is_odd(&self) -> bool
+    {
+        self.limbs[0] & 1 == 1
+    }
+
+    pub fn add(&self, other : &FieldElem) -> FieldElem
+    {
+        let mut r = FieldElem::from_u32(0);
+        let mut carry : u64 = 0;
+
+        for i in range(0u,8)
+        {
+            let v : u64 = (self.limbs[i] as u64)+(other.limbs[i] as u64)+carry;
+
+            r.limbs[i] = v as u32;
+            carry = v >> 32;
+        }
+
+        reduce_once(&mut r.limbs,carry as u32,&P);
+
+        r
+    }
+
+    /* p-a, then 0 rather than p for a = 0 */
+    pub fn neg(&self) -> FieldElem
+    {
+        let mut r = FieldElem::from_limbs(P);
+        let mut borrow : u64 = 0;
+
+        for i in range(0u,8)
+        {
+            let v : u64 = (1u64 << 32)+(P[i] as u64)-(self.limbs[i] as u64)-borrow;
+
+            r.limbs[i] = v as u32;
+            borrow = 1-(v >> 32);
+        }
+
+        reduce_once(&mut r.limbs,0,&P);
+
+        r
+    }
+
+    /* a if the mask is all ones, b if it is zero */
+    fn select(mask : u32, a : &FieldElem, b : &FieldElem) -> FieldElem
+    {
+        let mut r = FieldElem::from_u32(0);
+
+        for i in range(0u,8)
+        {
+            r.limbs[i] = (a.limbs[i] & mask) | (b.limbs[i] & !mask);
+        }
+
+        r
+    }
+
+    pub fn sub(&self, other : &FieldElem) -> FieldElem
+    {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other : &FieldElem) -> FieldElem
+    {
+        FieldElem::reduce(&mul_wide(&self.limbs,&other.limbs))
+    }
+
+    /* Since 2^256 = 2^32 + 977 (mod p), the high half is folded into the
+     * low one until it fits in 256 bits.
+     */
+    fn reduce(product : &[u32, ..16]) -> FieldElem
+    {
+        let mut r = FieldElem::from_u32(0);
+        let mut carry : u64 = 0;
+        let top : u64;
+        let extra : u64;
+
+        for i in range(0u,8)
+        {
+            let v : u64 = (product[i] as u64)+(product[8+i] as u64)*977+carry
+                        + if i > 0 { product[8+i-1] as u64 } else { 0 };
+
+            r.limbs[i] = v as u32;
+            carry = v >> 32;
+        }
+
+        top = (product[15] as u64)+carry;
+        carry = 0;
+
+        for i in range(0u,8)
+        {
+            let v : u64 = (r.limbs[i] as u64)+carry
+                        + match i { 0 => top*977, 1 => top, _ => 0 };
+
+            r.limbs[i] = v as u32;
+            carry = v >> 32;
+        }
+
+        /* At most one more 2^256, and what is left is small.  It is folded
+         * whether there is one or not, not to branch on the value.
+         */
+        extra = carry;
+        carry = 0;
+
+        for i in range(0u,8)
+        {
+            let v : u64 = (r.limbs[i] as u64)+carry
+                        + match i { 0 => extra*977, 1 => extra, _ => 0 };
+
+            r.limbs[i] = v as u32;
+            carry = v >> 32;
+        }
+
+        reduce_once(&mut r.limbs,0,&P);
+
+        r
+    }
+
+    pub fn mul_u32(&self, v : u32) -> FieldElem
+    {
+        self.mul(&FieldElem::from_u32(v))
+    }
+
+    pub fn sqr(&self) -> FieldElem
+    {
+        self.mul(self)
+    }
+
+    fn pow(&self, exp : &[u32, ..8]) -> FieldElem
+    {
+        let mut r = FieldElem::from_u32(1);
+
+        for i in range(0u,256).rev()
+        {
+            r = r.sqr();
+
+            if (exp[i/32] >> (i%32)) & 1 == 1
+            {
+                r = r.mul(self);
+            }
+        }
+
+        r
+    }
+
+    /* The inverse of zero is zero */
+    pub fn inv(&self) -> FieldElem
+    {
+        self.pow(&P_MINUS_2)
+    }
+
+    /* Since p = 3 (mod 4), a^((p+1)/4) is a square root of a if it has one */
+    pub fn sqrt(&self) -> Option<FieldElem>
+    {
+        let r : FieldElem = self.pow(&P_PLUS_1_DIV_4);
+
+        if r.sqr() == *self { Some(r) } else { None }
+    }
+}
+
+/* An integer modulo n, the order of the group */
+#[deriving(Clone, PartialEq)]
+struct Scalar
+{
+    limbs : [u32, ..8] /* Least significant limb first, always below n */
+}
+
+impl Scalar
+{
+    /* From 32 bytes big endian, None if not below n */
+    fn from_bytes(data : &[u8]) -> Option<Scalar>
+    {
+        let limbs : [u32, ..8] = limbs_from_bytes(data);
+
+        if geq(&limbs,&N_LIMBS) { None } else { Some(Scalar { limbs: limbs }) }
+    }
+
+    /* From 32 bytes big endian, reduced modulo n as for message hashes and
+     * x coordinates.  Since 2^256 < 2n, one subtraction is enough.
+     */
+    fn from_bytes_reduced(data : &[u8]) -> Scalar
+    {
+        let mut limbs : [u32, ..8] = limbs_from_bytes(data);
+
+        if geq(&limbs,&N_LIMBS)
+        {
+            sub_modulus(&mut limbs,&N_LIMBS);
+        }
+
+        Scalar { limbs: limbs }
+    }
+
+    fn to_bytes(&self) -> [u8, ..32]
+    {
+        limbs_to_bytes(&self.limbs)
+    }
+
+    fn is_zero(&self) -> bool
+    {
+        self.limbs.iter().all(|l| *l == 0)
+    }
+
+    #[cfg(test)]
+    fn add(&self, other : &Scalar) -> Scalar
+    {
+        let mut limbs : [u32, ..8] = [0u32, ..8];
+        let mut carry : u64 = 0;
+
+        for i in range(0u,8)
+        {
+            let v : u64 = (self.limbs[i] as u64)+(other.limbs[i] as u64)+carry;
+
+            limbs[i] = v as u32;
+            carry = v >> 32;
+        }
+
+        if carry != 0 || geq(&limbs,&N_LIMBS)
+        {
+            sub_modulus(&mut limbs,&N_LIMBS);
+        }
+
+        Scalar { limbs: limbs }
+    }
+
+    fn mul(&self, other : &Scalar) -> Scalar
+    {
+        Scalar::reduce(&mul_wide(&self.limbs,&other.limbs))
+    }
+
+    /* n has no convenient form like p, so this is plain shift and subtract,
+     * one bit of the product at a time.
+     */
+    fn reduce(product : &[u32, ..16]) -> Scalar
+    {
+        let mut limbs : [u32, ..8] = [0u32, ..8];
+
+        for i in range(0u,512).rev()
+        {
+            let mut carry : u32 = (product[i/32] >> (i%32)) & 1;
+
+            for j in range(0u,8)
+            {
+                let v : u32 = limbs[j];
+
+                limbs[j] = (v << 1) | carry;
+                carry = v >> 31;
+            }
+
+            /* Below 2n, so one subtraction is enough, the carry being the
+             * 2^256 the subtraction wraps around.
+             */
+            if carry != 0 || geq(&limbs,&N_LIMBS)
+            {
+                sub_modulus(&mut limbs,&N_LIMBS);
+            }
+        }
+
+        Scalar { limbs: limbs }
+    }
+
+    /* By Fermat's little theorem, the inverse of zero is zero */
+    fn inv(&self) -> Scalar
+    {
+        let mut r = Scalar { limbs: [1u32, 0, 0, 0, 0, 0, 0, 0] };
+
+        for i in range(0u,256).rev()
+        {
+            r = r.mul(&r);
+
+            if (N_MINUS_2[i/32] >> (i%32)) & 1 == 1
+            {
+                r = r.mul(self);
+            }
+        }
+
+        r
+    }
+}
+
+/* x^3 + 7 */
+fn curve_rhs(x : &FieldElem) -> FieldElem
+{
+    x.sqr().mul(x).add(&FieldElem::from_u32(7))
+}
+
+pub fn is_valid_x(x : &FieldElem) -> bool
+{
+    curve_rhs(x).sqrt().is_some()
+}
+
+/* A point of the curve in affine coordinates, never the point at infinity */
+#[deriving(Clone)]
+pub struct Point
+{
+    x : FieldElem,
+    y : FieldElem
+}
+
+/* (x/z, y/z), the point at infinity is (0, 1, 0) */
+struct ProjectivePoint
+{
+    x : FieldElem,
+    y : FieldElem,
+    z : FieldElem
+}
+
+impl ProjectivePoint
+{
+    fn infinity() -> ProjectivePoint
+    {
+        ProjectivePoint
+        {
+            x: FieldElem::from_u32(0),
+            y: FieldElem::from_u32(1),
+            z: FieldElem::from_u32(0)
+        }
+    }
+
+    fn from_affine(point : &Point) -> ProjectivePoint
+    {
+        ProjectivePoint
+        {
+            x: point.x.clone(),
+            y: point.y.clone(),
+            z: FieldElem::from_u32(1)
+        }
+    }
+
+    /* The complete addition formulas of Renes, Costello and Batina
+     * ("Complete addition formulas for prime order elliptic curves",
+     * algorithm 7), right for any two points, equal or not, at infinity or
+     * not, so they never branch.
+     */
+    fn add(&self, other : &ProjectivePoint) -> ProjectivePoint
+    {
+        let t0 : FieldElem = self.x.mul(&other.x);
+        let t1 : FieldElem = self.y.mul(&other.y);
+        let t2 : FieldElem = self.z.mul(&other.z);
+        let t3 : FieldElem = self.x.add(&self.y).mul(&other.x.add(&other.y)).sub(&t0.add(&t1));
+        let t4 : FieldElem = self.y.add(&self.z).mul(&other.y.add(&other.z)).sub(&t1.add(&t2));
+        let t5 : FieldElem = self.x.add(&self.z).mul(&other.x.add(&other.z)).sub(&t0.add(&t2));
+        let t0_3 : FieldElem = t0.mul_u32(3);
+        let t2_21 : FieldElem = t2.mul_u32(21); /* 3b */
+        let t5_21 : FieldElem = t5.mul_u32(21);
+        let sum : FieldElem = t1.add(&t2_21);
+        let diff : FieldElem = t1.sub(&t2_21);
+
+        ProjectivePoint
+        {
+            x: t3.mul(&diff).sub(&t4.mul(&t5_21)),
+            y: diff.mul(&sum).add(&t5_21.mul(&t0_3)),
+            z: sum.mul(&t4).add(&t0_3.mul(&t3))
+        }
+    }
+
+    /* a if the mask is all ones, b if it is zero */
+    fn select(mask : u32, a : &ProjectivePoint, b : &ProjectivePoint) -> ProjectivePoint
+    {
+        ProjectivePoint
+        {
+            x: FieldElem::select(mask,&a.x,&b.x),
+            y: FieldElem::select(mask,&a.y,&b.y),
+            z: FieldElem::select(mask,&a.z,&b.z)
+        }
+    }
+
+    fn to_affine(&self) -> Option<Point>
+    {
+        let zinv : FieldElem;
+
+        if self.z.is_zero()
+        {
+            return None;
+        }
+
+        zinv = self.z.inv();
+
+        Some(Point
+        {
+            x: self.x.mul(&zinv),
+            y: self.y.mul(&zinv)
+        })
+    }
+}
+
+#[allow(dead_code)]
+impl Point
+{
+    pub fn generator() -> Point
+    {
+        Point
+        {
+            x: FieldElem::from_limbs(G_X),
+            y: FieldElem::from_limbs(G_Y)
+        }
+    }
+
+    /* A public key, compressed (33 bytes) or not (65 bytes).  The hybrid
+     * encoding (0x06 and 0x07) is also accepted, like the reference
+     * implementation does.
+     */
+    pub fn from_bytes(data : &[u8]) -> Option<Point>
+    {
+        let x_limbs : [u32, ..8];
+        let x : FieldElem;
+
+        if data.len() != 33 && data.len() != 65
+        {
+            return None;
+        }
+
+        x_limbs = limbs_from_bytes(data.slice(1,33));
+
+        if geq(&x_limbs,&P)
+        {
+            return None;
+        }
+
+        x = FieldElem::from_limbs(x_limbs);
+
+        match data[0]
+        {
+            0x02 | 0x03 if data.len() == 33 => Point::lift_x(&x).map(|p|
+            {
+                if p.y.is_odd() == (data[0] == 0x03) { p }
+                else { Point { x: p.x.clone(), y: p.y.neg() } }
+            }),
+            0x04 | 0x06 | 0x07 if data.len() == 65 =>
+            {
+                let y_limbs : [u32, ..8] = limbs_from_bytes(data.slice(33,65));
+                let y : FieldElem = FieldElem::from_limbs(y_limbs);
+
+                if geq(&y_limbs,&P) || y.sqr() != curve_rhs(&x) ||
+                   data[0] != 0x04 && y.is_odd() != (data[0] == 0x07)
+                {
+                    return None;
+                }
+
+                Some(Point { x: x, y: y })
+            },
+            _ => None
+        }
+    }
+
+    /* The compressed encoding of a public key */
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut data : Vec<u8> = vec![if self.y.is_odd() { 0x03 } else { 0x02 }];
+
+        data.push_all(self.x.to_bytes().as_slice());
+
+        data
+    }
+
+    /* One of the two points with this x coordinate, if any */
+    pub fn lift_x(x : &FieldElem) -> Option<Point>
+    {
+        curve_rhs(x).sqrt().map(|y| Point { x: x.clone(), y: y })
+    }
+
+    pub fn get_x(&self) -> &FieldElem
+    {
+        &self.x
+    }
+
+    pub fn get_y(&self) -> &FieldElem
+    {
+        &self.y
+    }
+
+    /* Returns None if the result is the point at infinity.  In constant
+     * time, the scalar usually being a secret.
+     */
+    pub fn mul(&self, scalar : &[u8, ..32]) -> Option<Point>
+    {
+        let point = ProjectivePoint::from_affine(self);
+        let mut r = ProjectivePoint::infinity();
+
+        for i in range(0u,32)
+        {
+            for b in range(0u,8).rev()
+            {
+                let mask : u32 = 0u32-(((scalar[i] >> b) & 1) as u32);
+                let doubled : ProjectivePoint = r.add(&r);
+
+                r = ProjectivePoint::select(mask,&doubled.add(&point),&doubled);
+            }
+        }
+
+        r.to_affine()
+    }
+}
+
+/* Secrets must be in [1, n-1] */
+pub fn is_valid_secret(secret : &[u8, ..32]) -> bool
+{
+    if secret.iter().all(|b| *b == 0)
+    {
+        return false;
+    }
+
+    for i in range(0u,32)
+    {
+        if secret[i] != N[i]
+        {
+            return secret[i] < N[i];
+        }
+    }
+
+    false
+}
+
+pub fn random_secret() -> [u8, ..32]
+{
+    let mut secret : [u8, ..32] = [0u8, ..32];
+
+    while !is_valid_secret(&secret)
+    {
+        ::crypto::rng().fill_bytes(&mut secret);
+    }
+
+    secret
+}
+
+/* Check the ECDSA signature (r, s), both 32 bytes big endian, of the hash of
+ * a message.  Both s and n-s are accepted, whatever the encoding rules of
+ * the caller are.
+ */
+pub fn ecdsa_verify(pubkey : &Point,
+                    r      : &[u8, ..32],
+                    s      : &[u8, ..32],
+                    hash   : &[u8, ..32]) -> bool
+{
+    let mut sum = ProjectivePoint::infinity();
+    let z : Scalar = Scalar::from_bytes_reduced(hash);
+    let w : Scalar;
+    let r : Scalar = match Scalar::from_bytes(r)
+    {
+        Some(r) => r,
+        None    => return false
+    };
+    let s : Scalar = match Scalar::from_bytes(s)
+    {
+        Some(s) => s,
+        None    => return false
+    };
+
+    if r.is_zero() || s.is_zero()
+    {
+        return false;
+    }
+
+    w = s.inv();
+
+    /* z/s * G + r/s * Q */
+    match Point::generator().mul(&z.mul(&w).to_bytes())
+    {
+        Some(point) => sum = sum.add(&ProjectivePoint::from_affine(&point)),
+        None        => ()
+    }
+
+    match pubkey.mul(&r.mul(&w).to_bytes())
+    {
+        Some(point) => sum = sum.add(&ProjectivePoint::from_affine(&point)),
+        None        => ()
+    }
+
+    match sum.to_affine()
+    {
+        Some(point) => Scalar::from_bytes_reduced(&point.x.to_bytes()) == r,
+        None        => false
+    }
+}
+
+/* Only the tests sign, with a random nonce */
+#[cfg(test)]
+pub fn ecdsa_sign(secret : &[u8, ..32], hash : &[u8, ..32]) -> ([u8, ..32], [u8, ..32])
+{
+    let d : Scalar = Scalar::from_bytes(secret).unwrap();
+    let z : Scalar = Scalar::from_bytes_reduced(hash);
+
+    loop
+    {
+        let nonce : [u8, ..32] = random_secret();
+        let k : Scalar = Scalar::from_bytes(&nonce).unwrap();
+        let r : Scalar;
+        let s : Scalar;
+
+        r = Scalar::from_bytes_reduced(&Point::generator().mul(&nonce).unwrap().x.to_bytes());
+        s = k.inv().mul(&z.add(&r.mul(&d)));
+
+        if !r.is_zero() && !s.is_zero()
+        {
+            return (r.to_bytes(), s.to_bytes());
+        }
+    }
+}
+
+/* XSwiftEC from BIP324: map any pair of field elements to the x coordinate of
+ * a point of the curve.
+ */
+fn xswiftec(u : &FieldElem, t : &FieldElem) -> FieldElem
+{
+    let one = FieldElem::from_u32(1);
+    let half : FieldElem = FieldElem::from_u32(2).inv();
+    let u : FieldElem = if u.is_zero() { one.clone() } else { u.clone() };
+    let mut t : FieldElem = if t.is_zero() { one.clone() } else { t.clone() };
+    let u3_7 : FieldElem = curve_rhs(&u);
+    let big_x : FieldElem;
+    let big_y : FieldElem;
+    let x_over_y : FieldElem;
+    let candidates : [FieldElem, ..3];
+
+    if u3_7.add(&t.sqr()).is_zero()
+    {
+        t = t.mul_u32(2);
+    }
+
+    big_x = u3_7.sub(&t.sqr()).mul(&t.mul_u32(2).inv());
+    big_y = big_x.add(&t).mul(&FieldElem::from_limbs(SQRT_MINUS_3).mul(&u).inv());
+    x_over_y = big_x.mul(&big_y.inv());
+
+    candidates = [u.add(&big_y.sqr().mul_u32(4)),
+                  x_over_y.neg().sub(&u).mul(&half),
+                  x_over_y.sub(&u).mul(&half)];
+
+    for x in candidates.iter()
+    {
+        if is_valid_x(x)
+        {
+            return x.clone();
+        }
+    }
+
+    /* One of the three is always on the curve */
+    unreachable!()
+}
+
+/* One of the (up to 8) inverses of xswiftec for a given u, picked by case.
+ * Returns None if that one does not exist.
+ */
+fn xswiftec_inv(x : &FieldElem, u : &FieldElem, case : uint) -> Option<FieldElem>
+{
+    let half : FieldElem = FieldElem::from_u32(2).inv();
+    let sqrt_minus_3 = FieldElem::from_limbs(SQRT_MINUS_3);
+    let u3_7 : FieldElem = curve_rhs(u);
+    let mut v : FieldElem = x.clone();
+    let s : FieldElem;
+    let w : FieldElem;
+    let mut t : FieldElem;
+
+    if case & 2 == 0
+    {
+        if is_valid_x(&x.neg().sub(u))
+        {
+            return None;
+        }
+
+        s = u3_7.neg().mul(&u.sqr().add(&u.mul(&v)).add(&v.sqr()).inv());
+    }
+    else
+    {
+        let r : FieldElem;
+
+        s = x.sub(u);
+
+        if s.is_zero()
+        {
+            return None;
+        }
+
+        r = match s.neg().mul(&u3_7.mul_u32(4).add(&s.mul_u32(3).mul(&u.sqr()))).sqrt()
+        {
+            Some(r) => r,
+            None    => return None
+        };
+
+        if case & 1 == 1 && r.is_zero()
+        {
+            return None;
+        }
+
+        v = r.mul(&s.inv()).sub(u).mul(&half);
+    }
+
+    w = match s.sqrt()
+    {
+        Some(w) => w,
+        None    => return None
+    };
+
+    t = match case & 5
+    {
+        0 | 4 => FieldElem::from_u32(1).sub(&sqrt_minus_3),
+        _     => FieldElem::from_u32(1).add(&sqrt_minus_3)
+    };
+
+    t = w.mul(&t.mul(&half).mul(u).add(&v));
+
+    if case & 5 == 0 || case & 5 == 5
+    {
+        t = t.neg();
+    }
+
+    Some(t)
+}
+
+/* ElligatorSwift encoding of the public key of the secret: u || t, 64 bytes
+ * indistinguishable from random ones, with xswiftec(u,t) the x coordinate of
+ * the key.
+ */
+pub fn ellswift_create(secret : &[u8, ..32]) -> [u8, ..64]
+{
+    let mut rng = ::crypto::rng();
+    let mut encoding : [u8, ..64] = [0u8, ..64];
+    let x : FieldElem = Point::generator().mul(secret).unwrap().x;
+
+    loop
+    {
+        let mut bytes : [u8, ..32] = [0u8, ..32];
+        let u : FieldElem;
+        let case : uint = rng.gen::<uint>()%8;
+
+        rng.fill_bytes(&mut bytes);
+
+        u = FieldElem::from_bytes(&bytes);
+
+        if u.is_zero()
+        {
+            continue;
+        }
+
+        match xswiftec_inv(&x,&u,case)
+        {
+            Some(ref t) if xswiftec(&u,t) == x =>
+            {
+                let t_bytes : [u8, ..32] = t.to_bytes();
+
+                for i in range(0u,32)
+                {
+                    encoding[i] = bytes[i];
+                    encoding[32+i] = t_bytes[i];
+                }
+
+                return encoding;
+            },
+            _ => ()
+        }
+    }
+}
+
+/* The x coordinate of the key of an ElligatorSwift encoding, any 64 bytes
+ * being one.
+ */
+pub fn ellswift_decode(encoding : &[u8]) -> FieldElem
+{
+    assert!(encoding.len() == ELLSWIFT_SIZE);
+
+    xswiftec(&FieldElem::from_bytes(encoding.slice_to(32)),
+             &FieldElem::from_bytes(encoding.slice_from(32)))
+}
+
+/* x only ECDH with the key encoded in theirs.  Returns None if the result is
+ * the point at infinity.
+ */
+pub fn ellswift_xdh(secret : &[u8, ..32], theirs : &[u8]) -> Option<[u8, ..32]>
+{
+    let point : Point = Point::lift_x(&ellswift_decode(theirs)).unwrap();
+
+    point.mul(secret).map(|p| p.x.to_bytes())
+}
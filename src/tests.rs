@@ -0,0 +1,2666 @@
+extern crate time;
+extern crate serialize;
+
+use std::io::BufferedStream;
+use std::io::TcpStream;
+use std::io::TcpListener;
+use std::io::TcpAcceptor;
+use std::io::Listener;
+use std::io::Acceptor;
+use std::io::File;
+use std::io::TempDir;
+use std::io::net::ip::SocketAddr;
+use std::io::net::ip::Ipv4Addr;
+use std::io::timer::sleep;
+use std::sync::Arc;
+use std::time::duration::Duration;
+
+use self::time::Timespec;
+
+use self::serialize::json;
+use self::serialize::json::Json;
+use self::serialize::base64::ToBase64;
+use self::serialize::base64::STANDARD;
+
+use datatype::block::Block;
+use datatype::block::BlockHeader;
+use datatype::blockfilter::BlockFilter;
+use datatype::blockfilter::FILTER_TYPE_BASIC;
+use datatype::compact::BlockTxRequest;
+use datatype::compact::HeaderAndShortIds;
+use datatype::compact::short_id;
+use datatype::bloom::BloomFilter;
+use datatype::bloom::BloomUpdate;
+use datatype::hash::Hash;
+use datatype::invvect::InvEntry;
+use datatype::invvect::InvEntryType;
+use datatype::netaddr::NetAddr;
+use datatype::netaddr::NetAddrV2;
+use datatype::netaddr::NetworkId;
+use datatype::netaddr::MAX_ADDRV2_SIZE;
+use datatype::script::Script;
+use datatype::script::OP_TRUE;
+use datatype::script::OP_RETURN;
+use datatype::script::{OP_DUP, OP_HASH160, OP_EQUALVERIFY, OP_CHECKSIG};
+use datatype::transaction::Transaction;
+use datatype::transaction::TxIn;
+use datatype::transaction::TxOut;
+use datatype::transaction::TxLock;
+use datatype::transaction::OutPoint;
+use datatype::value::Value;
+
+use chain::Chain;
+
+use chainmng::ChainManagerReply;
+
+use consensus::ValidationError;
+
+use datatype::merkle::PartialMerkleTree;
+
+use config::Config;
+use config::Service;
+use config::Services;
+use config::NetworkParams;
+
+use interpreter::SIGHASH_ALL;
+
+use logger::LogLevel;
+use logger::NodeLogger;
+
+use marshalling::Marshalling;
+use marshalling::Unmarshalling;
+
+use message::cmpctblock::CmpctBlock;
+use message::filteradd::FilterAdd;
+use message::filterload::FilterLoad;
+use message::getdata::GetData;
+use message::getblocktxn::GetBlockTxn;
+use message::addrv2::AddrV2;
+use message::header::Header;
+use message::header::HEADER_SIZE;
+use message::headers::Headers;
+use message::headers::MAX_HEADERS_RESULTS;
+use message::merkleblock::MerkleBlock;
+use message::getheaders::GetHeaders;
+use message::getheaders::MAX_LOCATOR_SIZE;
+use message::reject::Reject;
+use message::reject::RejectType;
+use message::tx::Tx;
+use message::verack::VerAck;
+use message::version::Version;
+
+use v2transport::V2Handshake;
+use v2transport::V2Session;
+use v2transport::LENGTH_SIZE;
+
+use mempool::Mempool;
+use mempool::MempoolError;
+
+use orphanage::Orphanage;
+
+use relay::TxRequestTracker;
+
+use utxo::UtxoView;
+
+use node::Node;
+
+use peer::PeerInfo;
+
+use connector::Socks5Proxy;
+use connector::Destination;
+use connector::ConnectorError;
+
+use torcontrol::TorControl;
+use torcontrol::TorControlError;
+
+/* End to end tests with a few regtest nodes running in this process and
+ * talking to each other over the loopback, and tests of the parts they
+ * cannot easily reach.
+ */
+
+const TIMEOUT_S : i64 = 60;
+const POLL_MS : i64 = 100;
+
+const TX_FEE : u64 = 10000;
+
+/* The tests are all on regtest */
+fn regtest() -> &'static NetworkParams
+{
+    ::config::Network::RegTest.get_params()
+}
+
+struct TestNode
+{
+    node    : Node,
+    addr    : SocketAddr,
+    datadir : TempDir     /* Removed with the node */
+}
+
+/* A node listening on a port of its own, that advertises it to its peers */
+fn start_node() -> TestNode
+{
+    start_node_with(Config::new(Path::new(".")))
+}
+
+/* Every node has a data directory of its own, whatever the one of config */
+fn start_node_with(config : Config) -> TestNode
+{
+    let mut config : Config = config;
+    let datadir : TempDir = TempDir::new("node").unwrap();
+    let node : Node;
+    let logger : NodeLogger;
+    let addr : SocketAddr;
+    let local : NetAddr;
+
+    config.datadir = datadir.path().clone();
+    config.network = ::config::Network::RegTest;
+
+    logger = NodeLogger::new(&config).unwrap();
+    node = Node::start(Arc::new(config),logger);
+    addr = node.listen(SocketAddr { ip: Ipv4Addr(127,0,0,1), port: 0 }).unwrap();
+    local = NetAddr::new(None,node.get_config().get_services(),Some(addr));
+
+    node.set_local_address(NetAddrV2::from_netaddr(&local).unwrap());
+
+    TestNode { node: node, addr: addr, datadir: datadir }
+}
+
+fn wait_until(what : &str, cond : || -> bool)
+{
+    let deadline : Timespec = time::now_utc().to_timespec()+Duration::seconds(TIMEOUT_S);
+
+    while !cond()
+    {
+        if time::now_utc().to_timespec() > deadline
+        {
+            panic!("Timeout waiting for {}",what);
+        }
+
+        sleep(Duration::milliseconds(POLL_MS));
+    }
+}
+
+fn knows(a : &TestNode, b : &TestNode) -> bool
+{
+    a.node.get_addresses().iter().any(|addr| addr.to_socket_addr() == Some(b.addr))
+}
+
+/* Whether the node has count peers, all of them past the point from which
+ * what it accepts gets announced to them.
+ */
+fn relaying(a : &TestNode, count : uint) -> bool
+{
+    let peers : Vec<(uint,PeerInfo)> = a.node.get_peers();
+
+    peers.len() == count && peers.iter().all(|&(_, ref info)| info.relaying)
+}
+
+/* Connect a to b and wait for both to have advertised themselves, which
+ * happens after the handshake, and to relay to each other.
+ */
+fn connect(a : &TestNode, b : &TestNode)
+{
+    let count_a : uint = a.node.get_peers().len()+1;
+    let count_b : uint = b.node.get_peers().len()+1;
+
+    a.node.connect(b.addr);
+
+    wait_until("address gossip",|| knows(a,b) && knows(b,a));
+    wait_until("relay start",|| relaying(a,count_a) && relaying(b,count_b));
+}
+
+fn wait_sync(nodes : &[&TestNode], tip : &Hash)
+{
+    wait_until("tip convergence",|| nodes.iter().all(|n| {
+        let (hash, _) = n.node.get_tip();
+
+        hash == *tip
+    }));
+}
+
+fn from_hex(hex : &str) -> Vec<u8>
+{
+    ::crypto::from_hexstr(hex).unwrap()
+}
+
+fn from_hex32(hex : &str) -> [u8, ..32]
+{
+    let mut data : [u8, ..32] = [0u8, ..32];
+    let bytes : Vec<u8> = from_hex(hex);
+
+    assert!(bytes.len() == 32);
+
+    for i in range(0u,32)
+    {
+        data[i] = bytes[i];
+    }
+
+    data
+}
+
+fn from_hex64(hex : &str) -> [u8, ..64]
+{
+    let mut data : [u8, ..64] = [0u8, ..64];
+    let bytes : Vec<u8> = from_hex(hex);
+
+    assert!(bytes.len() == 64);
+
+    for i in range(0u,64)
+    {
+        data[i] = bytes[i];
+    }
+
+    data
+}
+
+fn op_true() -> Script
+{
+    Script::from_bytes(vec![OP_TRUE])
+}
+
+struct Key
+{
+    secret : [u8, ..32],
+    pubkey : Vec<u8>     /* Compressed */
+}
+
+fn new_key() -> Key
+{
+    let secret : [u8, ..32] = ::secp256k1::random_secret();
+
+    Key
+    {
+        secret: secret,
+        pubkey: ::secp256k1::Point::generator().mul(&secret).unwrap().to_bytes()
+    }
+}
+
+fn p2pkh(key : &Key) -> Script
+{
+    let mut script : Script = Script::new();
+
+    script.push_opcode(OP_DUP);
+    script.push_opcode(OP_HASH160);
+    script.push_data(::crypto::hash160(key.pubkey.as_slice()).as_slice());
+    script.push_opcode(OP_EQUALVERIFY);
+    script.push_opcode(OP_CHECKSIG);
+
+    script
+}
+
+/* Minimal DER encoding of a 32 bytes big endian integer */
+fn der_integer(v : &[u8, ..32]) -> Vec<u8>
+{
+    let mut start : uint = 0;
+    let mut der : Vec<u8> = vec![0x02u8];
+
+    while start < 31 && v[start] == 0
+    {
+        start += 1;
+    }
+
+    if v[start] & 0x80 != 0
+    {
+        der.push((32-start+1) as u8);
+        der.push(0);
+    }
+    else
+    {
+        der.push((32-start) as u8);
+    }
+
+    der.push_all(v.slice_from(start));
+
+    der
+}
+
+/* The same tx, with the input at index spending an output of script_pubkey
+ * signed by key.
+ */
+fn sign(tx : &Transaction, index : uint, script_pubkey : &Script, key : &Key) -> Transaction
+{
+    let hash : [u8, ..32] = ::interpreter::signature_hash(script_pubkey,tx,index,SIGHASH_ALL);
+    let (r, s) = ::secp256k1::ecdsa_sign(&key.secret,&hash);
+    let mut integers : Vec<u8> = der_integer(&r);
+    let mut sig : Vec<u8> = vec![0x30u8];
+    let mut sig_script : Script = Script::new();
+    let mut ins : Vec<TxIn> = tx.get_in_txs().clone();
+    let signed : TxIn;
+
+    integers.push_all(der_integer(&s).as_slice());
+
+    sig.push(integers.len() as u8);
+    sig.push_all(integers.as_slice());
+    sig.push(SIGHASH_ALL);
+
+    sig_script.push_data(sig.as_slice());
+    sig_script.push_data(key.pubkey.as_slice());
+
+    signed = TxIn::new(ins[index].get_prev_out().clone(),sig_script,ins[index].get_sequence());
+
+    *ins.get_mut(index) = signed;
+
+    Transaction::new(tx.get_version(),ins,tx.get_out_txs().clone(),tx.get_lock())
+}
+
+/* Spend the coinbase of the block, paid to key, to an anyone-can-spend
+ * output.
+ */
+fn spend_coinbase(block : &Block, key : &Key) -> Transaction
+{
+    let coinbase : &Transaction = &block.get_txs()[0];
+    let value : u64 = coinbase.get_out_txs()[0].get_value().get_satoshis();
+    let tx : Transaction;
+
+    tx = Transaction::new(1,
+                          vec![TxIn::new(OutPoint::new(coinbase.get_hash(),0),Script::new(),
+                                         0xffffffff)],
+                          vec![TxOut::new(Value::Satoshi(value-TX_FEE),op_true())],
+                          TxLock::from_u32(0));
+
+    sign(&tx,0,coinbase.get_out_txs()[0].get_script(),key)
+}
+
+#[test]
+fn test_generate()
+{
+    let a : TestNode = start_node();
+    let hashes : Vec<Hash> = a.node.generate(10,op_true());
+    let (tip, height) = a.node.get_tip();
+
+    assert!(hashes.len() == 10);
+    assert!(height == 10);
+    assert!(tip == hashes[9]);
+}
+
+/* Blocks mined on one end of a line of nodes get to the other end */
+#[test]
+fn test_block_relay()
+{
+    let a : TestNode = start_node();
+    let b : TestNode = start_node();
+    let c : TestNode = start_node();
+    let mut hashes : Vec<Hash>;
+
+    connect(&b,&a);
+    connect(&c,&b);
+
+    hashes = a.node.generate(20,op_true());
+
+    wait_sync(&[&a,&b,&c],hashes.last().unwrap());
+
+    /* And back */
+    hashes = c.node.generate(5,op_true());
+
+    wait_sync(&[&a,&b,&c],hashes.last().unwrap());
+
+    let (_, height) = a.node.get_tip();
+
+    assert!(height == 25);
+}
+
+/* A node connecting after blocks were mined gets them from its peer, then
+ * follows the new ones.
+ */
+#[test]
+fn test_chain_sync()
+{
+    let a : TestNode = start_node();
+    let b : TestNode = start_node();
+    let mut hashes : Vec<Hash>;
+
+    hashes = a.node.generate(30,op_true());
+
+    connect(&b,&a);
+
+    wait_sync(&[&a,&b],hashes.last().unwrap());
+
+    hashes = a.node.generate(2,op_true());
+
+    wait_sync(&[&a,&b],hashes.last().unwrap());
+
+    let (_, height) = b.node.get_tip();
+
+    assert!(height == 32);
+}
+
+/* Past the maximum of inbound peers, connections are closed right away */
+#[test]
+fn test_max_inbound()
+{
+    let mut config : Config = Config::new(Path::new("."));
+    let a : TestNode;
+    let b : TestNode = start_node();
+    let mut stream : TcpStream;
+
+    config.max_inbound = 1;
+    a = start_node_with(config);
+
+    connect(&b,&a);
+
+    stream = TcpStream::connect(a.addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT_S as u64*1000));
+
+    match stream.read_byte()
+    {
+        Err(ref err) if err.kind == ::std::io::EndOfFile => (),
+        result                                            => panic!("Not closed: {}",result)
+    }
+
+    assert!(a.node.get_peers().len() == 1);
+}
+
+/* A tx gets to the mempool of the other node and both agree on the block
+ * that confirms it.
+ */
+#[test]
+fn test_tx_relay()
+{
+    let a : TestNode = start_node();
+    let b : TestNode = start_node();
+    let key : Key = new_key();
+    let mut hashes : Vec<Hash>;
+    let tx : Transaction;
+    let txid : Hash;
+    let block : Block;
+
+    connect(&b,&a);
+
+    hashes = a.node.generate(::consensus::COINBASE_MATURITY as uint+1,p2pkh(&key));
+
+    wait_sync(&[&a,&b],hashes.last().unwrap());
+
+    tx = spend_coinbase(&a.node.get_block(hashes[0].clone()).unwrap(),&key);
+    txid = tx.get_hash();
+
+    match a.node.add_tx(a.addr,tx)
+    {
+        ChainManagerReply::ChainMngTxAccepted => (),
+        reply                                 => panic!("Tx not accepted: {}",reply)
+    }
+
+    wait_until("tx relay",|| b.node.get_mempool_tx(txid.clone()).is_some());
+
+    hashes = b.node.generate(1,op_true());
+
+    wait_sync(&[&a,&b],&hashes[0]);
+
+    block = a.node.get_block(hashes[0].clone()).unwrap();
+
+    assert!(block.get_txs().iter().any(|t| t.get_hash() == txid));
+    assert!(a.node.get_mempool_tx(txid.clone()).is_none());
+    assert!(b.node.get_mempool_tx(txid).is_none());
+}
+
+/* The first tx between two people, in block 170, spending a pay to pubkey
+ * output.
+ */
+#[test]
+fn test_historical_signature()
+{
+    let tx : Transaction = Unmarshalling::new(&::crypto::from_hexstr(
+        "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000\
+         004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd4102\
+         20181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200\
+         ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa2\
+         8414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee00\
+         00000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0\
+         eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000")
+        .unwrap()).read_transaction();
+    let script_pubkey : Script = Script::from_bytes(::crypto::from_hexstr(
+        "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84\
+         ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac").unwrap());
+    let tampered : Transaction;
+
+    assert!(format!("{}",tx.get_hash()).as_slice()
+            == "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16");
+    assert!(::interpreter::verify_script(tx.get_in_txs()[0].get_script(),&script_pubkey,
+                                         &tx,0).is_ok());
+
+    tampered = Transaction::new(tx.get_version(),tx.get_in_txs().clone(),
+                                tx.get_out_txs().slice_to(1).to_vec(),tx.get_lock());
+
+    assert!(::interpreter::verify_script(tx.get_in_txs()[0].get_script(),&script_pubkey,
+                                         &tampered,0).is_err());
+}
+
+#[test]
+fn test_p2pkh_signature()
+{
+    let key : Key = new_key();
+    let other : Key = new_key();
+    let script_pubkey : Script = p2pkh(&key);
+    let tx : Transaction;
+    let signed : Transaction;
+    let mut forged_script : Script = Script::new();
+    let forged : Transaction;
+    let tampered : Transaction;
+    let verify = |tx : &Transaction| {
+        ::interpreter::verify_script(tx.get_in_txs()[0].get_script(),&script_pubkey,tx,0)
+    };
+
+    tx = Transaction::new(1,
+                          vec![TxIn::new(OutPoint::new(Hash::from_digest(::crypto::sha256(b"prev")),
+                                                       1),
+                                         Script::new(),0xffffffff)],
+                          vec![TxOut::new(Value::Satoshi(50000),op_true())],
+                          TxLock::from_u32(0));
+
+    signed = sign(&tx,0,&script_pubkey,&key);
+
+    assert!(verify(&signed).is_ok());
+
+    /* The signature of another key, with our key */
+    forged_script.push_data(sign(&tx,0,&script_pubkey,&other).get_in_txs()[0].get_script()
+                            .get_pushes()[0].as_slice());
+    forged_script.push_data(key.pubkey.as_slice());
+
+    forged = Transaction::new(1,
+                              vec![TxIn::new(tx.get_in_txs()[0].get_prev_out().clone(),
+                                             forged_script,0xffffffff)],
+                              tx.get_out_txs().clone(),
+                              tx.get_lock());
+
+    assert!(verify(&forged).is_err());
+
+    tampered = Transaction::new(1,signed.get_in_txs().clone(),
+                                vec![TxOut::new(Value::Satoshi(50001),op_true())],
+                                signed.get_lock());
+
+    assert!(verify(&tampered).is_err());
+}
+
+/* A chain on which the coinbases of the first count blocks are mature */
+fn mature_chain(count : u32) -> Chain
+{
+    let mut chain : Chain;
+    let mempool : Mempool = Mempool::new();
+
+    chain = Chain::new(regtest());
+
+    for _ in range(0,::consensus::COINBASE_MATURITY+count)
+    {
+        let block : Block = ::miner::mine_block(&chain,&mempool,&op_true());
+
+        assert!(chain.accept_block(block).unwrap());
+    }
+
+    chain
+}
+
+fn coinbase_at(chain : &Chain, height : u32) -> (OutPoint, u64)
+{
+    let coinbase : &Transaction = &chain.get_block(chain.get_hash_at(height).unwrap()).unwrap()
+                                        .get_txs()[0];
+
+    (OutPoint::new(coinbase.get_hash(),0), coinbase.get_out_txs()[0].get_value().get_satoshis())
+}
+
+/* Spends the outpoints with OP_TRUE, which needs no signature */
+fn spend_to(prevs : &[OutPoint], value : u64, outputs : uint, script : &Script, lock : u32,
+            sequence : u32) -> Transaction
+{
+    Transaction::new(1,
+                     prevs.iter().map(|p| TxIn::new(p.clone(),Script::new(),sequence)).collect(),
+                     Vec::from_fn(outputs,|_| TxOut::new(Value::Satoshi(value),script.clone())),
+                     TxLock::from_u32(lock))
+}
+
+fn spend(prevs : &[OutPoint], value : u64) -> Transaction
+{
+    spend_to(prevs,value,1,&op_true(),0,0xffffffff)
+}
+
+#[test]
+fn test_mempool_limits()
+{
+    let chain : Chain = mature_chain(2);
+    let mut mempool : Mempool = Mempool::new();
+    let (mut prev, mut value) = coinbase_at(&chain,1);
+    let parent : Transaction;
+
+    /* A chain of 25 txs */
+    for _ in range(0,25u)
+    {
+        let tx : Transaction = spend(&[prev],value-TX_FEE);
+
+        prev = OutPoint::new(tx.get_hash(),0);
+        value -= TX_FEE;
+
+        assert!(mempool.accept(tx,&chain) == Ok(()));
+    }
+
+    assert!(mempool.accept(spend(&[prev],value-TX_FEE),&chain)
+            == Err(MempoolError::MempoolTooLongChain));
+
+    /* A tx and 24 descendants */
+    let (prev, value) = coinbase_at(&chain,2);
+
+    parent = spend_to(&[prev],(value-TX_FEE)/30,30,&op_true(),0,0xffffffff);
+
+    assert!(mempool.accept(parent.clone(),&chain) == Ok(()));
+
+    for i in range(0,24u)
+    {
+        assert!(mempool.accept(spend(&[OutPoint::new(parent.get_hash(),i as u32)],
+                                     (value-TX_FEE)/30-TX_FEE),&chain)
+                == Ok(()));
+    }
+
+    assert!(mempool.accept(spend(&[OutPoint::new(parent.get_hash(),24)],
+                                 (value-TX_FEE)/30-TX_FEE),&chain)
+            == Err(MempoolError::MempoolTooLongChain));
+}
+
+#[test]
+fn test_mempool_replacement()
+{
+    let chain : Chain = mature_chain(1);
+    let mut mempool : Mempool = Mempool::new();
+    let (prev, value) = coinbase_at(&chain,1);
+    let first : Transaction = spend(&[prev.clone()],value-TX_FEE);
+    let child : Transaction = spend(&[OutPoint::new(first.get_hash(),0)],value-2*TX_FEE);
+    let other : Script = p2pkh(&new_key());
+    let replacement : Transaction;
+
+    assert!(mempool.accept(first.clone(),&chain) == Ok(()));
+    assert!(mempool.accept(child.clone(),&chain) == Ok(()));
+
+    /* A lower fee rate, then a higher one that does not pay for the child */
+    assert!(mempool.accept(spend_to(&[prev.clone()],value-TX_FEE,1,&other,0,0xffffffff),&chain)
+            == Err(MempoolError::MempoolReplacementFee));
+    assert!(mempool.accept(spend_to(&[prev.clone()],value-2*TX_FEE,1,&other,0,0xffffffff),
+                           &chain)
+            == Err(MempoolError::MempoolReplacementFee));
+
+    replacement = spend_to(&[prev.clone()],value-3*TX_FEE,1,&other,0,0xffffffff);
+
+    assert!(mempool.accept(replacement.clone(),&chain) == Ok(()));
+    assert!(!mempool.contains(&first.get_hash()));
+    assert!(!mempool.contains(&child.get_hash()));
+    assert!(mempool.len() == 1);
+
+    /* It cannot be replaced by a tx that spends it */
+    assert!(mempool.accept(spend(&[prev,OutPoint::new(replacement.get_hash(),0)],TX_FEE),
+                           &chain)
+            == Err(MempoolError::MempoolSpendsConflicting));
+}
+
+#[test]
+fn test_mempool_eviction()
+{
+    let chain : Chain = mature_chain(4);
+    let mut mempool : Mempool = Mempool::new();
+    let mut txs : Vec<Transaction> = Vec::new();
+    let rate : u64;
+
+    for i in range(1,4u)
+    {
+        let (prev, value) = coinbase_at(&chain,i as u32);
+        let tx : Transaction = spend(&[prev],value-(i as u64)*TX_FEE);
+
+        assert!(mempool.accept(tx.clone(),&chain) == Ok(()));
+
+        txs.push(tx);
+    }
+
+    rate = mempool.get_fee_rate(&txs[0].get_hash()).unwrap();
+
+    /* One byte too many evicts the lowest fee rate */
+    let size : uint = mempool.get_size();
+
+    mempool.trim_to_size(size-1);
+
+    assert!(!mempool.contains(&txs[0].get_hash()));
+    assert!(mempool.contains(&txs[1].get_hash()));
+    assert!(mempool.contains(&txs[2].get_hash()));
+    assert!(mempool.get_min_fee_rate() == rate+::mempool::MIN_RELAY_FEE_RATE);
+
+    let (prev, value) = coinbase_at(&chain,4);
+
+    assert!(mempool.accept(spend(&[prev],value-TX_FEE),&chain)
+            == Err(MempoolError::MempoolInsufficientFee));
+}
+
+/* Locks are checked against the next block, and times against the median
+ * time past of the tip (BIP113).
+ */
+#[test]
+fn test_mempool_finality()
+{
+    let chain : Chain = mature_chain(4);
+    let mut mempool : Mempool = Mempool::new();
+    let height : u32 = chain.get_height();
+    let mtp : u32 = ::consensus::get_median_time_past(&chain,height).sec as u32;
+    let accept = |mempool : &mut Mempool, coinbase : u32, lock : u32| {
+        let (prev, value) = coinbase_at(&chain,coinbase);
+
+        mempool.accept(spend_to(&[prev],value-TX_FEE,1,&op_true(),lock,0),&chain)
+    };
+
+    assert!(accept(&mut mempool,1,height+1) == Err(MempoolError::MempoolNonFinal));
+    assert!(accept(&mut mempool,2,height) == Ok(()));
+    assert!(accept(&mut mempool,3,mtp) == Err(MempoolError::MempoolNonFinal));
+    assert!(accept(&mut mempool,4,mtp-1) == Ok(()));
+}
+
+/* A tx spending a parent we do not have, different for every i */
+fn orphan(i : uint) -> (Transaction, Vec<Hash>)
+{
+    let parent : Hash = Hash::from_digest(::crypto::sha256(format!("parent {}",i).as_bytes()));
+    let tx : Transaction;
+
+    tx = Transaction::new(1,
+                          vec![TxIn::new(OutPoint::new(parent.clone(),0),Script::new(),0xffffffff)],
+                          vec![TxOut::new(Value::Satoshi(1),op_true())],
+                          TxLock::from_u32(0));
+
+    (tx, vec![parent])
+}
+
+/* Every peer has a share of the orphanage, which overflows at random and
+ * loses the orphans of a peer when it disconnects, or when they get old.
+ */
+#[test]
+fn test_orphanage()
+{
+    let mut orphanage : Orphanage = Orphanage::new();
+    let peers : Vec<SocketAddr> = range(1,6u8).map(|i| SocketAddr { ip: Ipv4Addr(10,0,0,i),
+                                                                    port: 8333 }).collect();
+    let now : Timespec = time::now_utc().to_timespec();
+
+    /* 25 each for the first four peers fills it */
+    for i in range(0,100u)
+    {
+        let (tx, parents) = orphan(i);
+
+        assert!(orphanage.add(tx,peers[i/25],parents));
+    }
+
+    let (tx, parents) = orphan(100);
+
+    assert!(!orphanage.add(tx,peers[0],parents));
+    assert!(orphanage.len() == 100);
+
+    /* One more and one goes, whoever sent it */
+    let (tx, parents) = orphan(101);
+    let hash : Hash = tx.get_hash();
+
+    assert!(orphanage.add(tx,peers[4],parents));
+    assert!(orphanage.len() == 100);
+    assert!(orphanage.contains(&hash));
+
+    orphanage.erase_for_peer(&peers[0]);
+
+    for i in range(0,25u)
+    {
+        let (tx, parents) = orphan(i);
+
+        assert!(!orphanage.contains(&tx.get_hash()));
+        assert!(orphanage.get_children(&parents[0]).is_empty());
+    }
+
+    assert!(orphanage.len() >= 75 && orphanage.len() <= 76);
+
+    /* Its share is free again */
+    let (tx, parents) = orphan(0);
+    let hash : Hash = tx.get_hash();
+
+    assert!(orphanage.add(tx,peers[0],parents));
+
+    orphanage.expire_at(now);
+
+    assert!(orphanage.contains(&hash));
+
+    orphanage.expire_at(now+Duration::minutes(21));
+
+    assert!(orphanage.len() == 0);
+}
+
+/* A peer can only have so many announcements in flight, and they go to the
+ * other announcers when it disconnects.
+ */
+#[test]
+fn test_tx_request_tracker()
+{
+    let mut tracker : TxRequestTracker = TxRequestTracker::new();
+    let a : SocketAddr = SocketAddr { ip: Ipv4Addr(10,0,0,1), port: 8333 };
+    let b : SocketAddr = SocketAddr { ip: Ipv4Addr(10,0,0,2), port: 8333 };
+    let hash = |i : uint| Hash::from_digest(::crypto::sha256(format!("{}",i).as_bytes()));
+
+    for i in range(0,5000u)
+    {
+        assert!(tracker.announced(a,hash(i)));
+    }
+
+    assert!(!tracker.announced(a,hash(5000)));
+    assert!(tracker.len() == 5000);
+
+    /* Once one is received there is room again */
+    tracker.received(&hash(0));
+
+    assert!(tracker.announced(a,hash(5000)));
+
+    /* b announced one we asked a for, and gets it once a is gone */
+    assert!(!tracker.announced(b,hash(1)));
+
+    tracker.remove_peer(a);
+
+    assert!(tracker.len() == 1);
+    assert!(tracker.get_rerequests(b) == vec![hash(1)]);
+}
+
+/* A block the peer we asked does not have, which it answers with notfound,
+ * is asked for to the next peer that announced it.  Txs go the same way.
+ */
+#[test]
+fn test_block_not_found()
+{
+    let mut tracker : TxRequestTracker = TxRequestTracker::new();
+    let a : SocketAddr = SocketAddr { ip: Ipv4Addr(10,0,0,1), port: 8333 };
+    let b : SocketAddr = SocketAddr { ip: Ipv4Addr(10,0,0,2), port: 8333 };
+    let c : SocketAddr = SocketAddr { ip: Ipv4Addr(10,0,0,3), port: 8333 };
+    let hash : Hash = Hash::from_digest(::crypto::sha256(b"block"));
+
+    assert!(tracker.announced(a,hash.clone()));
+    assert!(!tracker.announced(b,hash.clone()));
+
+    /* Still in flight from a */
+    assert!(tracker.get_rerequests(b).is_empty());
+
+    tracker.not_found(a,&hash);
+
+    assert!(tracker.get_rerequests(a).is_empty());
+    assert!(tracker.get_rerequests(c).is_empty());
+    assert!(tracker.get_rerequests(b) == vec![hash.clone()]);
+
+    tracker.received(&hash);
+
+    assert!(tracker.len() == 0);
+}
+
+/* A tx spending its output of a previous tx in the same block.  The block
+ * fails to connect, and the output must not be left in the UTXO set.
+ */
+#[test]
+fn test_undo_spent_in_block()
+{
+    let mut chain : Chain;
+    let mempool : Mempool = Mempool::new();
+    let first : Transaction;
+    let value : u64;
+    let parent : Transaction;
+    let child : Transaction;
+    let template : Block;
+    let coinbase : Transaction;
+    let txs : Vec<Transaction>;
+    let mut header : BlockHeader;
+
+    chain = Chain::new(regtest());
+
+    for _ in range(0,::consensus::COINBASE_MATURITY+1)
+    {
+        let block : Block = ::miner::mine_block(&chain,&mempool,&op_true());
+
+        assert!(chain.accept_block(block).unwrap());
+    }
+
+    first = chain.get_block(chain.get_hash_at(1).unwrap()).unwrap().get_txs()[0].clone();
+    value = first.get_out_txs()[0].get_value().get_satoshis();
+
+    parent = Transaction::new(1,
+                              vec![TxIn::new(OutPoint::new(first.get_hash(),0),Script::new(),
+                                             0xffffffff)],
+                              vec![TxOut::new(Value::Satoshi(value-TX_FEE),op_true())],
+                              TxLock::from_u32(0));
+    child = Transaction::new(1,
+                             vec![TxIn::new(OutPoint::new(parent.get_hash(),0),Script::new(),
+                                            0xffffffff)],
+                             vec![TxOut::new(Value::Satoshi(value-2*TX_FEE),op_true())],
+                             TxLock::from_u32(0));
+
+    /* The coinbase claims one satoshi more than the fees, which is only
+     * found out once the txs are connected.
+     */
+    template = ::miner::mine_block(&chain,&mempool,&op_true());
+    coinbase = Transaction::new(1,template.get_txs()[0].get_in_txs().clone(),
+                                vec![TxOut::new(Value::Satoshi(::consensus::get_block_subsidy(
+                                                   chain.get_height()+1,&regtest().consensus)
+                                                   .get_satoshis()
+                                                   +2*TX_FEE+1),op_true())],
+                                TxLock::from_u32(0));
+    txs = vec![coinbase,parent.clone(),child];
+
+    let (merkle_root, _) = ::datatype::merkle::compute_merkle_root(
+        txs.iter().map(|tx| tx.get_hash()).collect::<Vec<Hash>>().as_slice());
+
+    header = BlockHeader::new(template.get_header().get_version(),chain.get_tip().clone(),
+                              merkle_root,template.get_header().get_time(),
+                              template.get_header().get_bits(),0);
+
+    assert!(::miner::solve(&mut header,&regtest().consensus));
+
+    assert!(chain.accept_block(Block::new(header,txs))
+            == Err(ValidationError::BlockBadCoinbaseValue));
+    assert!(chain.get_utxos().get_coin(&OutPoint::new(parent.get_hash(),0)).is_none());
+    assert!(chain.get_utxos().get_coin(&OutPoint::new(first.get_hash(),0)).is_some());
+}
+
+/* A coinbase paying to a script with one signature operation too many */
+#[test]
+fn test_too_many_sigops()
+{
+    let mut chain : Chain;
+    let mempool : Mempool = Mempool::new();
+    let template : Block;
+    let coinbase : Transaction;
+    let mut header : BlockHeader;
+    let max : uint = ::consensus::MAX_BLOCK_SIGOPS_COST/::consensus::WITNESS_SCALE_FACTOR;
+
+    chain = Chain::new(regtest());
+    template = ::miner::mine_block(&chain,&mempool,&op_true());
+    coinbase = Transaction::new(1,template.get_txs()[0].get_in_txs().clone(),
+                                vec![TxOut::new(template.get_txs()[0].get_out_txs()[0]
+                                                .get_value().clone(),
+                                                Script::from_bytes(Vec::from_elem(max+1,
+                                                                                  OP_CHECKSIG)))],
+                                TxLock::from_u32(0));
+
+    header = BlockHeader::new(template.get_header().get_version(),chain.get_tip().clone(),
+                              coinbase.get_hash(),template.get_header().get_time(),
+                              template.get_header().get_bits(),0);
+
+    assert!(::miner::solve(&mut header,&regtest().consensus));
+
+    assert!(chain.accept_block(Block::new(header,vec![coinbase]))
+            == Err(ValidationError::BlockBadSigops));
+    assert!(chain.get_height() == 0);
+}
+
+/* A branch off the active chain is checked like the active chain is.  Its
+ * first block is kept, one with other bits than the branch requires is not.
+ */
+#[test]
+fn test_side_branch_difficulty()
+{
+    let mut chain : Chain;
+    let mut other : Chain;
+    let mempool : Mempool = Mempool::new();
+    let side : Block;
+    let template : Block;
+    let mut header : BlockHeader;
+
+    chain = Chain::new(regtest());
+    other = Chain::new(regtest());
+
+    for _ in range(0,2u)
+    {
+        let block : Block = ::miner::mine_block(&chain,&mempool,&op_true());
+
+        assert!(chain.accept_block(block).unwrap());
+    }
+
+    /* The other chain pays to another script, so its blocks differ */
+    side = ::miner::mine_block(&other,&mempool,&p2pkh(&new_key()));
+
+    assert!(other.accept_block(side.clone()).unwrap());
+    assert!(chain.accept_block(side.clone()) == Ok(false));
+
+    template = ::miner::mine_block(&other,&mempool,&p2pkh(&new_key()));
+
+    /* The branch requires the regtest limit, and a target above it does not
+     * even pass the proof of work, so the bits are off the other way.
+     */
+    header = BlockHeader::new(template.get_header().get_version(),side.get_hash(),
+                              template.get_header().get_merkle_root().clone(),
+                              template.get_header().get_time(),0x1f7fffff,0);
+
+    assert!(::miner::solve(&mut header,&regtest().consensus));
+
+    assert!(chain.accept_block(Block::new(header,template.get_txs().clone()))
+            == Err(ValidationError::BlockBadDifficulty));
+    assert!(chain.get_height() == 2);
+}
+
+/* Chains of different networks in the same process, each with the genesis
+ * block and the rules of its own.
+ */
+#[test]
+fn test_networks_side_by_side()
+{
+    let testnet : &NetworkParams = ::config::Network::TestNet.get_params();
+    let mut chain : Chain = Chain::new(regtest());
+    let other : Chain = Chain::new(testnet);
+    let mempool : Mempool = Mempool::new();
+    let block : Block;
+
+    assert!(*chain.get_tip() == Hash::from_hexstr(
+        "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206").unwrap());
+    assert!(*other.get_tip() == Hash::from_hexstr(
+        "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943").unwrap());
+
+    /* Mined with the regtest difficulty, far from the one of testnet */
+    block = ::miner::mine_block(&chain,&mempool,&op_true());
+
+    assert!(::consensus::check_block(&block,&testnet.consensus)
+            == Err(ValidationError::BlockHighHash));
+    assert!(chain.accept_block(block).unwrap());
+}
+
+/* The block with a witness commitment, whose signet push has the solution */
+fn with_signet_solution(block : &Block, solution : &[u8]) -> Block
+{
+    let coinbase : &Transaction = &block.get_txs()[0];
+    let mut commitment : Vec<u8> = from_hex("aa21a9ed");
+    let mut push : Vec<u8> = ::consensus::SIGNET_HEADER.to_vec();
+    let mut script : Script = Script::new();
+    let mut outs : Vec<TxOut> = coinbase.get_out_txs().clone();
+    let mut txs : Vec<Transaction> = block.get_txs().clone();
+
+    commitment.push_all(&[0u8, ..32]);
+    push.push_all(solution);
+
+    script.push_opcode(OP_RETURN);
+    script.push_data(commitment.as_slice());
+    script.push_data(push.as_slice());
+
+    outs.push(TxOut::new(Value::Satoshi(0),script));
+
+    *txs.get_mut(0) = Transaction::new(coinbase.get_version(),coinbase.get_in_txs().clone(),
+                                       outs,coinbase.get_lock());
+
+    Block::new(block.get_header().clone(),txs)
+}
+
+/* A block signed for a signet, then the solution against another challenge,
+ * with data after it and moved to another block.
+ */
+#[test]
+fn test_signet_solution()
+{
+    let key : Key = new_key();
+    let challenge : Script = p2pkh(&key);
+    let chain : Chain = Chain::new(regtest());
+    let mempool : Mempool = Mempool::new();
+    let template : Block = ::miner::mine_block(&chain,&mempool,&op_true());
+    let unsigned : Block = with_signet_solution(&template,&[]);
+    let signed : Transaction;
+    let script_sig : Vec<u8>;
+    let mut solution : Vec<u8>;
+    let block : Block;
+    let moved : BlockHeader;
+
+    /* Without a witness commitment there is nothing to check */
+    assert!(::consensus::create_signet_txs(&template,&challenge).is_none());
+    assert!(!::consensus::check_signet_solution(&template,&op_true()));
+
+    /* A challenge that needs no signature passes without a solution */
+    assert!(::consensus::check_signet_solution(&unsigned,&op_true()));
+    assert!(!::consensus::check_signet_solution(&unsigned,&challenge));
+
+    let (_, to_sign) = ::consensus::create_signet_txs(&unsigned,&challenge).unwrap();
+
+    signed = sign(&to_sign,0,&challenge,&key);
+    script_sig = signed.get_in_txs()[0].get_script().get_bytes().clone();
+
+    /* The signature script and an empty witness stack */
+    solution = vec![script_sig.len() as u8];
+    solution.push_all(script_sig.as_slice());
+    solution.push(0);
+
+    /* The solution is left out of what is signed */
+    block = with_signet_solution(&template,solution.as_slice());
+
+    assert!(::consensus::check_signet_solution(&block,&challenge));
+    assert!(!::consensus::check_signet_solution(&block,&p2pkh(&new_key())));
+
+    solution.push(0);
+
+    assert!(!::consensus::check_signet_solution(
+        &with_signet_solution(&template,solution.as_slice()),&challenge));
+
+    moved = BlockHeader::new(block.get_header().get_version(),
+                             block.get_header().get_prev_block().clone(),
+                             block.get_header().get_merkle_root().clone(),
+                             Timespec { sec: block.get_header().get_time().sec+1, nsec: 0 },
+                             block.get_header().get_bits(),0);
+
+    assert!(!::consensus::check_signet_solution(&Block::new(moved,block.get_txs().clone()),
+                                                &challenge));
+}
+
+#[test]
+fn test_reject_roundtrip()
+{
+    let hash : Hash = Hash::from_digest(::crypto::sha256(b"rejected"));
+    let reason : &str = ValidationError::BlockMutated.get_reason();
+    let reject : Reject = Reject::new("block".to_string(),RejectType::RejectMalformed,
+                                      reason.to_string(),Some(hash.clone()));
+    let data : Vec<u8> = reject.serialize(regtest().magic);
+    let read : Reject;
+
+    read = Reject::unserialize(&data.slice_from(HEADER_SIZE).to_vec());
+
+    assert!(read.get_msg().as_slice() == "block");
+    assert!(read.get_type() == RejectType::RejectMalformed);
+    assert!(read.get_reason().as_slice() == reason);
+    assert!(read.get_hash() == Some(&hash));
+}
+
+/* Proofs for two of the seven txs of a block, and the same proofs with a
+ * hash or a flag changed.
+ */
+#[test]
+fn test_merkle_proof()
+{
+    let txids : Vec<Hash> = range(0u8,7).map(|i| Hash::from_digest(::crypto::sha256(&[i])))
+                                       .collect();
+    let matches : Vec<bool> = range(0u,7).map(|i| i == 2 || i == 5).collect();
+    let (root, _) = ::datatype::merkle::compute_merkle_root(txids.as_slice());
+    let header : BlockHeader = BlockHeader::new(1,Hash::zero(),root,
+                                                Timespec { sec: 1296688602, nsec: 0 },
+                                                0x207fffff,0);
+    let tree : PartialMerkleTree = PartialMerkleTree::from_txids(txids.as_slice(),
+                                                                 matches.as_slice());
+    let mut hashes : Vec<Hash>;
+    let mut flags : Vec<bool>;
+    let last : uint;
+
+    let (_, found) = tree.extract_matches().unwrap();
+
+    assert!(found == vec![(txids[2].clone(),2u),(txids[5].clone(),5u)]);
+    assert!(::datatype::merkle::verify_proof(&header,&tree,&txids[2]));
+    assert!(::datatype::merkle::verify_proof(&header,&tree,&txids[5]));
+    assert!(!::datatype::merkle::verify_proof(&header,&tree,&txids[3]));
+
+    hashes = tree.get_hashes().clone();
+    last = hashes.len()-1;
+    *hashes.get_mut(last) = txids[0].clone();
+
+    assert!(!::datatype::merkle::verify_proof(&header,
+                                              &PartialMerkleTree::new(7,hashes,
+                                                                      tree.get_flags().clone()),
+                                              &txids[2]));
+
+    flags = tree.get_flags().clone();
+    *flags.get_mut(0) = false;
+
+    assert!(!::datatype::merkle::verify_proof(&header,
+                                              &PartialMerkleTree::new(7,tree.get_hashes().clone(),
+                                                                      flags),
+                                              &txids[2]));
+}
+
+#[test]
+fn test_murmur3()
+{
+    let vectors : [(u32, u32, &'static str), ..14] =
+        [(0x00000000, 0x00000000, ""),
+         (0x6a396f08, 0xfba4c795, ""),
+         (0x81f16f39, 0xffffffff, ""),
+         (0x514e28b7, 0x00000000, "00"),
+         (0xea3f0b17, 0xfba4c795, "00"),
+         (0xfd6cf10d, 0x00000000, "ff"),
+         (0x16c6b7ab, 0x00000000, "0011"),
+         (0x8eb51c3d, 0x00000000, "001122"),
+         (0xb4471bf8, 0x00000000, "00112233"),
+         (0xe2301fa8, 0x00000000, "0011223344"),
+         (0xfc2e4a15, 0x00000000, "001122334455"),
+         (0xb074502c, 0x00000000, "00112233445566"),
+         (0x8034d2a0, 0x00000000, "0011223344556677"),
+         (0xb4698def, 0x00000000, "001122334455667788")];
+
+    for &(expected, seed, data) in vectors.iter()
+    {
+        assert!(::crypto::murmur3(seed,from_hex(data).as_slice()) == expected);
+    }
+}
+
+/* The filter of the BIP37 example and how it goes in a filterload */
+#[test]
+fn test_bloom_filter()
+{
+    let elements : [&'static str, ..3] = ["99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+                                          "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+                                          "b9300670b4c5366e95b2699e8b18bc75e5f729c5"];
+    let all : u8 = BloomUpdate::BloomUpdateAll as u8;
+    let mut filter : BloomFilter = BloomFilter::with_rate(3,0.01,0,all);
+    let mut tweaked : BloomFilter = BloomFilter::with_rate(3,0.01,2147483649,all);
+    let mut data : Vec<u8>;
+
+    filter.insert(from_hex(elements[0]).as_slice());
+
+    assert!(filter.contains(from_hex(elements[0]).as_slice()));
+    assert!(!filter.contains(from_hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8").as_slice()));
+
+    for element in elements.iter()
+    {
+        filter.insert(from_hex(*element).as_slice());
+        tweaked.insert(from_hex(*element).as_slice());
+    }
+
+    assert!(*filter.get_data() == from_hex("614e9b") && filter.get_hash_funcs() == 5);
+    assert!(*tweaked.get_data() == from_hex("ce4299") && tweaked.get_hash_funcs() == 5);
+
+    data = FilterLoad::new(filter).serialize(regtest().magic);
+
+    assert!(data.slice_from(HEADER_SIZE) == from_hex("03614e9b050000000000000001").as_slice());
+
+    data = FilterLoad::new(tweaked).serialize(regtest().magic);
+
+    assert!(data.slice_from(HEADER_SIZE) == from_hex("03ce4299050000000100008001").as_slice());
+}
+
+/* A tx paying the generator point both to its hash and to itself, and one
+ * spending the first of these outputs with a script pushing marker.
+ */
+fn bloom_txs(marker : &[u8]) -> (Transaction, Transaction)
+{
+    let mut secret : [u8, ..32] = [0u8, ..32];
+    let key : Key;
+    let mut p2pk : Script = Script::new();
+    let mut script_sig : Script = Script::new();
+    let funding : Transaction;
+    let spending : Transaction;
+
+    secret[31] = 1;
+    key = Key { secret: secret, pubkey: ::secp256k1::Point::generator().to_bytes() };
+
+    p2pk.push_data(key.pubkey.as_slice());
+    p2pk.push_opcode(OP_CHECKSIG);
+
+    script_sig.push_data(marker);
+
+    funding = Transaction::new(1,
+                               vec![TxIn::new(OutPoint::new(Hash::zero(),0),Script::new(),
+                                              0xffffffff)],
+                               vec![TxOut::new(Value::Satoshi(1000),p2pkh(&key)),
+                                    TxOut::new(Value::Satoshi(1000),p2pk)],
+                               TxLock::from_u32(0));
+    spending = Transaction::new(1,
+                                vec![TxIn::new(OutPoint::new(funding.get_hash(),0),script_sig,
+                                               0xffffffff)],
+                                vec![TxOut::new(Value::Satoshi(900),op_true())],
+                                TxLock::from_u32(0));
+
+    (funding, spending)
+}
+
+fn bloom_filter_of(elements : &[&[u8]], update : BloomUpdate) -> BloomFilter
+{
+    let mut filter : BloomFilter = BloomFilter::with_rate(10,0.000001,0,update as u8);
+
+    for element in elements.iter()
+    {
+        filter.insert(*element);
+    }
+
+    filter
+}
+
+/* What a filter matches, and the outputs it learns depending on its flags */
+#[test]
+fn test_bloom_filter_match()
+{
+    let pubkey : Vec<u8> = ::secp256k1::Point::generator().to_bytes();
+    let hash : [u8, ..20] = ::crypto::hash160(pubkey.as_slice());
+    let (funding, spending) = bloom_txs(&[0xab, 0xcd]);
+    let spending_p2pk : Transaction;
+    let mut filter : BloomFilter;
+
+    spending_p2pk = Transaction::new(1,
+                                     vec![TxIn::new(OutPoint::new(funding.get_hash(),1),
+                                                    Script::new(),0xffffffff)],
+                                     vec![TxOut::new(Value::Satoshi(900),op_true())],
+                                     TxLock::from_u32(0));
+
+    filter = bloom_filter_of(&[funding.get_hash().to_digest().as_slice()],
+                             BloomUpdate::BloomUpdateNone);
+
+    assert!(filter.is_relevant_and_update(&funding));
+    assert!(!filter.is_relevant_and_update(&spending));
+
+    filter = bloom_filter_of(&[hash.as_slice()],BloomUpdate::BloomUpdateAll);
+
+    assert!(filter.is_relevant_and_update(&funding));
+    assert!(filter.contains_outpoint(&OutPoint::new(funding.get_hash(),0)));
+    assert!(!filter.contains_outpoint(&OutPoint::new(funding.get_hash(),1)));
+    assert!(filter.is_relevant_and_update(&spending));
+    assert!(!filter.is_relevant_and_update(&spending_p2pk));
+
+    filter = bloom_filter_of(&[hash.as_slice()],BloomUpdate::BloomUpdateNone);
+
+    assert!(filter.is_relevant_and_update(&funding));
+    assert!(!filter.is_relevant_and_update(&spending));
+
+    filter = bloom_filter_of(&[hash.as_slice(), pubkey.as_slice()],
+                             BloomUpdate::BloomUpdateP2PubkeyOnly);
+
+    assert!(filter.is_relevant_and_update(&funding));
+    assert!(!filter.contains_outpoint(&OutPoint::new(funding.get_hash(),0)));
+    assert!(filter.contains_outpoint(&OutPoint::new(funding.get_hash(),1)));
+    assert!(!filter.is_relevant_and_update(&spending));
+    assert!(filter.is_relevant_and_update(&spending_p2pk));
+
+    filter = bloom_filter_of(&[[0xabu8, 0xcd].as_slice()],BloomUpdate::BloomUpdateNone);
+
+    assert!(!filter.is_relevant_and_update(&funding));
+    assert!(filter.is_relevant_and_update(&spending));
+}
+
+/* A bare v1 peer of the node, to send it what our own peers do not */
+fn raw_connect(a : &TestNode) -> BufferedStream<TcpStream>
+{
+    let mut socket : TcpStream = TcpStream::connect(a.addr).unwrap();
+    let version : Version = Version::new("/test/".to_string(),Service::NodeNetwork as Services,0);
+    let mut stream : BufferedStream<TcpStream>;
+
+    socket.set_read_timeout(Some(TIMEOUT_S as u64*1000));
+    stream = BufferedStream::new(socket);
+
+    raw_send(&mut stream,version.serialize(regtest().magic));
+    raw_wait(&mut stream,"verack");
+    raw_send(&mut stream,VerAck::new().serialize(regtest().magic));
+
+    stream
+}
+
+fn raw_send(stream : &mut BufferedStream<TcpStream>, data : Vec<u8>)
+{
+    stream.write(data.as_slice()).unwrap();
+    stream.flush().unwrap();
+}
+
+/* The payload of the next message with that command, the ones before it
+ * are skipped.
+ */
+fn raw_wait(stream : &mut BufferedStream<TcpStream>, command : &str) -> Vec<u8>
+{
+    loop
+    {
+        let header : Header = Header::unserialize(&stream.read_exact(HEADER_SIZE).unwrap());
+        let payload : Vec<u8> = stream.read_exact(header.get_payload_size()).unwrap();
+
+        if header.get_command().as_slice() == command
+        {
+            return payload;
+        }
+    }
+}
+
+/* A lightweight client loading a filter gets the blocks it asks for as
+ * merkleblocks followed by the txs matching the filter.
+ */
+#[test]
+fn test_filtered_block()
+{
+    let a : TestNode = start_node();
+    let key : Key = new_key();
+    let pubkey_hash : [u8, ..20] = ::crypto::hash160(key.pubkey.as_slice());
+    let filter : BloomFilter = BloomFilter::with_rate(10,0.000001,0,
+                                                      BloomUpdate::BloomUpdateAll as u8);
+    let mut hashes : Vec<Hash> = a.node.generate(1,p2pkh(&key));
+    let coinbase : Hash = a.node.get_block(hashes[0].clone()).unwrap().get_txs()[0].get_hash();
+    let mut stream : BufferedStream<TcpStream> = raw_connect(&a);
+    let mut getdata : GetData = GetData::new();
+    let mut merkleblock : MerkleBlock;
+    let tx : Tx;
+
+    hashes.push_all(a.node.generate(1,op_true()).as_slice());
+
+    for hash in hashes.iter()
+    {
+        getdata.add(InvEntry { typ: InvEntryType::MsgFilteredBlock, hash: hash.clone() });
+    }
+
+    raw_send(&mut stream,FilterLoad::new(filter).serialize(regtest().magic));
+    raw_send(&mut stream,FilterAdd::new(pubkey_hash.to_vec()).serialize(regtest().magic));
+    raw_send(&mut stream,getdata.serialize(regtest().magic));
+
+    merkleblock = MerkleBlock::unserialize(&raw_wait(&mut stream,"merkleblock"));
+
+    let (_, found) = merkleblock.get_tree().extract_matches().unwrap();
+
+    assert!(merkleblock.get_header().get_hash() == hashes[0]);
+    assert!(found == vec![(coinbase.clone(),0u)]);
+    assert!(::datatype::merkle::verify_proof(merkleblock.get_header(),merkleblock.get_tree(),
+                                             &coinbase));
+
+    tx = Tx::unserialize(&raw_wait(&mut stream,"tx"));
+
+    assert!(tx.get_transaction().get_hash() == coinbase);
+
+    merkleblock = MerkleBlock::unserialize(&raw_wait(&mut stream,"merkleblock"));
+
+    let (_, found) = merkleblock.get_tree().extract_matches().unwrap();
+
+    assert!(merkleblock.get_header().get_hash() == hashes[1]);
+    assert!(found.is_empty());
+}
+
+/* Adding to a filter the peer never loaded is a protocol violation */
+#[test]
+fn test_filteradd_without_filter()
+{
+    let a : TestNode = start_node();
+    let mut stream : BufferedStream<TcpStream> = raw_connect(&a);
+
+    raw_send(&mut stream,FilterAdd::new(vec![0xab]).serialize(regtest().magic));
+
+    loop
+    {
+        match stream.read_byte()
+        {
+            Ok(_)                                            => (),
+            Err(ref err) if err.kind == ::std::io::EndOfFile => break,
+            Err(err)                                         => panic!("Not closed: {}",err)
+        }
+    }
+}
+
+/* Peers sending indexes past the end of a block or truncated short ids are
+ * refused rather than crashing us.
+ */
+#[test]
+fn test_malformed_compact()
+{
+    let header : BlockHeader = BlockHeader::new(1,Hash::zero(),Hash::zero(),
+                                                Timespec { sec: 1296688602, nsec: 0 },
+                                                0x207fffff,0);
+    let request : BlockTxRequest = BlockTxRequest::new(header.get_hash(),vec![0,3]);
+    let data : Vec<u8> = GetBlockTxn::new(request).serialize(regtest().magic);
+    let mut getblocktxn : Marshalling = Marshalling::new();
+    let mut cmpctblock : Marshalling = Marshalling::new();
+
+    match GetBlockTxn::unserialize(&data.slice_from(HEADER_SIZE).to_vec())
+    {
+        Some(read) => assert!(*read.get_request().get_indexes() == vec![0u,3]),
+        None       => panic!("Valid getblocktxn refused")
+    }
+
+    getblocktxn.write_hash(&header.get_hash());
+    getblocktxn.write_varint(2);
+    getblocktxn.write_varint(0);
+    getblocktxn.write_varint(::std::u64::MAX);
+
+    assert!(GetBlockTxn::unserialize(&getblocktxn.get()).is_none());
+
+    cmpctblock.write_block_header(&header);
+    cmpctblock.write_uint64(0);
+    cmpctblock.write_varint(1);
+    cmpctblock.write_uint8(0);
+
+    assert!(CmpctBlock::unserialize(&cmpctblock.get()).is_none());
+}
+
+#[test]
+fn test_too_many_headers()
+{
+    let mut headers : Marshalling = Marshalling::new();
+
+    headers.write_varint((MAX_HEADERS_RESULTS+1) as u64);
+
+    assert!(Headers::unserialize(&headers.get()).is_none());
+}
+
+#[test]
+fn test_too_long_locator()
+{
+    let mut getheaders : Marshalling = Marshalling::new();
+
+    getheaders.write_uint32(::config::PROTOCOL_VERSION);
+    getheaders.write_varint((MAX_LOCATOR_SIZE+1) as u64);
+
+    assert!(GetHeaders::unserialize(&getheaders.get()).is_none());
+}
+
+/* Dense near the tip, then sparser down to the genesis block */
+#[test]
+fn test_locator()
+{
+    let chain : Chain = mature_chain(0);
+    let locator : Vec<Hash> = chain.get_locator();
+    let heights : Vec<u32> = locator.iter().map(|h| chain.get_active_height(h).unwrap()).collect();
+    let data : Vec<u8>;
+    let getheaders : GetHeaders;
+
+    assert!(heights == vec![100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 89, 85, 77, 61, 29, 0]);
+
+    assert!(chain.find_fork(locator.as_slice()) == 100);
+    assert!(chain.find_fork(&[Hash::zero(), chain.get_hash_at(50).unwrap().clone()]) == 50);
+    assert!(chain.find_fork(&[]) == 0);
+
+    data = GetHeaders::new(::config::PROTOCOL_VERSION,locator.clone(),Hash::zero())
+        .serialize(regtest().magic);
+    getheaders = GetHeaders::unserialize(&data.slice_from(HEADER_SIZE).to_vec()).unwrap();
+
+    assert!(*getheaders.get_locator() == locator);
+    assert!(getheaders.get_hash_stop().is_zero());
+}
+
+/* BIP155 addresses are at most 512 bytes, a message with a longer one is
+ * refused.
+ */
+#[test]
+fn test_addrv2_too_long()
+{
+    let mut addrv2 : Marshalling = Marshalling::new();
+
+    addrv2.write_varint(1);
+    addrv2.write_uint32(1296688602);
+    addrv2.write_varint(1);
+    addrv2.write_uint8(1);
+    addrv2.write_varbytes(Vec::from_elem(MAX_ADDRV2_SIZE+1,0u8).as_slice());
+    addrv2.write_uint16(8333);
+
+    assert!(AddrV2::unserialize(&addrv2.get()).is_none());
+}
+
+const ONION : &'static str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+
+/* IPv6 addresses fit in addr messages, the others only in addrv2 ones.  We
+ * keep the addresses of the networks we may reach.
+ */
+#[test]
+fn test_netaddr_networks()
+{
+    let ipv6 : SocketAddr = from_str("[2001:db8::1]:8333").unwrap();
+    let mut config : Config = Config::new(Path::new("."));
+    let mut data : Marshalling = Marshalling::new();
+    let netaddr : NetAddr;
+    let onion : NetAddrV2;
+    let other = |network : NetworkId, byte : u8, port : u16| {
+        let size : uint = NetAddrV2::expected_size(network as u8).unwrap();
+
+        NetAddrV2::new(None,0,network as u8,Vec::from_elem(size,byte),port)
+    };
+
+    data.write_netaddr(&NetAddr::new(None,0,Some(ipv6)),false);
+    netaddr = Unmarshalling::new(&data.get()).read_netaddr(false);
+
+    assert!(netaddr.addr == Some(ipv6));
+    assert!(NetAddrV2::from_netaddr(&netaddr).unwrap().to_socket_addr() == Some(ipv6));
+
+    onion = NetAddrV2::from_onion(ONION,8333,0,None).unwrap();
+
+    assert!(onion.is_valid_addr());
+    assert!(onion.to_netaddr().is_none());
+    assert!(other(NetworkId::NetI2P,1,8333).is_valid_addr());
+    assert!(!other(NetworkId::NetI2P,1,0).is_valid_addr());
+    assert!(!other(NetworkId::NetTorV2,1,8333).is_valid_addr());
+    assert!(!other(NetworkId::NetCJDNS,1,8333).is_valid_addr());
+    assert!(other(NetworkId::NetCJDNS,0xfc,8333).is_valid_addr());
+    assert!(!other(NetworkId::NetIPv6,0,8333).is_valid_addr());
+
+    /* Onion services only through the proxy */
+    assert!(::connector::destination(&onion,&config).is_none());
+
+    config.proxy = Some(from_str("127.0.0.1:9050").unwrap());
+
+    match ::connector::destination(&onion,&config)
+    {
+        Some(Destination::Host(ref host,8333)) if host.as_slice() == ONION => (),
+        dest => panic!("Not through the proxy: {}",dest)
+    }
+
+    assert!(::connector::destination(&other(NetworkId::NetI2P,1,8333),&config).is_none());
+}
+
+/* Onion addresses are kept and passed on like the others */
+#[test]
+fn test_addrv2_gossip()
+{
+    let a : TestNode = start_node();
+    let b : TestNode = start_node();
+    let services : Services = Service::NodeNetwork as Services;
+
+    b.node.set_local_address(NetAddrV2::from_onion(ONION,18444,services,None).unwrap());
+    a.node.connect(b.addr);
+
+    wait_until("onion address",|| a.node.get_network_sizes()[NetworkId::NetTorV3 as uint] == 1);
+
+    assert!(a.node.get_addresses().iter().any(|addr| addr.get_onion() == Some(ONION.to_string())));
+}
+
+/* Stand-in for the control port of tor.  It takes the password, as quoted
+ * on the wire, if there is one, otherwise the safe cookie of the file, and
+ * creates the service only once authenticated.
+ */
+fn mock_tor_control(cookie_path : Path, password : Option<String>, service_id : String)
+                    -> SocketAddr
+{
+    let mut acceptor : TcpAcceptor;
+    let addr : SocketAddr;
+
+    acceptor = TcpListener::bind(SocketAddr { ip: Ipv4Addr(127,0,0,1), port: 0 }).listen()
+                                                                               .unwrap();
+    addr = acceptor.socket_name().unwrap();
+
+    spawn(proc() {
+        let mut stream : BufferedStream<TcpStream>;
+        let cookie : Vec<u8> = File::open(&cookie_path).read_to_end().unwrap();
+        let server_nonce : Vec<u8> = Vec::from_elem(32,0x42u8);
+        let mut data : Vec<u8> = Vec::new();
+        let mut authenticated : bool = false;
+
+        stream = BufferedStream::new(acceptor.accept().unwrap());
+
+        loop
+        {
+            let line : String = match stream.read_line()
+            {
+                Ok(line) => line.as_slice().trim_right_chars(['\r','\n'].as_slice()).to_string(),
+                Err(_)   => return
+            };
+            let reply : String;
+
+            if line.as_slice().starts_with("PROTOCOLINFO")
+            {
+                reply = format!("250-PROTOCOLINFO 1\r\n\
+                                 250-AUTH METHODS={} COOKIEFILE=\"{}\"\r\n\
+                                 250-VERSION Tor=\"0.4.8.10\"\r\n\
+                                 250 OK",
+                                if password.is_some() { "HASHEDPASSWORD" }
+                                else                  { "COOKIE,SAFECOOKIE" },
+                                cookie_path.display());
+            }
+            else if line.as_slice().starts_with("AUTHCHALLENGE SAFECOOKIE ")
+            {
+                data = cookie.clone();
+                data.push_all(::crypto::from_hexstr(line.as_slice().slice_from(25)).unwrap()
+                                                                                    .as_slice());
+                data.push_all(server_nonce.as_slice());
+
+                reply = format!("250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}",
+                                ::crypto::to_hexstr(&::crypto::hmac_sha256(
+                                    b"Tor safe cookie authentication server-to-controller hash",
+                                    data.as_slice())),
+                                ::crypto::to_hexstr(server_nonce.as_slice()));
+            }
+            else if line.as_slice().starts_with("AUTHENTICATE")
+            {
+                let expected : String = match password
+                {
+                    Some(ref password) => format!("AUTHENTICATE {}",password),
+                    None               => format!("AUTHENTICATE {}",
+                        ::crypto::to_hexstr(&::crypto::hmac_sha256(
+                            b"Tor safe cookie authentication controller-to-server hash",
+                            data.as_slice())))
+                };
+
+                authenticated = line == expected;
+
+                reply = if authenticated { "250 OK".to_string() }
+                        else             { "515 Authentication failed".to_string() };
+            }
+            else if line.as_slice().starts_with("ADD_ONION") && authenticated
+            {
+                reply = format!("250-ServiceID={}\r\n250 OK",service_id);
+            }
+            else
+            {
+                reply = "514 Authentication required".to_string();
+            }
+
+            stream.write_str(reply.as_slice()).unwrap();
+            stream.write_str("\r\n").unwrap();
+            stream.flush().unwrap();
+        }
+    });
+
+    addr
+}
+
+#[test]
+fn test_tor_control()
+{
+    let dir : TempDir = TempDir::new("torcontrol").unwrap();
+    let cookie_path : Path = dir.path().join("control_auth_cookie");
+    let service_id : String = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd"
+                              .to_string();
+    let target : SocketAddr = SocketAddr { ip: Ipv4Addr(127,0,0,1), port: 18444 };
+    let mut control : TorControl;
+
+    File::create(&cookie_path).write(&[0x5a, ..32]).unwrap();
+
+    /* Safe cookie */
+    control = TorControl::connect(mock_tor_control(cookie_path.clone(),None,
+                                                   service_id.clone())).unwrap();
+
+    control.authenticate(None).unwrap();
+
+    assert!(control.add_onion(18444,target).unwrap() == service_id);
+
+    /* Password, that has to be quoted */
+    control = TorControl::connect(mock_tor_control(cookie_path.clone(),
+                                                   Some("\"pass\\\"word\"".to_string()),
+                                                   service_id.clone())).unwrap();
+
+    control.authenticate(Some("pass\"word")).unwrap();
+
+    assert!(control.add_onion(18444,target).unwrap() == service_id);
+
+    /* Wrong password */
+    control = TorControl::connect(mock_tor_control(cookie_path.clone(),
+                                                   Some("\"password\"".to_string()),
+                                                   service_id.clone())).unwrap();
+
+    match control.authenticate(Some("wrong"))
+    {
+        Err(TorControlError::ReplyError(515,_)) => (),
+        _                                       => panic!("Wrong password accepted")
+    }
+}
+
+/* Stand-in for a SOCKS5 proxy, that wants the credentials if there are any.
+ * It sends back the requests it accepts, and says hello on the connection
+ * instead of relaying it.
+ */
+fn mock_socks5(credentials : Option<(String,String)>) -> (SocketAddr, Receiver<Vec<u8>>)
+{
+    let mut acceptor : TcpAcceptor;
+    let addr : SocketAddr;
+    let (sender, receiver) = channel();
+
+    acceptor = TcpListener::bind(SocketAddr { ip: Ipv4Addr(127,0,0,1), port: 0 }).listen()
+                                                                               .unwrap();
+    addr = acceptor.socket_name().unwrap();
+
+    spawn(proc() {
+        let mut socket : TcpStream = acceptor.accept().unwrap();
+        let methods : Vec<u8>;
+        let mut request : Vec<u8>;
+        let count : u8;
+
+        assert!(socket.read_u8().unwrap() == 0x05);
+
+        count = socket.read_u8().unwrap();
+        methods = socket.read_exact(count as uint).unwrap();
+
+        match credentials
+        {
+            Some((ref user, ref pass)) if methods.contains(&0x02) =>
+            {
+                let user_len : u8;
+                let pass_len : u8;
+                let ok : bool;
+
+                socket.write(&[0x05,0x02]).unwrap();
+
+                assert!(socket.read_u8().unwrap() == 0x01);
+
+                user_len = socket.read_u8().unwrap();
+                ok = socket.read_exact(user_len as uint).unwrap() == user.as_bytes().to_vec();
+                pass_len = socket.read_u8().unwrap();
+
+                if !(socket.read_exact(pass_len as uint).unwrap() == pass.as_bytes().to_vec() && ok)
+                {
+                    socket.write(&[0x01,0x01]).unwrap();
+                    return;
+                }
+
+                socket.write(&[0x01,0x00]).unwrap();
+            },
+            None if methods.contains(&0x00) => socket.write(&[0x05,0x00]).unwrap(),
+            _ =>
+            {
+                socket.write(&[0x05,0xFF]).unwrap();
+                return;
+            }
+        }
+
+        request = socket.read_exact(4).unwrap();
+
+        match request[3]
+        {
+            0x01 => request.push_all(socket.read_exact(4).unwrap().as_slice()),
+            0x03 =>
+            {
+                let len : u8 = socket.read_u8().unwrap();
+
+                request.push(len);
+                request.push_all(socket.read_exact(len as uint).unwrap().as_slice());
+            },
+            0x04 => request.push_all(socket.read_exact(16).unwrap().as_slice()),
+            _    => panic!("Unknown address type")
+        }
+
+        request.push_all(socket.read_exact(2).unwrap().as_slice());
+
+        sender.send(request);
+
+        socket.write(&[0x05,0x00,0x00,0x01,127,0,0,1,0x20,0x8d]).unwrap();
+        socket.write(b"hello").unwrap();
+    });
+
+    (addr, receiver)
+}
+
+#[test]
+fn test_socks5_proxy()
+{
+    let timeout : Duration = Duration::seconds(TIMEOUT_S);
+    let dest : Destination = Destination::Ip(SocketAddr { ip: Ipv4Addr(1,2,3,4), port: 8333 });
+    let credentials : Option<(String,String)> = Some(("user".to_string(),"pass".to_string()));
+    let mut request : Vec<u8>;
+
+    /* No authentication */
+    let (addr, receiver) = mock_socks5(None);
+    let mut socket : TcpStream = Socks5Proxy::new(addr,None,false).connect(&dest,timeout)
+                                                                   .unwrap();
+
+    assert!(receiver.recv() == vec![0x05,0x01,0x00,0x01,1,2,3,4,0x20,0x8d]);
+    assert!(socket.read_exact(5).unwrap() == b"hello".to_vec());
+
+    /* Username and password (RFC1929) */
+    let (addr, receiver) = mock_socks5(credentials.clone());
+
+    socket = Socks5Proxy::new(addr,credentials.clone(),false).connect(&dest,timeout).unwrap();
+
+    assert!(receiver.recv() == vec![0x05,0x01,0x00,0x01,1,2,3,4,0x20,0x8d]);
+    assert!(socket.read_exact(5).unwrap() == b"hello".to_vec());
+
+    let (addr, _) = mock_socks5(credentials.clone());
+
+    match Socks5Proxy::new(addr,Some(("user".to_string(),"wrong".to_string())),false)
+                      .connect(&dest,timeout)
+    {
+        Err(ConnectorError::ProxyAuthFailed) => (),
+        _                                    => panic!("Wrong password accepted")
+    }
+
+    /* Hostname, resolved by the proxy */
+    let (addr, receiver) = mock_socks5(None);
+
+    socket = Socks5Proxy::new(addr,None,false)
+                         .connect(&Destination::Host(ONION.to_string(),8333),timeout).unwrap();
+
+    request = vec![0x05,0x01,0x00,0x03,ONION.len() as u8];
+    request.push_all(ONION.as_bytes());
+    request.push_all(&[0x20,0x8d]);
+
+    assert!(receiver.recv() == request);
+    assert!(socket.read_exact(5).unwrap() == b"hello".to_vec());
+}
+
+/* Base64 of "user:password" */
+const RPC_AUTH : &'static str = "dXNlcjpwYXNzd29yZA==";
+
+/* A node with its RPC server on a port of its own.  The receiver gets what
+ * the stop method sends.
+ */
+fn start_rpc_node(rest : bool) -> (TestNode, SocketAddr, Receiver<()>)
+{
+    let mut config : Config = Config::new(Path::new("."));
+    let node : TestNode;
+    let addr : SocketAddr;
+    let (stop_sender, stop_receiver) = channel();
+
+    config.rpc_port = Some(0);
+    config.rpc_user = Some("user".to_string());
+    config.rpc_password = Some("password".to_string());
+    config.rest = rest;
+
+    node = start_node_with(config);
+    addr = ::rpc::start(&node.node,stop_sender).unwrap();
+
+    (node, addr, stop_receiver)
+}
+
+/* Status and body, None if the server closed the connection instead */
+fn read_http_response(stream : &mut BufferedStream<TcpStream>) -> Option<(u16, Vec<u8>)>
+{
+    let status : u16;
+    let mut length : uint = 0;
+
+    status = match stream.read_line()
+    {
+        Ok(line) => from_str(line.as_slice().split(' ').nth(1).unwrap()).unwrap(),
+        Err(_)   => return None
+    };
+
+    loop
+    {
+        let line : String = stream.read_line().unwrap();
+        let header : &str = line.as_slice().trim_right();
+
+        if header.is_empty()
+        {
+            break;
+        }
+
+        if header.starts_with("Content-Length: ")
+        {
+            length = from_str(header.slice_from(16)).unwrap();
+        }
+    }
+
+    Some((status, stream.read_exact(length).unwrap()))
+}
+
+/* The server may close the connection before it read all of the request */
+fn http_request(addr : SocketAddr, request : &str) -> Option<(u16, Vec<u8>)>
+{
+    let mut stream : BufferedStream<TcpStream>;
+
+    stream = BufferedStream::new(TcpStream::connect(addr).unwrap());
+
+    if stream.write_str(request).is_err() || stream.flush().is_err()
+    {
+        return None;
+    }
+
+    read_http_response(&mut stream)
+}
+
+fn http_status(addr : SocketAddr, request : &str) -> Option<u16>
+{
+    http_request(addr,request).map(|(status, _)| status)
+}
+
+fn http_post(auth : &str, body : &str) -> String
+{
+    format!("POST / HTTP/1.1\r\nAuthorization: Basic {}\r\nContent-Length: {}\r\n\r\n{}",
+            auth,body.len(),body)
+}
+
+/* Status and reply of a call with our credentials */
+fn rpc_call(addr : SocketAddr, method : &str, params : &str) -> (u16, Json)
+{
+    let body : String = format!("{{\"id\":1,\"method\":\"{}\",\"params\":{}}}",method,params);
+    let (status, reply) = http_request(addr,http_post(RPC_AUTH,body.as_slice()).as_slice())
+                          .unwrap();
+
+    (status, json::from_str(String::from_utf8(reply).unwrap().as_slice()).unwrap())
+}
+
+fn rpc_error_code(reply : &Json) -> Option<i64>
+{
+    reply.find("error").and_then(|err| err.find("code")).and_then(|code| code.as_i64())
+}
+
+#[test]
+fn test_rpc_auth()
+{
+    let (_node, addr, _stop) = start_rpc_node(false);
+    let body : &str = "{\"method\":\"getblockchaininfo\",\"params\":[]}";
+
+    assert!(http_status(addr,format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                                     body.len(),body).as_slice()) == Some(401));
+    assert!(http_status(addr,http_post("dXNlcjp3cm9uZw==",body).as_slice()) == Some(401));
+    assert!(http_status(addr,http_post("not base64",body).as_slice()) == Some(401));
+
+    let (status, reply) = rpc_call(addr,"getblockchaininfo","[]");
+
+    assert!(status == 200);
+    assert!(reply.find("result").unwrap().find("chain").unwrap().as_string()
+            == Some("regtest"));
+}
+
+/* Without a password, clients read the credentials in a cookie file of the
+ * data directory.
+ */
+#[test]
+fn test_rpc_cookie()
+{
+    let mut config : Config = Config::new(Path::new("."));
+    let body : &str = "{\"method\":\"getblockchaininfo\",\"params\":[]}";
+    let (stop_sender, _stop) = channel();
+    let node : TestNode;
+    let addr : SocketAddr;
+    let cookie : String;
+
+    config.rpc_port = Some(0);
+
+    node = start_node_with(config);
+    addr = ::rpc::start(&node.node,stop_sender).unwrap();
+
+    cookie = File::open(&::common::cookie_path(node.datadir.path(),regtest().name))
+        .read_to_string().unwrap();
+
+    assert!(cookie.as_slice().starts_with("__cookie__:"));
+    assert!(http_status(addr,http_post(cookie.as_bytes().to_base64(STANDARD).as_slice(),
+                                       body).as_slice()) == Some(200));
+    assert!(http_status(addr,http_post(RPC_AUTH,body).as_slice()) == Some(401));
+}
+
+#[test]
+fn test_rpc_http()
+{
+    let (_node, addr, _stop) = start_rpc_node(false);
+    let body : &str = "{\"method\":\"getrawmempool\",\"params\":[]}";
+    let mut stream : BufferedStream<TcpStream>;
+    let mut long : String = String::from_str("GET / HTTP/1.1\r\nX-Long: ");
+
+    /* Two requests on the same connection */
+    stream = BufferedStream::new(TcpStream::connect(addr).unwrap());
+
+    for _ in range(0,2u)
+    {
+        stream.write_str(http_post(RPC_AUTH,body).as_slice()).unwrap();
+        stream.flush().unwrap();
+
+        let (status, reply) = read_http_response(&mut stream).unwrap();
+        let reply : Json = json::from_str(String::from_utf8(reply).unwrap().as_slice()).unwrap();
+
+        assert!(status == 200);
+        assert!(reply.find("result").unwrap().as_list().unwrap().is_empty());
+    }
+
+    assert!(http_status(addr,format!("GET / HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n",
+                                     RPC_AUTH).as_slice()) == Some(405));
+    assert!(http_status(addr,format!("POST /wallet HTTP/1.1\r\nAuthorization: Basic {}\r\n\
+                                      Content-Length: 0\r\n\r\n",RPC_AUTH).as_slice())
+            == Some(404));
+
+    /* Not HTTP, a line too long and a body too large close the connection */
+    assert!(http_status(addr,"HELLO\r\n\r\n").is_none());
+
+    for _ in range(0,10000u)
+    {
+        long.push('x');
+    }
+
+    long.push_str("\r\n\r\n");
+
+    assert!(http_status(addr,long.as_slice()).is_none());
+    assert!(http_status(addr,"POST / HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n")
+            .is_none());
+}
+
+#[test]
+fn test_rpc_methods()
+{
+    let (node, addr, stop) = start_rpc_node(false);
+    let hashes : Vec<Hash> = node.node.generate(3,op_true());
+
+    let (status, reply) = rpc_call(addr,"getblockchaininfo","[]");
+    let info : &Json = reply.find("result").unwrap();
+
+    assert!(status == 200);
+    assert!(info.find("blocks").unwrap().as_i64() == Some(3));
+    assert!(info.find("bestblockhash").unwrap().as_string()
+            == Some(format!("{}",hashes[2]).as_slice()));
+
+    /* Parameters by name */
+    let (status, reply) = rpc_call(addr,"getrawmempool","{\"verbose\":false}");
+
+    assert!(status == 200);
+    assert!(reply.find("result").unwrap().as_list().unwrap().is_empty());
+
+    let (_, reply) = rpc_call(addr,"getrawmempool","{\"verbosity\":false}");
+
+    assert!(rpc_error_code(&reply) == Some(-8));
+
+    let (status, reply) = rpc_call(addr,"getwalletinfo","[]");
+
+    assert!(status == 404);
+    assert!(rpc_error_code(&reply) == Some(-32601));
+
+    let (_, reply) = rpc_call(addr,"getrawtransaction","[\"00\"]");
+
+    assert!(rpc_error_code(&reply) == Some(-8));
+
+    let (_, reply) = rpc_call(addr,"sendrawtransaction","[\"0100\"]");
+
+    assert!(rpc_error_code(&reply) == Some(-22));
+
+    let (_, reply) = rpc_call(addr,"addnode","[\"[::1]:18444\", \"onetry\"]");
+
+    assert!(reply.find("error").unwrap().is_null());
+
+    let (_, reply) = rpc_call(addr,"addnode","[\"[::1]:port\", \"onetry\"]");
+
+    assert!(rpc_error_code(&reply) == Some(-8));
+
+    /* Not JSON */
+    assert!(http_status(addr,http_post(RPC_AUTH,"{").as_slice()) == Some(400));
+
+    let (status, _) = rpc_call(addr,"stop","[]");
+
+    assert!(status == 200);
+
+    stop.recv();
+}
+
+/* Onion nodes are dialed by hostname through the proxy, and not at all
+ * without one.
+ */
+/* Status and body of a REST request, which needs no credentials */
+fn rest_get(addr : SocketAddr, path : &str) -> (u16, Vec<u8>)
+{
+    http_request(addr,format!("GET /rest/{} HTTP/1.1\r\n\r\n",path).as_slice()).unwrap()
+}
+
+fn rest_status(addr : SocketAddr, path : &str) -> u16
+{
+    let (status, _) = rest_get(addr,path);
+
+    status
+}
+
+/* The body of a successful request in hex or JSON, without its new line */
+fn rest_text(addr : SocketAddr, path : &str) -> String
+{
+    let (status, body) = rest_get(addr,path);
+    let text : String = String::from_utf8(body).unwrap();
+
+    assert!(status == 200);
+
+    text.as_slice().trim_right().to_string()
+}
+
+fn rest_json(addr : SocketAddr, path : &str) -> Json
+{
+    json::from_str(rest_text(addr,path).as_slice()).unwrap()
+}
+
+/* The binary format of a request, and the same in hex */
+fn rest_bin(addr : SocketAddr, path : &str) -> Vec<u8>
+{
+    let (status, body) = rest_get(addr,format!("{}.bin",path).as_slice());
+
+    assert!(status == 200);
+    assert!(rest_text(addr,format!("{}.hex",path).as_slice())
+            == ::crypto::to_hexstr(body.as_slice()));
+
+    body
+}
+
+fn json_str(json : &Json, key : &str) -> String
+{
+    json.find(key).unwrap().as_string().unwrap().to_string()
+}
+
+#[test]
+fn test_rest()
+{
+    let (node, addr, _stop) = start_rpc_node(true);
+    let key : Key = new_key();
+    let hashes : Vec<Hash> = node.node.generate(::consensus::COINBASE_MATURITY as uint+1,
+                                                p2pkh(&key));
+    let block : Block = node.node.get_block(hashes[0].clone()).unwrap();
+    let coinbase : Hash = block.get_txs()[0].get_hash();
+    let last : Hash = node.node.get_block(hashes[1].clone()).unwrap().get_txs()[0].get_hash();
+    let tx : Transaction = spend_coinbase(&block,&key);
+    let mut marshalling : Marshalling = Marshalling::new();
+    let mut unmarshalling : Unmarshalling;
+    let mut json : Json;
+    let mut data : Vec<u8>;
+    let path : String;
+
+    match node.node.add_tx(node.addr,tx.clone())
+    {
+        ChainManagerReply::ChainMngTxAccepted => (),
+        reply                                 => panic!("Tx not accepted: {}",reply)
+    }
+
+    /* Blocks */
+    marshalling.write_block(&block);
+
+    assert!(rest_bin(addr,format!("block/{}",hashes[0]).as_slice()) == marshalling.get());
+
+    json = rest_json(addr,format!("block/{}.json",hashes[0]).as_slice());
+
+    assert!(json_str(&json,"hash") == format!("{}",hashes[0]));
+    assert!(json.find("height").unwrap().as_u64() == Some(1));
+    assert!(json_str(&json.find("tx").unwrap().as_list().unwrap()[0],"txid")
+            == format!("{}",coinbase));
+
+    json = rest_json(addr,format!("block/notxdetails/{}.json",hashes[0]).as_slice());
+
+    assert!(json.find("tx").unwrap().as_list().unwrap()[0].as_string()
+            == Some(format!("{}",coinbase).as_slice()));
+
+    /* Headers */
+    data = rest_bin(addr,format!("headers/2/{}",hashes[0]).as_slice());
+    unmarshalling = Unmarshalling::new(&data);
+
+    assert!(unmarshalling.read_block_header().get_hash() == hashes[0]);
+    assert!(unmarshalling.read_block_header().get_hash() == hashes[1]);
+    assert!(unmarshalling.remaining() == 0);
+
+    json = rest_json(addr,format!("headers/2/{}.json",hashes[0]).as_slice());
+
+    assert!(json.as_list().unwrap().len() == 2);
+    assert!(json.as_list().unwrap()[1].find("height").unwrap().as_u64() == Some(2));
+
+    /* Mempool txs */
+    marshalling = Marshalling::new();
+    marshalling.write_transaction(&tx);
+
+    assert!(rest_bin(addr,format!("tx/{}",tx.get_hash()).as_slice()) == marshalling.get());
+    assert!(json_str(&rest_json(addr,format!("tx/{}.json",tx.get_hash()).as_slice()),"txid")
+            == format!("{}",tx.get_hash()));
+
+    json = rest_json(addr,"mempool/info.json");
+
+    assert!(json.find("size").unwrap().as_u64() == Some(1));
+
+    /* Unspent outputs, the spent coinbase only counts as spent with the
+     * mempool.
+     */
+    path = format!("getutxos/{}-0/{}-0/{}-1",coinbase,last,last);
+    data = rest_bin(addr,path.as_slice());
+    unmarshalling = Unmarshalling::new(&data);
+
+    assert!(unmarshalling.read_uint32() == hashes.len() as u32);
+    assert!(unmarshalling.read_hash() == *hashes.last().unwrap());
+    assert!(unmarshalling.read_varbytes() == vec![0x03u8]);
+    assert!(unmarshalling.read_varint() == 2);
+    assert!(unmarshalling.read_uint32() == 0 && unmarshalling.read_uint32() == 1);
+
+    json = rest_json(addr,format!("{}.json",path).as_slice());
+
+    assert!(json_str(&json,"bitmap") == "110".to_string());
+    assert!(json.find("utxos").unwrap().as_list().unwrap().len() == 2);
+    assert!(json.find("chainHeight").unwrap().as_u64() == Some(hashes.len() as u64));
+
+    json = rest_json(addr,format!("getutxos/checkmempool/{}-0/{}-0.json",coinbase,
+                                  tx.get_hash()).as_slice());
+
+    assert!(json_str(&json,"bitmap") == "01".to_string());
+
+    /* Errors, and paths that only start like the endpoints */
+    assert!(rest_status(addr,format!("block/{}",hashes[0]).as_slice()) == 404);
+    assert!(rest_status(addr,"block/00.json") == 400);
+    assert!(rest_status(addr,"getutxos.json") == 400);
+    assert!(rest_status(addr,format!("getutxosX/{}-0.json",coinbase).as_slice()) == 404);
+    assert!(rest_status(addr,"mempool/infoX.json") == 404);
+    assert!(rest_status(addr,"mempool/info.bin") == 404);
+}
+
+#[test]
+fn test_addnode_onion()
+{
+    let (proxy, receiver) = mock_socks5(None);
+    let mut config : Config = Config::new(Path::new("."));
+    let (stop_sender, _stop) = channel();
+    let params : String = format!("[\"{}:18444\", \"onetry\"]",ONION);
+    let mut request : Vec<u8>;
+    let node : TestNode;
+    let addr : SocketAddr;
+
+    config.rpc_port = Some(0);
+    config.rpc_user = Some("user".to_string());
+    config.rpc_password = Some("password".to_string());
+    config.proxy = Some(proxy);
+
+    node = start_node_with(config);
+    addr = ::rpc::start(&node.node,stop_sender).unwrap();
+
+    let (_, reply) = rpc_call(addr,"addnode",params.as_slice());
+
+    assert!(reply.find("error").unwrap().is_null());
+
+    request = vec![0x05,0x01,0x00,0x03,ONION.len() as u8];
+    request.push_all(ONION.as_bytes());
+    request.push_all(&[0x48,0x0c]);
+
+    assert!(receiver.recv() == request);
+
+    let (_node, addr, _stop) = start_rpc_node(false);
+    let (_, reply) = rpc_call(addr,"addnode",params.as_slice());
+
+    assert!(rpc_error_code(&reply) == Some(-8));
+}
+
+#[test]
+fn test_config_services()
+{
+    let mut config : Config = Config::new(Path::new("."));
+    let bloom : Services = Service::NodeBloom as Services;
+    let filters : Services = Service::NodeCompactFilters as Services;
+    let v2 : Services = Service::NodeP2PV2 as Services;
+
+    assert!(config.get_services() & (bloom | filters | v2) == bloom | filters | v2);
+
+    config.set("peer-bloom-filters","false").unwrap();
+    config.set("peer-block-filters","false").unwrap();
+    config.set("peer-v2-transport","false").unwrap();
+
+    assert!(config.get_services() == Service::NodeNetwork as Services);
+
+    config.set("peer-v2-transport","true").unwrap();
+
+    assert!(config.peer_v2_transport);
+    assert!(config.get_services() & v2 == v2);
+    assert!(config.set("peer-bloom-filters","maybe").is_err());
+}
+
+/* A logger writing to a file of the directory, with the level and the file
+ * limits given.
+ */
+fn file_logger(dir : &TempDir, name : &str, level : LogLevel, max_size : u64) -> NodeLogger
+{
+    let mut config : Config = Config::new(Path::new("."));
+
+    config.log_level = level;
+    config.log_file = Some(dir.path().join(name));
+    config.log_file_max_size = max_size;
+    config.log_file_count = 2;
+
+    NodeLogger::new(&config).unwrap()
+}
+
+fn read_log(dir : &TempDir, name : &str) -> String
+{
+    File::open(&dir.path().join(name)).read_to_string().unwrap()
+}
+
+fn log_hash(i : u8) -> Hash
+{
+    Hash::from_digest(::crypto::sha256(&[i]))
+}
+
+fn logged(log : &String, hash : &Hash) -> bool
+{
+    log.as_slice().contains(format!("{}",hash).as_slice())
+}
+
+/* Each logger keeps its level, categories and peers, whatever the others of
+ * the process do.
+ */
+#[test]
+fn test_log_levels()
+{
+    let dir : TempDir = TempDir::new("log").unwrap();
+    let debug : NodeLogger = file_logger(&dir,"debug.log",LogLevel::LogLevelDebug,0);
+    let error : NodeLogger = file_logger(&dir,"error.log",LogLevel::LogLevelError,0);
+    let peer : SocketAddr = SocketAddr { ip: Ipv4Addr(10,0,0,1), port: 8333 };
+    let other : SocketAddr = SocketAddr { ip: Ipv4Addr(10,0,0,2), port: 8333 };
+    let log : String;
+
+    debug.install();
+
+    ::logger::log_mempool_accepted(&log_hash(0),1,100);
+    ::logger::log_mempool_removed(&log_hash(1));
+
+    error.install();
+
+    ::logger::log_mempool_accepted(&log_hash(2),1,100);
+    ::logger::log_chain_block_rejected(&log_hash(3),&ValidationError::BlockBadMerkleRoot);
+    ::logger::log_listen_error(&peer);
+
+    debug.set_level(LogLevel::LogLevelTrace);
+    debug.set_peers(vec![peer.ip]);
+
+    assert!(debug.get_level() == LogLevel::LogLevelTrace);
+    assert!(error.get_level() == LogLevel::LogLevelError);
+    assert!(debug.get_peers() == vec![peer.ip] && error.get_peers().is_empty());
+
+    debug.install();
+
+    ::logger::log_mempool_removed(&log_hash(4));
+    ::logger::log_orphan_added(&log_hash(5),&peer,1);
+    ::logger::log_orphan_added(&log_hash(6),&other,1);
+
+    debug.set_categories(::logger::parse_categories("chain").unwrap());
+
+    assert!(error.get_categories() == ::logger::DEFAULT_LOG_CATEGORIES);
+
+    ::logger::log_mempool_removed(&log_hash(7));
+    ::logger::log_chain_new_tip(&log_hash(8),1);
+
+    log = read_log(&dir,"debug.log");
+
+    assert!(logged(&log,&log_hash(0)) && !logged(&log,&log_hash(1)));
+    assert!(logged(&log,&log_hash(4)) && logged(&log,&log_hash(5)) && !logged(&log,&log_hash(6)));
+    assert!(!logged(&log,&log_hash(7)) && logged(&log,&log_hash(8)));
+
+    log = read_log(&dir,"error.log");
+
+    assert!(!logged(&log,&log_hash(2)) && !logged(&log,&log_hash(3)));
+    assert!(log.as_slice().contains(format!("Cannot listen on {}",peer).as_slice()));
+}
+
+/* Past its maximum size, the file is moved to .1, the one before to .2 and
+ * the one before that is gone.
+ */
+#[test]
+fn test_log_rotation()
+{
+    let dir : TempDir = TempDir::new("log").unwrap();
+    let logger : NodeLogger = file_logger(&dir,"node.log",LogLevel::LogLevelInfo,200);
+
+    logger.install();
+
+    for i in range(0u8,5)
+    {
+        ::logger::log_chain_new_tip(&log_hash(i),i as u32);
+    }
+
+    assert!(logged(&read_log(&dir,"node.log"),&log_hash(4)));
+    assert!(logged(&read_log(&dir,"node.log.1"),&log_hash(3)));
+    assert!(logged(&read_log(&dir,"node.log.2"),&log_hash(2)));
+    assert!(!dir.path().join("node.log.3").exists());
+
+    for name in ["node.log", "node.log.1", "node.log.2"].iter()
+    {
+        assert!(read_log(&dir,*name).len() <= 200);
+    }
+}
+
+#[test]
+fn test_ellswift()
+{
+    /* u = t = 0, u and t not reduced, any u and t, and u^3+t^2+7 = 0 */
+    let decodings : [(&str, &str), ..4] =
+        [("00000000000000000000000000000000000000000000000000000000000000000000000000000000\
+           000000000000000000000000000000000000000000000000",
+          "edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c"),
+         ("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff\
+           ffffffffffffffffffffffffffffffffffffffffffffffff",
+          "a9d2410259b9697cce4599ef2f96fbe8b47d53dcdff28ba28810f0607b89a740"),
+         ("972bb7dbf7b77817886cd457120143812f345ae05a24a1b365c7db4b9238cb32d81f75bc2177330a\
+           b741d929a97d0c8d037ff05a9d0dc59ad24ac36ecc340263",
+          "d89d54f33e05223f250bcc3dd9a3639e4d618ac127c111d34ce08e534586345d"),
+         ("0000000000000000000000000000000000000000000000000000000000000005350ae3b48047adac\
+           deea49fb8a0b289a94f726801078408aba79631fa7a1b6ba",
+          "aaee74bef85ee588ed43aa2a45b30e457127b97a48a8cfdff4de85545d2f1ccf")];
+    let generator = ::secp256k1::Point::generator();
+    let minus_generator : ::secp256k1::Point;
+
+    for &(encoding, x) in decodings.iter()
+    {
+        assert!(::secp256k1::ellswift_decode(from_hex(encoding).as_slice()).to_bytes().as_slice()
+                == from_hex(x).as_slice());
+    }
+
+    /* The encodings are random, but all of the same key */
+    for _ in range(0u,8)
+    {
+        let secret : [u8, ..32] = ::secp256k1::random_secret();
+        let encoding : [u8, ..64] = ::secp256k1::ellswift_create(&secret);
+
+        assert!(::secp256k1::ellswift_decode(&encoding)
+                == *generator.mul(&secret).unwrap().get_x());
+    }
+
+    /* (n-1)G = -G, 0G and nG are the point at infinity */
+    minus_generator = generator.mul(&from_hex32(
+        "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364140")).unwrap();
+
+    assert!(*minus_generator.get_x() == *generator.get_x());
+    assert!(*minus_generator.get_y() == generator.get_y().neg());
+    assert!(generator.mul(&[0u8, ..32]).is_none());
+    assert!(generator.mul(&from_hex32(
+        "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141")).is_none());
+}
+
+#[test]
+fn test_v2_session()
+{
+    let initiator_secret : [u8, ..32] =
+        from_hex32("59eb3cb130074f7b1ddb99c8fe14f27e1dbca735bb3da82e1ff0305340a32419");
+    let initiator_ellswift : [u8, ..64] =
+        from_hex64("cd155046155967dabdc5d15f10014098efbfc41ccc2d7c875a0fed629253f032\
+                    a53653005c6552cba59d2ab2d7da5e103de68e400ac6481758a0eeb05848700f");
+    let responder_secret : [u8, ..32] =
+        from_hex32("6dcde155f1f900e157d25bb5c24b1a54d71ea35b78661ed9b1a8be3532c3d4f5");
+    let responder_ellswift : [u8, ..64] =
+        from_hex64("1e57b933b0a78203e21d41cc4b16d731b255b04058d48a4ac2731f0089312129\
+                    454f00a065a5fff67305d63ec0650d4344dc619348f597e3e569197e84360f76");
+    let shared_x : [u8, ..32] =
+        from_hex32("5b977d599e112b87623ac10cadeabf2ec87c6d1ff5a0100671c27d5f80bc518b");
+    let terminators : Vec<u8> =
+        from_hex("177feb098bab2bd9e4fc56178bcf64a2d436d032fb93ae00efb36cd9e0f0b0a6");
+    let garbage : Vec<u8> = Vec::from_fn(10,|i| i as u8);
+    /* Across the rekeying of both ciphers, every 224 packets */
+    let packets : [(uint, &str), ..7] =
+        [(0,   "e0e0cd9779384bf4227baeea1e193d2ab3e7b193"),
+         (1,   "9100de00b54b3fcc728cf7634f7999d7042760531f"),
+         (223, "6a8708d423ae208e8566ff0ffbed01b3d38dd317104adac33d9b"),
+         (224, "e6d48f4ce5eb1cd4b9be25234b11ada47e4988b0"),
+         (447, "cd45ac5698cdad325c415d1a5e69c4930602bcb7f361e8128ed9"),
+         (448, "d33b5a7c64bf8664d9387ef530040ac82bbb3957"),
+         (449, "44ee5c34e7a960930d1d4560f00739e688eeed99c4")];
+    let mut initiator : V2Session;
+    let mut responder : V2Session;
+    let mut forged : Vec<u8>;
+
+    assert!(::secp256k1::ellswift_xdh(&initiator_secret,&responder_ellswift) == Some(shared_x));
+    assert!(::secp256k1::ellswift_xdh(&responder_secret,&initiator_ellswift) == Some(shared_x));
+
+    initiator = V2Handshake::from_keys(true,initiator_secret,initiator_ellswift,garbage.clone())
+        .complete(&responder_ellswift,regtest().magic).unwrap();
+    responder = V2Handshake::from_keys(false,responder_secret,responder_ellswift,Vec::new())
+        .complete(&initiator_ellswift,regtest().magic).unwrap();
+
+    assert!(initiator.session_id == from_hex32(
+        "3667b524f966df39386358f3563421182b3b2c388f3993cac5818b42cf0eb67d"));
+    assert!(responder.session_id == initiator.session_id);
+    assert!(initiator.send_terminator.as_slice() == terminators.slice_to(16));
+    assert!(initiator.recv_terminator.as_slice() == terminators.slice_from(16));
+    assert!(responder.send_terminator == initiator.recv_terminator);
+    assert!(responder.recv_terminator == initiator.send_terminator);
+
+    responder.recv.set_garbage(garbage);
+
+    for i in range(0u,450)
+    {
+        let contents : Vec<u8> = Vec::from_elem(i%7,i as u8);
+        let packet : Vec<u8> = initiator.send.encrypt_packet(contents.as_slice(),i == 1);
+
+        match packets.iter().find(|&&(j, _)| j == i)
+        {
+            Some(&(_, hex)) => assert!(packet == from_hex(hex)),
+            None            => ()
+        }
+
+        assert!(responder.recv.decrypt_length(packet.as_slice()) == contents.len());
+
+        /* The first one is the version packet, the second one a decoy */
+        match responder.recv.decrypt_packet(packet.slice_from(LENGTH_SIZE))
+        {
+            Some((ignore, plain)) => assert!(ignore == (i < 2) && plain == contents),
+            None                  => panic!("packet {} not authentic",i)
+        }
+    }
+
+    forged = initiator.send.encrypt_packet(&[1u8, 2, 3],false);
+    forged[LENGTH_SIZE] ^= 1;
+
+    assert!(responder.recv.decrypt_length(forged.as_slice()) == 3);
+    assert!(responder.recv.decrypt_packet(forged.slice_from(LENGTH_SIZE)).is_none());
+}
+
+/* The genesis block of testnet */
+const TESTNET_GENESIS_HEX : &'static str =
+    "0100000000000000000000000000000000000000000000000000000000000000000000003ba3\
+    edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff001d\
+    1aa4ae1801010000000100000000000000000000000000000000000000000000000000000000\
+    00000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039\
+    204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f7574\
+    20666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a6\
+    7130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c38\
+    4df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+/* A child of it with a coinbase, a tx and all kinds of scripts */
+const TEST_BLOCK_HEX : &'static str =
+    "0100000043497fd7f826957108f4a30fd9cec3aeba79972084e90ead01ea3309000000005d64\
+    6eac7bf99ea13e53224fc3440e1c638de1eb033673ccd1334900058e1e7120e7494dffff7f20\
+    0200000002010000000100000000000000000000000000000000000000000000000000000000\
+    00000000ffffffff03510101ffffffff0400f2052a010000001976a914000102030405060708\
+    090a0b0c0d0e0f1011121388ac00000000000000000d6a0b68656c6c6f20776f726c64000000\
+    00000000000000000000000000001976a914000102030405060708090a0b0c0d0e0f10111213\
+    88ac00000000010000000192c1f28f4510dbf0f50c9b43dc017aad4601200fe0a341ea1df17b\
+    929aac7ccc000000000151ffffffff0200e1f505000000001600141415161718191a1b1c1d1e\
+    1f202122232425262700e1f50500000000015100000000";
+
+/* The testnet genesis block of the BIP158 test vectors, then a block of our
+ * own with every kind of script: duplicated, empty, OP_RETURN and spent.
+ */
+#[test]
+fn test_block_filter()
+{
+    let genesis : Block = Unmarshalling::new(&from_hex(TESTNET_GENESIS_HEX)).read_block();
+    let block : Block = Unmarshalling::new(&from_hex(TEST_BLOCK_HEX)).read_block();
+    let p2pkh : Vec<u8> = from_hex("76a914000102030405060708090a0b0c0d0e0f1011121388ac");
+    let p2wpkh : Vec<u8> = from_hex("00141415161718191a1b1c1d1e1f2021222324252627");
+    let p2sh : Vec<u8> = from_hex("a91428292a2b2c2d2e2f303132333435363738393a3b87");
+    let spent : [Script, ..2] = [Script::from_bytes(p2sh.clone()), Script::new()];
+    let genesis_filter : BlockFilter;
+    let genesis_header : Hash;
+    let filter : BlockFilter;
+
+    assert!(genesis.get_hash() == Hash::from_hexstr(
+        "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943").unwrap());
+
+    genesis_filter = BlockFilter::build_basic(&genesis,&[]);
+    genesis_header = genesis_filter.compute_header(&Hash::zero());
+
+    assert!(*genesis_filter.get_encoded() == from_hex("019dfca8"));
+    assert!(genesis_header == Hash::from_hexstr(
+        "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750").unwrap());
+
+    filter = BlockFilter::build_basic(&block,&spent);
+
+    assert!(*filter.get_encoded() == from_hex("04c3063cf48966118b6d3190"));
+    assert!(filter.compute_header(&genesis_header) == Hash::from_hexstr(
+        "40a8d106f4b1d851c014d67f3ae03fe3317f5e3c657160469a148b64641c25fb").unwrap());
+
+    for element in [p2pkh, p2wpkh, p2sh, vec![0x51u8]].iter()
+    {
+        assert!(filter.contains(element.as_slice()));
+        assert!(!genesis_filter.contains(element.as_slice()));
+    }
+
+    assert!(!filter.contains(from_hex("6a0b68656c6c6f20776f726c64").as_slice()));
+    assert!(!filter.contains(&[]));
+
+    /* A filter of nothing */
+    assert!(!BlockFilter::new(FILTER_TYPE_BASIC,block.get_hash(),from_hex("00"))
+            .contains(from_hex("76a914000102030405060708090a0b0c0d0e0f1011121388ac").as_slice()));
+}
+
+/* SipHash-2-4 of its reference implementation, with the key 00 01 .. 0f and
+ * the messages 00 01 .. of many sizes, then the short ids of BIP152.
+ */
+#[test]
+fn test_short_ids()
+{
+    let message : Vec<u8> = Vec::from_fn(63,|i| i as u8);
+    let hashes : [(uint, u64), ..7] = [(0,  0x726fdb47dd0e0e31),
+                                       (1,  0x74f839c593dc67fd),
+                                       (7,  0xab0200f58b01d137),
+                                       (8,  0x93f5f5799a932462),
+                                       (15, 0xa129ca6149be45e5),
+                                       (16, 0x3f2acc7f57c29bdb),
+                                       (63, 0x958a324ceb064572)];
+    let block : Block = Unmarshalling::new(&from_hex(TEST_BLOCK_HEX)).read_block();
+    let cmpct : HeaderAndShortIds = HeaderAndShortIds::from_block(&block,0x0123456789abcdef);
+
+    for &(size, hash) in hashes.iter()
+    {
+        assert!(::crypto::siphash24(0x0706050403020100,0x0f0e0d0c0b0a0908,
+                                    message.slice_to(size)) == hash);
+    }
+
+    assert!(cmpct.get_keys() == (0xdbb8f388e758d50b, 0x109e204f4bf5afc7));
+    assert!(block.get_txs()[1].get_hash() == Hash::from_hexstr(
+        "bf8bb18ce54f4d3106f89a37deea707996ee68c2c7a23a7415e5a1d8e67ab5d0").unwrap());
+    assert!(*cmpct.get_short_ids() == vec![0x68bcf692cdbeu64]);
+    assert!(short_id(cmpct.get_keys(),&block.get_txs()[1].get_hash()) == 0x68bcf692cdbe);
+    assert!(cmpct.get_prefilled().len() == 1 && cmpct.get_prefilled()[0].index == 0);
+}
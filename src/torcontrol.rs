@@ -0,0 +1,358 @@
+use std::io::BufferedStream;
+use std::io::TcpStream;
+use std::io::File;
+use std::io::net::ip::SocketAddr;
+
+use std::rand::Rng;
+
+use datatype::netaddr::NetAddrV2;
+
+use addrmng::AddrManagerChannel;
+use addrmng::AddrManagerRequest;
+
+use config::Config;
+
+macro_rules! try_io(
+    ($e:expr) => (match $e { Ok(e) => e, Err(_) => return Err(TorControlError::IOError) }))
+
+/* Tor control protocol, to create an onion service that forwards the inbound
+ * connections to us.  The service is ephemeral, tor removes it as soon as the
+ * control connection is closed.
+ *
+ * https://spec.torproject.org/control-spec
+ */
+
+const REPLY_OK : u16 = 250;
+
+const SAFECOOKIE_SERVER_KEY : &'static str
+    = "Tor safe cookie authentication server-to-controller hash";
+const SAFECOOKIE_CLIENT_KEY : &'static str
+    = "Tor safe cookie authentication controller-to-server hash";
+
+const COOKIE_SIZE : uint = 32;
+const NONCE_SIZE : uint = 32;
+
+#[deriving(Show)]
+pub enum TorControlError
+{
+    InvalidListenAddress,
+    ConnectError,
+    IOError,
+    InvalidReply,
+    ReplyError(u16,String),
+    NoAuthMethod,
+    CookieError,
+    ServerHashMismatch,
+    InvalidServiceId
+}
+
+/* Replies are lines of a status code and a text.  The last line has a space
+ * after the code, the others a dash or, if data lines follow, a plus.
+ */
+struct Reply
+{
+    code  : u16,
+    lines : Vec<String>
+}
+
+impl Reply
+{
+    /* Value of a KEY=VALUE pair of the reply, where the value may be a quoted
+     * string.
+     */
+    fn get_value(&self, key : &str) -> Option<String>
+    {
+        let pattern : String = format!("{}=",key);
+
+        for line in self.lines.iter()
+        {
+            let start : uint;
+            let rest : &str;
+
+            start = match line.as_slice().find_str(pattern.as_slice())
+            {
+                Some(pos) if pos == 0 || line.as_bytes()[pos-1] == b' ' => pos+pattern.len(),
+                _                                                      => continue
+            };
+
+            rest = line.as_slice().slice_from(start);
+
+            if rest.starts_with("\"")
+            {
+                return unquote(rest);
+            }
+
+            return Some(rest.split(' ').next().unwrap_or("").to_string());
+        }
+
+        None
+    }
+}
+
+fn unquote(str : &str) -> Option<String>
+{
+    let mut value : String = String::new();
+    let mut escaped : bool = false;
+
+    for c in str.chars().skip(1)
+    {
+        match c
+        {
+            _ if escaped => { value.push(c); escaped = false; },
+            '\\'         => escaped = true,
+            '"'          => return Some(value),
+            _            => value.push(c)
+        }
+    }
+
+    None
+}
+
+fn quote(str : &str) -> String
+{
+    format!("\"{}\"",str.replace("\\","\\\\").replace("\"","\\\""))
+}
+
+pub struct TorControl
+{
+    stream : BufferedStream<TcpStream>
+}
+
+impl TorControl
+{
+    pub fn connect(addr : SocketAddr) -> Result<TorControl,TorControlError>
+    {
+        match TcpStream::connect(addr)
+        {
+            Ok(stream) => Ok(TorControl { stream: BufferedStream::new(stream) }),
+            Err(_)     => Err(TorControlError::ConnectError)
+        }
+    }
+
+    fn read_reply(&mut self) -> Result<Reply,TorControlError>
+    {
+        let mut lines : Vec<String> = Vec::new();
+
+        loop
+        {
+            let line : String = try_io!(self.stream.read_line());
+            let line : &str = line.as_slice().trim_right_chars(['\r','\n'].as_slice());
+            let code : u16;
+
+            if line.len() < 4
+            {
+                return Err(TorControlError::InvalidReply);
+            }
+
+            code = match from_str::<u16>(line.slice_to(3))
+            {
+                Some(code) => code,
+                None       => return Err(TorControlError::InvalidReply)
+            };
+
+            lines.push(line.slice_from(4).to_string());
+
+            match line.char_at(3)
+            {
+                ' ' => return Ok(Reply { code: code, lines: lines }),
+                '-' => (),
+                '+' => try!(self.read_data(&mut lines)),
+                _   => return Err(TorControlError::InvalidReply)
+            }
+        }
+    }
+
+    /* Data lines end with a line with a single dot */
+    fn read_data(&mut self, lines : &mut Vec<String>) -> Result<(),TorControlError>
+    {
+        loop
+        {
+            let line : String = try_io!(self.stream.read_line());
+            let line : &str = line.as_slice().trim_right_chars(['\r','\n'].as_slice());
+
+            if line == "."
+            {
+                return Ok(());
+            }
+
+            lines.push(line.to_string());
+        }
+    }
+
+    fn command(&mut self, command : &str) -> Result<Reply,TorControlError>
+    {
+        let reply : Reply;
+
+        try_io!(self.stream.write_str(command));
+        try_io!(self.stream.write_str("\r\n"));
+        try_io!(self.stream.flush());
+
+        reply = try!(self.read_reply());
+
+        if reply.code != REPLY_OK
+        {
+            return Err(TorControlError::ReplyError(reply.code,reply.lines.connect(" ")));
+        }
+
+        Ok(reply)
+    }
+
+    /* We take the password if there is one and tor accepts it, otherwise the
+     * cookie and, at last, no authentication.
+     */
+    pub fn authenticate(&mut self, password : Option<&str>) -> Result<(),TorControlError>
+    {
+        let protocolinfo : Reply = try!(self.command("PROTOCOLINFO 1"));
+        let methods : Vec<String>;
+
+        methods = match protocolinfo.get_value("METHODS")
+        {
+            Some(methods) => methods.as_slice().split(',').map(|m| m.to_string()).collect(),
+            None          => return Err(TorControlError::InvalidReply)
+        };
+
+        let has = |method : &str| methods.iter().any(|m| m.as_slice() == method);
+
+        match password
+        {
+            Some(password) if has("HASHEDPASSWORD") =>
+            {
+                try!(self.command(format!("AUTHENTICATE {}",quote(password)).as_slice()));
+
+                return Ok(());
+            },
+            _ => ()
+        }
+
+        if has("SAFECOOKIE")
+        {
+            match protocolinfo.get_value("COOKIEFILE")
+            {
+                Some(path) => return self.authenticate_safecookie(path.as_slice()),
+                None       => return Err(TorControlError::InvalidReply)
+            }
+        }
+
+        if has("NULL")
+        {
+            try!(self.command("AUTHENTICATE"));
+
+            return Ok(());
+        }
+
+        Err(TorControlError::NoAuthMethod)
+    }
+
+    /* We prove we can read the cookie without sending it, and tor proves it
+     * knows the cookie too.
+     */
+    fn authenticate_safecookie(&mut self, path : &str) -> Result<(),TorControlError>
+    {
+        let mut client_nonce : [u8, ..NONCE_SIZE] = [0u8, ..NONCE_SIZE];
+        let cookie : Vec<u8>;
+        let challenge : Reply;
+        let server_hash : Vec<u8>;
+        let server_nonce : Vec<u8>;
+        let mut data : Vec<u8>;
+
+        cookie = match File::open(&Path::new(path)).read_to_end()
+        {
+            Ok(ref cookie) if cookie.len() == COOKIE_SIZE => cookie.clone(),
+            _ => return Err(TorControlError::CookieError)
+        };
+
+        ::crypto::rng().fill_bytes(&mut client_nonce);
+
+        challenge = try!(self.command(format!("AUTHCHALLENGE SAFECOOKIE {}",
+                                              ::crypto::to_hexstr(&client_nonce)).as_slice()));
+
+        server_hash = match challenge.get_value("SERVERHASH")
+                                     .and_then(|h| ::crypto::from_hexstr(h.as_slice()))
+        {
+            Some(hash) => hash,
+            None       => return Err(TorControlError::InvalidReply)
+        };
+
+        server_nonce = match challenge.get_value("SERVERNONCE")
+                                      .and_then(|n| ::crypto::from_hexstr(n.as_slice()))
+        {
+            Some(nonce) => nonce,
+            None        => return Err(TorControlError::InvalidReply)
+        };
+
+        data = cookie;
+        data.push_all(&client_nonce);
+        data.push_all(server_nonce.as_slice());
+
+        if ::crypto::hmac_sha256(SAFECOOKIE_SERVER_KEY.as_bytes(),data.as_slice()).as_slice()
+            != server_hash.as_slice()
+        {
+            return Err(TorControlError::ServerHashMismatch);
+        }
+
+        try!(self.command(format!("AUTHENTICATE {}",
+                                  ::crypto::to_hexstr(&::crypto::hmac_sha256(
+                                      SAFECOOKIE_CLIENT_KEY.as_bytes(),
+                                      data.as_slice()))).as_slice()));
+
+        Ok(())
+    }
+
+    /* Creates a v3 onion service with a new key, that we do not keep, and
+     * returns its service id.
+     */
+    pub fn add_onion(&mut self, port : u16, target : SocketAddr) -> Result<String,TorControlError>
+    {
+        let reply : Reply;
+
+        reply = try!(self.command(format!("ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port={},{}",
+                                          port,target).as_slice()));
+
+        match reply.get_value("ServiceID")
+        {
+            Some(id) => Ok(id),
+            None     => Err(TorControlError::InvalidReply)
+        }
+    }
+
+    /* Keeps the control connection, and so the onion service, open until tor
+     * closes it.
+     */
+    pub fn wait(&mut self) -> Result<(),TorControlError>
+    {
+        loop
+        {
+            try!(self.read_reply());
+        }
+    }
+}
+
+/* Creates the onion service and gives its address to the address manager, so
+ * that peers advertise it.
+ */
+pub fn run_onion_service(config          : &Config,
+                         addrmng_channel : AddrManagerChannel) -> Result<(),TorControlError>
+{
+    let mut control : TorControl;
+    let service_id : String;
+    let local : NetAddrV2;
+
+    control = try!(TorControl::connect(config.tor_control));
+
+    try!(control.authenticate(config.tor_control_password.as_ref().map(|p| p.as_slice())));
+
+    service_id = try!(control.add_onion(config.get_params().default_port,config.onion_listen));
+
+    local = match NetAddrV2::from_onion(service_id.as_slice(),config.get_params().default_port,
+                                        config.get_services(),None)
+    {
+        Some(local) => local,
+        None        => return Err(TorControlError::InvalidServiceId)
+    };
+
+    ::logger::log_onion_service(&local);
+
+    addrmng_channel.sender.send(AddrManagerRequest::AddrMngSetLocalAddress(local));
+
+    control.wait()
+}
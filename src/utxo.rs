@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use datatype::transaction::Transaction;
+use datatype::transaction::TxOut;
+use datatype::transaction::OutPoint;
+
+/* An unspent transaction output, along with what we need to know about the
+ * transaction that created it.
+ */
+#[deriving(Clone)]
+pub struct Coin
+{
+    output   : TxOut,
+    height   : u32,       /* Height of the block that contains it */
+    coinbase : bool
+}
+
+#[allow(dead_code)]
+impl Coin
+{
+    pub fn new(output : TxOut, height : u32, coinbase : bool) -> Coin
+    {
+        Coin
+        {
+            output:   output,
+            height:   height,
+            coinbase: coinbase
+        }
+    }
+
+    pub fn get_output(&self) -> &TxOut
+    {
+        &self.output
+    }
+
+    pub fn get_height(&self) -> u32
+    {
+        self.height
+    }
+
+    pub fn is_coinbase(&self) -> bool
+    {
+        self.coinbase
+    }
+}
+
+/* Read access to a set of coins.  This allows validation to work against the
+ * UTXO set as well as on top of other things (e.g. the mempool).
+ */
+pub trait UtxoView
+{
+    fn get_coin(&self, outpoint : &OutPoint) -> Option<Coin>;
+}
+
+pub struct UtxoSet
+{
+    coins : HashMap<OutPoint,Coin>
+}
+
+#[allow(dead_code)]
+impl UtxoSet
+{
+    pub fn new() -> UtxoSet
+    {
+        UtxoSet
+        {
+            coins: HashMap::new()
+        }
+    }
+
+    pub fn len(&self) -> uint
+    {
+        self.coins.len()
+    }
+
+    pub fn contains(&self, outpoint : &OutPoint) -> bool
+    {
+        self.coins.contains_key(outpoint)
+    }
+
+    pub fn add(&mut self, outpoint : OutPoint, coin : Coin)
+    {
+        self.coins.insert(outpoint,coin);
+    }
+
+    pub fn add_tx_outputs(&mut self, tx : &Transaction, height : u32)
+    {
+        let hash = tx.get_hash();
+
+        for i in range(0,tx.get_out_txs().len())
+        {
+            let coin = Coin::new(tx.get_out_txs()[i].clone(),height,tx.is_coinbase());
+
+            self.add(OutPoint::new(hash.clone(),i as u32),coin);
+        }
+    }
+
+    /* Returns the coin spent, if it exists */
+    pub fn spend(&mut self, outpoint : &OutPoint) -> Option<Coin>
+    {
+        self.coins.remove(outpoint)
+    }
+}
+
+impl UtxoView for UtxoSet
+{
+    fn get_coin(&self, outpoint : &OutPoint) -> Option<Coin>
+    {
+        self.coins.get(outpoint).map(|c| c.clone())
+    }
+}
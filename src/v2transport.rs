@@ -0,0 +1,512 @@
+use std::rand::Rng;
+
+use message::header::Header;
+use message::header::HEADER_SIZE;
+
+/* BIP324 v2 transport: after an ElligatorSwift key exchange, every message is
+ * sent as an encrypted and authenticated packet, so the connection looks like
+ * random bytes to anyone watching it.
+ *
+ * We are the initiator of our outbound connections and the responder of the
+ * inbound ones.
+ */
+
+pub const GARBAGE_TERMINATOR_SIZE : uint = 16;
+pub const MAX_GARBAGE_SIZE : uint = 4095;
+
+/* A packet is the encrypted length of its contents, the encrypted header and
+ * contents, and the authentication tag.
+ */
+pub const LENGTH_SIZE : uint = 3;
+const PACKET_HEADER_SIZE : uint = 1;
+const TAG_SIZE : uint = 16;
+pub const PACKET_OVERHEAD : uint = LENGTH_SIZE+PACKET_HEADER_SIZE+TAG_SIZE;
+
+/* Size of the contents before the payload when the message type has no short
+ * id: a zero and the 12 bytes command.
+ */
+pub const MAX_MSG_TYPE_SIZE : uint = 13;
+
+/* The magic and the command of a v1 header */
+pub const V1_PREFIX_SIZE : uint = 16;
+
+/* Set in the header of decoy packets, which are ignored */
+const IGNORE_BIT : u8 = 0x80;
+
+/* Both ciphers of a direction change their key every this many messages */
+const REKEY_INTERVAL : u64 = 224;
+
+/* Message types sent as a single byte, the first one has id 1 */
+const SHORT_IDS : [&'static str, ..28] =
+    ["addr", "block", "blocktxn", "cmpctblock", "feefilter", "filteradd",
+     "filterclear", "filterload", "getblocks", "getblocktxn", "getdata",
+     "getheaders", "headers", "inv", "mempool", "merkleblock", "notfound",
+     "ping", "pong", "sendcmpct", "tx", "getcfilters", "cfilter",
+     "getcfheaders", "cfheaders", "getcfcheckpt", "cfcheckpt", "addrv2"];
+
+/* ChaCha20 as a stream cipher over many small chunks (the packet lengths),
+ * with a new key every REKEY_INTERVAL chunks.
+ */
+struct FSChaCha20
+{
+    key           : [u8, ..32],
+    chunk_counter : u64,
+    block_counter : u32,
+    keystream     : Vec<u8>   /* Unused keystream of the current block */
+}
+
+impl FSChaCha20
+{
+    fn new(key : [u8, ..32]) -> FSChaCha20
+    {
+        FSChaCha20
+        {
+            key:           key,
+            chunk_counter: 0,
+            block_counter: 0,
+            keystream:     Vec::new()
+        }
+    }
+
+    fn get_keystream(&mut self, size : uint) -> Vec<u8>
+    {
+        let mut nonce : [u8, ..12] = [0u8, ..12];
+        let keystream : Vec<u8>;
+
+        for j in range(0u,8)
+        {
+            nonce[4+j] = ((self.chunk_counter/REKEY_INTERVAL) >> 8*j) as u8;
+        }
+
+        while self.keystream.len() < size
+        {
+            let block = ::crypto::chacha20_block(&self.key,&nonce,self.block_counter);
+
+            self.keystream.push_all(&block);
+            self.block_counter += 1;
+        }
+
+        keystream = self.keystream.slice_to(size).to_vec();
+        self.keystream = self.keystream.slice_from(size).to_vec();
+
+        keystream
+    }
+
+    fn crypt(&mut self, data : &mut [u8])
+    {
+        let keystream : Vec<u8> = self.get_keystream(data.len());
+
+        for i in range(0,data.len())
+        {
+            data[i] ^= keystream[i];
+        }
+
+        /* The next key is the next 32 bytes of the keystream */
+        if (self.chunk_counter+1)%REKEY_INTERVAL == 0
+        {
+            let key : Vec<u8> = self.get_keystream(32);
+
+            for i in range(0u,32)
+            {
+                self.key[i] = key[i];
+            }
+
+            self.block_counter = 0;
+            self.keystream.clear();
+        }
+
+        self.chunk_counter += 1;
+    }
+}
+
+/* ChaCha20-Poly1305 with the packet counter as nonce, and a new key every
+ * REKEY_INTERVAL packets.
+ */
+struct FSChaCha20Poly1305
+{
+    key            : [u8, ..32],
+    packet_counter : u64
+}
+
+impl FSChaCha20Poly1305
+{
+    fn new(key : [u8, ..32]) -> FSChaCha20Poly1305
+    {
+        FSChaCha20Poly1305
+        {
+            key:            key,
+            packet_counter: 0
+        }
+    }
+
+    fn get_nonce(&self) -> [u8, ..12]
+    {
+        let mut nonce : [u8, ..12] = [0u8, ..12];
+
+        for j in range(0u,4)
+        {
+            nonce[j] = ((self.packet_counter%REKEY_INTERVAL) >> 8*j) as u8;
+        }
+
+        for j in range(0u,8)
+        {
+            nonce[4+j] = ((self.packet_counter/REKEY_INTERVAL) >> 8*j) as u8;
+        }
+
+        nonce
+    }
+
+    fn next_packet(&mut self)
+    {
+        /* The next key is the encryption of zeros with a nonce no packet
+         * uses.
+         */
+        if (self.packet_counter+1)%REKEY_INTERVAL == 0
+        {
+            let mut nonce : [u8, ..12] = self.get_nonce();
+            let key : Vec<u8>;
+
+            for j in range(0u,4)
+            {
+                nonce[j] = 0xff;
+            }
+
+            key = ::crypto::aead_chacha20_poly1305_encrypt(&self.key,&nonce,&[],&[0u8, ..32]);
+
+            for i in range(0u,32)
+            {
+                self.key[i] = key[i];
+            }
+        }
+
+        self.packet_counter += 1;
+    }
+
+    fn encrypt(&mut self, aad : &[u8], plain : &[u8]) -> Vec<u8>
+    {
+        let nonce : [u8, ..12] = self.get_nonce();
+        let data : Vec<u8>;
+
+        data = ::crypto::aead_chacha20_poly1305_encrypt(&self.key,&nonce,aad,plain);
+
+        self.next_packet();
+
+        data
+    }
+
+    fn decrypt(&mut self, aad : &[u8], data : &[u8]) -> Option<Vec<u8>>
+    {
+        let nonce : [u8, ..12] = self.get_nonce();
+        let plain : Option<Vec<u8>>;
+
+        plain = ::crypto::aead_chacha20_poly1305_decrypt(&self.key,&nonce,aad,data);
+
+        self.next_packet();
+
+        plain
+    }
+}
+
+/* The ciphers of one direction of a connection */
+pub struct V2Cipher
+{
+    length_cipher : FSChaCha20,
+    packet_cipher : FSChaCha20Poly1305,
+    /* Garbage of the sender, authenticated with its first packet */
+    aad           : Vec<u8>,
+    /* Whether we got the version packet, the first one that is not a decoy */
+    version_done  : bool
+}
+
+impl V2Cipher
+{
+    fn new(length_key : [u8, ..32], packet_key : [u8, ..32]) -> V2Cipher
+    {
+        V2Cipher
+        {
+            length_cipher: FSChaCha20::new(length_key),
+            packet_cipher: FSChaCha20Poly1305::new(packet_key),
+            aad:           Vec::new(),
+            version_done:  false
+        }
+    }
+
+    pub fn set_garbage(&mut self, garbage : Vec<u8>)
+    {
+        self.aad = garbage;
+    }
+
+    pub fn encrypt_packet(&mut self, contents : &[u8], ignore : bool) -> Vec<u8>
+    {
+        let mut packet : Vec<u8> = Vec::with_capacity(contents.len()+PACKET_OVERHEAD);
+        let mut plain : Vec<u8> = Vec::with_capacity(contents.len()+PACKET_HEADER_SIZE);
+        let aad : Vec<u8> = ::std::mem::replace(&mut self.aad,Vec::new());
+        let encrypted : Vec<u8>;
+
+        for j in range(0u,LENGTH_SIZE)
+        {
+            packet.push((contents.len() >> 8*j) as u8);
+        }
+
+        self.length_cipher.crypt(packet.as_mut_slice());
+
+        plain.push(if ignore { IGNORE_BIT } else { 0 });
+        plain.push_all(contents);
+
+        encrypted = self.packet_cipher.encrypt(aad.as_slice(),plain.as_slice());
+
+        packet.push_all(encrypted.as_slice());
+
+        packet
+    }
+
+    /* Put a serialized v1 message (header and payload) in a packet */
+    pub fn encrypt_message(&mut self, msg : &Vec<u8>) -> Vec<u8>
+    {
+        let header : Header = Header::unserialize(msg);
+        let contents : Vec<u8>;
+
+        contents = encode_contents(header.get_command().as_slice(),
+                                   msg.slice_from(HEADER_SIZE));
+
+        self.encrypt_packet(contents.as_slice(),false)
+    }
+
+    /* Size of the contents of the packet, the rest of the packet is this
+     * plus PACKET_OVERHEAD-LENGTH_SIZE.
+     */
+    pub fn decrypt_length(&mut self, data : &[u8]) -> uint
+    {
+        let mut length : [u8, ..3] = [data[0], data[1], data[2]];
+
+        self.length_cipher.crypt(&mut length);
+
+        (length[0] as uint) | (length[1] as uint << 8) | (length[2] as uint << 16)
+    }
+
+    /* Decrypt the packet after its length.  Returns None if it is not
+     * authentic, and whether it must be ignored along with its contents
+     * otherwise.  The contents of the version packet are reserved for future
+     * extensions, so it is ignored too.
+     */
+    pub fn decrypt_packet(&mut self, data : &[u8]) -> Option<(bool, Vec<u8>)>
+    {
+        let aad : Vec<u8> = ::std::mem::replace(&mut self.aad,Vec::new());
+        let plain : Vec<u8>;
+        let mut ignore : bool;
+
+        plain = match self.packet_cipher.decrypt(aad.as_slice(),data)
+        {
+            Some(plain) => plain,
+            None        => return None
+        };
+
+        ignore = plain[0] & IGNORE_BIT != 0;
+
+        if !ignore && !self.version_done
+        {
+            self.version_done = true;
+            ignore = true;
+        }
+
+        Some((ignore, plain.slice_from(PACKET_HEADER_SIZE).to_vec()))
+    }
+}
+
+/* The contents of a packet are the message type, as a short id or a zero
+ * followed by the 12 bytes command, and the payload.
+ */
+pub fn encode_contents(command : &str, payload : &[u8]) -> Vec<u8>
+{
+    let mut contents = ::marshalling::Marshalling::new();
+
+    match SHORT_IDS.iter().position(|c| *c == command)
+    {
+        Some(i) => contents.write(&[(i+1) as u8]),
+        None    =>
+        {
+            contents.write(&[0u8]);
+            contents.write_str12(&command.to_string());
+        }
+    }
+
+    contents.write(payload);
+
+    contents.get()
+}
+
+/* Returns the command and the size of the message type, the payload comes
+ * after it.  Unknown short ids give an empty command.
+ */
+pub fn decode_contents(contents : &[u8]) -> Option<(String, uint)>
+{
+    if contents.is_empty()
+    {
+        return None;
+    }
+
+    match contents[0] as uint
+    {
+        0 =>
+        {
+            let mut unmarshalling : ::marshalling::Unmarshalling;
+
+            if contents.len() < MAX_MSG_TYPE_SIZE
+            {
+                return None;
+            }
+
+            unmarshalling = ::marshalling::Unmarshalling::new(
+                &contents.slice(1,MAX_MSG_TYPE_SIZE).to_vec());
+
+            Some((unmarshalling.read_str12(), MAX_MSG_TYPE_SIZE))
+        },
+        id if id <= SHORT_IDS.len() => Some((SHORT_IDS[id-1].to_string(), 1)),
+        _                           => Some((String::new(), 1))
+    }
+}
+
+/* The ciphers and garbage terminators of a connection, once the keys are
+ * exchanged.
+ */
+pub struct V2Session
+{
+    pub send            : V2Cipher,
+    pub recv            : V2Cipher,
+    pub send_terminator : Vec<u8>,
+    pub recv_terminator : Vec<u8>,
+    pub session_id      : [u8, ..32]
+}
+
+/* What a v1 peer sends first: the magic and the version command.  A
+ * responder tells v1 peers apart with it, since no key starts like this.
+ */
+pub fn v1_prefix(magic : u32) -> Vec<u8>
+{
+    Header::new(magic,"version".to_string(),0,0).serialize()
+        .slice_to(V1_PREFIX_SIZE).to_vec()
+}
+
+/* Our side of the key exchange */
+pub struct V2Handshake
+{
+    initiator : bool,
+    secret    : [u8, ..32],
+    ellswift  : [u8, ..64],
+    garbage   : Vec<u8>
+}
+
+impl V2Handshake
+{
+    pub fn new(initiator : bool) -> V2Handshake
+    {
+        let secret : [u8, ..32] = ::secp256k1::random_secret();
+        let mut garbage : Vec<u8>;
+
+        garbage = Vec::from_elem(::crypto::rand_interval(0,MAX_GARBAGE_SIZE),0u8);
+
+        ::crypto::rng().fill_bytes(garbage.as_mut_slice());
+
+        V2Handshake
+        {
+            initiator: initiator,
+            secret:    secret,
+            ellswift:  ::secp256k1::ellswift_create(&secret),
+            garbage:   garbage
+        }
+    }
+
+    /* Only the tests pick the keys, to check known results */
+    #[cfg(test)]
+    pub fn from_keys(initiator : bool,
+                     secret    : [u8, ..32],
+                     ellswift  : [u8, ..64],
+                     garbage   : Vec<u8>) -> V2Handshake
+    {
+        V2Handshake
+        {
+            initiator: initiator,
+            secret:    secret,
+            ellswift:  ellswift,
+            garbage:   garbage
+        }
+    }
+
+    /* What we send first: our public key and the garbage */
+    pub fn get_key_and_garbage(&self) -> Vec<u8>
+    {
+        let mut data : Vec<u8> = self.ellswift.to_vec();
+
+        data.push_all(self.garbage.as_slice());
+
+        data
+    }
+
+    /* Derive the keys of both directions from the public key of the peer and
+     * the magic of the network.  Returns None if the shared secret is the
+     * point at infinity.
+     */
+    pub fn complete(self, theirs : &[u8], magic : u32) -> Option<V2Session>
+    {
+        let mut ecdh_data : Vec<u8> = Vec::with_capacity(3*32+32);
+        let mut salt : Vec<u8> = "bitcoin_v2_shared_secret".as_bytes().to_vec();
+        let shared_secret : [u8, ..32];
+        let prk : [u8, ..32];
+        let terminators : [u8, ..32];
+        let mut send : V2Cipher;
+        let recv : V2Cipher;
+        let (ours, theirs_side) = if self.initiator { ("initiator","responder") }
+                                  else { ("responder","initiator") };
+
+        match ::secp256k1::ellswift_xdh(&self.secret,theirs)
+        {
+            Some(x) if self.initiator =>
+            {
+                ecdh_data.push_all(&self.ellswift);
+                ecdh_data.push_all(theirs);
+                ecdh_data.push_all(&x);
+            },
+            Some(x) =>
+            {
+                ecdh_data.push_all(theirs);
+                ecdh_data.push_all(&self.ellswift);
+                ecdh_data.push_all(&x);
+            },
+            None    => return None
+        }
+
+        shared_secret = ::crypto::tagged_sha256("bip324_ellswift_xonly_ecdh",
+                                                ecdh_data.as_slice());
+
+        for j in range(0u,4)
+        {
+            salt.push((magic >> 8*j) as u8);
+        }
+
+        prk = ::crypto::hkdf_sha256_extract(salt.as_slice(),&shared_secret);
+
+        let expand = |info : &str| ::crypto::hkdf_sha256_expand32(&prk,info.as_bytes());
+
+        terminators = expand("garbage_terminators");
+
+        send = V2Cipher::new(expand(format!("{}_L",ours).as_slice()),
+                             expand(format!("{}_P",ours).as_slice()));
+        send.set_garbage(self.garbage);
+
+        recv = V2Cipher::new(expand(format!("{}_L",theirs_side).as_slice()),
+                             expand(format!("{}_P",theirs_side).as_slice()));
+
+        /* The initiator terminator comes first */
+        let (first, second) = (terminators.slice_to(GARBAGE_TERMINATOR_SIZE).to_vec(),
+                               terminators.slice_from(GARBAGE_TERMINATOR_SIZE).to_vec());
+
+        Some(V2Session
+        {
+            send:            send,
+            recv:            recv,
+            send_terminator: if self.initiator { first.clone() } else { second.clone() },
+            recv_terminator: if self.initiator { second } else { first },
+            session_id:      expand("session_id")
+        })
+    }
+}